@@ -0,0 +1,230 @@
+use parse_int::parse;
+
+use crate::gbc::{cpu::CpuRegister, Gbc};
+
+#[derive(Clone, Debug, PartialEq, Eq)]
+enum Token {
+    Word(String),
+    Plus,
+    Minus,
+    Star,
+    OpenParen,
+    CloseParen,
+    OpenBracket,
+    CloseBracket,
+}
+
+fn tokenize(text: &str) -> Result<Vec<Token>, String> {
+    let mut tokens = Vec::new();
+    let mut word = String::new();
+    for c in text.chars() {
+        let token = match c {
+            '+' => Token::Plus,
+            '-' => Token::Minus,
+            '*' => Token::Star,
+            '(' => Token::OpenParen,
+            ')' => Token::CloseParen,
+            '[' => Token::OpenBracket,
+            ']' => Token::CloseBracket,
+            c if c.is_alphanumeric() || c == '_' || c == ':' || c == '.' => {
+                word.push(c);
+                continue;
+            }
+            c if c.is_whitespace() => {
+                if !word.is_empty() {
+                    tokens.push(Token::Word(std::mem::take(&mut word)));
+                }
+                continue;
+            }
+            c => return Err(format!("unexpected {}", c)),
+        };
+        if !word.is_empty() {
+            tokens.push(Token::Word(std::mem::take(&mut word)));
+        }
+        tokens.push(token);
+    }
+    if !word.is_empty() {
+        tokens.push(Token::Word(word));
+    }
+    Ok(tokens)
+}
+
+struct Evaluator<'a> {
+    tokens: Vec<Token>,
+    position: usize,
+    gbc: &'a mut Gbc,
+}
+
+impl Evaluator<'_> {
+    fn peek(&self) -> Option<&Token> {
+        self.tokens.get(self.position)
+    }
+
+    fn next(&mut self) -> Option<Token> {
+        let token = self.tokens.get(self.position).cloned();
+        self.position += 1;
+        token
+    }
+
+    fn expect(&mut self, expected: &Token, what: &str) -> Result<(), String> {
+        match self.next() {
+            Some(ref token) if token == expected => Ok(()),
+            _ => Err(format!("missing {}", what)),
+        }
+    }
+
+    // sum := product (('+' | '-') product)*
+    fn sum(&mut self) -> Result<u16, String> {
+        let mut value = self.product()?;
+        loop {
+            match self.peek() {
+                Some(Token::Plus) => {
+                    self.position += 1;
+                    value = value.wrapping_add(self.product()?);
+                }
+                Some(Token::Minus) => {
+                    self.position += 1;
+                    value = value.wrapping_sub(self.product()?);
+                }
+                _ => return Ok(value),
+            }
+        }
+    }
+
+    // product := unary ('*' unary)*
+    fn product(&mut self) -> Result<u16, String> {
+        let mut value = self.unary()?;
+        while self.peek() == Some(&Token::Star) {
+            self.position += 1;
+            value = value.wrapping_mul(self.unary()?);
+        }
+        Ok(value)
+    }
+
+    // unary := '-' unary | atom
+    fn unary(&mut self) -> Result<u16, String> {
+        if self.peek() == Some(&Token::Minus) {
+            self.position += 1;
+            return Ok(self.unary()?.wrapping_neg());
+        }
+        self.atom()
+    }
+
+    // atom := word | '(' sum ')' | '[' sum ']'
+    fn atom(&mut self) -> Result<u16, String> {
+        match self.next() {
+            Some(Token::Word(word)) => self.word(&word),
+            Some(Token::OpenParen) => {
+                let value = self.sum()?;
+                self.expect(&Token::CloseParen, ")")?;
+                Ok(value)
+            }
+            Some(Token::OpenBracket) => {
+                let address = self.sum()?;
+                self.expect(&Token::CloseBracket, "]")?;
+                let low = self.gbc.peek_memory(address);
+                let high = self.gbc.peek_memory(address.wrapping_add(1));
+                Ok(u16::from_le_bytes([low, high]))
+            }
+            Some(_) => Err("expected a number, register or symbol".to_string()),
+            None => Err("unexpected end of expression".to_string()),
+        }
+    }
+
+    fn word(&self, word: &str) -> Result<u16, String> {
+        if word.starts_with(|c: char| c.is_ascii_digit()) {
+            return parse(word).map_err(|e| format!("{}: {}", word, e));
+        }
+        if let Ok(register) = word.parse::<CpuRegister>() {
+            return Ok(self.gbc.get_registers().get(register));
+        }
+        match self.gbc.get_symbols().find(word) {
+            Some(symbol) => Ok(symbol.address),
+            None => Err(format!("unknown register or symbol {}", word)),
+        }
+    }
+}
+
+/// Evaluates a debugger argument such as `hl+4`, `pc+0x20`, `[sp]` or
+/// `Main::loop+2*3`. Values are numbers as `parse_int` reads them, register
+/// names and symbols, in that order, combined with `+`, `-`, `*` and
+/// parentheses. `[x]` reads the little endian 16 bit value at `x`, without
+/// triggering watchpoints. Everything wraps around at 16 bits.
+pub fn evaluate(text: &str, gbc: &mut Gbc) -> Result<u16, String> {
+    let mut evaluator = Evaluator {
+        tokens: tokenize(text)?,
+        position: 0,
+        gbc,
+    };
+    let value = evaluator.sum()?;
+    match evaluator.peek() {
+        None => Ok(value),
+        Some(_) => Err("unexpected text after expression".to_string()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gbc::InputState;
+    use std::sync::atomic::AtomicBool;
+    use std::sync::mpsc::sync_channel;
+    use std::sync::{Arc, Mutex};
+
+    fn create_gbc() -> Gbc {
+        let (frame_sender, _) = sync_channel(1);
+        Gbc::from_rom_bytes(
+            &[0; 0x8000],
+            frame_sender,
+            Arc::new(AtomicBool::new(true)),
+            false,
+            false,
+            Arc::new(Mutex::new(InputState::default())),
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn test_precedence() {
+        let mut gbc = create_gbc();
+        assert_eq!(evaluate("2+3*4", &mut gbc), Ok(14));
+        assert_eq!(evaluate("(2+3)*4", &mut gbc), Ok(20));
+        assert_eq!(evaluate("10-2-3", &mut gbc), Ok(5));
+        assert_eq!(evaluate("-2*-3", &mut gbc), Ok(6));
+        assert_eq!(evaluate(" 0x10 + 0b11 ", &mut gbc), Ok(0x13));
+    }
+
+    #[test]
+    fn test_wrapping() {
+        let mut gbc = create_gbc();
+        assert_eq!(evaluate("0xffff+2", &mut gbc), Ok(1));
+        assert_eq!(evaluate("1-2", &mut gbc), Ok(0xffff));
+        assert_eq!(evaluate("-1", &mut gbc), Ok(0xffff));
+        assert_eq!(evaluate("0x100*0x100", &mut gbc), Ok(0));
+    }
+
+    #[test]
+    fn test_registers_and_deref() {
+        let mut gbc = create_gbc();
+        gbc.set_register(CpuRegister::Hl, 0xc000);
+        gbc.write_memory(0xc004, &[0x34, 0x12]);
+        assert_eq!(evaluate("hl+4", &mut gbc), Ok(0xc004));
+        assert_eq!(evaluate("[hl+4]", &mut gbc), Ok(0x1234));
+        assert_eq!(evaluate("[hl+4]+1", &mut gbc), Ok(0x1235));
+    }
+
+    #[test]
+    fn test_errors() {
+        let mut gbc = create_gbc();
+        assert!(evaluate("1 2", &mut gbc).is_err());
+        assert!(evaluate("(1+2", &mut gbc).is_err());
+        assert!(evaluate("1)", &mut gbc).is_err());
+        assert!(evaluate("[1", &mut gbc).is_err());
+        assert!(evaluate("1+", &mut gbc).is_err());
+        assert!(evaluate("", &mut gbc).is_err());
+        assert!(evaluate("1/2", &mut gbc).is_err());
+        assert!(evaluate("1+*2", &mut gbc).is_err());
+        assert!(evaluate("nowhere", &mut gbc).is_err());
+        assert!(evaluate("0xfg", &mut gbc).is_err());
+    }
+}
@@ -0,0 +1,283 @@
+use crate::gbc::Gbc;
+
+const INTERRUPT_NAMES: [&str; 5] = ["vblank", "stat", "timer", "serial", "joypad"];
+
+fn pick<'a>(value: u8, bit: u8, set: &'a str, clear: &'a str) -> &'a str {
+    if value & (1 << bit) != 0 {
+        set
+    } else {
+        clear
+    }
+}
+
+fn interrupts(value: u8) -> String {
+    let names: Vec<&str> = INTERRUPT_NAMES
+        .iter()
+        .enumerate()
+        .filter(|&(bit, _)| value & (1 << bit) != 0)
+        .map(|(_, &name)| name)
+        .collect();
+    if names.is_empty() {
+        "none".to_string()
+    } else {
+        names.join(" ")
+    }
+}
+
+fn palette(value: u8) -> String {
+    format!(
+        "{} {} {} {}",
+        value & 3,
+        (value >> 2) & 3,
+        (value >> 4) & 3,
+        (value >> 6) & 3
+    )
+}
+
+fn joypad(value: u8) -> String {
+    let selected = match (value >> 4) & 3 {
+        0 => "both",
+        1 => "buttons",
+        2 => "directions",
+        _ => "none",
+    };
+    // Inputs read 0 when pressed
+    let pressed: Vec<String> = (0..4)
+        .filter(|bit| value & (1 << bit) == 0)
+        .map(|bit| bit.to_string())
+        .collect();
+    format!("select {}, pressed lines [{}]", selected, pressed.join(" "))
+}
+
+fn serial_control(value: u8) -> String {
+    format!(
+        "{}, {} clock",
+        pick(value, 7, "transferring", "idle"),
+        pick(value, 0, "internal", "external")
+    )
+}
+
+fn timer_control(value: u8) -> String {
+    let frequency = match value & 3 {
+        0 => "4096 Hz",
+        1 => "262144 Hz",
+        2 => "65536 Hz",
+        _ => "16384 Hz",
+    };
+    format!("timer {}, {}", pick(value, 2, "on", "off"), frequency)
+}
+
+fn lcd_control(value: u8) -> String {
+    [
+        pick(value, 7, "LCD on", "LCD off"),
+        pick(value, 6, "window map 9c00", "window map 9800"),
+        pick(value, 5, "window on", "window off"),
+        pick(value, 4, "tiles 8000", "tiles 8800"),
+        pick(value, 3, "bg map 9c00", "bg map 9800"),
+        pick(value, 2, "8x16 objects", "8x8 objects"),
+        pick(value, 1, "objects on", "objects off"),
+        pick(value, 0, "bg on", "bg off"),
+    ]
+    .join(", ")
+}
+
+fn lcd_status(value: u8) -> String {
+    let mode = match value & 3 {
+        0 => "0 (hblank)",
+        1 => "1 (vblank)",
+        2 => "2 (OAM scan)",
+        _ => "3 (drawing)",
+    };
+    let sources: Vec<&str> = [(6, "lyc"), (5, "oam"), (4, "vblank"), (3, "hblank")]
+        .iter()
+        .filter(|&&(bit, _)| value & (1 << bit) != 0)
+        .map(|&(_, name)| name)
+        .collect();
+    format!(
+        "mode {}, ly=lyc {}, interrupts on [{}]",
+        mode,
+        pick(value, 2, "yes", "no"),
+        sources.join(" ")
+    )
+}
+
+fn sound_control(value: u8) -> String {
+    let playing: Vec<String> = (0..4)
+        .filter(|bit| value & (1 << bit) != 0)
+        .map(|bit| (bit + 1).to_string())
+        .collect();
+    format!(
+        "sound {}, channels playing [{}]",
+        pick(value, 7, "on", "off"),
+        playing.join(" ")
+    )
+}
+
+fn duty(value: u8) -> String {
+    let duty = match value >> 6 {
+        0 => "12.5%",
+        1 => "25%",
+        2 => "50%",
+        _ => "75%",
+    };
+    format!("duty {}, length {}", duty, value & 0x3f)
+}
+
+fn envelope(value: u8) -> String {
+    format!(
+        "volume {}, {}, pace {}",
+        value >> 4,
+        pick(value, 3, "up", "down"),
+        value & 7
+    )
+}
+
+fn trigger(value: u8) -> String {
+    format!(
+        "trigger {}, length {}, period high {}",
+        pick(value, 7, "yes", "no"),
+        pick(value, 6, "on", "off"),
+        value & 7
+    )
+}
+
+fn sweep(value: u8) -> String {
+    format!(
+        "pace {}, {}, step {}",
+        (value >> 4) & 7,
+        pick(value, 3, "down", "up"),
+        value & 7
+    )
+}
+
+fn wave_dac(value: u8) -> String {
+    pick(value, 7, "DAC on", "DAC off").to_string()
+}
+
+fn wave_level(value: u8) -> String {
+    let level = match (value >> 5) & 3 {
+        0 => "mute",
+        1 => "100%",
+        2 => "50%",
+        _ => "25%",
+    };
+    format!("volume {}", level)
+}
+
+fn noise(value: u8) -> String {
+    format!(
+        "shift {}, {} bit, divider {}",
+        value >> 4,
+        pick(value, 3, "7", "15"),
+        value & 7
+    )
+}
+
+fn panning(value: u8) -> String {
+    let channels = |shift: u8| -> String {
+        (0..4)
+            .filter(|bit| value & (1 << (bit + shift)) != 0)
+            .map(|bit| (bit + 1).to_string())
+            .collect::<Vec<_>>()
+            .join(" ")
+    };
+    format!("left [{}], right [{}]", channels(4), channels(0))
+}
+
+fn master_volume(value: u8) -> String {
+    format!("left {}, right {}", (value >> 4) & 7, value & 7)
+}
+
+fn plain(value: u8) -> String {
+    format!("{}", value)
+}
+
+fn boot_rom(value: u8) -> String {
+    if value == 0 {
+        "boot ROM mapped".to_string()
+    } else {
+        "boot ROM unmapped".to_string()
+    }
+}
+
+type Decoder = fn(u8) -> String;
+
+const REGISTERS: [(u16, &str, Decoder); 42] = [
+    (0xff00, "P1", joypad),
+    (0xff01, "SB", plain),
+    (0xff02, "SC", serial_control),
+    (0xff04, "DIV", plain),
+    (0xff05, "TIMA", plain),
+    (0xff06, "TMA", plain),
+    (0xff07, "TAC", timer_control),
+    (0xff0f, "IF", interrupts),
+    (0xff10, "NR10", sweep),
+    (0xff11, "NR11", duty),
+    (0xff12, "NR12", envelope),
+    (0xff13, "NR13", plain),
+    (0xff14, "NR14", trigger),
+    (0xff16, "NR21", duty),
+    (0xff17, "NR22", envelope),
+    (0xff18, "NR23", plain),
+    (0xff19, "NR24", trigger),
+    (0xff1a, "NR30", wave_dac),
+    (0xff1b, "NR31", plain),
+    (0xff1c, "NR32", wave_level),
+    (0xff1d, "NR33", plain),
+    (0xff1e, "NR34", trigger),
+    (0xff20, "NR41", |v| format!("length {}", v & 0x3f)),
+    (0xff21, "NR42", envelope),
+    (0xff22, "NR43", noise),
+    (0xff23, "NR44", trigger),
+    (0xff24, "NR50", master_volume),
+    (0xff25, "NR51", panning),
+    (0xff26, "NR52", sound_control),
+    (0xff40, "LCDC", lcd_control),
+    (0xff41, "STAT", lcd_status),
+    (0xff42, "SCY", plain),
+    (0xff43, "SCX", plain),
+    (0xff44, "LY", plain),
+    (0xff45, "LYC", plain),
+    (0xff46, "DMA", |v| format!("from {:04x}", u16::from(v) << 8)),
+    (0xff47, "BGP", palette),
+    (0xff48, "OBP0", palette),
+    (0xff49, "OBP1", palette),
+    (0xff4a, "WY", plain),
+    (0xff4b, "WX", plain),
+    (0xff50, "BOOT", boot_rom),
+];
+
+/// Prints every I/O register in FF00-FF7F this emulator knows about with
+/// its fields decoded, plus IE at FFFF. Registers are peeked, so reading
+/// them here has no side effects. If `filter` is given, only registers
+/// whose name contains it are shown.
+pub fn print(gbc: &mut Gbc, filter: Option<&str>) {
+    let filter = filter.map(str::to_uppercase);
+    let shown = |name: &str| match &filter {
+        Some(filter) => name.contains(filter.as_str()),
+        None => true,
+    };
+
+    for &(address, name, decode) in &REGISTERS {
+        if shown(name) {
+            let value = gbc.peek_memory(address);
+            println!(
+                "{:04x} {:<5} {:02x}  {}",
+                address,
+                name,
+                value,
+                decode(value)
+            );
+        }
+    }
+    if shown("WAVE") {
+        let wave: Vec<String> = (0xff30..=0xff3f)
+            .map(|address| format!("{:02x}", gbc.peek_memory(address)))
+            .collect();
+        println!("ff30 WAVE      {}", wave.concat());
+    }
+    if shown("IE") {
+        let value = gbc.peek_memory(0xffff);
+        println!("ffff {:<5} {:02x}  {}", "IE", value, interrupts(value));
+    }
+}
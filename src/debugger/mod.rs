@@ -1,11 +1,24 @@
-use std::{fmt, fmt::Display};
+use std::{collections::BTreeMap, convert::TryFrom, fmt, fmt::Display, fs, path::Path};
 
 use rustyline::{error::ReadlineError, Editor};
 
-use crate::gbc::{Gbc, debug::{AccessType, BreakReason}, ppu::TileAddressingMethod};
+use crate::gbc::{
+    cpu::{assembler, CpuError, CpuRegister},
+    debug::{AccessType, BreakReason, RomPatch, WatchCondition},
+    mmio::{
+        lcd::{DOTS_PER_FRAME, DOTS_PER_LINE},
+        serial::SerialDeviceKind,
+    },
+    png,
+    ppu::TileAddressingMethod,
+    Gbc, StopCondition,
+};
 
 use parse_int::parse;
 
+pub mod expression;
+pub mod io_registers;
+
 #[derive(Debug)]
 enum TokenizerError {
     UnmatchedQuote(char),
@@ -51,6 +64,30 @@ fn tokenize_line(line: &str) -> Result<Vec<String>, TokenizerError> {
     Ok(tokens)
 }
 
+/// Parses indices into a list `count` long, given as any mix of separate
+/// arguments, comma separated lists and ranges like `2-5`
+fn parse_indices(args: &[String], count: usize) -> Result<Vec<usize>, String> {
+    let mut indices = Vec::new();
+    for index_str in args.iter().flat_map(|i| i.split(',')) {
+        let (first, last) = index_str.split_once('-').unwrap_or((index_str, index_str));
+        let parse_index = |s: &str| -> Result<usize, String> {
+            parse(s).map_err(|e| format!("Invalid index {}: {}", index_str, e))
+        };
+        let first = parse_index(first)?;
+        let last = parse_index(last)?;
+        if last < first {
+            return Err(format!("Invalid range {}", index_str));
+        }
+        if last >= count {
+            return Err(format!("Unknown index {}", last));
+        }
+        indices.extend(first..=last);
+    }
+    indices.sort_unstable();
+    indices.dedup();
+    Ok(indices)
+}
+
 enum Command {
     Unknown,
     Exit,
@@ -59,14 +96,55 @@ enum Command {
     AddBreakpoint,
     ListBreakpoints,
     DeleteBreakpoint,
+    EnableBreakpoint,
+    DisableBreakpoint,
+    IgnoreBreakpoint,
+    Watch,
+    TemporaryBreakpoint,
+    InterruptBreakpoint,
+    RstBreakpoint,
+    Until,
     Run,
     Step,
+    Next,
+    Finish,
+    Frame,
+    RunFor,
+    Backtrace,
+    History,
+    Symbols,
     Read,
+    Write,
+    SetRegister,
+    Trace,
+    Display,
+    Undisplay,
     Disassemble,
     PrintHeaderDetails,
     DumpTileMap,
     DumpTiles,
     DumpSprites,
+    Ppu,
+    Io,
+    Time,
+    Banks,
+    Rng,
+    Savepoint,
+    SaveRam,
+    LoadRam,
+    SaveState,
+    LoadState,
+    Audio,
+    Assemble,
+    Patch,
+    TimeIt,
+    Profile,
+    Coverage,
+    Budget,
+    Serial,
+    Source,
+    Alias,
+    Unalias,
 }
 
 impl Command {
@@ -78,14 +156,55 @@ impl Command {
             "break" | "breakpoint" | "b" | "bp" => Command::AddBreakpoint,
             "list" | "bl" | "lb" | "listbreak" => Command::ListBreakpoints,
             "bc" | "delete" | "del" | "clear" | "clearbreak" | "cb" => Command::DeleteBreakpoint,
+            "enable" | "be" => Command::EnableBreakpoint,
+            "disable" | "bd" => Command::DisableBreakpoint,
+            "ignore" => Command::IgnoreBreakpoint,
+            "watch" | "wp" => Command::Watch,
+            "tbreak" | "tb" => Command::TemporaryBreakpoint,
+            "break-int" | "bint" => Command::InterruptBreakpoint,
+            "break-rst" | "brst" => Command::RstBreakpoint,
+            "until" | "runto" => Command::Until,
             "r" | "run" | "g" | "go" => Command::Run,
-            "s" | "step" | "n" | "next" => Command::Step,
+            "s" | "step" => Command::Step,
+            "n" | "next" | "over" => Command::Next,
+            "finish" | "fin" | "out" => Command::Finish,
+            "frame" | "fr" => Command::Frame,
+            "runfor" | "rf" => Command::RunFor,
+            "bt" | "backtrace" | "where" => Command::Backtrace,
+            "history" | "hist" => Command::History,
+            "symbols" | "sym" => Command::Symbols,
             "p" | "print" | "read" | "readmem" => Command::Read,
+            "write" | "writemem" | "poke" => Command::Write,
+            "set" | "setreg" => Command::SetRegister,
+            "trace" => Command::Trace,
+            "display" | "disp" => Command::Display,
+            "undisplay" | "undisp" => Command::Undisplay,
             "disassemble" | "dis" | "disass" | "u" => Command::Disassemble,
             "header" => Command::PrintHeaderDetails,
             "tilemap" => Command::DumpTileMap,
             "tiles" => Command::DumpTiles,
             "sprites" => Command::DumpSprites,
+            "ppu" | "lcd" => Command::Ppu,
+            "io" | "ioregs" => Command::Io,
+            "time" | "clock" => Command::Time,
+            "banks" | "mbc" => Command::Banks,
+            "rng" => Command::Rng,
+            "savepoint" | "savepoints" | "sp" => Command::Savepoint,
+            "savram" | "saveram" => Command::SaveRam,
+            "loadram" => Command::LoadRam,
+            "savestate" => Command::SaveState,
+            "loadstate" => Command::LoadState,
+            "audio" | "apu" | "sound" => Command::Audio,
+            "asm" | "assemble" | "a" => Command::Assemble,
+            "patch" | "patches" => Command::Patch,
+            "timeit" => Command::TimeIt,
+            "profile" | "prof" => Command::Profile,
+            "coverage" | "cov" => Command::Coverage,
+            "budget" | "vblank" => Command::Budget,
+            "serial" | "sio" => Command::Serial,
+            "source" | "." => Command::Source,
+            "alias" => Command::Alias,
+            "unalias" => Command::Unalias,
             _ => Command::Unknown,
         }
     }
@@ -93,21 +212,56 @@ impl Command {
 
 const LINE_LENGTH: u16 = 4;
 
+// Deep enough for scripts sourcing each other, shallow enough to stop one
+// sourcing itself
+const MAX_SOURCE_DEPTH: usize = 8;
+// How long timeit waits for an address to be reached, 10 seconds
+const TIME_IT_CYCLE_LIMIT: u64 = 10 * 4_194_304;
+
+/// Where pressing enter after a `read` carries on dumping from
+struct ReadContinuation {
+    address: u16,
+    length: u16,
+    width: u16,
+}
+
 pub struct Debugger {
     gbc: Gbc,
+    // Expressions printed every time execution stops
+    displays: Vec<String>,
+    // Command names standing for a command line, arguments are appended
+    aliases: BTreeMap<String, Vec<String>>,
+    source_depth: usize,
+    // Taken by savestate without a file, for quickly going back
+    snapshot: Option<Vec<u8>>,
+    next_read: Option<ReadContinuation>,
 }
 
 impl Debugger {
     #[must_use]
     pub fn new(gbc: Gbc) -> Self {
-        Debugger { gbc }
+        Debugger {
+            gbc,
+            displays: Vec::new(),
+            aliases: BTreeMap::new(),
+            source_depth: 0,
+            snapshot: None,
+            next_read: None,
+        }
     }
 
-    pub fn run(mut self) {
+    /// Runs the prompt until the debugger is exited, after running the
+    /// commands in `script` if there is one
+    pub fn run(mut self, script: Option<&Path>) {
         let mut rl = Editor::<()>::new();
         if rl.load_history("history.txt").is_err() {
             // Do nothing
         }
+        if let Some(script) = script {
+            if !self.source(script) {
+                return;
+            }
+        }
         loop {
             let readline = rl.readline(">> ");
             match readline {
@@ -118,7 +272,7 @@ impl Debugger {
                             if tokens.is_empty() {
                                 match rl.history().last() {
                                     Some(l) => match tokenize_line(l.as_str()) {
-                                        Ok(tokens) => tokens,
+                                        Ok(tokens) => self.repeat_tokens(tokens),
                                         Err(_) => continue,
                                     },
                                     None => continue,
@@ -133,38 +287,14 @@ impl Debugger {
                         }
                     };
 
-                    let should_continue = match Command::from_string(tokens[0].to_lowercase()) {
-                        Command::Exit => self.run_command_exit(&tokens[..]),
-                        Command::Reset => self.run_command_reset(&tokens[..]),
-                        Command::DumpState => self.run_command_dump_state(&tokens[..]),
-                        Command::AddBreakpoint => self.run_command_add_breakpoint(&tokens[..]),
-                        Command::ListBreakpoints => self.run_command_list_breakpoints(&tokens[..]),
-                        Command::DeleteBreakpoint => {
-                            self.run_command_delete_breakpoint(&tokens[..])
-                        }
-                        Command::Run => self.run_command_run(&tokens[..]),
-                        Command::Step => self.run_command_step(&tokens[..]),
-                        Command::Read => self.run_command_read(&tokens[..]),
-                        Command::Disassemble => self.run_command_disassemble(&tokens[..]),
-                        Command::PrintHeaderDetails => {
-                            self.run_command_print_header_details(&tokens[..])
-                        }
-                        Command::DumpTileMap => self.run_command_dump_tile_map(&tokens[..]),
-                        Command::DumpTiles => self.run_command_dump_tiles(&tokens[..]),
-                        Command::DumpSprites => self.run_command_dump_sprites(&tokens[..]),
-                        Command::Unknown => {
-                            println!("Unknown command {}", tokens[0]);
-                            true
-                        }
-                    };
-
-                    if !should_continue {
+                    if !self.run_tokens(&tokens) {
                         break;
                     }
                 }
+                // Ctrl-C while the game runs stops it and comes back here,
+                // so at the prompt it mustn't quit either
                 Err(ReadlineError::Interrupted) => {
-                    println!("CTRL-C");
-                    break;
+                    println!("CTRL-C, use quit or CTRL-D to exit");
                 }
                 Err(ReadlineError::Eof) => {
                     println!("CTRL-D");
@@ -179,6 +309,193 @@ impl Debugger {
         rl.save_history("history.txt").unwrap();
     }
 
+    /// The command line to run when enter is pressed on an empty line after
+    /// `tokens`. That's the same command again, except a `read` carries on
+    /// from where it stopped.
+    fn repeat_tokens(&self, tokens: Vec<String>) -> Vec<String> {
+        let command = match self.aliases.get(&tokens[0].to_lowercase()) {
+            Some(expansion) => expansion[0].to_lowercase(),
+            None => tokens[0].to_lowercase(),
+        };
+        match (Command::from_string(command), &self.next_read) {
+            (Command::Read, Some(next)) => vec![
+                "read".to_string(),
+                format!("{:#06x}", next.address),
+                next.length.to_string(),
+                "-w".to_string(),
+                next.width.to_string(),
+            ],
+            _ => tokens,
+        }
+    }
+
+    /// Runs one tokenized command line, expanding aliases first. Returns
+    /// false when the debugger should exit.
+    fn run_tokens(&mut self, tokens: &[String]) -> bool {
+        if tokens.is_empty() {
+            return true;
+        }
+        let tokens = match self.aliases.get(&tokens[0].to_lowercase()) {
+            Some(expansion) => expansion.iter().chain(&tokens[1..]).cloned().collect(),
+            None => tokens.to_vec(),
+        };
+        match Command::from_string(tokens[0].to_lowercase()) {
+            Command::Exit => self.run_command_exit(&tokens[..]),
+            Command::Reset => self.run_command_reset(&tokens[..]),
+            Command::DumpState => self.run_command_dump_state(&tokens[..]),
+            Command::AddBreakpoint => self.run_command_add_breakpoint(&tokens[..]),
+            Command::ListBreakpoints => self.run_command_list_breakpoints(&tokens[..]),
+            Command::DeleteBreakpoint => self.run_command_delete_breakpoint(&tokens[..]),
+            Command::EnableBreakpoint => self.run_command_enable_breakpoint(&tokens[..], true),
+            Command::DisableBreakpoint => self.run_command_enable_breakpoint(&tokens[..], false),
+            Command::IgnoreBreakpoint => self.run_command_ignore_breakpoint(&tokens[..]),
+            Command::Watch => self.run_command_watch(&tokens[..]),
+            Command::TemporaryBreakpoint => self.run_command_temporary_breakpoint(&tokens[..]),
+            Command::InterruptBreakpoint => self.run_command_interrupt_breakpoint(&tokens[..]),
+            Command::RstBreakpoint => self.run_command_rst_breakpoint(&tokens[..]),
+            Command::Until => self.run_command_until(&tokens[..]),
+            Command::Run => self.run_command_run(&tokens[..]),
+            Command::Step => self.run_command_step(&tokens[..]),
+            Command::Next => self.run_command_next(&tokens[..]),
+            Command::Finish => self.run_command_finish(&tokens[..]),
+            Command::Frame => self.run_command_frame(&tokens[..]),
+            Command::RunFor => self.run_command_run_for(&tokens[..]),
+            Command::Backtrace => self.run_command_backtrace(&tokens[..]),
+            Command::History => self.run_command_history(&tokens[..]),
+            Command::Symbols => self.run_command_symbols(&tokens[..]),
+            Command::Read => self.run_command_read(&tokens[..]),
+            Command::Write => self.run_command_write(&tokens[..]),
+            Command::SetRegister => self.run_command_set_register(&tokens[..]),
+            Command::Trace => self.run_command_trace(&tokens[..]),
+            Command::Display => self.run_command_display(&tokens[..]),
+            Command::Undisplay => self.run_command_undisplay(&tokens[..]),
+            Command::Disassemble => self.run_command_disassemble(&tokens[..]),
+            Command::PrintHeaderDetails => self.run_command_print_header_details(&tokens[..]),
+            Command::DumpTileMap => self.run_command_dump_tile_map(&tokens[..]),
+            Command::DumpTiles => self.run_command_dump_tiles(&tokens[..]),
+            Command::DumpSprites => self.run_command_dump_sprites(&tokens[..]),
+            Command::Ppu => self.run_command_ppu(&tokens[..]),
+            Command::Io => self.run_command_io(&tokens[..]),
+            Command::Time => self.run_command_time(&tokens[..]),
+            Command::Banks => self.run_command_banks(&tokens[..]),
+            Command::Rng => self.run_command_rng(&tokens[..]),
+            Command::Savepoint => self.run_command_savepoint(&tokens[..]),
+            Command::SaveRam => self.run_command_save_ram(&tokens[..]),
+            Command::LoadRam => self.run_command_load_ram(&tokens[..]),
+            Command::SaveState => self.run_command_save_state(&tokens[..]),
+            Command::LoadState => self.run_command_load_state(&tokens[..]),
+            Command::Audio => self.run_command_audio(&tokens[..]),
+            Command::Assemble => self.run_command_assemble(&tokens[..]),
+            Command::Patch => self.run_command_patch(&tokens[..]),
+            Command::TimeIt => self.run_command_time_it(&tokens[..]),
+            Command::Profile => self.run_command_profile(&tokens[..]),
+            Command::Coverage => self.run_command_coverage(&tokens[..]),
+            Command::Budget => self.run_command_budget(&tokens[..]),
+            Command::Serial => self.run_command_serial(&tokens[..]),
+            Command::Source => self.run_command_source(&tokens[..]),
+            Command::Alias => self.run_command_alias(&tokens[..]),
+            Command::Unalias => self.run_command_unalias(&tokens[..]),
+            Command::Unknown => {
+                println!("Unknown command {}", tokens[0]);
+                true
+            }
+        }
+    }
+
+    /// Runs every line of a script as if it were typed in, skipping blank
+    /// lines and `#` comments. Returns false if the script exited the
+    /// debugger.
+    fn source<P: AsRef<Path>>(&mut self, path: P) -> bool {
+        let path = path.as_ref();
+        let script = match fs::read_to_string(path) {
+            Ok(script) => script,
+            Err(e) => {
+                println!("Error reading {}: {}", path.display(), e);
+                return true;
+            }
+        };
+        if self.source_depth >= MAX_SOURCE_DEPTH {
+            println!("Error: scripts nested too deeply at {}", path.display());
+            return true;
+        }
+
+        self.source_depth += 1;
+        let mut should_continue = true;
+        for (number, line) in script.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let tokens: Vec<String> = match tokenize_line(line) {
+                Ok(tokens) => tokens.into_iter().filter(|t| !t.is_empty()).collect(),
+                Err(e) => {
+                    println!("{}:{}: Error: {}", path.display(), number + 1, e);
+                    continue;
+                }
+            };
+            // A line like '' has nothing left to run
+            if tokens.is_empty() {
+                continue;
+            }
+            if !self.run_tokens(&tokens) {
+                should_continue = false;
+                break;
+            }
+        }
+        self.source_depth -= 1;
+
+        should_continue
+    }
+
+    fn run_command_source(&mut self, args: &[String]) -> bool {
+        if args.len() != 2 {
+            println!("Usage: {} <file>", args[0]);
+            return true;
+        }
+        self.source(&args[1])
+    }
+
+    fn run_command_alias(&mut self, args: &[String]) -> bool {
+        if args.len() == 1 {
+            if self.aliases.is_empty() {
+                println!("No aliases");
+            }
+            for (name, expansion) in &self.aliases {
+                println!("{} = {}", name, expansion.join(" "));
+            }
+            return true;
+        }
+        if args.len() == 2 {
+            match self.aliases.get(&args[1].to_lowercase()) {
+                Some(expansion) => println!("{} = {}", args[1], expansion.join(" ")),
+                None => println!("No alias {}", args[1]),
+            }
+            return true;
+        }
+        // Aliases are only expanded once, so one naming another alias
+        // would never work
+        if self.aliases.contains_key(&args[2].to_lowercase()) {
+            println!("Error: {} is itself an alias", args[2]);
+            return true;
+        }
+        self.aliases
+            .insert(args[1].to_lowercase(), args[2..].to_vec());
+
+        true
+    }
+
+    fn run_command_unalias(&mut self, args: &[String]) -> bool {
+        if args.len() != 2 {
+            println!("Usage: {} <name>", args[0]);
+            return true;
+        }
+        if self.aliases.remove(&args[1].to_lowercase()).is_none() {
+            println!("No alias {}", args[1]);
+        }
+
+        true
+    }
+
     fn run_command_exit(&mut self, _args: &[String]) -> bool {
         println!("Exiting");
         false
@@ -196,15 +513,53 @@ impl Debugger {
         true
     }
 
+    /// Evaluates a numeric argument, see `expression::evaluate`
+    fn evaluate(&mut self, text: &str) -> Result<u16, String> {
+        expression::evaluate(text, &mut self.gbc)
+    }
+
+    fn evaluate_byte(&mut self, text: &str) -> Result<u8, String> {
+        let value = self.evaluate(text)?;
+        u8::try_from(value).map_err(|_| format!("{:#x} doesn't fit in a byte", value))
+    }
+
+    fn parse_address(&mut self, text: &str) -> Result<u16, String> {
+        self.evaluate(text)
+            .map_err(|e| format!("invalid address: {}", e))
+    }
+
+    /// Parses `[bank:]<address>`, both of which can be expressions. Text
+    /// that evaluates as a whole, like `Main::loop+2`, has no bank.
+    fn parse_banked_address(&mut self, text: &str) -> Result<(Option<u16>, u16), String> {
+        let whole = self.parse_address(text);
+        let (bank, address) = match (whole, text.split_once(':')) {
+            (Ok(address), _) => return Ok((None, address)),
+            (Err(e), None) => return Err(e),
+            (Err(_), Some(location)) => location,
+        };
+        let bank = self
+            .evaluate(bank)
+            .map_err(|e| format!("invalid bank: {}", e))?;
+        Ok((Some(bank), self.parse_address(address)?))
+    }
+
+    /// Formats an address with the symbol it's in, if there is one
+    fn format_address(&self, address: u16) -> String {
+        match self.gbc.describe_address(address) {
+            Some(name) => format!("{:#06x} <{}>", address, name),
+            None => format!("{:#06x}", address),
+        }
+    }
+
     fn run_command_add_breakpoint(&mut self, args: &[String]) -> bool {
         if args.len() < 2 {
-            println!("Usage: {} <address> [access type] [length]", args[0]);
+            println!("Usage: {} <address|symbol> [access type] [length]", args[0]);
             return true;
         }
-        let address = match parse(args[1].as_str()) {
+        let address = match self.parse_address(&args[1]) {
             Ok(address) => address,
             Err(e) => {
-                println!("Error: invalid address: {}", e);
+                println!("Error: {}", e);
                 return true;
             }
         };
@@ -220,7 +575,7 @@ impl Debugger {
             AccessType::Execute
         };
         let length = if args.len() > 3 {
-            match parse(args[3].as_str()) {
+            match self.evaluate(&args[3]) {
                 Ok(length) => length,
                 Err(e) => {
                     println!("Error: invalid length: {}", e);
@@ -236,256 +591,1441 @@ impl Debugger {
         true
     }
 
-    fn run_command_list_breakpoints(&mut self, _args: &[String]) -> bool {
-        let breakpoints = self.gbc.list_breakpoints();
-        if breakpoints.is_empty() {
-            println!("No breakpoints");
-        } else {
-            for (i, bp) in breakpoints.iter().enumerate() {
-                println!(
-                    "Breakpiont {}: {:04x} {} {} bytes {}",
-                    i, bp.address, bp.access_type, bp.length, bp.reason
-                );
+    fn run_command_watch(&mut self, args: &[String]) -> bool {
+        if args.len() < 2 {
+            println!(
+                "Usage: {} <address|symbol> [read|write|rw] [== <value>|changes]",
+                args[0]
+            );
+            return true;
+        }
+        let address = match self.parse_address(&args[1]) {
+            Ok(address) => address,
+            Err(e) => {
+                println!("Error: {}", e);
+                return true;
+            }
+        };
+        let access_type = match args.get(2).map(|a| a.to_lowercase()).as_deref() {
+            None | Some("w") | Some("write") => AccessType::Write,
+            Some("r") | Some("read") => AccessType::Read,
+            Some("rw") | Some("readwrite") => AccessType::ReadWrite,
+            Some(other) => {
+                println!("Error: expected read, write or rw, got {}", other);
+                return true;
+            }
+        };
+        let condition = match args.get(3).map(String::as_str) {
+            None => None,
+            Some("changes") => Some(WatchCondition::Changes),
+            Some("==") if args.len() > 4 => match self.evaluate_byte(&args[4]) {
+                Ok(value) => Some(WatchCondition::Equals(value)),
+                Err(e) => {
+                    println!("Error: invalid value: {}", e);
+                    return true;
+                }
+            },
+            Some(other) => {
+                println!("Error: expected == <value> or changes, got {}", other);
+                return true;
             }
+        };
+        if matches!(
+            (access_type, condition),
+            (AccessType::Read, Some(WatchCondition::Changes))
+        ) {
+            println!("Error: reads never change a value, watch writes instead");
+            return true;
+        }
+        match condition {
+            Some(condition) => self.gbc.add_watchpoint(address, access_type, 1, condition),
+            None => self
+                .gbc
+                .add_breakpoint(address, access_type, 1, BreakReason::User),
         }
 
         true
     }
 
-    fn run_command_delete_breakpoint(&mut self, args: &[String]) -> bool {
-        let mut indices: Vec<usize> = Vec::new();
-        for index_str in args[1..].iter().flat_map(|i| i.split(',')) {
-            match parse(index_str) {
-                Ok(index) => indices.push(index),
-                Err(e) => println!("Invalid index {}: {}", index_str, e),
+    /// Parses the address argument of tbreak and until, printing usage or
+    /// the problem when there isn't a valid one
+    fn parse_temporary_breakpoint(&mut self, args: &[String]) -> Option<u16> {
+        if args.len() < 2 {
+            println!("Usage: {} <address|symbol>", args[0]);
+            return None;
+        }
+        match self.parse_address(&args[1]) {
+            Ok(address) => Some(address),
+            Err(e) => {
+                println!("Error: {}", e);
+                None
             }
         }
-
-        // TODO remove breakpoints
-
-        true
     }
 
-    fn run_command_run(&mut self, _args: &[String]) -> bool {
-        let (_, problem) = self.gbc.run();
-        if problem {
-            println!("Encountered an unknown instruction!");
-        } else {
-            match self.gbc.get_last_breakpoint() {
-                Some(bp) => println!("Break Reason: {}", bp),
-                None => println!("Break Reason: None"),
-            }
+    fn run_command_temporary_breakpoint(&mut self, args: &[String]) -> bool {
+        if let Some(address) = self.parse_temporary_breakpoint(args) {
+            self.gbc.add_temporary_breakpoint(address);
         }
-        self.gbc.print_next_instruction();
 
         true
     }
 
-    fn run_command_step(&mut self, _args: &[String]) -> bool {
-        self.gbc.single_step();
-        self.gbc.print_next_instruction();
-        self.gbc.dump_cpu_state();
+    fn run_command_interrupt_breakpoint(&mut self, args: &[String]) -> bool {
+        let number = match args.get(1).map(|a| a.to_lowercase()).as_deref() {
+            Some("vblank") => 0,
+            Some("stat") | Some("lcd") => 1,
+            Some("timer") => 2,
+            Some("serial") => 3,
+            Some("joypad") => 4,
+            _ => {
+                println!("Usage: {} vblank|stat|timer|serial|joypad", args[0]);
+                return true;
+            }
+        };
+        let handler = 0x40 + 8 * u16::from(number);
+        self.gbc.add_breakpoint(
+            handler,
+            AccessType::Execute,
+            1,
+            BreakReason::Interrupt(number),
+        );
 
         true
     }
 
-    fn run_command_read(&mut self, args: &[String]) -> bool {
+    fn run_command_rst_breakpoint(&mut self, args: &[String]) -> bool {
         if args.len() < 2 {
-            println!("Usage: {} <address> [length]", args[0]);
+            println!("Usage: {} <vector>", args[0]);
             return true;
         }
-        let address = match parse(args[1].as_str()) {
-            Ok(address) => address,
-            Err(e) => {
-                println!("Error: invalid address: {}", e);
+        let vector = match self.evaluate_byte(&args[1]) {
+            Ok(vector) if vector <= 0x38 && vector % 8 == 0 => vector,
+            Ok(vector) => {
+                println!("Error: {:#04x} isn't an RST vector", vector);
                 return true;
             }
-        };
-        let length = if args.len() > 2 {
-            match parse(args[2].as_str()) {
-                Ok(length) => length,
-                Err(e) => {
-                    println!("Error: invalid length: {}", e);
-                    return true;
-                }
+            Err(e) => {
+                println!("Error: invalid vector: {}", e);
+                return true;
             }
-        } else {
-            16
         };
+        self.gbc.add_breakpoint(
+            u16::from(vector),
+            AccessType::Execute,
+            1,
+            BreakReason::Rst(vector),
+        );
 
-        let bytes = self.gbc.read_memory(address, length);
-        let mut cur_addr = address;
-        for chunk in bytes.chunks(LINE_LENGTH as usize) {
-            print!("{:04x}: ", cur_addr);
-            for byte in chunk {
-                print!("{:02x} ", byte);
-            }
-            for byte in chunk {
-                if let 0x20..=0x7e = byte {
-                    print!("{}", *byte as char);
-                } else {
-                    print!(".");
-                }
-            }
-            println!();
-            cur_addr += LINE_LENGTH as u16;
+        true
+    }
+
+    fn run_command_until(&mut self, args: &[String]) -> bool {
+        if let Some(address) = self.parse_temporary_breakpoint(args) {
+            self.gbc.add_temporary_breakpoint(address);
+            let (_, error) = self.gbc.run();
+            // Something else may have stopped the run first
+            self.gbc.remove_temporary_breakpoint(address);
+            self.report_stop(error);
         }
 
         true
     }
 
-    fn run_command_disassemble(&mut self, args: &[String]) -> bool {
-        let address = if args.len() > 1 {
-            match parse(args[1].as_str()) {
-                Ok(address) => Some(address),
-                Err(e) => {
-                    println!("Error: invalid address: {}", e);
-                    return true;
+    fn run_command_list_breakpoints(&mut self, _args: &[String]) -> bool {
+        let breakpoints = self.gbc.list_breakpoints();
+        if breakpoints.is_empty() {
+            println!("No breakpoints");
+        } else {
+            for (i, bp) in breakpoints.iter().enumerate() {
+                match self.gbc.describe_address(bp.address) {
+                    Some(name) => println!("Breakpoint {}: {} <{}>", i, bp, name),
+                    None => println!("Breakpoint {}: {}", i, bp),
                 }
             }
+        }
+
+        true
+    }
+
+    fn run_command_delete_breakpoint(&mut self, args: &[String]) -> bool {
+        if args.len() < 2 {
+            println!("Usage: {} <index|first-last|all>...", args[0]);
+            return true;
+        }
+        let count = self.gbc.list_breakpoints().len();
+        let indices = if args[1] == "all" {
+            (0..count).collect()
         } else {
-            None
-        };
-        let length = if args.len() > 2 {
-            match parse(args[2].as_str()) {
-                Ok(length) => length,
+            match parse_indices(&args[1..], count) {
+                Ok(indices) => indices,
                 Err(e) => {
-                    println!("Error: invalid length: {}", e);
+                    println!("Error: {}", e);
                     return true;
                 }
             }
-        } else {
-            16
         };
 
-        self.gbc.print_instructions(address, length);
+        // Last first, so removing one doesn't move the ones still to go
+        for &index in indices.iter().rev() {
+            if !self.gbc.remove_breakpoint(index) {
+                println!("Unknown index {}", index);
+            }
+        }
 
         true
     }
 
-    fn run_command_print_header_details(&mut self, _args: &[String]) -> bool {
-        let cart = self.gbc.get_cartridge();
-        println!("Cartridge: {}", cart.title);
-        println!("\tManufacturer Code: {:?}", cart.manufacturer_code);
-        println!(
-            "\tLicensee Code: {} {}",
-            cart.licensee_code[0] as char, cart.licensee_code[1] as char
-        );
-        println!("\tCartridge Type: {:?}", cart.cartridge_type);
-        println!("\tColor Support: {:?}", cart.color_support);
-        println!("\tSGB Support: {}", cart.supports_sgb);
-        println!(
-            "\tROM Size: {} ({} banks)",
-            cart.rom_size,
-            cart.rom_size / 16384
-        );
-        println!(
-            "\tRAM Size: {} ({} banks)",
-            cart.external_ram_size,
-            cart.external_ram_size / 8192
-        );
-        println!("\tIs Japanese: {}", cart.is_japanese);
-        println!("\tROM Version: {}", cart.rom_version);
-        println!(
-            "\tExternal RAM currently enabled: {}",
-            cart.enable_external_ram
-        );
-        println!("\tCurrently selected ROM bank: {}", cart.rom_bank_selected);
-        println!(
-            "\tCurrent banking mode: {}",
-            if cart.advanced_banking_mode {
-                "advanced"
-            } else {
-                "basic"
+    fn run_command_enable_breakpoint(&mut self, args: &[String], enabled: bool) -> bool {
+        if args.len() < 2 {
+            println!("Usage: {} <index|first-last>...", args[0]);
+            return true;
+        }
+        match parse_indices(&args[1..], self.gbc.list_breakpoints().len()) {
+            Ok(indices) => {
+                for index in indices {
+                    if !self.gbc.set_breakpoint_enabled(index, enabled) {
+                        println!("Unknown index {}", index);
+                    }
+                }
             }
-        );
+            Err(e) => println!("Error: {}", e),
+        }
 
-        return true;
+        true
     }
 
-    fn run_command_dump_tile_map(&mut self, args: &[String]) -> bool {
-        if args.len() != 2 {
-            println!("Usage: {} <tilemap number, 0 or 1>", args[0]);
+    fn run_command_ignore_breakpoint(&mut self, args: &[String]) -> bool {
+        if args.len() < 3 {
+            println!("Usage: {} <index> <count>", args[0]);
             return true;
         }
-
-        let map = match parse(args[1].as_str()) {
-            Ok(m) => m,
-            Err(e) => {
-                println!("Error: invalid number: {}", e);
-                return true;
-            }
-        };
-
-        let tilemap = match self.gbc.get_tile_map(map) {
-            Some(m) => m,
-            None => {
-                println!("Error: no tilemap {}, only 0 or 1", map);
+        let (index, count) = match (parse(args[1].as_str()), parse(args[2].as_str())) {
+            (Ok(index), Ok(count)) => (index, count),
+            (Err(e), _) | (_, Err(e)) => {
+                println!("Error: {}", e);
                 return true;
             }
         };
+        if self.gbc.set_breakpoint_ignore_count(index, count) {
+            println!("Ignoring the next {} hits of breakpoint {}", count, index);
+        } else {
+            println!("Unknown index {}", index);
+        }
 
-        // let show_color = args.len() == 3 && args[2] == "color";
+        true
+    }
 
-        for tiles in tilemap.chunks(32) {
-            for tile in tiles {
-                print!("{:02x} ", tile);
+    fn run_command_run(&mut self, _args: &[String]) -> bool {
+        let (_, error) = self.gbc.run();
+        if let Some(e) = error {
+            println!("Error: {}", e);
+            self.gbc.dump_cpu_state();
+        } else {
+            match self.gbc.get_last_breakpoint() {
+                Some(bp) => println!("Break Reason: {}", bp),
+                None => println!("Break Reason: None"),
             }
-            println!();
         }
+        if let Some(stop) = self.gbc.take_trace_comparison_stop() {
+            println!("{}", stop);
+        }
+        self.gbc.print_next_instruction();
+        self.print_displays();
 
         true
     }
 
-    fn run_command_dump_tiles(&mut self, args: &[String]) -> bool {
-        if args.len() != 3 {
-            println!("Usage: {} <tile index> <indexing method: 8000 | 9000", args[0]);
+    fn run_command_step(&mut self, _args: &[String]) -> bool {
+        if let Err(e) = self.gbc.single_step() {
+            println!("Error: {}", e);
+        }
+        self.gbc.print_next_instruction();
+        self.gbc.dump_cpu_state();
+        self.print_displays();
+
+        true
+    }
+
+    fn run_command_next(&mut self, _args: &[String]) -> bool {
+        let (_, error) = self.gbc.step_over();
+        self.report_stop(error);
+
+        true
+    }
+
+    fn run_command_finish(&mut self, _args: &[String]) -> bool {
+        let (_, error) = self.gbc.finish();
+        self.report_stop(error);
+
+        true
+    }
+
+    /// Runs until the given number of frames, one by default, have finished
+    fn run_command_frame(&mut self, args: &[String]) -> bool {
+        let frames = match args.get(1).map(|count| parse::<u64>(count)) {
+            Some(Ok(frames)) if frames > 0 => frames,
+            Some(Ok(_)) => {
+                println!("Error: need at least one frame");
+                return true;
+            }
+            Some(Err(e)) => {
+                println!("Error: invalid count: {}", e);
+                return true;
+            }
+            None => 1,
+        };
+        let first_frame = self.gbc.frame_count();
+        let (cycles, error) = self.gbc.run_until(StopCondition::Frames(frames));
+        println!(
+            "Ran {} frames, {} cycles",
+            self.gbc.frame_count() - first_frame,
+            cycles
+        );
+        self.report_stop(error);
+
+        true
+    }
+
+    /// Runs for a number of cycles or instructions
+    fn run_command_run_for(&mut self, args: &[String]) -> bool {
+        let count = match args.get(1).map(|count| parse::<u64>(count)) {
+            Some(Ok(count)) if count > 0 => count,
+            Some(Ok(_)) | None => {
+                println!("Usage: {} <count> [cycles|instructions]", args[0]);
+                return true;
+            }
+            Some(Err(e)) => {
+                println!("Error: invalid count: {}", e);
+                return true;
+            }
+        };
+        let condition = match args.get(2).map(String::as_str) {
+            None | Some("cycles") | Some("c") => StopCondition::Cycles(count),
+            Some("instructions") | Some("insns") | Some("i") => StopCondition::Instructions(count),
+            Some(unit) => {
+                println!("Error: unknown unit {}, cycles or instructions", unit);
+                return true;
+            }
+        };
+        let (cycles, error) = self.gbc.run_until(condition);
+        println!("Ran {} cycles", cycles);
+        self.report_stop(error);
+
+        true
+    }
+
+    /// Prints the call chain innermost first: where each frame is running,
+    /// the function it's in and how that function was entered
+    fn run_command_backtrace(&mut self, _args: &[String]) -> bool {
+        let mut address = self.gbc.get_program_counter();
+        for (depth, frame) in self.gbc.get_call_stack().iter().rev().enumerate() {
+            println!(
+                "#{} {} in {} ({}, sp {:#06x})",
+                depth,
+                self.format_address(address),
+                self.format_address(frame.target),
+                frame.kind,
+                frame.stack_pointer
+            );
+            address = frame.return_address;
+        }
+        let depth = self.gbc.get_call_stack().len();
+        println!("#{} {}", depth, self.format_address(address));
+
+        true
+    }
+
+    /// Prints the last instructions run, oldest first, numbered back from
+    /// the most recent
+    fn run_command_history(&mut self, args: &[String]) -> bool {
+        let count = match args.get(1).map(|count| parse::<usize>(count)) {
+            Some(Ok(count)) => count,
+            Some(Err(e)) => {
+                println!("Error: invalid count: {}", e);
+                return true;
+            }
+            None => 20,
+        };
+        let history = self.gbc.get_history();
+        if history.is_empty() {
+            println!("Nothing has run yet");
+        }
+        let skip = history.len().saturating_sub(count);
+        for (i, entry) in history.iter().enumerate().skip(skip) {
+            let age = format!("-{}", history.len() - i);
+            println!("{:>5} {}", age, entry);
+        }
+
+        true
+    }
+
+    /// Loads a symbol file, or looks up what's at an address or symbol
+    fn run_command_symbols(&mut self, args: &[String]) -> bool {
+        if args.len() < 2 {
+            println!("{} symbols loaded", self.gbc.get_symbols().len());
+            println!("Usage: {0} <file.sym> | {0} <address|symbol>", args[0]);
             return true;
         }
+        if args[1].ends_with(".sym") {
+            match self.gbc.load_symbols(&args[1]) {
+                Ok(count) => println!("Loaded {} symbols from {}", count, args[1]),
+                Err(e) => println!("Error reading {}: {}", args[1], e),
+            }
+        } else {
+            match self.parse_address(&args[1]) {
+                Ok(address) => println!("{}", self.format_address(address)),
+                Err(e) => println!("Error: {}", e),
+            }
+        }
 
-        let tile_index: i16 = match parse(args[1].as_str()) {
-            Ok(i) => i,
+        true
+    }
+
+    /// Says why running stopped, if it was a breakpoint or an error, and
+    /// where it stopped
+    fn report_stop(&mut self, error: Option<CpuError>) {
+        if let Some(e) = error {
+            println!("Error: {}", e);
+            self.gbc.dump_cpu_state();
+        } else if let Some(bp) = self.gbc.get_last_breakpoint() {
+            println!("Break Reason: {}", bp);
+        }
+        if let Some(stop) = self.gbc.take_trace_comparison_stop() {
+            println!("{}", stop);
+        }
+        self.gbc.print_next_instruction();
+        self.print_displays();
+    }
+
+    fn print_displays(&mut self) {
+        for i in 0..self.displays.len() {
+            let expression = self.displays[i].clone();
+            match self.evaluate(&expression) {
+                Ok(value) => println!("{}: {} = {:#06x} ({})", i, expression, value, value),
+                Err(e) => println!("{}: {} = <{}>", i, expression, e),
+            }
+        }
+    }
+
+    /// Adds an expression to print at every stop, or prints them all now
+    fn run_command_display(&mut self, args: &[String]) -> bool {
+        if args.len() < 2 {
+            if self.displays.is_empty() {
+                println!("Nothing to display, usage: {} <expression>", args[0]);
+            }
+            self.print_displays();
+            return true;
+        }
+        let expression = args[1..].join(" ");
+        if let Err(e) = self.evaluate(&expression) {
+            println!("Error: {}", e);
+            return true;
+        }
+        self.displays.push(expression);
+        self.print_displays();
+
+        true
+    }
+
+    fn run_command_undisplay(&mut self, args: &[String]) -> bool {
+        if args.len() < 2 {
+            println!("Usage: {} <index|first-last|all>...", args[0]);
+            return true;
+        }
+        if args[1] == "all" {
+            self.displays.clear();
+            return true;
+        }
+        let indices = match parse_indices(&args[1..], self.displays.len()) {
+            Ok(indices) => indices,
             Err(e) => {
-                println!("Error: invalid tile_index: {}", e);
+                println!("Error: {}", e);
                 return true;
             }
         };
+        for &index in indices.iter().rev() {
+            self.displays.remove(index);
+        }
 
-        let tile_address = match args[2].as_str() {
-            "8000" => {
-                if tile_index < 0 || tile_index > 255 {
-                    println!("Error: tile index {} out of range for From8000 addressing, should be 0 <= tile index < 256", tile_index);
+        true
+    }
+
+    /// Dumps memory as hex and ASCII, `width` bytes a line. A line the same
+    /// as the one before is shown as `*`, like hexdump does.
+    fn run_command_read(&mut self, args: &[String]) -> bool {
+        self.next_read = None;
+        // -w <width> can go anywhere after the command
+        let mut width = LINE_LENGTH;
+        let mut positional = Vec::new();
+        let mut args_iter = args[1..].iter();
+        while let Some(arg) = args_iter.next() {
+            if arg == "-w" || arg == "--width" {
+                match args_iter.next().map(|width| parse::<u16>(width)) {
+                    Some(Ok(w)) if w > 0 => width = w,
+                    Some(Ok(_)) | None => {
+                        println!("Error: {} needs a width of at least 1", arg);
+                        return true;
+                    }
+                    Some(Err(e)) => {
+                        println!("Error: invalid width: {}", e);
+                        return true;
+                    }
+                }
+            } else {
+                positional.push(arg);
+            }
+        }
+        if positional.is_empty() {
+            println!("Usage: {} <address|symbol> [length] [-w width]", args[0]);
+            return true;
+        }
+        let address = match self.parse_address(positional[0]) {
+            Ok(address) => address,
+            Err(e) => {
+                println!("Error: {}", e);
+                return true;
+            }
+        };
+        let length = if positional.len() > 1 {
+            match self.evaluate(positional[1]) {
+                Ok(length) => length,
+                Err(e) => {
+                    println!("Error: invalid length: {}", e);
                     return true;
                 }
-                TileAddressingMethod::From8000(tile_index as u8)
             }
-            "9000" => {
-                if tile_index < -128 || tile_index > 127 {
-                    println!("Error: tile index {} out of range for From9000 addressing, should be -128 <= tile index < 128", tile_index);
+        } else {
+            16
+        };
+
+        let bytes = self.gbc.read_memory(address, length);
+        let mut cur_addr = address;
+        let mut previous: Option<&[u8]> = None;
+        let mut skipping = false;
+        for chunk in bytes.chunks(width as usize) {
+            if previous == Some(chunk) {
+                if !skipping {
+                    println!("*");
+                    skipping = true;
+                }
+            } else {
+                skipping = false;
+                print!("{:04x}: ", cur_addr);
+                for byte in chunk {
+                    print!("{:02x} ", byte);
+                }
+                for byte in chunk {
+                    if let 0x20..=0x7e = byte {
+                        print!("{}", *byte as char);
+                    } else {
+                        print!(".");
+                    }
+                }
+                println!();
+            }
+            previous = Some(chunk);
+            cur_addr = cur_addr.wrapping_add(width);
+        }
+        self.next_read = Some(ReadContinuation {
+            address: address.wrapping_add(length),
+            length,
+            width,
+        });
+
+        true
+    }
+
+    fn run_command_write(&mut self, args: &[String]) -> bool {
+        if args.len() < 3 {
+            println!("Usage: {} <address|symbol> <byte>...", args[0]);
+            return true;
+        }
+        let address = match self.parse_address(&args[1]) {
+            Ok(address) => address,
+            Err(e) => {
+                println!("Error: {}", e);
+                return true;
+            }
+        };
+        let mut bytes = Vec::new();
+        for byte in &args[2..] {
+            match self.evaluate_byte(byte) {
+                Ok(byte) => bytes.push(byte),
+                Err(e) => {
+                    println!("Error: invalid byte {}: {}", byte, e);
                     return true;
                 }
-                TileAddressingMethod::From9000(tile_index as i8)
             }
-            _ => {
-                println!("Error: unknown addressing method {}", args[2]);
+        }
+        if address < 0x8000 {
+            println!("Note: writes to ROM go to the MBC, use asm or patch to change the ROM");
+        }
+        self.gbc.write_memory(address, &bytes);
+
+        true
+    }
+
+    fn run_command_set_register(&mut self, args: &[String]) -> bool {
+        if args.len() < 3 {
+            println!("Usage: {} <register> <value>", args[0]);
+            return true;
+        }
+        let register: CpuRegister = match args[1].parse() {
+            Ok(register) => register,
+            Err(e) => {
+                println!("Error: {}", e);
                 return true;
             }
         };
+        let value = if register.is_16_bit() {
+            self.evaluate(&args[2])
+        } else {
+            self.evaluate_byte(&args[2]).map(u16::from)
+        };
+        match value {
+            Ok(value) => {
+                self.gbc.set_register(register, value);
+                self.gbc.dump_cpu_state();
+            }
+            Err(e) => println!("Error: invalid value: {}", e),
+        }
 
-        let tile = self.gbc.get_tile(tile_address);
+        true
+    }
 
-        for row in (&tile.deinterleave()[..]).chunks(8) {
-            for &color in row {
-                let c: u8 = color.into();
-                print!("{}", c);
+    fn run_command_trace(&mut self, args: &[String]) -> bool {
+        match args.get(1).map(String::as_str) {
+            Some("on") => {
+                let path = args.get(2).map_or("trace.log", String::as_str);
+                match self.gbc.start_trace(path) {
+                    Ok(()) => println!("Tracing instructions to {}", path),
+                    Err(e) => println!("Error creating {}: {}", path, e),
+                }
             }
-            println!();
+            Some("off") => self.gbc.stop_trace(),
+            None => println!(
+                "Instruction trace is {}",
+                if self.gbc.is_tracing() { "on" } else { "off" }
+            ),
+            Some(_) => println!("Usage: {} [on [file]|off]", args[0]),
         }
 
         true
     }
 
-    fn run_command_dump_sprites(&mut self, _args: &[String]) -> bool {
-        todo!();
+    fn run_command_disassemble(&mut self, args: &[String]) -> bool {
+        let address = if args.len() > 1 {
+            match self.parse_address(&args[1]) {
+                Ok(address) => Some(address),
+                Err(e) => {
+                    println!("Error: {}", e);
+                    return true;
+                }
+            }
+        } else {
+            None
+        };
+        let length = if args.len() > 2 {
+            match self.evaluate(&args[2]) {
+                Ok(length) => length,
+                Err(e) => {
+                    println!("Error: invalid length: {}", e);
+                    return true;
+                }
+            }
+        } else {
+            16
+        };
+
+        self.gbc.print_instructions(address, length);
+
+        true
+    }
+
+    fn run_command_print_header_details(&mut self, _args: &[String]) -> bool {
+        let cart = self.gbc.get_cartridge();
+        println!("Cartridge: {}", cart.title);
+        println!("\tManufacturer Code: {:?}", cart.manufacturer_code);
+        println!(
+            "\tLicensee Code: {} {}",
+            cart.licensee_code[0] as char, cart.licensee_code[1] as char
+        );
+        println!("\tCartridge Type: {:?}", cart.cartridge_type);
+        println!("\tColor Support: {:?}", cart.color_support);
+        println!("\tSGB Support: {}", cart.supports_sgb);
+        println!(
+            "\tROM Size: {} ({} banks)",
+            cart.rom_size,
+            cart.rom_size / 16384
+        );
+        println!(
+            "\tRAM Size: {} ({} banks)",
+            cart.external_ram_size,
+            cart.external_ram_size / 8192
+        );
+        println!("\tIs Japanese: {}", cart.is_japanese);
+        println!("\tROM Version: {}", cart.rom_version);
+        println!(
+            "\tExternal RAM currently enabled: {}",
+            cart.get_external_ram_enabled()
+        );
+        println!(
+            "\tCurrently selected ROM bank: {}",
+            cart.get_rom_bank_selected()
+        );
+        println!(
+            "\tCurrently selected RAM bank: {}",
+            cart.get_ram_bank_selected()
+        );
+        println!("\tMapper state: {:?}", cart.get_mapper());
+        if cart.warnings.is_empty() {
+            println!("\tWarnings: None");
+        } else {
+            println!("\tWarnings:");
+            for warning in &cart.warnings {
+                println!("\t\t{}", warning);
+            }
+        }
+
+        return true;
+    }
+
+    fn run_command_dump_tile_map(&mut self, args: &[String]) -> bool {
+        let png_path = match args.len() {
+            2 => None,
+            4 if args[2] == "png" => Some(&args[3]),
+            _ => {
+                println!("Usage: {} <tilemap number, 0 or 1> [png <path>]", args[0]);
+                return true;
+            }
+        };
+
+        let map = match parse(args[1].as_str()) {
+            Ok(m) => m,
+            Err(e) => {
+                println!("Error: invalid number: {}", e);
+                return true;
+            }
+        };
+
+        if let Some(path) = png_path {
+            match self.gbc.render_tile_map(map) {
+                Some(pixels) => Self::save_png(path, 256, 256, &pixels),
+                None => println!("Error: no tilemap {}, only 0 or 1", map),
+            }
+            return true;
+        }
+
+        let tilemap = match self.gbc.get_tile_map(map) {
+            Some(m) => m,
+            None => {
+                println!("Error: no tilemap {}, only 0 or 1", map);
+                return true;
+            }
+        };
+
+        // let show_color = args.len() == 3 && args[2] == "color";
+
+        for tiles in tilemap.chunks(32) {
+            for tile in tiles {
+                print!("{:02x} ", tile);
+            }
+            println!();
+        }
+
+        true
+    }
+
+    fn save_png(path: &str, width: u32, height: u32, pixels: &[u8]) {
+        match png::save_rgb(path, width, height, pixels) {
+            Ok(()) => println!("Wrote {}x{} image to {}", width, height, path),
+            Err(e) => println!("Error writing {}: {}", path, e),
+        }
+    }
+
+    fn run_command_dump_tiles(&mut self, args: &[String]) -> bool {
+        if args.len() == 3 && args[1] == "png" {
+            let pixels = self.gbc.render_tiles();
+            Self::save_png(&args[2], 128, 192, &pixels);
+            return true;
+        }
+
+        if args.len() != 3 {
+            println!("Usage: {} <tile index> <indexing method: 8000 | 9000", args[0]);
+            println!("       {} png <path>", args[0]);
+            return true;
+        }
+
+        let tile_index: i16 = match parse(args[1].as_str()) {
+            Ok(i) => i,
+            Err(e) => {
+                println!("Error: invalid tile_index: {}", e);
+                return true;
+            }
+        };
+
+        let tile_address = match args[2].as_str() {
+            "8000" => {
+                if tile_index < 0 || tile_index > 255 {
+                    println!("Error: tile index {} out of range for From8000 addressing, should be 0 <= tile index < 256", tile_index);
+                    return true;
+                }
+                TileAddressingMethod::From8000(tile_index as u8)
+            }
+            "9000" => {
+                if tile_index < -128 || tile_index > 127 {
+                    println!("Error: tile index {} out of range for From9000 addressing, should be -128 <= tile index < 128", tile_index);
+                    return true;
+                }
+                TileAddressingMethod::From9000(tile_index as i8)
+            }
+            _ => {
+                println!("Error: unknown addressing method {}", args[2]);
+                return true;
+            }
+        };
+
+        let tile = self.gbc.get_tile(tile_address);
+
+        for row in (&tile.deinterleave()[..]).chunks(8) {
+            for &color in row {
+                let c: u8 = color.into();
+                print!("{}", c);
+            }
+            println!();
+        }
+
+        true
+    }
+
+    fn run_command_dump_sprites(&mut self, _args: &[String]) -> bool {
+        let this_line = self.gbc.get_sprites_this_line();
+        for (i, sprite) in self.gbc.get_sprites().iter().enumerate() {
+            let selected = this_line.iter().any(|s| usize::from(s.oam_index) == i);
+            println!("{} {:2}: {}", if selected { '*' } else { ' ' }, i, sprite);
+        }
+        let order: Vec<String> = this_line.iter().map(|s| s.oam_index.to_string()).collect();
+        println!(
+            "Picked for the current line ({}/10): {}",
+            this_line.len(),
+            order.join(", ")
+        );
+        true
+    }
+
+    fn run_command_ppu(&mut self, _args: &[String]) -> bool {
+        println!("{}", self.gbc.get_ppu_debug_state());
+        true
+    }
+
+    fn run_command_io(&mut self, args: &[String]) -> bool {
+        io_registers::print(&mut self.gbc, args.get(1).map(String::as_str));
+        true
+    }
+
+    fn run_command_time(&mut self, _args: &[String]) -> bool {
+        let emulated_time = self.gbc.emulated_time();
+        println!("Frame: {}", self.gbc.frame_count());
+        println!("Cycles: {}", self.gbc.cycle_count());
+        println!(
+            "Emulated time: {}:{:02}.{:06}",
+            emulated_time.as_secs() / 60,
+            emulated_time.as_secs() % 60,
+            emulated_time.subsec_micros()
+        );
+
+        true
+    }
+
+    fn run_command_banks(&mut self, args: &[String]) -> bool {
+        if args.len() < 2 {
+            let cartridge = self.gbc.get_cartridge();
+            println!("ROM bank: {:03x}", cartridge.get_rom_bank_selected());
+            println!("RAM bank: {:02x}", cartridge.get_ram_bank_selected());
+            println!(
+                "RAM enabled: {}",
+                if cartridge.get_external_ram_enabled() {
+                    "yes"
+                } else {
+                    "no"
+                }
+            );
+            return true;
+        }
+        if args[1] != "history" {
+            println!("Usage: {} [history [count]]", args[0]);
+            return true;
+        }
+        let count = if args.len() > 2 {
+            match parse(args[2].as_str()) {
+                Ok(count) => count,
+                Err(e) => {
+                    println!("Error: invalid count: {}", e);
+                    return true;
+                }
+            }
+        } else {
+            16
+        };
+
+        let history = self.gbc.get_mbc_write_history();
+        if history.is_empty() {
+            println!("No MBC writes");
+        }
+        for write in history.iter().skip(history.len().saturating_sub(count)) {
+            println!("{}", write);
+        }
+
+        true
+    }
+
+    fn run_command_rng(&mut self, args: &[String]) -> bool {
+        if args.len() < 2 {
+            let watches = self.gbc.get_rng_watches();
+            if watches.is_empty() {
+                println!("No RNG addresses watched");
+            }
+            for watch in watches {
+                println!("{}", watch);
+            }
+            return true;
+        }
+        let usage = || {
+            println!(
+                "Usage: {} [watch <address> | unwatch <address> | lock <address> <value> | unlock <address>]",
+                args[0]
+            );
+        };
+        if args.len() < 3 {
+            usage();
+            return true;
+        }
+        let address = match self.parse_address(&args[2]) {
+            Ok(address) => address,
+            Err(e) => {
+                println!("Error: {}", e);
+                return true;
+            }
+        };
+        let found = match args[1].as_str() {
+            "watch" => {
+                self.gbc.add_rng_watch(address);
+                true
+            }
+            "unwatch" => self.gbc.remove_rng_watch(address),
+            "lock" => {
+                if args.len() < 4 {
+                    usage();
+                    return true;
+                }
+                let value = match self.evaluate_byte(&args[3]) {
+                    Ok(value) => value,
+                    Err(e) => {
+                        println!("Error: invalid value: {}", e);
+                        return true;
+                    }
+                };
+                self.gbc.lock_rng_watch(address, Some(value))
+            }
+            "unlock" => self.gbc.lock_rng_watch(address, None),
+            _ => {
+                usage();
+                return true;
+            }
+        };
+        if !found {
+            println!("{:04x} is not a watched RNG address", address);
+        }
+
+        true
+    }
+
+    fn run_command_savepoint(&mut self, args: &[String]) -> bool {
+        if args.len() < 2 {
+            let savepoints = self.gbc.get_savepoints();
+            if savepoints.is_empty() {
+                println!("No savepoints");
+            }
+            for savepoint in savepoints {
+                println!("{}", savepoint);
+            }
+            return true;
+        }
+        if args.len() < 3 || !(args[1] == "add" || args[1] == "remove") {
+            println!("Usage: {} [add <address> [label] | remove <address>]", args[0]);
+            return true;
+        }
+        let address = match self.parse_address(&args[2]) {
+            Ok(address) => address,
+            Err(e) => {
+                println!("Error: {}", e);
+                return true;
+            }
+        };
+        if args[1] == "add" {
+            let label = args.get(3).map_or("savepoint", String::as_str);
+            self.gbc.add_savepoint(address, label.to_string());
+        } else if !self.gbc.remove_savepoint(address) {
+            println!("No savepoint at {:04x}", address);
+        }
+
+        true
+    }
+
+    fn run_command_save_ram(&mut self, args: &[String]) -> bool {
+        if args.len() != 2 {
+            println!("Usage: {} <path>", args[0]);
+            return true;
+        }
+        let ram = self.gbc.get_external_ram();
+        if ram.is_empty() {
+            println!("Cartridge has no external RAM");
+            return true;
+        }
+        match fs::write(&args[1], ram) {
+            Ok(()) => println!("Wrote {} bytes to {}", ram.len(), args[1]),
+            Err(e) => println!("Error writing {}: {}", args[1], e),
+        }
+
+        true
+    }
+
+    /// Saves the machine state to a file, or to memory without one
+    fn run_command_save_state(&mut self, args: &[String]) -> bool {
+        match args.get(1) {
+            Some(path) => match self.gbc.save_state(path) {
+                Ok(()) => println!("Saved state to {}", path),
+                Err(e) => println!("Error saving {}: {}", path, e),
+            },
+            None => {
+                self.snapshot = Some(self.gbc.save_state_bytes());
+                println!("Saved state in memory, loadstate restores it");
+            }
+        }
+
+        true
+    }
+
+    /// Restores the machine state from a file, or the one in memory
+    fn run_command_load_state(&mut self, args: &[String]) -> bool {
+        let result = match (args.get(1), &self.snapshot) {
+            (Some(path), _) => self.gbc.load_state(path),
+            (None, Some(snapshot)) => self.gbc.load_state_bytes(snapshot),
+            (None, None) => {
+                println!("No state saved in memory, usage: {} [file]", args[0]);
+                return true;
+            }
+        };
+        match result {
+            Ok(()) => self.gbc.print_next_instruction(),
+            Err(e) => println!("Error loading state: {}", e),
+        }
+
+        true
+    }
+
+    fn run_command_load_ram(&mut self, args: &[String]) -> bool {
+        if args.len() != 2 {
+            println!("Usage: {} <path>", args[0]);
+            return true;
+        }
+        let data = match fs::read(&args[1]) {
+            Ok(data) => data,
+            Err(e) => {
+                println!("Error reading {}: {}", args[1], e);
+                return true;
+            }
+        };
+        let ram_size = self.gbc.get_external_ram().len();
+        if ram_size == 0 {
+            println!("Cartridge has no external RAM");
+            return true;
+        }
+        let copied = self.gbc.import_external_ram(&data);
+        if data.len() != ram_size {
+            println!(
+                "Warning: {} is {} bytes, but the cartridge has {} bytes of RAM",
+                args[1],
+                data.len(),
+                ram_size
+            );
+        }
+        println!("Loaded {} bytes from {}", copied, args[1]);
+
+        true
+    }
+
+    fn run_command_audio(&mut self, _args: &[String]) -> bool {
+        let status = self.gbc.get_sound_status();
+        println!("Sound: {}", if status.powered { "on" } else { "off" });
+        for channel in &status.channels {
+            println!("\t{}", channel);
+        }
+        match status.frame_sequencer_step {
+            Some(step) => println!("Frame sequencer step: {}", step),
+            None => println!("Frame sequencer step: not emulated"),
+        }
+
+        true
+    }
+
+    fn run_command_serial(&mut self, args: &[String]) -> bool {
+        if args.len() < 2 {
+            println!("Serial device: {}", self.gbc.get_serial_device().name());
+            return true;
+        }
+        match args[1].parse::<SerialDeviceKind>() {
+            Ok(kind) => {
+                self.gbc.set_serial_device(kind.create());
+                println!("Serial device: {}", kind);
+            }
+            Err(e) => println!("Error: {}", e),
+        }
+
+        true
+    }
+
+    fn run_command_assemble(&mut self, args: &[String]) -> bool {
+        if args.len() < 3 {
+            println!("Usage: {} [bank:]<address> <instruction>", args[0]);
+            return true;
+        }
+        let (bank, address) = match self.parse_banked_address(&args[1]) {
+            Ok(location) => location,
+            Err(e) => {
+                println!("Error: {}", e);
+                return true;
+            }
+        };
+        let bytes = match assembler::assemble(address, &args[2..].join(" ")) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                println!("Error: {}", e);
+                return true;
+            }
+        };
+
+        if address < 0x8000 {
+            // Writes to the ROM window would go to the MBC, so patch the
+            // ROM itself, in the bank that is mapped there unless told otherwise
+            let bank = bank.unwrap_or_else(|| self.mapped_rom_bank(address));
+            if !self.gbc.patch_rom(bank, address, &bytes) {
+                println!("Error: bank {:03x} is past the end of the ROM", bank);
+                return true;
+            }
+            print!("{:03x}:{:04x}:", bank, address);
+        } else if bank.is_some() {
+            println!("Error: banks can only be given for ROM addresses");
+            return true;
+        } else {
+            self.gbc.write_memory(address, &bytes);
+            print!("{:04x}:", address);
+        }
+        for byte in &bytes {
+            print!(" {:02x}", byte);
+        }
+        println!();
+
+        true
+    }
+
+    fn mapped_rom_bank(&self, address: u16) -> u16 {
+        if address < 0x4000 {
+            0
+        } else {
+            self.gbc.get_cartridge().get_rom_bank_selected()
+        }
+    }
+
+    fn run_command_patch(&mut self, args: &[String]) -> bool {
+        if args.len() < 2 {
+            let patches = self.gbc.get_patches();
+            if patches.is_empty() {
+                println!("No patches");
+            }
+            for patch in patches {
+                println!("[{}] {}", if patch.enabled { "on " } else { "off" }, patch);
+            }
+            return true;
+        }
+        match (args[1].as_str(), args.len()) {
+            ("on", 3) | ("off", 3) => {
+                if !self.gbc.set_patch_enabled(&args[2], args[1] == "on") {
+                    println!("No patch named {}", args[2]);
+                }
+            }
+            ("remove", 3) | ("delete", 3) => {
+                if !self.gbc.remove_patch(&args[2]) {
+                    println!("No patch named {}", args[2]);
+                }
+            }
+            ("add", len) if len >= 5 => self.add_patch(&args[2], &args[3], &args[4..].join(" ")),
+            _ => {
+                println!("Usage: {} [on|off|remove <name>]", args[0]);
+                println!("       {} add <name> [bank:]<address> <instruction>", args[0]);
+            }
+        }
+
+        true
+    }
+
+    fn add_patch(&mut self, name: &str, location: &str, instruction: &str) {
+        let (bank, address) = match self.parse_banked_address(location) {
+            Ok((_, address)) if address >= 0x8000 => {
+                println!("Error: patches can only change ROM");
+                return;
+            }
+            Ok((bank, address)) => (bank.unwrap_or_else(|| self.mapped_rom_bank(address)), address),
+            Err(e) => {
+                println!("Error: {}", e);
+                return;
+            }
+        };
+        let replacement = match assembler::assemble(address, instruction) {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                println!("Error: {}", e);
+                return;
+            }
+        };
+        let original = match self
+            .gbc
+            .get_cartridge()
+            .read_rom_bytes(bank, address, replacement.len())
+        {
+            Some(original) => original.to_vec(),
+            None => {
+                println!("Error: bank {:03x} is past the end of the ROM", bank);
+                return;
+            }
+        };
+        let patch = RomPatch {
+            name: name.to_string(),
+            bank,
+            address,
+            original,
+            replacement,
+            enabled: true,
+        };
+        println!("Add this to the game's .cfg file to apply it at load:");
+        println!("patch = {}", patch);
+        if let Err(e) = self.gbc.add_patch(patch) {
+            println!("Error: {}", e);
+        }
+    }
+
+    /// Starts and stops the profiler, or prints the hottest addresses, or
+    /// functions when symbols are loaded
+    fn run_command_profile(&mut self, args: &[String]) -> bool {
+        match args.get(1).map(String::as_str) {
+            Some("start") | Some("on") => {
+                self.gbc.start_profiling();
+                println!("Profiling, run to collect samples");
+                return true;
+            }
+            Some("stop") | Some("off") => {
+                self.gbc.stop_profiling();
+                return true;
+            }
+            _ => {}
+        }
+        let count = match args.get(1).map(|count| parse::<usize>(count)) {
+            Some(Ok(count)) => count,
+            Some(Err(_)) => {
+                println!("Usage: {} [start|stop|<count>]", args[0]);
+                return true;
+            }
+            None => 20,
+        };
+        let profile = match self.gbc.get_profile() {
+            Some(profile) => profile,
+            None => {
+                println!("Not profiling, start with {} start", args[0]);
+                return true;
+            }
+        };
+        let total = profile.total_cycles();
+        let samples = if self.gbc.get_symbols().is_empty() {
+            profile.by_address()
+        } else {
+            profile.by_symbol(self.gbc.get_symbols())
+        };
+        let stopped = if self.gbc.is_profiling() {
+            ""
+        } else {
+            ", stopped"
+        };
+        println!("{} cycles profiled{}", total, stopped);
+        for (location, sample) in samples.iter().take(count) {
+            #[allow(clippy::cast_precision_loss)]
+            let percent = sample.cycles as f64 * 100.0 / total.max(1) as f64;
+            println!(
+                "{:>12} {:>6.2}% {:>10} steps  {}",
+                sample.cycles, percent, sample.steps, location
+            );
+        }
+
+        true
+    }
+
+    fn run_command_coverage(&mut self, args: &[String]) -> bool {
+        match args.get(1).map(String::as_str) {
+            None => {
+                let (executed, total) = self.gbc.get_coverage_summary();
+                #[allow(clippy::cast_precision_loss)]
+                let percent = executed as f64 * 100.0 / total.max(1) as f64;
+                let stopped = if self.gbc.is_recording_coverage() {
+                    ""
+                } else {
+                    ", stopped"
+                };
+                println!(
+                    "{} of {} ROM bytes executed ({:.2}%){}",
+                    executed, total, percent, stopped
+                );
+            }
+            Some("save") if args.len() > 2 => match self.gbc.save_coverage(&args[2]) {
+                Ok(()) => println!("Saved coverage to {}", args[2]),
+                Err(e) => println!("Error writing {}: {}", args[2], e),
+            },
+            Some("start") | Some("on") => {
+                self.gbc.start_coverage();
+                println!("Recording coverage, run to collect it");
+            }
+            Some("stop") | Some("off") => self.gbc.stop_coverage(),
+            Some("clear") => self.gbc.clear_coverage(),
+            Some(_) => println!("Usage: {} [start|stop|save <file>|clear]", args[0]),
+        }
+
+        true
+    }
+
+    fn run_command_time_it(&mut self, args: &[String]) -> bool {
+        if args.len() < 3 {
+            println!("Usage: {} <start address> <end address>", args[0]);
+            return true;
+        }
+        let start = match self.evaluate(&args[1]) {
+            Ok(address) => address,
+            Err(e) => {
+                println!("Error: invalid start address: {}", e);
+                return true;
+            }
+        };
+        let end = match self.evaluate(&args[2]) {
+            Ok(address) => address,
+            Err(e) => {
+                println!("Error: invalid end address: {}", e);
+                return true;
+            }
+        };
+
+        if self.gbc.get_program_counter() != start {
+            match self.gbc.run_until_pc(start, TIME_IT_CYCLE_LIMIT) {
+                Ok(Some(_)) => {}
+                Ok(None) => {
+                    println!("Didn't reach {:04x} within 10 seconds", start);
+                    return true;
+                }
+                Err(e) => {
+                    println!("Error: {}", e);
+                    return true;
+                }
+            }
+        }
+        let cycles = match self.gbc.run_until_pc(end, TIME_IT_CYCLE_LIMIT) {
+            Ok(Some(cycles)) => cycles,
+            Ok(None) => {
+                println!("Didn't reach {:04x} within 10 seconds of {:04x}", end, start);
+                return true;
+            }
+            Err(e) => {
+                println!("Error: {}", e);
+                return true;
+            }
+        };
+
+        // The LCD runs at the same speed in double speed mode
+        let dots = if self.gbc.is_double_speed() {
+            cycles / 2
+        } else {
+            cycles
+        };
+        #[allow(clippy::cast_precision_loss)]
+        let (lines, frames) = (
+            dots as f64 / DOTS_PER_LINE as f64,
+            dots as f64 / DOTS_PER_FRAME as f64,
+        );
+        println!(
+            "{:04x} -> {:04x}: {} cycles, {:.2} scanlines, {:.3} frames",
+            start, end, cycles, lines, frames
+        );
+        self.gbc.print_next_instruction();
+        self.print_displays();
+
+        true
+    }
+
+    fn run_command_budget(&mut self, args: &[String]) -> bool {
+        let count = match args.get(1).map(String::as_str) {
+            Some("on") => {
+                self.gbc.set_track_frame_budget(true);
+                println!("Tracking the vblank budget from the next frame");
+                return true;
+            }
+            Some("off") => {
+                self.gbc.set_track_frame_budget(false);
+                return true;
+            }
+            Some(count) => match parse::<usize>(count) {
+                Ok(count) => count,
+                Err(e) => {
+                    println!("Usage: {} [on | off | <frames>]", args[0]);
+                    println!("Error: {}", e);
+                    return true;
+                }
+            },
+            None => 10,
+        };
+        if !self.gbc.is_tracking_frame_budget() {
+            println!("Not tracking the vblank budget, use \"{} on\" first", args[0]);
+            return true;
+        }
+        let history = self.gbc.get_frame_budget_history();
+        if history.is_empty() {
+            println!("No complete frames yet");
+        }
+        for budget in history.iter().skip(history.len().saturating_sub(count)) {
+            println!("{}", budget);
+        }
+        let over_budget = history.iter().filter(|b| b.is_over_budget()).count();
+        let blocked = history.iter().filter(|b| b.has_blocked_accesses()).count();
+        println!(
+            "{} of the last {} frames over budget, {} with accesses outside vblank",
+            over_budget,
+            history.len(),
+            blocked
+        );
+
+        true
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn args(text: &str) -> Vec<String> {
+        text.split_whitespace().map(str::to_string).collect()
+    }
+
+    #[test]
+    fn test_parse_indices() {
+        assert_eq!(parse_indices(&args("3 0,1 1-2"), 4), Ok(vec![0, 1, 2, 3]));
+        assert!(parse_indices(&args("2-1"), 4).is_err());
+        assert!(parse_indices(&args("4"), 4).is_err());
+        // Huge ranges are rejected rather than expanded
+        assert!(parse_indices(&args("0-99999999999"), 4).is_err());
     }
 }
@@ -0,0 +1,144 @@
+use std::{
+    fs,
+    io::ErrorKind,
+    path::{Path, PathBuf},
+};
+
+use parse_int::parse;
+
+use super::keymap::{Button, Keymap};
+use crate::gbc::{debug::RomPatch, SaveRamFlush, SoftResetCombo};
+
+/// Per game settings, read from a `<rom>.cfg` file next to the ROM. Each
+/// line is `key = value`, lines starting with `#` are ignored, e.g.
+///
+/// ```text
+/// # This game has no soft reset of its own
+/// soft_reset_combo = reset
+/// # Write the save file once the game is done saving, instead of every second
+/// save_interval = change
+/// # Where the game keeps its random number, can be repeated
+/// rng_address = 0xffe1
+/// # Capture a practice savestate every time this byte changes
+/// savepoint = 0xd35e room
+/// # Named ROM patch: [bank:]address, original bytes, replacement bytes
+/// patch = infinite_lives 0x1:0x4a3c 3d 00
+///
+/// # Everything after this is key bindings, see `Keymap`
+/// [keymap]
+/// a = Z, J
+/// ```
+#[derive(Debug, Default)]
+pub struct GameConfig {
+    pub soft_reset_combo: Option<SoftResetCombo>,
+    pub save_ram_flush: Option<SaveRamFlush>,
+    pub rng_addresses: Vec<u16>,
+    pub savepoints: Vec<(u16, String)>,
+    pub patches: Vec<RomPatch>,
+    pub keymap: Vec<(Button, Vec<String>)>,
+}
+
+impl GameConfig {
+    #[must_use]
+    pub fn path_for_rom<P: AsRef<Path>>(rom_path: P) -> PathBuf {
+        rom_path.as_ref().with_extension("cfg")
+    }
+
+    /// Reads the config for the given ROM. A missing file just means the
+    /// defaults, problems with the contents are reported and skipped.
+    #[must_use]
+    pub fn load_for_rom<P: AsRef<Path>>(rom_path: P) -> Self {
+        let path = Self::path_for_rom(rom_path);
+        match fs::read_to_string(&path) {
+            Ok(contents) => Self::parse(&contents, &path),
+            Err(e) if e.kind() == ErrorKind::NotFound => Self::default(),
+            Err(e) => {
+                eprintln!("Error reading {}: {}", path.display(), e);
+                Self::default()
+            }
+        }
+    }
+
+    /// Reads the bindings in a keymap file, which is laid out like the
+    /// `[keymap]` section of a config. The section header can be left out.
+    /// Problems with the contents are reported and skipped.
+    #[must_use]
+    pub fn parse_keymap(contents: &str, path: &Path) -> Vec<(Button, Vec<String>)> {
+        Self::parse_from(contents, path, true).keymap
+    }
+
+    fn parse(contents: &str, path: &Path) -> Self {
+        Self::parse_from(contents, path, false)
+    }
+
+    fn parse_from(contents: &str, path: &Path, mut in_keymap: bool) -> Self {
+        let mut config = Self::default();
+        for (line_number, line) in contents.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            if line.starts_with('[') {
+                if line == "[keymap]" {
+                    in_keymap = true;
+                } else {
+                    eprintln!(
+                        "{}:{}: unknown section {}",
+                        path.display(),
+                        line_number + 1,
+                        line
+                    );
+                }
+                continue;
+            }
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => {
+                    eprintln!("{}:{}: expected key = value", path.display(), line_number + 1);
+                    continue;
+                }
+            };
+            if in_keymap {
+                match Keymap::parse_binding(key, value) {
+                    Ok(binding) => config.keymap.push(binding),
+                    Err(e) => eprintln!("{}:{}: {}", path.display(), line_number + 1, e),
+                }
+                continue;
+            }
+            match key {
+                "soft_reset_combo" => match value.parse() {
+                    Ok(action) => config.soft_reset_combo = Some(action),
+                    Err(e) => eprintln!("{}:{}: {}", path.display(), line_number + 1, e),
+                },
+                "save_interval" => match value.parse() {
+                    Ok(when) => config.save_ram_flush = Some(when),
+                    Err(e) => eprintln!("{}:{}: {}", path.display(), line_number + 1, e),
+                },
+                "rng_address" => match parse(value) {
+                    Ok(address) => config.rng_addresses.push(address),
+                    Err(e) => eprintln!("{}:{}: {}", path.display(), line_number + 1, e),
+                },
+                "savepoint" => {
+                    let mut parts = value.split_whitespace();
+                    let address = parts.next().unwrap_or("");
+                    let label = parts.next().unwrap_or("savepoint").to_string();
+                    match parse(address) {
+                        Ok(address) => config.savepoints.push((address, label)),
+                        Err(e) => eprintln!("{}:{}: {}", path.display(), line_number + 1, e),
+                    }
+                }
+                "patch" => match value.parse() {
+                    Ok(patch) => config.patches.push(patch),
+                    Err(e) => eprintln!("{}:{}: {}", path.display(), line_number + 1, e),
+                },
+                _ => eprintln!(
+                    "{}:{}: unknown setting {}",
+                    path.display(),
+                    line_number + 1,
+                    key
+                ),
+            }
+        }
+        config
+    }
+}
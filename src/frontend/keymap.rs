@@ -0,0 +1,219 @@
+use std::{
+    fmt::{self, Display},
+    fs, io,
+    path::Path,
+};
+
+use super::game_config::GameConfig;
+use crate::gbc::InputState;
+
+/// A Game Boy button, or one of the turbo buttons that keep pressing and
+/// releasing A or B while they're held (see `Turbo`)
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Button {
+    A,
+    B,
+    Select,
+    Start,
+    Right,
+    Left,
+    Up,
+    Down,
+    TurboA,
+    TurboB,
+}
+
+impl std::str::FromStr for Button {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "a" => Ok(Self::A),
+            "b" => Ok(Self::B),
+            "select" => Ok(Self::Select),
+            "start" => Ok(Self::Start),
+            "right" => Ok(Self::Right),
+            "left" => Ok(Self::Left),
+            "up" => Ok(Self::Up),
+            "down" => Ok(Self::Down),
+            "turbo_a" => Ok(Self::TurboA),
+            "turbo_b" => Ok(Self::TurboB),
+            _ => Err(format!("Unknown button {}", s)),
+        }
+    }
+}
+
+impl Display for Button {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::A => write!(f, "a"),
+            Self::B => write!(f, "b"),
+            Self::Select => write!(f, "select"),
+            Self::Start => write!(f, "start"),
+            Self::Right => write!(f, "right"),
+            Self::Left => write!(f, "left"),
+            Self::Up => write!(f, "up"),
+            Self::Down => write!(f, "down"),
+            Self::TurboA => write!(f, "turbo_a"),
+            Self::TurboB => write!(f, "turbo_b"),
+        }
+    }
+}
+
+impl Button {
+    #[must_use]
+    pub fn is_turbo(self) -> bool {
+        matches!(self, Self::TurboA | Self::TurboB)
+    }
+
+    /// Presses or releases the button, turbo buttons press the one they
+    /// repeat
+    pub fn set(self, input_state: &mut InputState, pressed: bool) {
+        match self {
+            Self::A | Self::TurboA => input_state.a_pressed = pressed,
+            Self::B | Self::TurboB => input_state.b_pressed = pressed,
+            Self::Select => input_state.select_pressed = pressed,
+            Self::Start => input_state.start_pressed = pressed,
+            Self::Right => input_state.right_pressed = pressed,
+            Self::Left => input_state.left_pressed = pressed,
+            Self::Up => input_state.up_pressed = pressed,
+            Self::Down => input_state.down_pressed = pressed,
+        }
+    }
+}
+
+/// Which keys press which buttons. Keys are named like SDL names
+/// scancodes, so they stay in the same place whatever the keyboard layout.
+///
+/// Bindings come from the `[keymap]` section of a game's config, or a file
+/// passed with `--keymap`, one button per line with any number of keys:
+///
+/// ```text
+/// [keymap]
+/// a = Z, J
+/// b = X, K
+/// start = Return
+/// turbo_a = C
+/// ```
+///
+/// Binding a button replaces its default keys, the others keep theirs.
+/// The turbo buttons aren't bound to anything by default.
+#[derive(Clone, Debug)]
+pub struct Keymap {
+    bindings: Vec<(Button, Vec<String>)>,
+}
+
+impl Default for Keymap {
+    fn default() -> Self {
+        let binding = |button, key: &str| (button, vec![key.to_string()]);
+        Self {
+            bindings: vec![
+                binding(Button::A, "Z"),
+                binding(Button::B, "X"),
+                binding(Button::Select, "A"),
+                binding(Button::Start, "S"),
+                binding(Button::Right, "Right"),
+                binding(Button::Left, "Left"),
+                binding(Button::Up, "Up"),
+                binding(Button::Down, "Down"),
+            ],
+        }
+    }
+}
+
+impl Keymap {
+    /// Parses a `button = key, key` line, already split at the `=`
+    pub fn parse_binding(button: &str, keys: &str) -> Result<(Button, Vec<String>), String> {
+        let button = button.parse()?;
+        let keys: Vec<String> = keys
+            .split(',')
+            .map(str::trim)
+            .filter(|key| !key.is_empty())
+            .map(str::to_string)
+            .collect();
+        if keys.is_empty() {
+            return Err(format!("No keys given for {}", button));
+        }
+        Ok((button, keys))
+    }
+
+    /// Replaces the keys bound to `button`
+    pub fn bind(&mut self, button: Button, keys: Vec<String>) {
+        match self.bindings.iter_mut().find(|(b, _)| *b == button) {
+            Some((_, bound)) => *bound = keys,
+            None => self.bindings.push((button, keys)),
+        }
+    }
+
+    /// Applies the bindings in a keymap file on top of the current ones.
+    /// Problems with the contents are reported and skipped.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        for (button, keys) in GameConfig::parse_keymap(&contents, path) {
+            self.bind(button, keys);
+        }
+        Ok(())
+    }
+
+    /// Every key that is bound, with the button it presses
+    pub fn iter(&self) -> impl Iterator<Item = (&str, Button)> {
+        self.bindings
+            .iter()
+            .flat_map(|(button, keys)| keys.iter().map(move |key| (key.as_str(), *button)))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn keys_for(keymap: &Keymap, button: Button) -> Vec<&str> {
+        keymap
+            .iter()
+            .filter(|&(_, b)| b == button)
+            .map(|(key, _)| key)
+            .collect()
+    }
+
+    #[test]
+    fn test_parse_binding() {
+        assert_eq!(
+            Keymap::parse_binding("A", " Z, J ,"),
+            Ok((Button::A, vec!["Z".to_string(), "J".to_string()]))
+        );
+        assert_eq!(
+            Keymap::parse_binding("turbo_b", "Left Shift"),
+            Ok((Button::TurboB, vec!["Left Shift".to_string()]))
+        );
+        assert!(Keymap::parse_binding("c", "Z").is_err());
+        assert!(Keymap::parse_binding("start", " , ").is_err());
+    }
+
+    #[test]
+    fn test_bind_replaces_keys() {
+        let mut keymap = Keymap::default();
+        keymap.bind(Button::A, vec!["J".to_string(), "K".to_string()]);
+        assert_eq!(keys_for(&keymap, Button::A), ["J", "K"]);
+        assert_eq!(keys_for(&keymap, Button::B), ["X"]);
+
+        assert!(keys_for(&keymap, Button::TurboA).is_empty());
+        keymap.bind(Button::TurboA, vec!["C".to_string()]);
+        assert_eq!(keys_for(&keymap, Button::TurboA), ["C"]);
+    }
+
+    #[test]
+    fn test_keymap_file_without_section() {
+        let bindings = GameConfig::parse_keymap(
+            "# Home row\na = J\n[keymap]\nb = K, L\n",
+            Path::new("test.keymap"),
+        );
+        assert_eq!(
+            bindings,
+            [
+                (Button::A, vec!["J".to_string()]),
+                (Button::B, vec!["K".to_string(), "L".to_string()]),
+            ]
+        );
+    }
+}
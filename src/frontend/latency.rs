@@ -0,0 +1,87 @@
+use std::fmt::{self, Display};
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+
+/// Shared slot the emulator thread stamps whenever the PPU finishes a frame.
+/// The frontend takes the value when it uploads that frame, so each produced
+/// frame is measured at most once.
+pub type FrameProducedProbe = Arc<Mutex<Option<Instant>>>;
+
+#[derive(Debug, Default)]
+struct Samples {
+    durations: Vec<Duration>,
+}
+
+impl Samples {
+    fn push(&mut self, d: Duration) {
+        self.durations.push(d);
+    }
+
+    fn percentile(sorted: &[Duration], percent: usize) -> Duration {
+        let index = (sorted.len() - 1) * percent / 100;
+        sorted[index]
+    }
+}
+
+impl Display for Samples {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.durations.is_empty() {
+            return write!(f, "no samples");
+        }
+        let mut sorted = self.durations.clone();
+        sorted.sort();
+        let total: Duration = sorted.iter().sum();
+        #[allow(clippy::cast_possible_truncation)]
+        let mean = total / sorted.len() as u32;
+        write!(
+            f,
+            "min {:>8.3}ms mean {:>8.3}ms p50 {:>8.3}ms p95 {:>8.3}ms max {:>8.3}ms",
+            sorted[0].as_secs_f64() * 1000.0,
+            mean.as_secs_f64() * 1000.0,
+            Self::percentile(&sorted, 50).as_secs_f64() * 1000.0,
+            Self::percentile(&sorted, 95).as_secs_f64() * 1000.0,
+            sorted[sorted.len() - 1].as_secs_f64() * 1000.0,
+        )
+    }
+}
+
+/// Collects per-frame timestamps from the frontend and summarizes where the
+/// time between the PPU finishing a frame and it reaching the screen goes.
+#[derive(Debug, Default)]
+pub struct LatencyStats {
+    produced_to_uploaded: Samples,
+    uploaded_to_presented: Samples,
+    produced_to_presented: Samples,
+    presents_without_new_frame: u64,
+}
+
+impl LatencyStats {
+    pub fn record(&mut self, produced: Option<Instant>, uploaded: Instant, presented: Instant) {
+        match produced {
+            Some(produced) => {
+                self.produced_to_uploaded.push(uploaded - produced);
+                self.uploaded_to_presented.push(presented - uploaded);
+                self.produced_to_presented.push(presented - produced);
+            }
+            None => self.presents_without_new_frame += 1,
+        }
+    }
+}
+
+impl Display for LatencyStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Frame latency over {} frames:",
+            self.produced_to_presented.durations.len()
+        )?;
+        writeln!(f, "\tproduced -> uploaded:  {}", self.produced_to_uploaded)?;
+        writeln!(f, "\tuploaded -> presented: {}", self.uploaded_to_presented)?;
+        writeln!(f, "\tproduced -> presented: {}", self.produced_to_presented)?;
+        write!(
+            f,
+            "\tpresents without a new frame: {}",
+            self.presents_without_new_frame
+        )
+    }
+}
@@ -0,0 +1,6 @@
+pub mod game_config;
+pub mod keymap;
+pub mod latency;
+pub mod overlay;
+pub mod tile_viewer;
+pub mod turbo;
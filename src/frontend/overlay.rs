@@ -0,0 +1,77 @@
+use std::time::Duration;
+
+const GLYPH_WIDTH: usize = 3;
+const GLYPH_HEIGHT: usize = 5;
+const MARGIN: usize = 1;
+
+// Each row is 3 bits wide, most significant bit is the leftmost pixel
+const DIGITS: [[u8; GLYPH_HEIGHT]; 10] = [
+    [0b111, 0b101, 0b101, 0b101, 0b111], // 0
+    [0b010, 0b110, 0b010, 0b010, 0b111], // 1
+    [0b111, 0b001, 0b111, 0b100, 0b111], // 2
+    [0b111, 0b001, 0b111, 0b001, 0b111], // 3
+    [0b101, 0b101, 0b111, 0b001, 0b001], // 4
+    [0b111, 0b100, 0b111, 0b001, 0b111], // 5
+    [0b111, 0b100, 0b111, 0b101, 0b111], // 6
+    [0b111, 0b001, 0b001, 0b001, 0b001], // 7
+    [0b111, 0b101, 0b111, 0b101, 0b111], // 8
+    [0b111, 0b101, 0b111, 0b001, 0b111], // 9
+];
+const COLON: [u8; GLYPH_HEIGHT] = [0b000, 0b010, 0b000, 0b010, 0b000];
+const PERIOD: [u8; GLYPH_HEIGHT] = [0b000, 0b000, 0b000, 0b000, 0b010];
+
+fn glyph_for(c: char) -> Option<&'static [u8; GLYPH_HEIGHT]> {
+    match c {
+        '0'..='9' => c.to_digit(10).map(|d| &DIGITS[d as usize]),
+        ':' => Some(&COLON),
+        '.' => Some(&PERIOD),
+        _ => None,
+    }
+}
+
+fn set_pixel(data: &mut [u8], pitch: usize, x: usize, y: usize, value: u8) {
+    let i = y * pitch + x * 4;
+    data[i] = value;
+    data[i + 1] = value;
+    data[i + 2] = value;
+}
+
+/// Formats a duration as a race timer, e.g. 12:34.56
+#[must_use]
+pub fn format_timer(elapsed: Duration) -> String {
+    let total_centis = elapsed.as_millis() / 10;
+    let centis = total_centis % 100;
+    let seconds = (total_centis / 100) % 60;
+    let minutes = total_centis / 6000;
+    format!("{:02}:{:02}.{:02}", minutes, seconds, centis)
+}
+
+/// Draws the timer in the top left corner of a 4 bytes per pixel texture,
+/// white on a black box so it stays readable over any game
+pub fn draw_timer(data: &mut [u8], pitch: usize, elapsed: Duration) {
+    let text = format_timer(elapsed);
+    let glyph_count = text.chars().count();
+    let box_width = MARGIN + glyph_count * (GLYPH_WIDTH + MARGIN);
+    let box_height = GLYPH_HEIGHT + 2 * MARGIN;
+
+    for y in 0..box_height {
+        for x in 0..box_width {
+            set_pixel(data, pitch, x, y, 0x00);
+        }
+    }
+
+    for (n, c) in text.chars().enumerate() {
+        let glyph = match glyph_for(c) {
+            Some(glyph) => glyph,
+            None => continue,
+        };
+        let left = MARGIN + n * (GLYPH_WIDTH + MARGIN);
+        for (row, bits) in glyph.iter().enumerate() {
+            for column in 0..GLYPH_WIDTH {
+                if bits & (1 << (GLYPH_WIDTH - 1 - column)) != 0 {
+                    set_pixel(data, pitch, left + column, MARGIN + row, 0xff);
+                }
+            }
+        }
+    }
+}
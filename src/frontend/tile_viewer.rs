@@ -0,0 +1,181 @@
+use crate::gbc::memory_snapshot::MemorySnapshot;
+use crate::gbc::mmio::lcd::Color;
+use crate::gbc::ppu::RgbaLayout;
+
+// The three 128 tile blocks, 16 tiles to a row
+const TILES_X: usize = 0;
+const TILES_Y: usize = 0;
+const TILES_PER_ROW: usize = 16;
+const TILE_COUNT: usize = 384;
+// Both background maps side by side, right of the tiles
+const MAP_X: [usize; 2] = [136, 400];
+const MAP_Y: usize = 0;
+// The 40 sprites as they are in OAM, under the tiles
+const SPRITES_X: usize = 0;
+const SPRITES_Y: usize = 200;
+const SPRITES_PER_ROW: usize = 10;
+const SPRITE_CELL_WIDTH: usize = 12;
+const SPRITE_CELL_HEIGHT: usize = 20;
+
+pub const WIDTH: u32 = 656;
+pub const HEIGHT: u32 = 280;
+
+const BACKGROUND: [u8; 3] = [0x40, 0x40, 0x40];
+const SCROLL_RECT: [u8; 3] = [0xff, 0x40, 0x40];
+
+const LCDC: u16 = 0xff40;
+const SCY: u16 = 0xff42;
+const SCX: u16 = 0xff43;
+const BGP: u16 = 0xff47;
+const OBP0: u16 = 0xff48;
+const OBP1: u16 = 0xff49;
+const OAM: u16 = 0xfe00;
+
+// The texture being drawn into
+struct Target<'a> {
+    data: &'a mut [u8],
+    pitch: usize,
+    layout: RgbaLayout,
+}
+
+impl Target<'_> {
+    fn set_pixel(&mut self, x: usize, y: usize, rgb: [u8; 3]) {
+        let i = y * self.pitch + x * 4;
+        self.data[i + self.layout.red] = rgb[0];
+        self.data[i + self.layout.green] = rgb[1];
+        self.data[i + self.layout.blue] = rgb[2];
+        self.data[i + self.layout.alpha] = 0xff;
+    }
+}
+
+/// Picks the shade a palette register gives a color index
+fn shade(palette: u8, index: u8) -> [u8; 3] {
+    Color::from((palette >> (2 * index)) & 0x3).to_rgb()
+}
+
+/// Draws the 8x8 tile starting at `address` with its top left corner at
+/// `x`, `y`
+fn draw_tile(
+    target: &mut Target<'_>,
+    snapshot: &MemorySnapshot,
+    address: u16,
+    palette: u8,
+    x: usize,
+    y: usize,
+) {
+    for row in 0..8 {
+        let low = snapshot.peek_u8(address + 2 * row);
+        let high = snapshot.peek_u8(address + 2 * row + 1);
+        for column in 0..8 {
+            let bit = 7 - column;
+            let index = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+            let rgb = shade(palette, index);
+            target.set_pixel(x + column, y + row as usize, rgb);
+        }
+    }
+}
+
+/// Where the tile a map entry points at starts, following the addressing
+/// mode LCDC bit 4 picks
+fn map_tile_address(lcdc: u8, tile_index: u8) -> u16 {
+    if lcdc & 0x10 != 0 {
+        0x8000 + 16 * u16::from(tile_index)
+    } else {
+        #[allow(clippy::cast_possible_wrap)]
+        let signed = i32::from(tile_index as i8);
+        #[allow(clippy::cast_possible_truncation)]
+        #[allow(clippy::cast_sign_loss)]
+        let address = (0x9000 + 16 * signed) as u16;
+        address
+    }
+}
+
+/// Outlines the 160x144 area the background is scrolled to, wrapping
+/// around the edges of the map like the PPU does
+fn draw_scroll_rect(target: &mut Target<'_>, left: usize, scroll_x: u8, scroll_y: u8) {
+    let mut outline = |x: usize, y: usize| {
+        target.set_pixel(
+            left + (usize::from(scroll_x) + x) % 256,
+            MAP_Y + (usize::from(scroll_y) + y) % 256,
+            SCROLL_RECT,
+        );
+    };
+    for x in 0..160 {
+        outline(x, 0);
+        outline(x, 143);
+    }
+    for y in 0..144 {
+        outline(0, y);
+        outline(159, y);
+    }
+}
+
+/// Draws everything in VRAM and OAM into a `WIDTH` by `HEIGHT`, 4 bytes
+/// per pixel texture: all three tile blocks with BGP, both background maps
+/// with the one being shown outlined where SCX and SCY put the screen,
+/// and every sprite in OAM order with its own palette.
+pub fn draw(data: &mut [u8], pitch: usize, layout: RgbaLayout, snapshot: &MemorySnapshot) {
+    let mut target = Target {
+        data,
+        pitch,
+        layout,
+    };
+    for y in 0..HEIGHT as usize {
+        for x in 0..WIDTH as usize {
+            target.set_pixel(x, y, BACKGROUND);
+        }
+    }
+
+    let lcdc = snapshot.peek_u8(LCDC);
+    let bgp = snapshot.peek_u8(BGP);
+
+    for tile in 0..TILE_COUNT {
+        let x = TILES_X + 8 * (tile % TILES_PER_ROW);
+        let y = TILES_Y + 8 * (tile / TILES_PER_ROW);
+        #[allow(clippy::cast_possible_truncation)]
+        let address = 0x8000 + 16 * tile as u16;
+        draw_tile(&mut target, snapshot, address, bgp, x, y);
+    }
+
+    for (map, &left) in MAP_X.iter().enumerate() {
+        #[allow(clippy::cast_possible_truncation)]
+        let map_address = 0x9800 + 0x400 * map as u16;
+        for entry in 0..0x400_u16 {
+            let tile_index = snapshot.peek_u8(map_address + entry);
+            let x = left + 8 * usize::from(entry % 32);
+            let y = MAP_Y + 8 * usize::from(entry / 32);
+            let address = map_tile_address(lcdc, tile_index);
+            draw_tile(&mut target, snapshot, address, bgp, x, y);
+        }
+    }
+    let shown_map = usize::from(lcdc & 0x08 != 0);
+    draw_scroll_rect(
+        &mut target,
+        MAP_X[shown_map],
+        snapshot.peek_u8(SCX),
+        snapshot.peek_u8(SCY),
+    );
+
+    let tall_sprites = lcdc & 0x04 != 0;
+    for sprite in 0..40 {
+        let x = SPRITES_X + SPRITE_CELL_WIDTH * (sprite % SPRITES_PER_ROW);
+        let y = SPRITES_Y + SPRITE_CELL_HEIGHT * (sprite / SPRITES_PER_ROW);
+        #[allow(clippy::cast_possible_truncation)]
+        let entry = OAM + 4 * sprite as u16;
+        let mut tile_index = snapshot.peek_u8(entry + 2);
+        let attributes = snapshot.peek_u8(entry + 3);
+        let palette = if attributes & 0x10 == 0 {
+            snapshot.peek_u8(OBP0)
+        } else {
+            snapshot.peek_u8(OBP1)
+        };
+        if tall_sprites {
+            tile_index &= 0xfe;
+        }
+        let address = 0x8000 + 16 * u16::from(tile_index);
+        draw_tile(&mut target, snapshot, address, palette, x, y);
+        if tall_sprites {
+            draw_tile(&mut target, snapshot, address + 16, palette, x, y + 8);
+        }
+    }
+}
@@ -0,0 +1,131 @@
+use crate::gbc::InputState;
+
+use super::keymap::Button;
+
+// Close enough to the Game Boy's 59.7 frames a second for picking how long
+// each press lasts
+const FRAMES_PER_SECOND: u32 = 60;
+
+/// Autofire for the turbo buttons. While one is held its button is pressed
+/// and released over and over, switching every few frames rather than on a
+/// wall clock timer, so a game sees the same pattern whatever speed it runs
+/// at. Every held turbo button is pressed and released together.
+#[derive(Debug)]
+pub struct Turbo {
+    // Frames each press, and each release, lasts
+    half_period: u32,
+    held: Vec<Button>,
+    frames: u32,
+    pressed: bool,
+}
+
+impl Turbo {
+    /// `rate` is how many times a second the buttons are pressed
+    #[must_use]
+    pub fn new(rate: u32) -> Self {
+        Self {
+            half_period: (FRAMES_PER_SECOND / (2 * rate.max(1))).max(1),
+            held: Vec::new(),
+            frames: 0,
+            pressed: false,
+        }
+    }
+
+    /// A turbo button going down or up. The first one to go down starts out
+    /// pressed, so autofire reacts as quickly as a normal press.
+    pub fn hold(&mut self, button: Button, held: bool, input_state: &mut InputState) {
+        if held {
+            if self.held.contains(&button) {
+                return;
+            }
+            if self.held.is_empty() {
+                self.frames = 0;
+                self.pressed = true;
+            }
+            self.held.push(button);
+            button.set(input_state, self.pressed);
+        } else {
+            self.held.retain(|&b| b != button);
+            button.set(input_state, false);
+        }
+    }
+
+    /// Lets go of every turbo button without touching the input state
+    pub fn release_all(&mut self) {
+        self.held.clear();
+    }
+
+    /// Moves on a frame, pressing or releasing the held buttons when it's
+    /// time
+    pub fn next_frame(&mut self, input_state: &mut InputState) {
+        if self.held.is_empty() {
+            return;
+        }
+        self.frames += 1;
+        if self.frames < self.half_period {
+            return;
+        }
+        self.frames = 0;
+        self.pressed = !self.pressed;
+        for button in &self.held {
+            button.set(input_state, self.pressed);
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // Whether A is pressed on each of the next `frames` frames
+    fn a_presses(turbo: &mut Turbo, input_state: &mut InputState, frames: usize) -> Vec<bool> {
+        (0..frames)
+            .map(|_| {
+                turbo.next_frame(input_state);
+                input_state.a_pressed
+            })
+            .collect()
+    }
+
+    #[test]
+    fn test_turbo_toggles_every_half_period() {
+        let mut input_state = InputState::default();
+        // 10 presses a second, each press and release lasts 3 frames
+        let mut turbo = Turbo::new(10);
+        turbo.hold(Button::TurboA, true, &mut input_state);
+        assert!(input_state.a_pressed);
+        assert_eq!(
+            a_presses(&mut turbo, &mut input_state, 8),
+            [true, true, false, false, false, true, true, true]
+        );
+
+        turbo.hold(Button::TurboA, false, &mut input_state);
+        assert!(!input_state.a_pressed);
+        assert_eq!(a_presses(&mut turbo, &mut input_state, 4), [false; 4]);
+    }
+
+    #[test]
+    fn test_turbo_buttons_press_together() {
+        let mut input_state = InputState::default();
+        let mut turbo = Turbo::new(30);
+        turbo.hold(Button::TurboA, true, &mut input_state);
+        turbo.next_frame(&mut input_state);
+        // B joins in released, in step with A
+        turbo.hold(Button::TurboB, true, &mut input_state);
+        assert!(!input_state.a_pressed && !input_state.b_pressed);
+        turbo.next_frame(&mut input_state);
+        assert!(input_state.a_pressed && input_state.b_pressed);
+    }
+
+    #[test]
+    fn test_turbo_rate_is_clamped() {
+        let mut input_state = InputState::default();
+        // Faster than the frame rate still switches at most every frame
+        let mut turbo = Turbo::new(1000);
+        turbo.hold(Button::TurboA, true, &mut input_state);
+        assert_eq!(
+            a_presses(&mut turbo, &mut input_state, 4),
+            [false, true, false, true]
+        );
+    }
+}
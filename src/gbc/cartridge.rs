@@ -1,18 +1,33 @@
 use std::{
     convert::{TryFrom, TryInto},
+    error::Error,
     fmt,
-    fs::File,
-    io::{self, Read},
+    fs::{self, File},
+    io::{self, Cursor, ErrorKind, Read},
+    ops::Range,
     path::{Path, PathBuf},
+    time::{SystemTime, UNIX_EPOCH},
 };
 
-const NINTENDO_LOGO_BYTES: [u8; 0x30] = [
+use flate2::read::GzDecoder;
+use zip::{result::ZipError, ZipArchive};
+
+use super::mapper::{self, Mapper, MbcRegister};
+use super::rtc::{RealTimeClock, RTC_FOOTER_SIZE};
+use super::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+const ZIP_MAGIC: [u8; 4] = [b'P', b'K', 0x03, 0x04];
+
+pub const NINTENDO_LOGO_BYTES: [u8; 0x30] = [
     0xce, 0xed, 0x66, 0x66, 0xcc, 0x0d, 0x00, 0x0b, 0x03, 0x73, 0x00, 0x83, 0x00, 0x0c, 0x00, 0x0d,
     0x00, 0x08, 0x11, 0x1f, 0x88, 0x89, 0x00, 0x0e, 0xdc, 0xcc, 0x6e, 0xe6, 0xdd, 0xdd, 0xd9, 0x99,
     0xbb, 0xbb, 0x67, 0x63, 0x6e, 0x0e, 0xec, 0xcc, 0xdd, 0xdc, 0x99, 0x9f, 0xbb, 0xb9, 0x33, 0x3e,
 ];
 
-#[derive(Debug)]
+const MBC2_RAM_SIZE: u32 = 512;
+
+#[derive(Debug, Clone, Copy)]
 pub enum Type {
     Rom = 0x00,
     Mbc1 = 0x01,
@@ -51,7 +66,8 @@ impl Default for Type {
 }
 
 impl Type {
-    fn mbc_type(&self) -> u8 {
+    #[must_use]
+    pub fn mbc_type(&self) -> u8 {
         match self {
             Type::Mbc1 | Type::Mbc1Ram | Type::Mbc1RamBattery => 1,
             Type::Mbc2 | Type::Mbc2Battery => 2,
@@ -71,6 +87,64 @@ impl Type {
             _ => 0,
         }
     }
+
+    /// Name of the chip handling banking, as used in the header docs
+    #[must_use]
+    pub fn mapper_name(&self) -> &'static str {
+        match self {
+            Type::Rom | Type::RomRam | Type::RomRamBattery => "none",
+            Type::Mmm01 | Type::Mmm01Ram | Type::Mmm01RamBattery => "MMM01",
+            Type::PocketCamera => "Pocket Camera",
+            Type::BandaiTama5 => "Bandai TAMA5",
+            Type::HuC3 => "HuC3",
+            Type::Huc1RamBattery => "HuC1",
+            _ => match self.mbc_type() {
+                1 => "MBC1",
+                2 => "MBC2",
+                3 => "MBC3",
+                5 => "MBC5",
+                6 => "MBC6",
+                _ => "MBC7",
+            },
+        }
+    }
+
+    /// Whether there is a `Mapper` for this cartridge type
+    #[must_use]
+    pub fn is_supported(&self) -> bool {
+        matches!(self, Type::Rom | Type::RomRam | Type::RomRamBattery)
+            || matches!(self.mbc_type(), 1 | 2 | 3 | 5)
+    }
+
+    #[must_use]
+    pub fn has_rtc(&self) -> bool {
+        matches!(self, Type::Mbc3TimerBattery | Type::Mbc3TimerRamBattery)
+    }
+
+    fn has_battery(&self) -> bool {
+        matches!(
+            self,
+            Type::Mbc1RamBattery
+                | Type::Mbc2Battery
+                | Type::RomRamBattery
+                | Type::Mmm01RamBattery
+                | Type::Mbc3TimerBattery
+                | Type::Mbc3TimerRamBattery
+                | Type::Mbc3RamBattery
+                | Type::Mbc5RamBattery
+                | Type::Mbc5RumbleRamBattery
+                | Type::Mbc7SensorRumbleRamBattery
+                | Type::Huc1RamBattery
+        )
+    }
+
+    #[must_use]
+    pub fn has_rumble(&self) -> bool {
+        matches!(
+            self,
+            Type::Mbc5Rumble | Type::Mbc5RumbleRam | Type::Mbc5RumbleRamBattery
+        )
+    }
 }
 
 impl TryFrom<u8> for Type {
@@ -117,6 +191,28 @@ impl TryFrom<u8> for Type {
     }
 }
 
+/// Finds the first .gb or .gbc file in a zip archive and decompresses it
+fn read_rom_from_zip(data: &[u8]) -> Result<Vec<u8>, CartridgeError> {
+    let mut archive = ZipArchive::new(Cursor::new(data))?;
+    for i in 0..archive.len() {
+        let mut entry = archive.by_index(i)?;
+        let name = entry.name().to_lowercase();
+        if !(name.ends_with(".gb") || name.ends_with(".gbc")) {
+            continue;
+        }
+        let mut rom = Vec::new();
+        entry.read_to_end(&mut rom)?;
+        return Ok(rom);
+    }
+    Err(CartridgeError::NoRomInArchive)
+}
+
+fn unix_time() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map_or(0, |d| d.as_secs())
+}
+
 fn calculate_header_checksum(header: &[u8]) -> u8 {
     assert!(header.len() > 0x4d);
     let mut x: u8 = 0;
@@ -214,9 +310,103 @@ impl fmt::Display for IsJapanese {
     }
 }
 
+/// Where support for more cartridge types is tracked
+const ISSUES_URL: &str = "https://github.com/AgentX1994/rust_gbc_emu/issues";
+
+/// Reasons a ROM can't be loaded at all
+#[derive(Debug)]
+pub enum CartridgeError {
+    Io(io::Error),
+    Zip(ZipError),
+    NoRomInArchive,
+    TooSmall(usize),
+    UnknownType(u8),
+    UnsupportedType(Type),
+    UnknownRomSize(u8),
+    UnknownRamSize(u8),
+}
+
+impl fmt::Display for CartridgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::Zip(e) => write!(f, "{}", e),
+            Self::NoRomInArchive => write!(f, "archive does not contain a .gb or .gbc file"),
+            Self::TooSmall(size) => write!(
+                f,
+                "ROM is {} bytes, too small to contain a header",
+                size
+            ),
+            Self::UnknownType(code) => write!(f, "unknown cartridge type {:#04x}", code),
+            Self::UnsupportedType(cartridge_type) => write!(
+                f,
+                "cartridge type {:#04x} uses the {} mapper, which isn't supported yet, see {}",
+                *cartridge_type as u8,
+                cartridge_type.mapper_name(),
+                ISSUES_URL
+            ),
+            Self::UnknownRomSize(code) => write!(f, "unknown ROM size code {:#04x}", code),
+            Self::UnknownRamSize(code) => write!(f, "unknown RAM size code {:#04x}", code),
+        }
+    }
+}
+
+impl Error for CartridgeError {}
+
+impl From<io::Error> for CartridgeError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+impl From<ZipError> for CartridgeError {
+    fn from(e: ZipError) -> Self {
+        Self::Zip(e)
+    }
+}
+
+/// Problems with the header that real hardware (or at least an emulator)
+/// can live with, kept so they can be shown later
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum CartridgeWarning {
+    LogoMismatch,
+    HeaderChecksumMismatch { header: u8, calculated: u8 },
+    GlobalChecksumMismatch { header: u16, calculated: u16 },
+    RomSizeMismatch { declared: u32, actual: usize },
+    Mbc2RamSizeNotZero(u8),
+}
+
+impl fmt::Display for CartridgeWarning {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::LogoMismatch => write!(f, "Nintendo logo doesn't match"),
+            Self::HeaderChecksumMismatch { header, calculated } => write!(
+                f,
+                "header checksum doesn't match, header contains {:#04x} but calculated {:#04x}",
+                header, calculated
+            ),
+            Self::GlobalChecksumMismatch { header, calculated } => write!(
+                f,
+                "global checksum doesn't match, header contains {:#06x} but calculated {:#06x}",
+                header, calculated
+            ),
+            Self::RomSizeMismatch { declared, actual } => write!(
+                f,
+                "header declares {} bytes of ROM but the file has {}",
+                declared, actual
+            ),
+            Self::Mbc2RamSizeNotZero(code) => write!(
+                f,
+                "cartridge uses MBC2 but the RAM size code is {:#04x} instead of 0",
+                code
+            ),
+        }
+    }
+}
+
 #[derive(Debug, Default)]
 pub struct Cartridge {
-    pub rom_path: PathBuf,
+    pub rom_path: Option<PathBuf>,
     pub rom: Vec<u8>,
     pub title: String,
     pub manufacturer_code: [u8; 4],
@@ -231,17 +421,50 @@ pub struct Cartridge {
     pub header_checksum: u8,
     pub global_checksum: u16,
     pub external_ram: Vec<u8>,
-    pub enable_external_ram: bool,
-    pub rom_bank_selected: u8,
-    pub advanced_banking_mode: bool,
+    mapper: Box<dyn Mapper>,
+    save_ram_dirty: bool,
+    save_ram_written: bool,
+    pub warnings: Vec<CartridgeWarning>,
 }
 
 impl Cartridge {
-    pub fn new<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+    /// Loads a ROM file, which may also be gzip compressed or a zip archive
+    /// containing a .gb/.gbc file
+    pub fn new<P: AsRef<Path>>(path: P) -> Result<Self, CartridgeError> {
         let rom_path = path.as_ref().to_owned();
         let mut file = File::open(path)?;
-        let mut rom = Vec::<u8>::new();
-        file.read_to_end(&mut rom)?;
+        let mut data = Vec::<u8>::new();
+        file.read_to_end(&mut data)?;
+
+        let rom = if data.starts_with(&GZIP_MAGIC) {
+            let mut rom = Vec::new();
+            GzDecoder::new(&data[..]).read_to_end(&mut rom)?;
+            rom
+        } else if data.starts_with(&ZIP_MAGIC) {
+            read_rom_from_zip(&data)?
+        } else {
+            data
+        };
+
+        let mut cartridge = Self::from_rom(rom)?;
+        cartridge.rom_path = Some(rom_path);
+        if cartridge.has_save_ram() {
+            cartridge.load_save_ram()?;
+        }
+        Ok(cartridge)
+    }
+
+    /// Creates a cartridge from ROM data that is already in memory. There is
+    /// no file to put a save next to, so battery backed RAM is not persisted.
+    pub fn from_bytes(bytes: &[u8]) -> Result<Self, CartridgeError> {
+        Self::from_rom(bytes.to_vec())
+    }
+
+    fn from_rom(mut rom: Vec<u8>) -> Result<Self, CartridgeError> {
+        if rom.len() < 0x150 {
+            return Err(CartridgeError::TooSmall(rom.len()));
+        }
+        let mut warnings = Vec::new();
 
         // Read header
         let header = &rom[0x100..0x150];
@@ -250,7 +473,7 @@ impl Cartridge {
         // This isn't a fatal error for an emulator
         let nintendo_logo = &header[0x04..0x34];
         if nintendo_logo != NINTENDO_LOGO_BYTES {
-            eprintln!("Nintendo Logo doesn't match!");
+            warnings.push(CartridgeWarning::LogoMismatch);
         }
 
         // is this a color cartridge?
@@ -284,15 +507,25 @@ impl Cartridge {
             }
         };
         let supports_sgb: SuperGameBoySupport = (header[0x46] == 0x3).into();
-        let cartridge_type: Type =
-            header[0x47].try_into().expect("Invalid cartridge type!");
+        let cartridge_type: Type = header[0x47]
+            .try_into()
+            .map_err(|_| CartridgeError::UnknownType(header[0x47]))?;
+        // Better to refuse now than to boot and misbehave once the game
+        // starts switching banks
+        if !cartridge_type.is_supported() {
+            return Err(CartridgeError::UnsupportedType(cartridge_type));
+        }
+        if header[0x48] > 8 {
+            return Err(CartridgeError::UnknownRomSize(header[0x48]));
+        }
         let rom_size: u32 = (32 * 1024) << header[0x48];
         let external_ram_size_code = header[0x49];
         let external_ram_size: u32 = if cartridge_type.mbc_type() == 2 {
             if external_ram_size_code != 0 {
-                eprintln!("Error: Cartridge uses MBC2 but ram size code is not 0!");
+                warnings.push(CartridgeWarning::Mbc2RamSizeNotZero(external_ram_size_code));
             }
-            256
+            // MBC2 has 512 half-bytes of RAM built in
+            MBC2_RAM_SIZE
         } else {
             match external_ram_size_code {
                 0 => 0,
@@ -300,7 +533,7 @@ impl Cartridge {
                 3 => 32 * 1024,
                 4 => 128 * 1024,
                 5 => 64 * 1024,
-                _ => panic!("Unknown ram size code {}!", external_ram_size_code),
+                _ => return Err(CartridgeError::UnknownRamSize(external_ram_size_code)),
             }
         };
         let is_japanese = (header[0x4a] == 0).into();
@@ -308,22 +541,34 @@ impl Cartridge {
         let header_checksum = header[0x4d];
         let calculated_checksum = calculate_header_checksum(header);
         if header_checksum != calculated_checksum {
-            eprintln!(
-                "Error: header checksum doesn't match! header contains {}, but calculated {}",
-                header_checksum, calculated_checksum
-            );
+            warnings.push(CartridgeWarning::HeaderChecksumMismatch {
+                header: header_checksum,
+                calculated: calculated_checksum,
+            });
         }
         let global_checksum: u16 = (u16::from(header[0x4e]) << 8) | u16::from(header[0x4f]);
         let calculated_global_checksum = calculate_global_checksum(&rom[..]);
         if global_checksum != calculated_global_checksum {
-            eprintln!(
-                "Error: global checksum doesn't match! header contains {}, but calculated {}",
-                global_checksum, calculated_global_checksum
-            );
+            warnings.push(CartridgeWarning::GlobalChecksumMismatch {
+                header: global_checksum,
+                calculated: calculated_global_checksum,
+            });
         }
 
+        if rom.len() != rom_size as usize {
+            warnings.push(CartridgeWarning::RomSizeMismatch {
+                declared: rom_size,
+                actual: rom.len(),
+            });
+            // Pad (or trim) to the declared size so bank switching can't
+            // run off the end. Unconnected ROM reads as 0xff.
+            rom.resize(rom_size as usize, 0xff);
+        }
+
+        let mapper = mapper::for_cartridge(&cartridge_type, &rom);
+
         Ok(Cartridge {
-            rom_path,
+            rom_path: None,
             rom,
             title,
             manufacturer_code,
@@ -338,81 +583,217 @@ impl Cartridge {
             header_checksum,
             global_checksum,
             external_ram: vec![0; external_ram_size as usize],
-            enable_external_ram: false,
-            rom_bank_selected: 1,
-            advanced_banking_mode: false,
+            mapper,
+            save_ram_dirty: false,
+            save_ram_written: false,
+            warnings,
         })
     }
 
+    /// Whether the external RAM or clock is battery backed and should
+    /// outlive the emulator
     #[must_use]
-    pub fn read_rom_bank_0(&self, offset: u16) -> u8 {
-        assert!(offset < 16384);
-        self.rom[offset as usize]
+    pub fn has_save_ram(&self) -> bool {
+        self.cartridge_type.has_battery()
+            && (!self.external_ram.is_empty() || self.cartridge_type.has_rtc())
     }
 
-    pub fn write_rom_bank_0(&mut self, offset: u16, byte: u8) {
-        match offset {
-            0x0000..=0x1fff => {
-                // external ram enable
-                self.enable_external_ram = (byte & 0xf) == 0x0a;
+    /// The save file lives next to the ROM, e.g. game.gb -> game.sav. ROMs
+    /// loaded from memory have nowhere to save to.
+    #[must_use]
+    pub fn save_path(&self) -> Option<PathBuf> {
+        self.rom_path.as_ref().map(|path| {
+            // game.gb.gz should save to game.sav, not game.gb.sav
+            let extension = path.extension().and_then(|e| e.to_str());
+            if matches!(extension, Some(e) if e.eq_ignore_ascii_case("gz")) {
+                path.with_extension("").with_extension("sav")
+            } else {
+                path.with_extension("sav")
             }
-            0x2000..=0x3fff => {
-                // rom bank switch
-                // TODO Check MBC implementation
-                self.rom_bank_selected = byte & 0x1f;
-                if self.rom_bank_selected == 0 {
-                    self.rom_bank_selected = 1; // Don't select bank 0 again
+        })
+    }
+
+    fn load_save_ram(&mut self) -> io::Result<()> {
+        let save_path = match self.save_path() {
+            Some(save_path) => save_path,
+            None => return Ok(()),
+        };
+        let data = match fs::read(&save_path) {
+            Ok(data) => data,
+            Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(e),
+        };
+        if data.len() < self.external_ram.len() {
+            eprintln!(
+                "Warning: {} is only {} bytes, expected {}",
+                save_path.display(),
+                data.len(),
+                self.external_ram.len()
+            );
+        }
+        let length = data.len().min(self.external_ram.len());
+        self.external_ram[..length].copy_from_slice(&data[..length]);
+
+        if let Some(current_rtc) = self.mapper.rtc_mut() {
+            match RealTimeClock::from_footer(&data[length..]) {
+                Some((mut rtc, saved_at)) => {
+                    // Keep the clock running while the emulator was closed
+                    rtc.advance_seconds(unix_time().saturating_sub(saved_at));
+                    *current_rtc = rtc;
                 }
-                #[allow(clippy::cast_possible_truncation)]
-                let available_banks = (self.rom_size / 16384) as u8;
-                self.rom_bank_selected &= available_banks - 1;
+                None => eprintln!(
+                    "Warning: {} has no clock data, the clock starts from 0",
+                    save_path.display()
+                ),
             }
-            _ => unreachable!(),
         }
+        Ok(())
+    }
+
+    /// Writes the external RAM (and clock) to the save file if the game
+    /// changed it since the last flush. The clock is always changing, so
+    /// `include_clock` forces a write for cartridges that have one.
+    ///
+    /// # Errors
+    /// Returns an error if the save file cannot be written
+    pub fn flush_save_ram(&mut self, include_clock: bool) -> io::Result<()> {
+        let clock_needs_saving = include_clock && self.mapper.rtc().is_some();
+        if !(self.save_ram_dirty || clock_needs_saving) || !self.has_save_ram() {
+            return Ok(());
+        }
+        let save_path = match self.save_path() {
+            Some(save_path) => save_path,
+            None => return Ok(()),
+        };
+        let mut data = self.external_ram.clone();
+        if let Some(rtc) = self.mapper.rtc() {
+            data.reserve(RTC_FOOTER_SIZE);
+            data.extend_from_slice(&rtc.to_footer(unix_time()));
+        }
+        // Write to a temporary file and move it over the old save, so a
+        // crash or power loss mid-write leaves the previous save intact
+        let temporary_path = save_path.with_extension("sav.tmp");
+        fs::write(&temporary_path, data)?;
+        fs::rename(&temporary_path, &save_path)?;
+        self.save_ram_dirty = false;
+        Ok(())
+    }
+
+    /// Overwrites the start of the external RAM with `data`, e.g. a save
+    /// from another emulator, and marks it as needing to be saved. Returns
+    /// how many bytes were copied.
+    pub fn import_external_ram(&mut self, data: &[u8]) -> usize {
+        let length = data.len().min(self.external_ram.len());
+        self.external_ram[..length].copy_from_slice(&data[..length]);
+        self.save_ram_dirty = true;
+        length
     }
 
+    /// Reads from the ROM window, `address` is 0x0000-0x7fff
     #[must_use]
-    pub fn read_rom_selected_bank(&self, offset: u16) -> u8 {
-        assert!(offset < 16384);
-        // TODO check MBC implementation
-        let address = u32::from(offset) + 16384_u32 * u32::from(self.rom_bank_selected);
-        self.rom[address as usize]
-    }
-
-    pub fn write_rom_selected_bank(&mut self, offset: u16, byte: u8) {
-        match offset {
-            0x0000..=0x1fff => {
-                if self.advanced_banking_mode {
-                    todo!()
-                } else {
-                    self.rom_bank_selected = ((byte & 0x3) << 5) | (self.rom_bank_selected | 0x1f);
-                    #[allow(clippy::cast_possible_truncation)]
-                    let available_banks = (self.rom_size / 16384) as u8;
-                    self.rom_bank_selected &= available_banks - 1;
-                }
-            }
-            0x2000..=0x3fff => {
-                self.advanced_banking_mode = (byte & 1) != 0;
+    pub fn read_rom(&self, address: u16) -> u8 {
+        self.mapper.read_rom(&self.rom, address)
+    }
+
+    fn rom_range(bank: u16, address: u16, length: usize) -> Range<usize> {
+        let start = usize::from(bank) * mapper::ROM_BANK_SIZE
+            + usize::from(address) % mapper::ROM_BANK_SIZE;
+        start..start + length
+    }
+
+    /// Reads ROM data from the given bank, whether or not it is mapped,
+    /// starting at the offset of `address` into the bank
+    #[must_use]
+    pub fn read_rom_bytes(&self, bank: u16, address: u16, length: usize) -> Option<&[u8]> {
+        self.rom.get(Self::rom_range(bank, address, length))
+    }
+
+    /// Overwrites ROM data in the given bank, starting at the offset of
+    /// `address` into the bank. Returns false if that runs past the end of
+    /// the ROM.
+    pub fn patch_rom(&mut self, bank: u16, address: u16, bytes: &[u8]) -> bool {
+        match self.rom.get_mut(Self::rom_range(bank, address, bytes.len())) {
+            Some(rom) => {
+                rom.copy_from_slice(bytes);
+                true
             }
-            _ => unreachable!(),
+            None => false,
         }
     }
 
+    /// Writes to the ROM window, which goes to the MBC registers
+    pub fn write_rom(&mut self, address: u16, byte: u8) {
+        self.mapper.write_rom(address, byte);
+    }
+
+    /// Reads from the external RAM window, `offset` is from 0xa000
     #[must_use]
     pub fn read_from_external_ram(&self, offset: u16) -> u8 {
-        if !self.enable_external_ram {
-            return 0xff;
-        }
-        if (offset as usize) < self.external_ram.len() {
-            self.external_ram[offset as usize]
-        } else {
-            0x00
+        self.mapper.read_ram(&self.external_ram, offset)
+    }
+
+    pub fn write_to_external_ram(&mut self, offset: u16, byte: u8) {
+        if self.mapper.write_ram(&mut self.external_ram, offset, byte) {
+            self.save_ram_dirty = true;
+            self.save_ram_written = true;
         }
     }
 
-    pub fn write_to_external_ram(&mut self, offset: u16, v: u8) {
-        if self.enable_external_ram && (offset as usize) < self.external_ram.len() {
-            self.external_ram[offset as usize] = v;
+    /// Whether the game wrote to battery backed state since the last call
+    pub fn take_save_ram_written(&mut self) -> bool {
+        std::mem::take(&mut self.save_ram_written)
+    }
+
+    pub fn tick(&mut self, cycles: u64) {
+        self.mapper.tick(cycles);
+    }
+
+    /// Returns the new state of the rumble motor if the game changed it
+    /// since the last call
+    pub fn take_rumble_change(&mut self) -> Option<bool> {
+        self.mapper.take_rumble_change()
+    }
+
+    /// Decodes which control register a write to the given address
+    /// (0x0000-0x7fff) would hit on this cartridge's MBC
+    #[must_use]
+    pub fn mbc_register_at(&self, address: u16) -> MbcRegister {
+        self.mapper.register_at(address)
+    }
+
+    #[must_use]
+    pub fn get_rom_bank_selected(&self) -> u16 {
+        self.mapper.rom_bank()
+    }
+
+    #[must_use]
+    pub fn get_ram_bank_selected(&self) -> u8 {
+        self.mapper.ram_bank()
+    }
+
+    #[must_use]
+    pub fn get_external_ram_enabled(&self) -> bool {
+        self.mapper.ram_enabled()
+    }
+
+    #[must_use]
+    pub fn get_mapper(&self) -> &dyn Mapper {
+        self.mapper.as_ref()
+    }
+}
+
+impl SaveState for Cartridge {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_vec(&self.external_ram);
+        self.mapper.save_state(writer);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        let external_ram = reader.read_vec()?;
+        if external_ram.len() != self.external_ram.len() {
+            return Err(SaveStateError::Invalid("external RAM size"));
         }
+        self.external_ram = external_ram;
+        self.mapper.load_state(reader)
     }
 }
@@ -0,0 +1,354 @@
+use std::fmt;
+
+use parse_int::parse;
+
+/// Reasons a line can't be assembled
+#[derive(Debug, PartialEq)]
+pub enum AssembleError {
+    Empty,
+    UnknownMnemonic(String),
+    InvalidOperands(String),
+    InvalidNumber(String),
+    ImmediateOutOfRange(i64),
+    JumpOutOfRange(u16),
+}
+
+impl fmt::Display for AssembleError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Empty => write!(f, "no instruction given"),
+            Self::UnknownMnemonic(mnemonic) => write!(f, "unknown mnemonic {}", mnemonic),
+            Self::InvalidOperands(line) => write!(f, "invalid operands for {}", line),
+            Self::InvalidNumber(number) => write!(f, "invalid number {}", number),
+            Self::ImmediateOutOfRange(value) => {
+                write!(f, "immediate {:#x} doesn't fit in the instruction", value)
+            }
+            Self::JumpOutOfRange(target) => {
+                write!(f, "{:#06x} is too far away for a relative jump", target)
+            }
+        }
+    }
+}
+
+#[derive(Debug, PartialEq)]
+enum Arg {
+    // B, C, D, E, H, L, (HL), A in encoding order
+    Reg8(u8),
+    Bc,
+    De,
+    Hl,
+    Sp,
+    Af,
+    DerefBc,
+    DerefDe,
+    DerefHlPlus,
+    DerefHlMinus,
+    DerefAddress(u16),
+    // Written as (0xff00+n), which is always the 2 byte high page load
+    DerefHighPage(u8),
+    DerefFf00PlusC,
+    Condition(u8),
+    Immediate(i64),
+    StackOffset(i64),
+}
+
+const REGISTER_C: u8 = 1;
+const DEREF_HL: u8 = 6;
+const REGISTER_A: u8 = 7;
+const CONDITION_CARRY: u8 = 3;
+
+fn parse_number(text: &str) -> Result<i64, AssembleError> {
+    let (negative, digits) = match text.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, text),
+    };
+    let digits = digits
+        .strip_prefix('$')
+        .map_or_else(|| digits.to_string(), |hex| format!("0x{}", hex));
+    let value: i64 = parse(&digits).map_err(|_| AssembleError::InvalidNumber(text.to_string()))?;
+    Ok(if negative { -value } else { value })
+}
+
+fn parse_deref(inner: &str) -> Result<Arg, AssembleError> {
+    Ok(match inner {
+        "bc" => Arg::DerefBc,
+        "de" => Arg::DerefDe,
+        "hl" => Arg::Reg8(DEREF_HL),
+        "hl+" | "hli" => Arg::DerefHlPlus,
+        "hl-" | "hld" => Arg::DerefHlMinus,
+        "c" | "0xff00+c" | "$ff00+c" | "ff00+c" => Arg::DerefFf00PlusC,
+        _ => {
+            // The disassembler prints high page accesses as (0xff00+0x44)
+            match inner.split_once('+') {
+                Some((base, offset)) if parse_number(base)? == 0xff00 => {
+                    Arg::DerefHighPage(to_u8(parse_number(offset)?)?)
+                }
+                Some(_) => return Err(AssembleError::InvalidOperands(inner.to_string())),
+                None => Arg::DerefAddress(to_u16(parse_number(inner)?)?),
+            }
+        }
+    })
+}
+
+fn parse_arg(text: &str) -> Result<Arg, AssembleError> {
+    if let Some(inner) = text.strip_prefix('(').and_then(|t| t.strip_suffix(')')) {
+        return parse_deref(inner);
+    }
+    if let Some(offset) = text.strip_prefix("sp+") {
+        return Ok(Arg::StackOffset(parse_number(offset)?));
+    }
+    if let Some(offset) = text.strip_prefix("sp-") {
+        return Ok(Arg::StackOffset(-parse_number(offset)?));
+    }
+    Ok(match text {
+        "b" => Arg::Reg8(0),
+        "c" => Arg::Reg8(REGISTER_C),
+        "d" => Arg::Reg8(2),
+        "e" => Arg::Reg8(3),
+        "h" => Arg::Reg8(4),
+        "l" => Arg::Reg8(5),
+        "a" => Arg::Reg8(REGISTER_A),
+        "bc" => Arg::Bc,
+        "de" => Arg::De,
+        "hl" => Arg::Hl,
+        "sp" => Arg::Sp,
+        "af" => Arg::Af,
+        "nz" => Arg::Condition(0),
+        "z" => Arg::Condition(1),
+        "nc" => Arg::Condition(2),
+        _ => Arg::Immediate(parse_number(text)?),
+    })
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn to_u8(value: i64) -> Result<u8, AssembleError> {
+    match value {
+        0..=0xff => Ok(value as u8),
+        // Allow negative numbers as two's complement
+        -0x80..=-1 => Ok(value as i8 as u8),
+        _ => Err(AssembleError::ImmediateOutOfRange(value)),
+    }
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn to_i8(value: i64) -> Result<u8, AssembleError> {
+    match value {
+        -0x80..=0x7f => Ok(value as i8 as u8),
+        _ => Err(AssembleError::ImmediateOutOfRange(value)),
+    }
+}
+
+#[allow(clippy::cast_possible_truncation, clippy::cast_sign_loss)]
+fn to_u16(value: i64) -> Result<u16, AssembleError> {
+    match value {
+        0..=0xffff => Ok(value as u16),
+        -0x8000..=-1 => Ok(value as i16 as u16),
+        _ => Err(AssembleError::ImmediateOutOfRange(value)),
+    }
+}
+
+fn with_u16(opcode: u8, value: i64) -> Result<Vec<u8>, AssembleError> {
+    let [low, high] = to_u16(value)?.to_le_bytes();
+    Ok(vec![opcode, low, high])
+}
+
+// Index of a 16 bit register in the ld/inc/dec/add encodings
+fn register_pair(arg: &Arg) -> Option<u8> {
+    match arg {
+        Arg::Bc => Some(0),
+        Arg::De => Some(1),
+        Arg::Hl => Some(2),
+        Arg::Sp => Some(3),
+        _ => None,
+    }
+}
+
+// C is both a register and a condition, so only jumps and returns read it
+// as a condition
+fn condition(arg: &Arg) -> Option<u8> {
+    match arg {
+        Arg::Condition(condition) => Some(*condition),
+        Arg::Reg8(REGISTER_C) => Some(CONDITION_CARRY),
+        _ => None,
+    }
+}
+
+fn relative_jump(opcode: u8, address: u16, target: i64) -> Result<Vec<u8>, AssembleError> {
+    let target = to_u16(target)?;
+    // Like the disassembler, the operand is the target, relative to the
+    // end of the 2 byte instruction, wrapping around the address space
+    #[allow(clippy::cast_possible_wrap)]
+    let offset = i64::from(target.wrapping_sub(address.wrapping_add(2)) as i16);
+    let offset = to_i8(offset).map_err(|_| AssembleError::JumpOutOfRange(target))?;
+    Ok(vec![opcode, offset])
+}
+
+fn alu_index(mnemonic: &str) -> Option<u8> {
+    ["add", "adc", "sub", "sbc", "and", "xor", "or", "cp"]
+        .iter()
+        .zip(0..)
+        .find_map(|(m, index)| if *m == mnemonic { Some(index) } else { None })
+}
+
+fn rotate_shift_index(mnemonic: &str) -> Option<u8> {
+    ["rlc", "rrc", "rl", "rr", "sla", "sra", "swap", "srl"]
+        .iter()
+        .zip(0..)
+        .find_map(|(m, index)| if *m == mnemonic { Some(index) } else { None })
+}
+
+fn encode_ld(args: &[Arg]) -> Option<Result<Vec<u8>, AssembleError>> {
+    Some(match args {
+        [Arg::Reg8(DEREF_HL), Arg::Reg8(DEREF_HL)] => return None,
+        [Arg::Reg8(d), Arg::Reg8(s)] => Ok(vec![0x40 | d << 3 | s]),
+        [Arg::Reg8(d), Arg::Immediate(n)] => to_u8(*n).map(|n| vec![0x06 | d << 3, n]),
+        [Arg::DerefBc, Arg::Reg8(REGISTER_A)] => Ok(vec![0x02]),
+        [Arg::DerefDe, Arg::Reg8(REGISTER_A)] => Ok(vec![0x12]),
+        [Arg::DerefHlPlus, Arg::Reg8(REGISTER_A)] => Ok(vec![0x22]),
+        [Arg::DerefHlMinus, Arg::Reg8(REGISTER_A)] => Ok(vec![0x32]),
+        [Arg::Reg8(REGISTER_A), Arg::DerefBc] => Ok(vec![0x0a]),
+        [Arg::Reg8(REGISTER_A), Arg::DerefDe] => Ok(vec![0x1a]),
+        [Arg::Reg8(REGISTER_A), Arg::DerefHlPlus] => Ok(vec![0x2a]),
+        [Arg::Reg8(REGISTER_A), Arg::DerefHlMinus] => Ok(vec![0x3a]),
+        [Arg::DerefFf00PlusC, Arg::Reg8(REGISTER_A)] => Ok(vec![0xe2]),
+        [Arg::Reg8(REGISTER_A), Arg::DerefFf00PlusC] => Ok(vec![0xf2]),
+        [Arg::DerefHighPage(n), Arg::Reg8(REGISTER_A)] => Ok(vec![0xe0, *n]),
+        [Arg::Reg8(REGISTER_A), Arg::DerefHighPage(n)] => Ok(vec![0xf0, *n]),
+        [Arg::DerefAddress(address), Arg::Reg8(REGISTER_A)] => with_u16(0xea, i64::from(*address)),
+        [Arg::Reg8(REGISTER_A), Arg::DerefAddress(address)] => with_u16(0xfa, i64::from(*address)),
+        [Arg::DerefAddress(address), Arg::Sp] => with_u16(0x08, i64::from(*address)),
+        [Arg::Sp, Arg::Hl] => Ok(vec![0xf9]),
+        [Arg::Hl, Arg::StackOffset(offset)] => to_i8(*offset).map(|e| vec![0xf8, e]),
+        [rr, Arg::Immediate(nn)] => with_u16(0x01 | register_pair(rr)? << 4, *nn),
+        _ => return None,
+    })
+}
+
+fn encode_ldh(args: &[Arg]) -> Option<Result<Vec<u8>, AssembleError>> {
+    // ldh takes the full address or just the offset into the high page
+    let offset = |address: u16| match address {
+        0xff00..=0xffff => Ok(address.to_le_bytes()[0]),
+        _ => to_u8(i64::from(address)),
+    };
+    Some(match args {
+        [Arg::DerefAddress(address), Arg::Reg8(REGISTER_A)] => {
+            offset(*address).map(|n| vec![0xe0, n])
+        }
+        [Arg::Reg8(REGISTER_A), Arg::DerefAddress(address)] => {
+            offset(*address).map(|n| vec![0xf0, n])
+        }
+        [Arg::DerefHighPage(n), Arg::Reg8(REGISTER_A)] => Ok(vec![0xe0, *n]),
+        [Arg::Reg8(REGISTER_A), Arg::DerefHighPage(n)] => Ok(vec![0xf0, *n]),
+        [Arg::DerefFf00PlusC, Arg::Reg8(REGISTER_A)] => Ok(vec![0xe2]),
+        [Arg::Reg8(REGISTER_A), Arg::DerefFf00PlusC] => Ok(vec![0xf2]),
+        _ => return None,
+    })
+}
+
+fn encode(mnemonic: &str, args: &[Arg], address: u16) -> Option<Result<Vec<u8>, AssembleError>> {
+    if let Some(op) = alu_index(mnemonic) {
+        // The A destination is optional, except for the 16 bit adds
+        let operand = match args {
+            [Arg::Reg8(REGISTER_A), operand] | [operand] => operand,
+            [Arg::Hl, rr] if op == 0 => return Some(Ok(vec![0x09 | register_pair(rr)? << 4])),
+            [Arg::Sp, Arg::Immediate(e)] if op == 0 => {
+                return Some(to_i8(*e).map(|e| vec![0xe8, e]))
+            }
+            _ => return None,
+        };
+        return Some(match operand {
+            Arg::Reg8(r) => Ok(vec![0x80 | op << 3 | r]),
+            Arg::Immediate(n) => to_u8(*n).map(|n| vec![0xc6 | op << 3, n]),
+            _ => return None,
+        });
+    }
+    if let Some(op) = rotate_shift_index(mnemonic) {
+        return match args {
+            [Arg::Reg8(r)] => Some(Ok(vec![0xcb, op << 3 | r])),
+            _ => None,
+        };
+    }
+    Some(match (mnemonic, args) {
+        ("nop", []) => Ok(vec![0x00]),
+        ("stop", []) => Ok(vec![0x10, 0x00]),
+        ("halt", []) => Ok(vec![0x76]),
+        ("di", []) => Ok(vec![0xf3]),
+        ("ei", []) => Ok(vec![0xfb]),
+        ("ret", []) => Ok(vec![0xc9]),
+        ("reti", []) => Ok(vec![0xd9]),
+        ("cpl", []) => Ok(vec![0x2f]),
+        ("daa", []) => Ok(vec![0x27]),
+        ("rlca", []) => Ok(vec![0x07]),
+        ("rla", []) => Ok(vec![0x17]),
+        ("rrca", []) => Ok(vec![0x0f]),
+        ("rra", []) => Ok(vec![0x1f]),
+        ("scf", []) => Ok(vec![0x37]),
+        ("ccf", []) => Ok(vec![0x3f]),
+        ("ld", args) => return encode_ld(args),
+        ("ldh", args) => return encode_ldh(args),
+        ("inc", [Arg::Reg8(r)]) => Ok(vec![0x04 | r << 3]),
+        ("dec", [Arg::Reg8(r)]) => Ok(vec![0x05 | r << 3]),
+        ("inc", [rr]) => Ok(vec![0x03 | register_pair(rr)? << 4]),
+        ("dec", [rr]) => Ok(vec![0x0b | register_pair(rr)? << 4]),
+        ("jp", [Arg::Hl]) | ("jp", [Arg::Reg8(DEREF_HL)]) => Ok(vec![0xe9]),
+        ("jp", [Arg::Immediate(nn)]) => with_u16(0xc3, *nn),
+        ("jp", [cc, Arg::Immediate(nn)]) => with_u16(0xc2 | condition(cc)? << 3, *nn),
+        ("jr", [Arg::Immediate(target)]) => relative_jump(0x18, address, *target),
+        ("jr", [cc, Arg::Immediate(target)]) => {
+            relative_jump(0x20 | condition(cc)? << 3, address, *target)
+        }
+        ("call", [Arg::Immediate(nn)]) => with_u16(0xcd, *nn),
+        ("call", [cc, Arg::Immediate(nn)]) => with_u16(0xc4 | condition(cc)? << 3, *nn),
+        ("ret", [cc]) => Ok(vec![0xc0 | condition(cc)? << 3]),
+        ("push", [Arg::Af]) => Ok(vec![0xf5]),
+        ("pop", [Arg::Af]) => Ok(vec![0xf1]),
+        ("push", [rr]) if *rr != Arg::Sp => Ok(vec![0xc5 | register_pair(rr)? << 4]),
+        ("pop", [rr]) if *rr != Arg::Sp => Ok(vec![0xc1 | register_pair(rr)? << 4]),
+        ("rst", [Arg::Immediate(vector)]) => match *vector {
+            0..=0x38 if vector % 8 == 0 => to_u8(*vector).map(|v| vec![0xc7 | v]),
+            _ => Err(AssembleError::ImmediateOutOfRange(*vector)),
+        },
+        ("bit", [Arg::Immediate(bit), Arg::Reg8(r)])
+        | ("res", [Arg::Immediate(bit), Arg::Reg8(r)])
+        | ("set", [Arg::Immediate(bit), Arg::Reg8(r)]) => {
+            let base = match mnemonic {
+                "bit" => 0x40,
+                "res" => 0x80,
+                _ => 0xc0,
+            };
+            match *bit {
+                0..=7 => to_u8(*bit).map(|b| vec![0xcb, base | b << 3 | r]),
+                _ => Err(AssembleError::ImmediateOutOfRange(*bit)),
+            }
+        }
+        _ => return None,
+    })
+}
+
+const MNEMONICS: [&str; 44] = [
+    "nop", "stop", "halt", "di", "ei", "ret", "reti", "cpl", "daa", "rlca", "rla", "rrca", "rra",
+    "scf", "ccf", "ld", "ldh", "inc", "dec", "jp", "jr", "call", "push", "pop", "rst", "bit",
+    "res", "set", "add", "adc", "sub", "sbc", "and", "xor", "or", "cp", "rlc", "rrc", "rl", "rr",
+    "sla", "sra", "swap", "srl",
+];
+
+/// Assembles a single instruction to be placed at `address`. This accepts
+/// the syntax the disassembler prints, e.g. `ld A (HL+)`, `jr nz,0x150` or
+/// `ld (0xff00+0x44) A`, along with the usual comma separated forms.
+/// Relative jumps take the target address, not the offset.
+pub fn assemble(address: u16, line: &str) -> Result<Vec<u8>, AssembleError> {
+    let line = line.trim().to_lowercase();
+    let mut words = line.split(|c: char| c == ',' || c.is_whitespace());
+    let mnemonic = words
+        .next()
+        .filter(|m| !m.is_empty())
+        .ok_or(AssembleError::Empty)?;
+    if !MNEMONICS.contains(&mnemonic) {
+        return Err(AssembleError::UnknownMnemonic(mnemonic.to_string()));
+    }
+    let args = words
+        .filter(|word| !word.is_empty())
+        .map(parse_arg)
+        .collect::<Result<Vec<_>, _>>()?;
+    encode(mnemonic, &args, address).unwrap_or(Err(AssembleError::InvalidOperands(line)))
+}
@@ -3,7 +3,7 @@ use std::{fmt::Display, mem};
 
 use crate::gbc::memory_bus::MemoryBus;
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Register {
     A,
     B,
@@ -42,7 +42,7 @@ impl Display for Register {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum DerefOperand {
     Register(Register),
     Address(u16),
@@ -50,6 +50,27 @@ pub enum DerefOperand {
     Ff00PlusC,
 }
 
+impl DerefOperand {
+    /// Number of immediate bytes this operand adds after the opcode
+    #[must_use]
+    pub fn immediate_size(&self) -> u8 {
+        match self {
+            DerefOperand::Address(..) => 2,
+            DerefOperand::Ff00Offset(..) => 1,
+            DerefOperand::Register(..) | DerefOperand::Ff00PlusC => 0,
+        }
+    }
+
+    /// Cycles taken by an 8 bit load between a register and this operand,
+    /// in either direction. This is the single source of truth for the
+    /// 0xe0/0xe2/0xf0/0xf2 high page loads, which are easy to get wrong.
+    #[must_use]
+    pub fn load8_cycles(&self) -> u64 {
+        // One memory access plus one cycle per immediate byte fetched
+        4 * (2 + u64::from(self.immediate_size()))
+    }
+}
+
 impl Display for DerefOperand {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
@@ -61,7 +82,7 @@ impl Display for DerefOperand {
     }
 }
 
-#[derive(Debug, PartialEq)]
+#[derive(Clone, Debug, PartialEq)]
 pub enum Operand {
     Register(Register),
     I8(i8),
@@ -90,7 +111,7 @@ impl Display for Operand {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum ConditionType {
     NonZero,
     Zero,
@@ -109,7 +130,7 @@ impl Display for ConditionType {
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub enum Opcode {
     Unknown {
         opcode: u8,
@@ -269,12 +290,12 @@ impl Opcode {
                 condition,
                 destination,
             } => format!("jp {},{:#x}", condition, destination),
-            Opcode::Jr { offset } => format!("jr {:#x}", i32::from(address) + i32::from(*offset)),
+            Opcode::Jr { offset } => format!("jr {:#x}", address.wrapping_add(*offset as u16)),
             Opcode::JrCond { condition, offset } => {
                 format!(
                     "jr {},{:#x}",
                     condition,
-                    i32::from(address) + i32::from(*offset)
+                    address.wrapping_add(*offset as u16)
                 )
             }
             Opcode::Call { destination } => format!("call {:#x}", destination),
@@ -335,21 +356,11 @@ impl Opcode {
             Opcode::Ld8 {
                 destination,
                 source,
-            } => {
-                if let Operand::U8(..) = source {
-                    2
-                } else if let Operand::Deref(DerefOperand::Ff00Offset(..)) = source {
-                    2
-                } else if let Operand::Deref(DerefOperand::Address(..)) = source {
-                    3
-                } else if let Operand::Deref(DerefOperand::Address(..)) = destination {
-                    3
-                } else if let Operand::Deref(DerefOperand::Ff00Offset(..)) = destination {
-                    2
-                } else {
-                    1
-                }
-            }
+            } => match (destination, source) {
+                (_, Operand::U8(..)) => 2,
+                (Operand::Deref(d), _) | (_, Operand::Deref(d)) => 1 + d.immediate_size(),
+                _ => 1,
+            },
             Opcode::Ld16 {
                 destination,
                 source,
@@ -484,7 +495,7 @@ fn make_i8(v: u8) -> i8 {
     unsafe { mem::transmute(v) }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Instruction {
     pub address: u16,
     pub op: Opcode,
@@ -496,8 +507,41 @@ impl Instruction {
         self.op.size()
     }
 
+    /// Where a jump, call or RST goes if it's taken, None for other
+    /// instructions and for `jp hl`
+    #[must_use]
+    pub fn jump_target(&self) -> Option<u16> {
+        let next = self.address.wrapping_add(u16::from(self.size()));
+        match self.op {
+            Opcode::Jp {
+                destination: Operand::U16(destination),
+            }
+            | Opcode::JpCond { destination, .. }
+            | Opcode::Call { destination }
+            | Opcode::CallCond { destination, .. } => Some(destination),
+            Opcode::Jr { offset } | Opcode::JrCond { offset, .. } => {
+                Some(next.wrapping_add(offset as u16))
+            }
+            Opcode::Rst { vector } => Some(u16::from(vector)),
+            _ => None,
+        }
+    }
+
     #[must_use]
     pub fn new(address: u16, memory_bus: &mut MemoryBus) -> Self {
+        Self::decode(address, address.wrapping_add(1), memory_bus)
+    }
+
+    /// Decodes the instruction following a HALT that hit the HALT bug. The
+    /// PC isn't incremented after fetching the opcode, so its byte is read
+    /// again as the first operand (or as the second half of a 0xcb opcode).
+    #[must_use]
+    pub fn new_after_halt_bug(address: u16, memory_bus: &mut MemoryBus) -> Self {
+        Self::decode(address, address, memory_bus)
+    }
+
+    // `operands` is the address of the byte following the opcode
+    fn decode(address: u16, operands: u16, memory_bus: &mut MemoryBus) -> Self {
         let byte = memory_bus.read_u8(address);
 
         #[allow(clippy::match_same_arms)]
@@ -511,8 +555,8 @@ impl Instruction {
                 op: Opcode::Ld16 {
                     destination: Operand::Register(Register::Bc),
                     source: Operand::U16(make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     )),
                 },
             },
@@ -545,7 +589,7 @@ impl Instruction {
                 address,
                 op: Opcode::Ld8 {
                     destination: Operand::Register(Register::B),
-                    source: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    source: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0x07 => Instruction {
@@ -556,8 +600,8 @@ impl Instruction {
                 address,
                 op: Opcode::Ld16 {
                     destination: Operand::Deref(DerefOperand::Address(make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     ))),
                     source: Operand::Register(Register::Sp),
                 },
@@ -598,7 +642,7 @@ impl Instruction {
                 address,
                 op: Opcode::Ld8 {
                     destination: Operand::Register(Register::C),
-                    source: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    source: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0x0f => Instruction {
@@ -606,7 +650,7 @@ impl Instruction {
                 op: Opcode::Rrca,
             },
             0x10 => {
-                let next_byte = memory_bus.read_u8(address.wrapping_add(1));
+                let next_byte = memory_bus.read_u8(operands);
                 if next_byte != 0 {
                     println!("Corrupted STOP!");
                 }
@@ -620,8 +664,8 @@ impl Instruction {
                 op: Opcode::Ld16 {
                     destination: Operand::Register(Register::De),
                     source: Operand::U16(make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     )),
                 },
             },
@@ -654,7 +698,7 @@ impl Instruction {
                 address,
                 op: Opcode::Ld8 {
                     destination: Operand::Register(Register::D),
-                    source: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    source: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0x17 => Instruction {
@@ -664,7 +708,7 @@ impl Instruction {
             0x18 => Instruction {
                 address,
                 op: Opcode::Jr {
-                    offset: make_i8(memory_bus.read_u8(address.wrapping_add(1))),
+                    offset: make_i8(memory_bus.read_u8(operands)),
                 },
             },
             0x19 => Instruction {
@@ -703,7 +747,7 @@ impl Instruction {
                 address,
                 op: Opcode::Ld8 {
                     destination: Operand::Register(Register::E),
-                    source: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    source: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0x1f => Instruction {
@@ -714,7 +758,7 @@ impl Instruction {
                 address,
                 op: Opcode::JrCond {
                     condition: ConditionType::NonZero,
-                    offset: make_i8(memory_bus.read_u8(address.wrapping_add(1))),
+                    offset: make_i8(memory_bus.read_u8(operands)),
                 },
             },
             0x21 => Instruction {
@@ -722,8 +766,8 @@ impl Instruction {
                 op: Opcode::Ld16 {
                     destination: Operand::Register(Register::Hl),
                     source: Operand::U16(make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     )),
                 },
             },
@@ -756,7 +800,7 @@ impl Instruction {
                 address,
                 op: Opcode::Ld8 {
                     destination: Operand::Register(Register::H),
-                    source: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    source: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0x27 => Instruction {
@@ -767,7 +811,7 @@ impl Instruction {
                 address,
                 op: Opcode::JrCond {
                     condition: ConditionType::Zero,
-                    offset: make_i8(memory_bus.read_u8(address.wrapping_add(1))),
+                    offset: make_i8(memory_bus.read_u8(operands)),
                 },
             },
             0x29 => Instruction {
@@ -806,7 +850,7 @@ impl Instruction {
                 address,
                 op: Opcode::Ld8 {
                     destination: Operand::Register(Register::L),
-                    source: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    source: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0x2f => Instruction {
@@ -817,7 +861,7 @@ impl Instruction {
                 address,
                 op: Opcode::JrCond {
                     condition: ConditionType::NotCarry,
-                    offset: make_i8(memory_bus.read_u8(address.wrapping_add(1))),
+                    offset: make_i8(memory_bus.read_u8(operands)),
                 },
             },
             0x31 => Instruction {
@@ -825,8 +869,8 @@ impl Instruction {
                 op: Opcode::Ld16 {
                     destination: Operand::Register(Register::Sp),
                     source: Operand::U16(make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     )),
                 },
             },
@@ -859,7 +903,7 @@ impl Instruction {
                 address,
                 op: Opcode::Ld8 {
                     destination: Operand::Deref(DerefOperand::Register(Register::Hl)),
-                    source: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    source: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0x37 => Instruction {
@@ -870,7 +914,7 @@ impl Instruction {
                 address,
                 op: Opcode::JrCond {
                     condition: ConditionType::Carry,
-                    offset: make_i8(memory_bus.read_u8(address.wrapping_add(1))),
+                    offset: make_i8(memory_bus.read_u8(operands)),
                 },
             },
             0x39 => Instruction {
@@ -909,7 +953,7 @@ impl Instruction {
                 address,
                 op: Opcode::Ld8 {
                     destination: Operand::Register(Register::A),
-                    source: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    source: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0x3f => Instruction {
@@ -1762,8 +1806,8 @@ impl Instruction {
                 op: Opcode::JpCond {
                     condition: ConditionType::NonZero,
                     destination: make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     ),
                 },
             },
@@ -1771,8 +1815,8 @@ impl Instruction {
                 address,
                 op: Opcode::Jp {
                     destination: Operand::U16(make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     )),
                 },
             },
@@ -1781,8 +1825,8 @@ impl Instruction {
                 op: Opcode::CallCond {
                     condition: ConditionType::NonZero,
                     destination: make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     ),
                 },
             },
@@ -1795,7 +1839,7 @@ impl Instruction {
             0xc6 => Instruction {
                 address,
                 op: Opcode::Add8 {
-                    operand: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    operand: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0xc7 => Instruction {
@@ -1817,19 +1861,19 @@ impl Instruction {
                 op: Opcode::JpCond {
                     condition: ConditionType::Zero,
                     destination: make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     ),
                 },
             },
-            0xcb => Self::make_cb_instruction(address, memory_bus),
+            0xcb => Self::make_cb_instruction(address, operands, memory_bus),
             0xcc => Instruction {
                 address,
                 op: Opcode::CallCond {
                     condition: ConditionType::Zero,
                     destination: make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     ),
                 },
             },
@@ -1837,15 +1881,15 @@ impl Instruction {
                 address,
                 op: Opcode::Call {
                     destination: make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     ),
                 },
             },
             0xce => Instruction {
                 address,
                 op: Opcode::Adc {
-                    operand: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    operand: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0xcf => Instruction {
@@ -1869,8 +1913,8 @@ impl Instruction {
                 op: Opcode::JpCond {
                     condition: ConditionType::NotCarry,
                     destination: make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     ),
                 },
             },
@@ -1883,8 +1927,8 @@ impl Instruction {
                 op: Opcode::CallCond {
                     condition: ConditionType::NotCarry,
                     destination: make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     ),
                 },
             },
@@ -1897,7 +1941,7 @@ impl Instruction {
             0xd6 => Instruction {
                 address,
                 op: Opcode::Sub {
-                    operand: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    operand: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0xd7 => Instruction {
@@ -1919,8 +1963,8 @@ impl Instruction {
                 op: Opcode::JpCond {
                     condition: ConditionType::Carry,
                     destination: make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     ),
                 },
             },
@@ -1933,8 +1977,8 @@ impl Instruction {
                 op: Opcode::CallCond {
                     condition: ConditionType::Carry,
                     destination: make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     ),
                 },
             },
@@ -1945,7 +1989,7 @@ impl Instruction {
             0xde => Instruction {
                 address,
                 op: Opcode::Sbc {
-                    operand: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    operand: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0xdf => Instruction {
@@ -1956,7 +2000,7 @@ impl Instruction {
                 address,
                 op: Opcode::Ld8 {
                     destination: Operand::Deref(DerefOperand::Ff00Offset(
-                        memory_bus.read_u8(address.wrapping_add(1)),
+                        memory_bus.read_u8(operands),
                     )),
                     source: Operand::Register(Register::A),
                 },
@@ -1991,7 +2035,7 @@ impl Instruction {
             0xe6 => Instruction {
                 address,
                 op: Opcode::And {
-                    operand: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    operand: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0xe7 => Instruction {
@@ -2002,7 +2046,7 @@ impl Instruction {
                 address,
                 op: Opcode::Add16 {
                     register: Register::Sp,
-                    operand: Operand::I8(make_i8(memory_bus.read_u8(address.wrapping_add(1)))),
+                    operand: Operand::I8(make_i8(memory_bus.read_u8(operands))),
                 },
             },
             0xe9 => Instruction {
@@ -2015,8 +2059,8 @@ impl Instruction {
                 address,
                 op: Opcode::Ld8 {
                     destination: Operand::Deref(DerefOperand::Address(make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     ))),
                     source: Operand::Register(Register::A),
                 },
@@ -2036,7 +2080,7 @@ impl Instruction {
             0xee => Instruction {
                 address,
                 op: Opcode::Xor {
-                    operand: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    operand: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0xef => Instruction {
@@ -2048,7 +2092,7 @@ impl Instruction {
                 op: Opcode::Ld8 {
                     destination: Operand::Register(Register::A),
                     source: Operand::Deref(DerefOperand::Ff00Offset(
-                        memory_bus.read_u8(address.wrapping_add(1)),
+                        memory_bus.read_u8(operands),
                     )),
                 },
             },
@@ -2082,7 +2126,7 @@ impl Instruction {
             0xf6 => Instruction {
                 address,
                 op: Opcode::Or {
-                    operand: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    operand: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0xf7 => Instruction {
@@ -2094,7 +2138,7 @@ impl Instruction {
                 op: Opcode::Ld16 {
                     destination: Operand::Register(Register::Hl),
                     source: Operand::StackOffset(make_i8(
-                        memory_bus.read_u8(address.wrapping_add(1)),
+                        memory_bus.read_u8(operands),
                     )),
                 },
             },
@@ -2110,8 +2154,8 @@ impl Instruction {
                 op: Opcode::Ld8 {
                     destination: Operand::Register(Register::A),
                     source: Operand::Deref(DerefOperand::Address(make_u16(
-                        memory_bus.read_u8(address.wrapping_add(1)),
-                        memory_bus.read_u8(address.wrapping_add(2)),
+                        memory_bus.read_u8(operands),
+                        memory_bus.read_u8(operands.wrapping_add(1)),
                     ))),
                 },
             },
@@ -2130,7 +2174,7 @@ impl Instruction {
             0xfe => Instruction {
                 address,
                 op: Opcode::Cp {
-                    operand: Operand::U8(memory_bus.read_u8(address.wrapping_add(1))),
+                    operand: Operand::U8(memory_bus.read_u8(operands)),
                 },
             },
             0xff => Instruction {
@@ -2140,8 +2184,12 @@ impl Instruction {
         }
     }
 
-    fn make_cb_instruction(address: u16, memory_bus: &mut MemoryBus) -> Instruction {
-        let op = memory_bus.read_u8(address.wrapping_add(1));
+    fn make_cb_instruction(
+        address: u16,
+        operands: u16,
+        memory_bus: &mut MemoryBus,
+    ) -> Instruction {
+        let op = memory_bus.read_u8(operands);
         #[allow(clippy::match_same_arms)]
         match op {
             0x00 => Instruction {
@@ -3883,7 +3931,7 @@ impl Display for Instruction {
             f,
             "0x{:04x} - {} (size = {})",
             self.address,
-            self.op.print(self.address + u16::from(size)),
+            self.op.print(self.address.wrapping_add(u16::from(size))),
             size
         )
     }
@@ -1,19 +1,33 @@
+pub mod assembler;
 pub mod instruction;
 pub mod register;
+pub mod trace;
+
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
 
 use register::RegisterStorage;
+use trace::{TraceComparison, TraceComparisonStop};
 
 use crate::gbc::cpu::instruction::{
     ConditionType, DerefOperand, Instruction, Opcode, Operand, Register,
 };
 
 use crate::gbc::memory_bus::MemoryBus;
+use crate::gbc::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 
 // Flags register bits
 const CARRY_BIT_MASK: u8 = 1 << 4;
 const HALF_CARRY_BIT_MASK: u8 = 1 << 5;
 const SUBTRACTION_BIT_MASK: u8 = 1 << 6;
 const ZERO_BIT_MASK: u8 = 1 << 7;
+// The low 4 bits of F don't exist and always read as 0
+const FLAGS_MASK: u8 = 0xf0;
 
 use crate::gbc::utils::Flag;
 
@@ -26,16 +40,176 @@ pub struct InterruptRequest {
     pub joypad: Flag,
 }
 
-#[derive(Debug, PartialEq)]
+impl InterruptRequest {
+    /// Adds the interrupts requested by `other` to this request
+    pub fn merge(&mut self, other: &InterruptRequest) {
+        self.vblank = (self.vblank.to_bool() || other.vblank.to_bool()).into();
+        self.stat = (self.stat.to_bool() || other.stat.to_bool()).into();
+        self.timer = (self.timer.to_bool() || other.timer.to_bool()).into();
+        self.serial = (self.serial.to_bool() || other.serial.to_bool()).into();
+        self.joypad = (self.joypad.to_bool() || other.joypad.to_bool()).into();
+    }
+
+    /// The request as IF register bits
+    #[must_use]
+    pub fn bits(&self) -> u8 {
+        u8::from(self.vblank.to_bool())
+            | (u8::from(self.stat.to_bool()) << 1)
+            | (u8::from(self.timer.to_bool()) << 2)
+            | (u8::from(self.serial.to_bool()) << 3)
+            | (u8::from(self.joypad.to_bool()) << 4)
+    }
+}
+
+/// Reasons the CPU can't carry on executing
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum CpuError {
+    /// One of the opcodes that don't exist on the SM83, which lock up a
+    /// real Game Boy
+    IllegalOpcode { pc: u16, opcode: u8 },
+}
+
+impl fmt::Display for CpuError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::IllegalOpcode { pc, opcode } => {
+                write!(f, "illegal opcode {:#04x} at {:#06x}", opcode, pc)
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
 pub enum State {
     Running,
     Halted,
     Stopped,
 }
 
+/// A copy of the CPU's registers, for debugger UIs and trace tools
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Registers {
+    pub a: u8,
+    pub f: u8,
+    pub b: u8,
+    pub c: u8,
+    pub d: u8,
+    pub e: u8,
+    pub h: u8,
+    pub l: u8,
+    pub sp: u16,
+    pub pc: u16,
+    pub ime: bool,
+    pub state: State,
+}
+
+impl Registers {
+    /// The value of one register, 8 bit registers zero extended
+    #[must_use]
+    pub fn get(&self, register: CpuRegister) -> u16 {
+        let pair = |high, low| u16::from_be_bytes([high, low]);
+        match register {
+            CpuRegister::A => u16::from(self.a),
+            CpuRegister::F => u16::from(self.f),
+            CpuRegister::B => u16::from(self.b),
+            CpuRegister::C => u16::from(self.c),
+            CpuRegister::D => u16::from(self.d),
+            CpuRegister::E => u16::from(self.e),
+            CpuRegister::H => u16::from(self.h),
+            CpuRegister::L => u16::from(self.l),
+            CpuRegister::Af => pair(self.a, self.f),
+            CpuRegister::Bc => pair(self.b, self.c),
+            CpuRegister::De => pair(self.d, self.e),
+            CpuRegister::Hl => pair(self.h, self.l),
+            CpuRegister::Sp => self.sp,
+            CpuRegister::Pc => self.pc,
+        }
+    }
+}
+
+/// A register the debugger can read and change, see `Cpu::set_register`
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CpuRegister {
+    A,
+    F,
+    B,
+    C,
+    D,
+    E,
+    H,
+    L,
+    Af,
+    Bc,
+    De,
+    Hl,
+    Sp,
+    Pc,
+}
+
+impl std::str::FromStr for CpuRegister {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "a" => Ok(Self::A),
+            "f" => Ok(Self::F),
+            "b" => Ok(Self::B),
+            "c" => Ok(Self::C),
+            "d" => Ok(Self::D),
+            "e" => Ok(Self::E),
+            "h" => Ok(Self::H),
+            "l" => Ok(Self::L),
+            "af" => Ok(Self::Af),
+            "bc" => Ok(Self::Bc),
+            "de" => Ok(Self::De),
+            "hl" => Ok(Self::Hl),
+            "sp" => Ok(Self::Sp),
+            "pc" => Ok(Self::Pc),
+            _ => Err(format!("Unknown register {}", s)),
+        }
+    }
+}
+
+impl fmt::Display for CpuRegister {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::A => write!(f, "a"),
+            Self::F => write!(f, "f"),
+            Self::B => write!(f, "b"),
+            Self::C => write!(f, "c"),
+            Self::D => write!(f, "d"),
+            Self::E => write!(f, "e"),
+            Self::H => write!(f, "h"),
+            Self::L => write!(f, "l"),
+            Self::Af => write!(f, "af"),
+            Self::Bc => write!(f, "bc"),
+            Self::De => write!(f, "de"),
+            Self::Hl => write!(f, "hl"),
+            Self::Sp => write!(f, "sp"),
+            Self::Pc => write!(f, "pc"),
+        }
+    }
+}
+
+impl CpuRegister {
+    #[must_use]
+    pub fn is_16_bit(self) -> bool {
+        matches!(
+            self,
+            Self::Af | Self::Bc | Self::De | Self::Hl | Self::Sp | Self::Pc
+        )
+    }
+}
+
 #[derive(Debug)]
 pub struct Cpu {
     show_instructions: bool,
+    // Where each instruction is logged before it runs, see `start_trace`
+    trace: Option<BufWriter<File>>,
+    // The reference log the same lines are checked against, see
+    // `start_trace_comparison`
+    trace_comparison: Option<TraceComparison>,
+    trace_comparison_stop: Option<TraceComparisonStop>,
     af: RegisterStorage,
     bc: RegisterStorage,
     de: RegisterStorage,
@@ -43,13 +217,31 @@ pub struct Cpu {
     pc: u16,
     sp: u16,
     state: State,
-    interrupts_enabled: bool,
+    // The interrupt master enable flag, only changed by EI, DI, RETI and
+    // servicing an interrupt. IE at 0xffff picks which interrupts are
+    // enabled while this is set.
+    ime: bool,
+    // EI only sets IME after the instruction following it has run
+    ime_scheduled: bool,
+    // HALT with IME off and an interrupt already pending doesn't halt, and
+    // the PC fails to increment past the next opcode
+    halt_bug: bool,
+    last_interrupt_dispatch: Option<InterruptDispatch>,
+    // Calls, RSTs and interrupt dispatches that haven't returned yet,
+    // outermost first. Only kept for the debugger, it isn't saved.
+    call_stack: Vec<CallFrame>,
+    // The last HISTORY_LENGTH things the CPU did, oldest first. Also only
+    // for the debugger.
+    history: VecDeque<HistoryEntry>,
 }
 
 impl Default for Cpu {
     fn default() -> Self {
         Cpu {
             show_instructions: false,
+            trace: None,
+            trace_comparison: None,
+            trace_comparison_stop: None,
             af: RegisterStorage::default(),
             bc: RegisterStorage::default(),
             de: RegisterStorage::default(),
@@ -57,13 +249,174 @@ impl Default for Cpu {
             pc: 0x0000,
             sp: 0x0000,
             state: State::Running,
-            interrupts_enabled: false,
+            ime: false,
+            ime_scheduled: false,
+            halt_bug: false,
+            last_interrupt_dispatch: None,
+            call_stack: Vec::new(),
+            history: VecDeque::with_capacity(HISTORY_LENGTH),
         }
     }
 }
 
+impl SaveState for Cpu {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.af.get_u16());
+        writer.write_u16(self.bc.get_u16());
+        writer.write_u16(self.de.get_u16());
+        writer.write_u16(self.hl.get_u16());
+        writer.write_u16(self.pc);
+        writer.write_u16(self.sp);
+        writer.write_u8(match self.state {
+            State::Running => 0,
+            State::Halted => 1,
+            State::Stopped => 2,
+        });
+        writer.write_bool(self.ime);
+        writer.write_bool(self.ime_scheduled);
+        writer.write_bool(self.halt_bug);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        self.set_af(reader.read_u16()?);
+        self.bc.set_u16(reader.read_u16()?);
+        self.de.set_u16(reader.read_u16()?);
+        self.hl.set_u16(reader.read_u16()?);
+        self.pc = reader.read_u16()?;
+        self.sp = reader.read_u16()?;
+        self.state = match reader.read_u8()? {
+            0 => State::Running,
+            1 => State::Halted,
+            2 => State::Stopped,
+            _ => return Err(SaveStateError::Invalid("cpu state")),
+        };
+        self.ime = reader.read_bool()?;
+        self.ime_scheduled = reader.read_bool()?;
+        self.halt_bug = reader.read_bool()?;
+        self.call_stack.clear();
+        self.history.clear();
+        Ok(())
+    }
+}
+
 const INTERRUPT_ENABLE_REGISTER_ADDRESS: u16 = 0xffff;
 const INTERRUPT_FLAGS_REGISTER_ADDRESS: u16 = 0xff0f;
+const DIVIDER_REGISTER_ADDRESS: u16 = 0xff04;
+// The CPU is paused for 2050 M-cycles while the clock speed changes
+const SPEED_SWITCH_CYCLES: u64 = 2050 * 4;
+// Only the low 5 bits of IE and IF correspond to interrupts
+const INTERRUPT_BITS_MASK: u8 = 0x1f;
+// 2 wait states, 2 for pushing the PC and 1 for jumping to the handler
+const INTERRUPT_DISPATCH_CYCLES: u64 = 5 * 4;
+// Waking up from HALT takes one more cycle before the dispatch starts
+const HALT_EXIT_CYCLES: u64 = 4;
+// Games that never return from their calls would otherwise grow the call
+// stack forever
+const MAX_CALL_STACK_DEPTH: usize = 256;
+const HISTORY_LENGTH: usize = 4096;
+
+/// How the CPU got to the most recent interrupt handler
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct InterruptDispatch {
+    /// The interrupt serviced, or None if writing the PC to the stack
+    /// disabled it in IE and the CPU jumped to 0x0000 instead
+    pub interrupt: Option<u8>,
+    /// Whether the CPU was woken from HALT to service it
+    pub from_halt: bool,
+    /// Cycles from the end of the last instruction to the handler's first
+    /// instruction
+    pub cycles: u64,
+}
+
+impl fmt::Display for InterruptDispatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.interrupt {
+            Some(number) => write!(f, "{}", Cpu::interrupt_number_to_string(number))?,
+            None => write!(f, "cancelled")?,
+        }
+        write!(f, " in {} cycles", self.cycles)?;
+        if self.from_halt {
+            write!(f, " (from HALT)")?;
+        }
+        Ok(())
+    }
+}
+
+/// How a call stack frame was entered
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum CallKind {
+    Call,
+    Rst,
+    /// An interrupt dispatch, None if it was cancelled and jumped to 0x0000
+    Interrupt(Option<u8>),
+}
+
+impl fmt::Display for CallKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Call => write!(f, "call"),
+            Self::Rst => write!(f, "rst"),
+            Self::Interrupt(Some(number)) => {
+                write!(f, "{} interrupt", Cpu::interrupt_number_to_string(*number))
+            }
+            Self::Interrupt(None) => write!(f, "cancelled interrupt"),
+        }
+    }
+}
+
+/// A call, RST or interrupt dispatch that hasn't returned yet. Frames are
+/// tracked by where the return address was pushed, so returning, or moving
+/// SP above it, drops the frame.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct CallFrame {
+    pub kind: CallKind,
+    /// The address jumped to
+    pub target: u16,
+    pub return_address: u16,
+    /// Where the return address is on the stack
+    pub stack_pointer: u16,
+}
+
+impl fmt::Display for CallFrame {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} to {:#06x}, returning to {:#06x}",
+            self.kind, self.target, self.return_address
+        )
+    }
+}
+
+/// Something the CPU did, kept in its history for the debugger
+#[derive(Clone, Debug)]
+pub enum HistoryEntry {
+    /// An instruction and the registers from just before it ran
+    Instruction {
+        instruction: Instruction,
+        registers: Registers,
+    },
+    Interrupt(InterruptDispatch),
+}
+
+impl fmt::Display for HistoryEntry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Instruction {
+                instruction,
+                registers: r,
+            } => {
+                let instruction = instruction.to_string();
+                write!(f, "{:<40} a={:02x} f={:02x}", instruction, r.a, r.f)?;
+                write!(
+                    f,
+                    " bc={:02x}{:02x} de={:02x}{:02x} hl={:02x}{:02x} sp={:04x}",
+                    r.b, r.c, r.d, r.e, r.h, r.l, r.sp
+                )
+            }
+            Self::Interrupt(dispatch) => write!(f, "interrupt: {}", dispatch),
+        }
+    }
+}
 
 impl Cpu {
     #[must_use]
@@ -76,30 +429,106 @@ impl Cpu {
 
     pub fn reset(&mut self) {
         let show_instructions = self.show_instructions;
+        let trace = self.trace.take();
+        let trace_comparison = self.trace_comparison.take();
         *self = Self {
             show_instructions,
+            trace,
+            trace_comparison,
             ..Self::default()
         };
     }
 
+    /// Starts logging every instruction to a file before it runs, in the
+    /// format used by gameboy-doctor and many reference emulators:
+    ///
+    /// ```text
+    /// A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02
+    /// ```
+    ///
+    /// Nothing is logged while the CPU is halted or stopped, or for
+    /// interrupt dispatches. Like those logs, the trace starts at the
+    /// cartridge's entry point: nothing is logged while the boot ROM is
+    /// mapped.
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be created
+    pub fn start_trace<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.stop_trace();
+        self.trace = Some(BufWriter::new(File::create(path)?));
+        Ok(())
+    }
+
+    pub fn stop_trace(&mut self) {
+        if let Some(mut trace) = self.trace.take() {
+            if let Err(e) = trace.flush() {
+                eprintln!("Error writing trace: {}", e);
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn is_tracing(&self) -> bool {
+        self.trace.is_some()
+    }
+
+    /// Checks every instruction's trace line, as `start_trace` would write
+    /// it, against the reference log at `path`. When a line differs, or the
+    /// reference runs out, comparing stops and `take_trace_comparison_stop`
+    /// says why. The boot ROM is skipped, as it is for the trace.
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be opened
+    pub fn start_trace_comparison<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.trace_comparison = Some(TraceComparison::open(path)?);
+        self.trace_comparison_stop = None;
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn is_comparing_trace(&self) -> bool {
+        self.trace_comparison.is_some()
+    }
+
+    pub fn take_trace_comparison_stop(&mut self) -> Option<TraceComparisonStop> {
+        self.trace_comparison_stop.take()
+    }
+
+    fn write_trace_line(&mut self, memory_bus: &mut MemoryBus) {
+        let mut line = format!(
+            "A:{:02X} F:{:02X} B:{:02X} C:{:02X} D:{:02X} E:{:02X} H:{:02X} L:{:02X}",
+            self.af.get_high(),
+            self.af.get_low(),
+            self.bc.get_high(),
+            self.bc.get_low(),
+            self.de.get_high(),
+            self.de.get_low(),
+            self.hl.get_high(),
+            self.hl.get_low()
+        );
+        line += &format!(" SP:{:04X} PC:{:04X} PCMEM:", self.sp, self.pc);
+        let pc_mem: Vec<String> = (0..4)
+            .map(|i| format!("{:02X}", memory_bus.peek_u8(self.pc.wrapping_add(i))))
+            .collect();
+        line += &pc_mem.join(",");
+        if let Some(trace) = &mut self.trace {
+            if let Err(e) = writeln!(trace, "{}", line) {
+                eprintln!("Error writing trace, stopping it: {}", e);
+                self.trace = None;
+            }
+        }
+        if let Some(comparison) = &mut self.trace_comparison {
+            if let Some(stop) = comparison.check(&line) {
+                self.trace_comparison = None;
+                self.trace_comparison_stop = Some(stop);
+            }
+        }
+    }
+
     pub fn interrupt(&mut self, memory_bus: &mut MemoryBus, interrupt_number: u8) {
         assert!(interrupt_number < 5);
-        let interrupts_enabled = memory_bus.read_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS);
-        let this_interrupt_enabled = (interrupts_enabled & (1 << interrupt_number)) != 0;
-
-        // If this interrupt is enabled, then wake the CPU from Halt,
-        // If the CPU is stopped, then the interrupt must be number 4 (joypad)
-        if (self.state == State::Halted
-            || (self.state == State::Stopped && interrupt_number == 4))
-            && this_interrupt_enabled
-        {
-            // println!(
-            //     "Un-Halted by interrupt {} ({})",
-            //     interrupt_number,
-            //     Self::interrupt_number_to_string(interrupt_number)
-            // );
-            self.state = State::Running;
-        }
+        // Waking from HALT and STOP is handled by single_step, since IE and
+        // the joypad can also change while the CPU sleeps
         let mut interrupt_flags = memory_bus.read_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS);
         Self::set_bit(interrupt_number, &mut interrupt_flags);
         memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, interrupt_flags);
@@ -123,11 +552,95 @@ impl Cpu {
         }
     }
 
+    /// Whether the CPU is waiting in HALT
+    #[must_use]
+    pub fn is_halted(&self) -> bool {
+        self.state == State::Halted
+    }
+
+    /// Whether STOP has put the CPU and LCD to sleep
+    #[must_use]
+    pub fn is_stopped(&self) -> bool {
+        self.state == State::Stopped
+    }
+
     #[must_use]
     pub fn get_program_counter(&self) -> u16 {
         self.pc
     }
 
+    #[must_use]
+    pub fn get_last_interrupt_dispatch(&self) -> Option<InterruptDispatch> {
+        self.last_interrupt_dispatch
+    }
+
+    /// The last few thousand instructions executed and interrupts
+    /// dispatched, oldest first
+    #[must_use]
+    pub fn get_history(&self) -> &VecDeque<HistoryEntry> {
+        &self.history
+    }
+
+    fn record_history(&mut self, entry: HistoryEntry) {
+        if self.history.len() == HISTORY_LENGTH {
+            self.history.pop_front();
+        }
+        self.history.push_back(entry);
+    }
+
+    /// The calls that haven't returned yet, outermost first. Frames whose
+    /// return address is below SP were abandoned by moving the stack
+    /// pointer and are left out.
+    #[must_use]
+    pub fn get_call_stack(&self) -> &[CallFrame] {
+        let live = self
+            .call_stack
+            .iter()
+            .take_while(|frame| frame.stack_pointer >= self.sp)
+            .count();
+        &self.call_stack[..live]
+    }
+
+    /// Changes a register, for the debugger. 8 bit registers take the low
+    /// byte of `value`, and the low 4 bits of F stay 0 as on hardware.
+    pub fn set_register(&mut self, register: CpuRegister, value: u16) {
+        let [low, _] = value.to_le_bytes();
+        match register {
+            CpuRegister::A => self.af.set_high(low),
+            CpuRegister::F => self.set_flags(low),
+            CpuRegister::B => self.bc.set_high(low),
+            CpuRegister::C => self.bc.set_low(low),
+            CpuRegister::D => self.de.set_high(low),
+            CpuRegister::E => self.de.set_low(low),
+            CpuRegister::H => self.hl.set_high(low),
+            CpuRegister::L => self.hl.set_low(low),
+            CpuRegister::Af => self.set_af(value),
+            CpuRegister::Bc => self.bc.set_u16(value),
+            CpuRegister::De => self.de.set_u16(value),
+            CpuRegister::Hl => self.hl.set_u16(value),
+            CpuRegister::Sp => self.sp = value,
+            CpuRegister::Pc => self.pc = value,
+        }
+    }
+
+    #[must_use]
+    pub fn get_registers(&self) -> Registers {
+        Registers {
+            a: self.af.get_high(),
+            f: self.af.get_low(),
+            b: self.bc.get_high(),
+            c: self.bc.get_low(),
+            d: self.de.get_high(),
+            e: self.de.get_low(),
+            h: self.hl.get_high(),
+            l: self.hl.get_low(),
+            sp: self.sp,
+            pc: self.pc,
+            ime: self.ime,
+            state: self.state,
+        }
+    }
+
     #[must_use]
     pub fn get_instruction_at_address(memory_bus: &mut MemoryBus, address: u16) -> Instruction {
         Instruction::new(address, memory_bus)
@@ -138,43 +651,113 @@ impl Cpu {
         Self::get_instruction_at_address(memory_bus, self.pc)
     }
 
-    pub fn single_step(&mut self, memory_bus: &mut MemoryBus) -> Option<u64> {
-        if self.state != State::Running {
-            return Some(1);
+    pub fn single_step(&mut self, memory_bus: &mut MemoryBus) -> Result<u64, CpuError> {
+        let woke_from_halt = self.state == State::Halted;
+        if woke_from_halt {
+            // HALT ends as soon as an enabled interrupt is requested, even
+            // with IME off. Then execution just carries on after the HALT
+            // without servicing it.
+            if Self::pending_interrupts(memory_bus) == 0 {
+                return Ok(4);
+            }
+            self.state = State::Running;
+        }
+        if self.state == State::Stopped {
+            // STOP lasts until a button on a selected joypad line is pressed
+            if !memory_bus.joypad.any_line_low() {
+                return Ok(4);
+            }
+            self.state = State::Running;
         }
 
         if self.should_service_interrupt(memory_bus) {
-            self.service_interrupt(memory_bus);
-            return Some(5);
+            let mut cycles = INTERRUPT_DISPATCH_CYCLES;
+            if woke_from_halt {
+                Self::internal_cycle(memory_bus);
+                cycles += HALT_EXIT_CYCLES;
+            }
+            let interrupt = self.service_interrupt(memory_bus);
+            let dispatch = InterruptDispatch {
+                interrupt,
+                from_halt: woke_from_halt,
+                cycles,
+            };
+            self.last_interrupt_dispatch = Some(dispatch);
+            self.record_history(HistoryEntry::Interrupt(dispatch));
+            return Ok(cycles);
         }
-        let insn = self.get_next_instruction(memory_bus);
+        let tracing = self.trace.is_some() || self.trace_comparison.is_some();
+        if tracing && !memory_bus.is_boot_rom_mapped() {
+            self.write_trace_line(memory_bus);
+        }
+        let ime_was_scheduled = self.ime_scheduled;
+        let insn = if self.halt_bug {
+            self.halt_bug = false;
+            let insn = Instruction::new_after_halt_bug(self.pc, memory_bus);
+            // The PC only moves past the operands
+            self.pc = self.pc.wrapping_sub(1);
+            insn
+        } else {
+            self.get_next_instruction(memory_bus)
+        };
         if self.show_instructions {
             println!("{}", insn);
             self.dump_state();
         }
-        self.execute_instruction(memory_bus, insn)
+        self.record_history(HistoryEntry::Instruction {
+            instruction: insn.clone(),
+            registers: self.get_registers(),
+        });
+        let cycles = self.execute_instruction(memory_bus, insn);
+        // The instruction after EI has now run, unless it was a DI, which
+        // cancels the EI
+        if ime_was_scheduled && self.ime_scheduled {
+            self.ime = true;
+            self.ime_scheduled = false;
+        }
+        cycles
     }
 
     #[allow(clippy::cast_possible_wrap)]
     #[allow(clippy::cast_possible_truncation)]
     #[allow(clippy::cast_sign_loss)]
-    fn execute_instruction(&mut self, memory_bus: &mut MemoryBus, insn: Instruction) -> Option<u64> {
-        self.pc += u16::from(insn.size());
+    fn execute_instruction(
+        &mut self,
+        memory_bus: &mut MemoryBus,
+        insn: Instruction,
+    ) -> Result<u64, CpuError> {
+        self.pc = self.pc.wrapping_add(u16::from(insn.size()));
+        // Every byte of the instruction took a cycle to fetch. Any cycles
+        // the instruction doesn't spend on memory accesses below are ticked
+        // by the caller afterwards.
+        memory_bus.tick(4 * u64::from(insn.size()), false);
 
         match insn.op {
-            Opcode::Unknown { opcode: _ } => {
-                println!("Unknown instruction! {}", insn);
-                self.dump_state();
-                None
-            },
-            Opcode::Nop => Some(4),
+            Opcode::Unknown { opcode } => Err(CpuError::IllegalOpcode {
+                pc: insn.address,
+                opcode,
+            }),
+            Opcode::Nop => Ok(4),
             Opcode::Stop => {
-                self.state = State::Stopped;
-                Some(4)
+                // STOP resets DIV, whether or not it switches speed
+                memory_bus.write_u8(DIVIDER_REGISTER_ADDRESS, 0);
+                if memory_bus.switch_speed() {
+                    Ok(SPEED_SWITCH_CYCLES)
+                } else {
+                    self.state = State::Stopped;
+                    Ok(8)
+                }
             }
             Opcode::Halt => {
-                self.state = State::Halted;
-                Some(4)
+                // With an interrupt already pending HALT returns straight
+                // away, either to service it or, with IME off, to hit the
+                // HALT bug
+                if Self::pending_interrupts(memory_bus) == 0 {
+                    self.state = State::Halted;
+                } else if !self.ime {
+                    self.halt_bug = true;
+                }
+                Ok(4)
             }
             Opcode::Ld8 {
                 destination,
@@ -184,17 +767,17 @@ impl Cpu {
                     Operand::Register(r_src) => {
                         let v = self.get_r8(&r_src);
                         self.set_r8(&r_dest, v);
-                        Some(4)
+                        Ok(4)
                     }
                     Operand::U8(v) => {
                         self.set_r8(&r_dest, v);
-                        Some(8)
+                        Ok(8)
                     }
                     Operand::Deref(d) => match d {
                         DerefOperand::Register(Register::Hl) => {
-                            let v = memory_bus.read_u8(self.hl.get_u16());
+                            let v = Self::read_cycle(memory_bus, self.hl.get_u16());
                             self.set_r8(&r_dest, v);
-                            Some(8)
+                            Ok(8)
                         }
                         DerefOperand::Register(r_src)
                             if r_src == Register::Bc || r_src == Register::De =>
@@ -202,9 +785,9 @@ impl Cpu {
                             if r_dest != Register::A {
                                 panic!("Destination must be A for load from (BC) or (DE)!");
                             }
-                            let v = memory_bus.read_u8(self.get_r16(&r_src));
+                            let v = Self::read_cycle(memory_bus, self.get_r16(&r_src));
                             self.set_a(v);
-                            Some(8)
+                            Ok(8)
                         }
                         DerefOperand::Register(Register::HlPlus) => {
                             if r_dest != Register::A {
@@ -212,12 +795,12 @@ impl Cpu {
                             }
                             let v = {
                                 let hl = self.hl.get_u16_mut();
-                                let temp = memory_bus.read_u8(*hl);
+                                let temp = Self::read_cycle(memory_bus, *hl);
                                 *hl = hl.wrapping_add(1);
                                 temp
                             };
                             self.set_a(v);
-                            Some(8)
+                            Ok(8)
                         }
                         DerefOperand::Register(Register::HlMinus) => {
                             if r_dest != Register::A {
@@ -225,37 +808,42 @@ impl Cpu {
                             }
                             let v = {
                                 let hl = self.hl.get_u16_mut();
-                                let temp = memory_bus.read_u8(*hl);
-                                *hl -= 1;
+                                let temp = Self::read_cycle(memory_bus, *hl);
+                                *hl = hl.wrapping_sub(1);
                                 temp
                             };
                             self.set_a(v);
-                            Some(8)
+                            Ok(8)
                         }
                         DerefOperand::Address(addr) => {
                             if r_dest != Register::A {
                                 panic!("Destination must be A for load from (nn)!");
                             }
-                            let v = memory_bus.read_u8(addr);
+                            let v = Self::read_cycle(memory_bus, addr);
                             self.set_a(v);
-                            Some(16)
+                            Ok(d.load8_cycles())
                         }
                         DerefOperand::Ff00Offset(offset) => {
                             if r_dest != Register::A {
                                 panic!("Destination must be A for load from (0xff00+n)!");
                             }
-                            let v = memory_bus.read_u8(0xff00_u16.wrapping_add(u16::from(offset)));
+                            let v = Self::read_cycle(
+                                memory_bus,
+                                0xff00_u16.wrapping_add(u16::from(offset)),
+                            );
                             self.set_a(v);
-                            Some(12)
+                            Ok(d.load8_cycles())
                         }
                         DerefOperand::Ff00PlusC => {
                             if r_dest != Register::A {
                                 panic!("Destination must be A for load from (0xff00+C)!");
                             }
-                            let v = memory_bus
-                                .read_u8(0xff00_u16.wrapping_add(u16::from(self.bc.get_low())));
+                            let v = Self::read_cycle(
+                                memory_bus,
+                                0xff00_u16.wrapping_add(u16::from(self.bc.get_low())),
+                            );
                             self.set_a(v);
-                            Some(8)
+                            Ok(d.load8_cycles())
                         }
                         DerefOperand::Register(_) => unreachable!(),
                     },
@@ -269,16 +857,16 @@ impl Cpu {
                                 Operand::U8(v) => (v, 12),
                                 _ => unreachable!(),
                             };
-                            memory_bus.write_u8(self.hl.get_u16(), v);
-                            Some(cycles)
+                            Self::write_cycle(memory_bus, self.hl.get_u16(), v);
+                            Ok(cycles)
                         }
                         Register::Bc | Register::De => {
                             if source != Operand::Register(Register::A) {
                                 panic!("Source must be A for load to (BC) or (DE)!");
                             }
                             let v = self.get_a();
-                            memory_bus.write_u8(self.get_r16(&r), v);
-                            Some(8)
+                            Self::write_cycle(memory_bus, self.get_r16(&r), v);
+                            Ok(8)
                         }
                         Register::HlPlus => {
                             if source != Operand::Register(Register::A) {
@@ -286,9 +874,9 @@ impl Cpu {
                             }
                             let v = self.get_a();
                             let hl = self.hl.get_u16_mut();
-                            memory_bus.write_u8(*hl, v);
-                            *hl += 1;
-                            Some(8)
+                            Self::write_cycle(memory_bus, *hl, v);
+                            *hl = hl.wrapping_add(1);
+                            Ok(8)
                         }
                         Register::HlMinus => {
                             if source != Operand::Register(Register::A) {
@@ -296,9 +884,9 @@ impl Cpu {
                             }
                             let v = self.get_a();
                             let hl = self.hl.get_u16_mut();
-                            memory_bus.write_u8(*hl, v);
-                            *hl -= 1;
-                            Some(8)
+                            Self::write_cycle(memory_bus, *hl, v);
+                            *hl = hl.wrapping_sub(1);
+                            Ok(8)
                         }
                         _ => unreachable!(),
                     },
@@ -307,24 +895,28 @@ impl Cpu {
                             panic!("Source must be A for load to (nn)!");
                         }
                         let v = self.get_a();
-                        memory_bus.write_u8(addr, v);
-                        Some(16)
+                        Self::write_cycle(memory_bus, addr, v);
+                        Ok(d.load8_cycles())
                     }
                     DerefOperand::Ff00Offset(offset) => {
                         if source != Operand::Register(Register::A) {
                             panic!("Source must be A for load to (0xff00+n)!");
                         }
                         let v = self.get_a();
-                        memory_bus.write_u8(0xff00_u16.wrapping_add(u16::from(offset)), v);
-                        Some(8)
+                        Self::write_cycle(memory_bus, 0xff00_u16.wrapping_add(u16::from(offset)), v);
+                        Ok(d.load8_cycles())
                     }
                     DerefOperand::Ff00PlusC => {
                         if source != Operand::Register(Register::A) {
                             panic!("Source must be A for load to (0xff00+C)!");
                         }
                         let v = self.get_a();
-                        memory_bus.write_u8(0xff00_u16.wrapping_add(u16::from(self.bc.get_low())), v);
-                        Some(8)
+                        Self::write_cycle(
+                            memory_bus,
+                            0xff00_u16.wrapping_add(u16::from(self.bc.get_low())),
+                            v,
+                        );
+                        Ok(d.load8_cycles())
                     }
                 },
                 _ => unreachable!(),
@@ -342,14 +934,14 @@ impl Cpu {
                     match source {
                         Operand::U16(v) => {
                             self.set_r16(&r, v);
-                            Some(12)
+                            Ok(12)
                         }
                         Operand::Register(Register::Hl) => {
                             if r != Register::Sp {
                                 panic!("Destination must be SP for ld from HL!");
                             }
                             self.set_r16(&r, self.hl.get_u16());
-                            Some(8)
+                            Ok(8)
                         }
                         Operand::StackOffset(d) => {
                             if r != Register::Hl {
@@ -371,15 +963,17 @@ impl Cpu {
                                 (self.sp & 0xf) + (d_u16 & 0xf) > 0xf,
                             );
                             self.set_r16(&r, sum);
-                            Some(12)
+                            Ok(12)
                         }
                         _ => unreachable!(),
                     }
                 }
                 Operand::Deref(DerefOperand::Address(addr)) => match source {
                     Operand::Register(Register::Sp) => {
-                        memory_bus.write_u16(addr, self.sp);
-                        Some(20)
+                        let [low, high] = self.sp.to_le_bytes();
+                        Self::write_cycle(memory_bus, addr, low);
+                        Self::write_cycle(memory_bus, addr.wrapping_add(1), high);
+                        Ok(20)
                     }
                     _ => unreachable!(),
                 },
@@ -388,11 +982,11 @@ impl Cpu {
             Opcode::Jp { destination } => match destination {
                 Operand::U16(address) => {
                     self.pc = address;
-                    Some(16)
+                    Ok(16)
                 }
                 Operand::Register(Register::Hl) => {
                     self.pc = self.hl.get_u16();
-                    Some(4)
+                    Ok(4)
                 }
                 _ => unreachable!(),
             },
@@ -402,54 +996,58 @@ impl Cpu {
             } => {
                 if self.check_condition(&condition) {
                     self.pc = destination;
-                    Some(16)
+                    Ok(16)
                 } else {
-                    Some(12)
+                    Ok(12)
                 }
             }
             Opcode::Jr { offset } => {
-                self.pc = (i32::from(self.pc) + i32::from(offset)) as u16;
-                Some(12)
+                self.pc = self.pc.wrapping_add(offset as u16);
+                Ok(12)
             }
             Opcode::JrCond { condition, offset } => {
                 if self.check_condition(&condition) {
-                    self.pc = (i32::from(self.pc) + i32::from(offset)) as u16;
-                    Some(12)
+                    self.pc = self.pc.wrapping_add(offset as u16);
+                    Ok(12)
                 } else {
-                    Some(8)
+                    Ok(8)
                 }
             }
             Opcode::Call { destination } => {
-                self.call(memory_bus, destination);
-                Some(24)
+                self.call(memory_bus, destination, CallKind::Call);
+                Ok(24)
             }
             Opcode::CallCond {
                 condition,
                 destination,
             } => {
                 if self.check_condition(&condition) {
-                    self.call(memory_bus, destination);
-                    Some(24)
+                    self.call(memory_bus, destination, CallKind::Call);
+                    Ok(24)
                 } else {
-                    Some(16)
+                    Ok(12)
                 }
             }
             Opcode::Ret => {
                 self.ret(memory_bus);
-                Some(16)
+                Ok(16)
             }
             Opcode::RetCond { condition } => {
+                // Checking the condition takes a cycle of its own
+                Self::internal_cycle(memory_bus);
                 if self.check_condition(&condition) {
                     self.ret(memory_bus);
-                    Some(20)
+                    Ok(20)
                 } else {
-                    Some(8)
+                    Ok(8)
                 }
             }
             Opcode::Reti => {
+                // Unlike EI, RETI enables interrupts immediately
                 self.ret(memory_bus);
-                self.interrupts_enabled = true;
-                Some(16)
+                self.ime = true;
+                self.ime_scheduled = false;
+                Ok(16)
             }
             Opcode::Pop { register } => {
                 match register {
@@ -467,12 +1065,11 @@ impl Cpu {
                     }
                     Register::Af => {
                         let v = self.pop(memory_bus);
-                        let af = self.af.get_u16_mut();
-                        *af = v & 0xfff0;
+                        self.set_af(v);
                     }
                     _ => unreachable!(),
                 }
-                Some(12)
+                Ok(12)
             }
             Opcode::Push { register } => {
                 match register {
@@ -482,11 +1079,11 @@ impl Cpu {
                     Register::Af => self.push(memory_bus, self.af.get_u16()),
                     _ => unreachable!(),
                 }
-                Some(16)
+                Ok(16)
             }
             Opcode::Rst { vector } => {
-                self.call(memory_bus, u16::from(vector));
-                Some(16)
+                self.call(memory_bus, u16::from(vector), CallKind::Rst);
+                Ok(16)
             }
             Opcode::Bit { bit, destination } => {
                 // Clear subtraction flag, set half-carry flag
@@ -502,10 +1099,10 @@ impl Cpu {
                         } else {
                             self.set_zero_flag();
                         }
-                        Some(8)
+                        Ok(8)
                     }
                     Operand::Deref(DerefOperand::Register(Register::Hl)) => {
-                        let v = memory_bus.read_u8(self.hl.get_u16());
+                        let v = Self::read_cycle(memory_bus, self.hl.get_u16());
                         self.clear_subtraction_flag();
                         self.set_half_carry_flag();
                         if Self::test_bit(bit, v) {
@@ -513,7 +1110,7 @@ impl Cpu {
                         } else {
                             self.set_zero_flag();
                         }
-                        Some(12)
+                        Ok(12)
                     }
                     _ => unreachable!(),
                 }
@@ -522,13 +1119,13 @@ impl Cpu {
                 Operand::Register(r) => {
                     let v = self.get_r8_mut(&r);
                     Self::reset_bit(bit, v);
-                    Some(8)
+                    Ok(8)
                 }
                 Operand::Deref(DerefOperand::Register(Register::Hl)) => {
-                    let mut v = memory_bus.read_u8(self.hl.get_u16());
+                    let mut v = Self::read_cycle(memory_bus, self.hl.get_u16());
                     Self::reset_bit(bit, &mut v);
-                    memory_bus.write_u8(self.hl.get_u16(), v);
-                    Some(16)
+                    Self::write_cycle(memory_bus, self.hl.get_u16(), v);
+                    Ok(16)
                 }
                 _ => unreachable!(),
             },
@@ -536,13 +1133,13 @@ impl Cpu {
                 Operand::Register(r) => {
                     let v = self.get_r8_mut(&r);
                     Self::set_bit(bit, v);
-                    Some(8)
+                    Ok(8)
                 }
                 Operand::Deref(DerefOperand::Register(Register::Hl)) => {
-                    let mut v = memory_bus.read_u8(self.hl.get_u16());
+                    let mut v = Self::read_cycle(memory_bus, self.hl.get_u16());
                     Self::set_bit(bit, &mut v);
-                    memory_bus.write_u8(self.hl.get_u16(), v);
-                    Some(16)
+                    Self::write_cycle(memory_bus, self.hl.get_u16(), v);
+                    Ok(16)
                 }
                 _ => unreachable!(),
             },
@@ -550,7 +1147,7 @@ impl Cpu {
                 let (v, cycles) = self.extract_u8_arithmetic_operand(memory_bus, operand);
 
                 self.add8_with_carry(v, false, false);
-                Some(cycles)
+                Ok(cycles)
             }
             Opcode::Add16 { register, operand } => {
                 match register {
@@ -569,7 +1166,7 @@ impl Cpu {
                             self.set_carry_flag_from_bool(carry);
                             self.set_half_carry_flag_from_bool((hl & 0xfff) + (v & 0xfff) > 0xfff);
                             self.hl.set_u16(sum);
-                            Some(8)
+                            Ok(8)
                         } else {
                             unreachable!()
                         }
@@ -592,7 +1189,7 @@ impl Cpu {
                                 (self.sp & 0xf) + (d_u16 & 0xf) > 0xf,
                             );
                             self.sp = sum;
-                            Some(16)
+                            Ok(16)
                         } else {
                             unreachable!()
                         }
@@ -608,40 +1205,31 @@ impl Cpu {
                     self.clear_subtraction_flag();
                     self.set_zero_flag_from_bool(res == 0);
                     self.set_half_carry_flag_from_bool((v & 0xf) == 0xf);
-                    Some(4)
+                    Ok(4)
                 }
                 Operand::Deref(DerefOperand::Register(Register::Hl)) => {
-                    let v = memory_bus.read_u8(self.hl.get_u16());
+                    let v = Self::read_cycle(memory_bus, self.hl.get_u16());
                     let (res, _) = v.overflowing_add(1);
-                    memory_bus.write_u8(self.hl.get_u16(), res);
+                    Self::write_cycle(memory_bus, self.hl.get_u16(), res);
                     self.clear_subtraction_flag();
                     self.set_zero_flag_from_bool(res == 0);
                     self.set_half_carry_flag_from_bool((v & 0xf) == 0xf);
-                    Some(12)
+                    Ok(12)
                 }
                 _ => unreachable!(),
             },
             Opcode::Inc16 { register } => {
-                match register {
-                    Register::Bc => {
-                        let reg = self.bc.get_u16_mut();
-                        *reg = reg.wrapping_add(1);
-                    }
-                    Register::De => {
-                        let reg = self.de.get_u16_mut();
-                        *reg = reg.wrapping_add(1);
-                    }
-                    Register::Hl => {
-                        let reg = self.hl.get_u16_mut();
-                        *reg = reg.wrapping_add(1);
-                    }
-                    Register::Sp => {
-                        let reg = &mut self.sp;
-                        *reg = reg.wrapping_add(1);
-                    }
+                let reg = match register {
+                    Register::Bc => self.bc.get_u16_mut(),
+                    Register::De => self.de.get_u16_mut(),
+                    Register::Hl => self.hl.get_u16_mut(),
+                    Register::Sp => &mut self.sp,
                     _ => unreachable!(),
                 };
-                Some(8)
+                let value = *reg;
+                *reg = value.wrapping_add(1);
+                memory_bus.trigger_oam_corruption(value);
+                Ok(8)
             }
             Opcode::Dec { operand } => match operand {
                 Operand::Register(r) => {
@@ -651,46 +1239,37 @@ impl Cpu {
                     self.set_zero_flag_from_bool(res == 0);
                     self.set_subtraction_flag();
                     self.set_half_carry_flag_from_bool((res & 0xf) == 0xf);
-                    Some(4)
+                    Ok(4)
                 }
                 Operand::Deref(DerefOperand::Register(Register::Hl)) => {
-                    let v = memory_bus.read_u8(self.hl.get_u16());
+                    let v = Self::read_cycle(memory_bus, self.hl.get_u16());
                     let (res, _) = v.overflowing_add(0xff); // - 1 is the same as + 0xff
-                    memory_bus.write_u8(self.hl.get_u16(), res);
+                    Self::write_cycle(memory_bus, self.hl.get_u16(), res);
                     self.set_zero_flag_from_bool(res == 0);
                     self.set_subtraction_flag();
                     self.set_half_carry_flag_from_bool((res & 0xf) == 0xf);
-                    Some(12)
+                    Ok(12)
                 }
                 _ => unreachable!(),
             },
             Opcode::Dec16 { register } => {
-                match register {
-                    Register::Bc => {
-                        let reg = self.bc.get_u16_mut();
-                        *reg = reg.wrapping_sub(1);
-                    }
-                    Register::De => {
-                        let reg = self.de.get_u16_mut();
-                        *reg = reg.wrapping_sub(1);
-                    }
-                    Register::Hl => {
-                        let reg = self.hl.get_u16_mut();
-                        *reg = reg.wrapping_sub(1);
-                    }
-                    Register::Sp => {
-                        let reg = &mut self.sp;
-                        *reg = reg.wrapping_sub(1);
-                    }
+                let reg = match register {
+                    Register::Bc => self.bc.get_u16_mut(),
+                    Register::De => self.de.get_u16_mut(),
+                    Register::Hl => self.hl.get_u16_mut(),
+                    Register::Sp => &mut self.sp,
                     _ => unreachable!(),
                 };
-                Some(8)
+                let value = *reg;
+                *reg = value.wrapping_sub(1);
+                memory_bus.trigger_oam_corruption(value);
+                Ok(8)
             }
             Opcode::Adc { operand } => {
                 let (v, cycles) = self.extract_u8_arithmetic_operand(memory_bus, operand);
 
                 self.add8_with_carry(v, true, false);
-                Some(cycles)
+                Ok(cycles)
             }
             Opcode::Sub { operand } => {
                 let (v, cycles) = self.extract_u8_arithmetic_operand(memory_bus, operand);
@@ -698,7 +1277,7 @@ impl Cpu {
                 self.add8_with_carry(v, false, true);
                 // Toggle carry
                 // self.set_carry_flag_from_bool(!self.get_carry_flag());
-                Some(cycles)
+                Ok(cycles)
             }
             Opcode::Sbc { operand } => {
                 let (v, cycles) = self.extract_u8_arithmetic_operand(memory_bus, operand);
@@ -706,7 +1285,7 @@ impl Cpu {
                 self.add8_with_carry(v, true, true);
                 // Toggle carry
                 // self.set_carry_flag_from_bool(!self.get_carry_flag());
-                Some(cycles)
+                Ok(cycles)
             }
             Opcode::And { operand } => {
                 let (v, cycles) = self.extract_u8_arithmetic_operand(memory_bus, operand);
@@ -720,7 +1299,7 @@ impl Cpu {
                 self.clear_subtraction_flag();
                 self.set_half_carry_flag();
                 self.clear_carry_flag();
-                Some(cycles)
+                Ok(cycles)
             }
             Opcode::Xor { operand } => {
                 let (v, cycles) = self.extract_u8_arithmetic_operand(memory_bus, operand);
@@ -734,7 +1313,7 @@ impl Cpu {
                 self.clear_subtraction_flag();
                 self.clear_carry_flag();
                 self.clear_half_carry_flag();
-                Some(cycles)
+                Ok(cycles)
             }
             Opcode::Or { operand } => {
                 let (v, cycles) = self.extract_u8_arithmetic_operand(memory_bus, operand);
@@ -748,7 +1327,7 @@ impl Cpu {
                 self.clear_subtraction_flag();
                 self.clear_carry_flag();
                 self.clear_half_carry_flag();
-                Some(cycles)
+                Ok(cycles)
             }
             Opcode::Cp { operand } => {
                 let (v, cycles) = self.extract_u8_arithmetic_operand(memory_bus, operand);
@@ -758,40 +1337,44 @@ impl Cpu {
                 self.set_subtraction_flag();
                 self.set_carry_flag_from_bool(a < v);
                 self.set_half_carry_flag_from_bool((a % 16) < (v % 16));
-                Some(cycles)
+                Ok(cycles)
             }
             Opcode::Cpl => {
                 self.set_a(!self.get_a());
                 self.set_subtraction_flag();
                 self.set_half_carry_flag();
-                Some(4)
+                Ok(4)
             }
             Opcode::Daa => {
                 // from https://forums.nesdev.com/viewtopic.php?t=15944
-                // If not subtraction
+                // Turns the result of adding or subtracting two BCD numbers
+                // back into BCD, using the flags left by that operation
                 let mut a = self.get_a();
+                let mut carry = self.get_carry_flag();
                 if self.get_subtraction_flag() {
-                    if self.get_carry_flag() {
+                    // A subtraction can only have borrowed, so the carry
+                    // is left as it was
+                    if carry {
                         a = a.wrapping_sub(0x60);
-                        self.set_carry_flag();
                     }
                     if self.get_half_carry_flag() {
-                        a = a.wrapping_sub(0x6);
+                        a = a.wrapping_sub(0x06);
                     }
                 } else {
-                    if self.get_carry_flag() || a > 0x99 {
+                    if carry || a > 0x99 {
                         a = a.wrapping_add(0x60);
-                        self.set_carry_flag();
+                        carry = true;
                     }
-                    if self.get_half_carry_flag() || (a & 0xf) > 0x9 {
-                        a = a.wrapping_add(0x6);
+                    if self.get_half_carry_flag() || (a & 0x0f) > 0x09 {
+                        a = a.wrapping_add(0x06);
                     }
                 }
 
                 self.set_zero_flag_from_bool(a == 0);
                 self.clear_half_carry_flag();
+                self.set_carry_flag_from_bool(carry);
                 self.set_a(a);
-                Some(4)
+                Ok(4)
             }
             Opcode::Rlca => {
                 let mut a = self.get_a();
@@ -803,7 +1386,7 @@ impl Cpu {
                 // only the carry flag should be set after this;
                 self.clear_flags();
                 self.set_carry_flag_from_bool(carry);
-                Some(4)
+                Ok(4)
             }
             Opcode::Rla => {
                 let mut a = self.get_a();
@@ -816,7 +1399,7 @@ impl Cpu {
                 // only the carry flag should be set after this;
                 self.clear_flags();
                 self.set_carry_flag_from_bool(carry);
-                Some(4)
+                Ok(4)
             }
             Opcode::Rrca => {
                 let mut a = self.get_a();
@@ -828,7 +1411,7 @@ impl Cpu {
                 // only the carry flag should be set after this;
                 self.clear_flags();
                 self.set_carry_flag_from_bool(carry);
-                Some(4)
+                Ok(4)
             }
             Opcode::Rra => {
                 let mut a = self.get_a();
@@ -841,7 +1424,7 @@ impl Cpu {
                 // only the carry flag should be set after this;
                 self.clear_flags();
                 self.set_carry_flag_from_bool(carry);
-                Some(4)
+                Ok(4)
             }
             Opcode::Rlc { operand } => match operand {
                 Operand::Register(r) => {
@@ -855,20 +1438,20 @@ impl Cpu {
                     self.clear_flags();
                     self.set_carry_flag_from_bool(carry);
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(8)
+                    Ok(8)
                 }
                 Operand::Deref(DerefOperand::Register(Register::Hl)) => {
-                    let mut v = memory_bus.read_u8(self.hl.get_u16());
+                    let mut v = Self::read_cycle(memory_bus, self.hl.get_u16());
                     let high_bit = v >> 7;
                     let carry = high_bit == 1;
                     v = (v << 1) | high_bit;
-                    memory_bus.write_u8(self.hl.get_u16(), v);
+                    Self::write_cycle(memory_bus, self.hl.get_u16(), v);
 
                     // only the carry and zero flags should be set after this;
                     self.clear_flags();
                     self.set_carry_flag_from_bool(carry);
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(16)
+                    Ok(16)
                 }
                 _ => unreachable!(),
             },
@@ -885,21 +1468,21 @@ impl Cpu {
                     self.clear_flags();
                     self.set_carry_flag_from_bool(carry);
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(8)
+                    Ok(8)
                 }
                 Operand::Deref(DerefOperand::Register(Register::Hl)) => {
-                    let mut v = memory_bus.read_u8(self.hl.get_u16());
+                    let mut v = Self::read_cycle(memory_bus, self.hl.get_u16());
                     let old_carry = self.get_carry_flag() as u8;
                     let high_bit = v >> 7;
                     let carry = high_bit == 1;
                     v = (v << 1) | old_carry;
-                    memory_bus.write_u8(self.hl.get_u16(), v);
+                    Self::write_cycle(memory_bus, self.hl.get_u16(), v);
 
                     // only the carry and zero flags should be set after this;
                     self.clear_flags();
                     self.set_carry_flag_from_bool(carry);
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(16)
+                    Ok(16)
                 }
                 _ => unreachable!(),
             },
@@ -915,20 +1498,20 @@ impl Cpu {
                     self.clear_flags();
                     self.set_carry_flag_from_bool(carry);
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(8)
+                    Ok(8)
                 }
                 Operand::Deref(DerefOperand::Register(Register::Hl)) => {
-                    let mut v = memory_bus.read_u8(self.hl.get_u16());
+                    let mut v = Self::read_cycle(memory_bus, self.hl.get_u16());
                     let low_bit = v & 1;
                     let carry = low_bit == 1;
                     v = (v >> 1) | (low_bit << 7);
-                    memory_bus.write_u8(self.hl.get_u16(), v);
+                    Self::write_cycle(memory_bus, self.hl.get_u16(), v);
 
                     // only the carry and zero flags should be set after this;
                     self.clear_flags();
                     self.set_carry_flag_from_bool(carry);
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(16)
+                    Ok(16)
                 }
                 _ => unreachable!(),
             },
@@ -945,21 +1528,21 @@ impl Cpu {
                     self.clear_flags();
                     self.set_carry_flag_from_bool(carry);
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(8)
+                    Ok(8)
                 }
                 Operand::Deref(DerefOperand::Register(Register::Hl)) => {
-                    let mut v = memory_bus.read_u8(self.hl.get_u16());
+                    let mut v = Self::read_cycle(memory_bus, self.hl.get_u16());
                     let old_carry = self.get_carry_flag() as u8;
                     let low_bit = v & 1;
                     let carry = low_bit == 1;
                     v = (v >> 1) | (old_carry << 7);
-                    memory_bus.write_u8(self.hl.get_u16(), v);
+                    Self::write_cycle(memory_bus, self.hl.get_u16(), v);
 
                     // only the carry and zero flags should be set after this;
                     self.clear_flags();
                     self.set_carry_flag_from_bool(carry);
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(16)
+                    Ok(16)
                 }
                 _ => unreachable!(),
             },
@@ -975,20 +1558,20 @@ impl Cpu {
                     self.clear_flags();
                     self.set_carry_flag_from_bool(carry);
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(8)
+                    Ok(8)
                 }
                 Operand::Deref(DerefOperand::Register(Register::Hl)) => {
-                    let mut v = memory_bus.read_u8(self.hl.get_u16());
+                    let mut v = Self::read_cycle(memory_bus, self.hl.get_u16());
                     let high_bit = v >> 7;
                     let carry = high_bit == 1;
                     v = v.wrapping_mul(2);
-                    memory_bus.write_u8(self.hl.get_u16(), v);
+                    Self::write_cycle(memory_bus, self.hl.get_u16(), v);
 
                     // only the carry and zero flags should be set after this;
                     self.clear_flags();
                     self.set_carry_flag_from_bool(carry);
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(16)
+                    Ok(16)
                 }
                 _ => unreachable!(),
             },
@@ -1001,17 +1584,17 @@ impl Cpu {
                     // only the zero flag should be set after this;
                     self.clear_flags();
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(8)
+                    Ok(8)
                 }
                 Operand::Deref(DerefOperand::Register(Register::Hl)) => {
-                    let mut v = memory_bus.read_u8(self.hl.get_u16());
+                    let mut v = Self::read_cycle(memory_bus, self.hl.get_u16());
                     v = (v >> 4) | (v << 4);
-                    memory_bus.write_u8(self.hl.get_u16(), v);
+                    Self::write_cycle(memory_bus, self.hl.get_u16(), v);
 
                     // only the zero flag should be set after this;
                     self.clear_flags();
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(16)
+                    Ok(16)
                 }
                 _ => unreachable!(),
             },
@@ -1027,20 +1610,20 @@ impl Cpu {
                     self.clear_flags();
                     self.set_carry_flag_from_bool(carry);
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(8)
+                    Ok(8)
                 }
                 Operand::Deref(DerefOperand::Register(Register::Hl)) => {
-                    let mut v = memory_bus.read_u8(self.hl.get_u16());
+                    let mut v = Self::read_cycle(memory_bus, self.hl.get_u16());
                     let low_bit = v & 1;
                     let carry = low_bit == 1;
                     v = ((v as i8) >> 1) as u8;
-                    memory_bus.write_u8(self.hl.get_u16(), v);
+                    Self::write_cycle(memory_bus, self.hl.get_u16(), v);
 
                     // only the carry and zero flags should be set after this;
                     self.clear_flags();
                     self.set_carry_flag_from_bool(carry);
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(16)
+                    Ok(16)
                 }
                 _ => unreachable!(),
             },
@@ -1056,20 +1639,20 @@ impl Cpu {
                     self.clear_flags();
                     self.set_carry_flag_from_bool(carry);
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(8)
+                    Ok(8)
                 }
                 Operand::Deref(DerefOperand::Register(Register::Hl)) => {
-                    let mut v = memory_bus.read_u8(self.hl.get_u16());
+                    let mut v = Self::read_cycle(memory_bus, self.hl.get_u16());
                     let low_bit = v & 1;
                     let carry = low_bit == 1;
                     v >>= 1;
-                    memory_bus.write_u8(self.hl.get_u16(), v);
+                    Self::write_cycle(memory_bus, self.hl.get_u16(), v);
 
                     // only the carry and zero flags should be set after this;
                     self.clear_flags();
                     self.set_carry_flag_from_bool(carry);
                     self.set_zero_flag_from_bool(v == 0);
-                    Some(16)
+                    Ok(16)
                 }
                 _ => unreachable!(),
             },
@@ -1078,7 +1661,7 @@ impl Cpu {
                 self.set_carry_flag();
                 self.clear_half_carry_flag();
                 self.clear_subtraction_flag();
-                Some(4)
+                Ok(4)
             }
             Opcode::Ccf => {
                 // Toggles Carry, clears half carry and subtraction
@@ -1089,15 +1672,18 @@ impl Cpu {
                 }
                 self.clear_half_carry_flag();
                 self.clear_subtraction_flag();
-                Some(4)
+                Ok(4)
             }
             Opcode::Di => {
-                self.interrupts_enabled = false;
-                Some(4)
+                self.ime = false;
+                self.ime_scheduled = false;
+                Ok(4)
             }
             Opcode::Ei => {
-                self.interrupts_enabled = true;
-                Some(4)
+                if !self.ime {
+                    self.ime_scheduled = true;
+                }
+                Ok(4)
             }
         }
     }
@@ -1111,25 +1697,78 @@ impl Cpu {
         }
     }
 
+    /// Spends a machine cycle without touching memory, letting the rest of
+    /// the hardware catch up
+    fn internal_cycle(memory_bus: &mut MemoryBus) {
+        memory_bus.tick(4, false);
+    }
+
+    /// Reads memory at the end of a machine cycle, so the access sees the
+    /// PPU and timer as they are at that point of the instruction
+    fn read_cycle(memory_bus: &mut MemoryBus, address: u16) -> u8 {
+        memory_bus.tick(4, false);
+        memory_bus.read_u8(address)
+    }
+
+    fn write_cycle(memory_bus: &mut MemoryBus, address: u16, byte: u8) {
+        memory_bus.tick(4, false);
+        memory_bus.write_u8(address, byte);
+    }
+
     fn push(&mut self, memory_bus: &mut MemoryBus, v: u16) {
-        self.sp = self.sp.wrapping_sub(2);
-        memory_bus.write_mem(self.sp, &v.to_le_bytes()[..]);
+        // There's an internal delay before the high byte is written first
+        Self::internal_cycle(memory_bus);
+        let [low, high] = v.to_le_bytes();
+        self.sp = self.sp.wrapping_sub(1);
+        Self::write_cycle(memory_bus, self.sp, high);
+        self.sp = self.sp.wrapping_sub(1);
+        Self::write_cycle(memory_bus, self.sp, low);
     }
 
     fn pop(&mut self, memory_bus: &mut MemoryBus) -> u16 {
-        let v = memory_bus.read_mem(self.sp, 2);
-        self.sp = self.sp.wrapping_add(2);
-        assert!(v.len() == 2);
-        (u16::from(v[1]) << 8) | u16::from(v[0])
+        let low = Self::read_cycle(memory_bus, self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        let high = Self::read_cycle(memory_bus, self.sp);
+        self.sp = self.sp.wrapping_add(1);
+        u16::from_le_bytes([low, high])
     }
 
-    fn call(&mut self, memory_bus: &mut MemoryBus, address: u16) {
-        self.push(memory_bus, self.pc);
+    fn call(&mut self, memory_bus: &mut MemoryBus, address: u16, kind: CallKind) {
+        let return_address = self.pc;
+        self.push(memory_bus, return_address);
         self.pc = address;
+        self.enter_call_frame(kind, return_address);
     }
 
     fn ret(&mut self, memory_bus: &mut MemoryBus) {
         self.pc = self.pop(memory_bus);
+        self.leave_call_frames();
+    }
+
+    /// Records a call whose return address was just pushed to SP, dropping
+    /// any frames it overwrote
+    fn enter_call_frame(&mut self, kind: CallKind, return_address: u16) {
+        let sp = self.sp;
+        while matches!(self.call_stack.last(), Some(frame) if frame.stack_pointer <= sp) {
+            self.call_stack.pop();
+        }
+        if self.call_stack.len() == MAX_CALL_STACK_DEPTH {
+            self.call_stack.remove(0);
+        }
+        self.call_stack.push(CallFrame {
+            kind,
+            target: self.pc,
+            return_address,
+            stack_pointer: sp,
+        });
+    }
+
+    /// Drops the frames whose return addresses are now above SP
+    fn leave_call_frames(&mut self) {
+        let sp = self.sp;
+        while matches!(self.call_stack.last(), Some(frame) if frame.stack_pointer < sp) {
+            self.call_stack.pop();
+        }
     }
 
     fn test_bit(bit: u8, v: u8) -> bool {
@@ -1152,7 +1791,11 @@ impl Cpu {
     }
 
     fn set_flags(&mut self, flags: u8) {
-        self.af.set_low(flags);
+        self.af.set_low(flags & FLAGS_MASK);
+    }
+
+    fn set_af(&mut self, value: u16) {
+        self.af.set_u16(value & (0xff00 | u16::from(FLAGS_MASK)));
     }
 
     fn add8_with_carry(&mut self, b: u8, use_carry: bool, subtraction: bool) {
@@ -1201,7 +1844,7 @@ impl Cpu {
             Operand::U8(v) => (v, 8),
             Operand::Register(r) => (self.get_r8(&r), 4),
             Operand::Deref(DerefOperand::Register(Register::Hl)) => {
-                (memory_bus.read_u8(self.hl.get_u16()), 8)
+                (Self::read_cycle(memory_bus, self.hl.get_u16()), 8)
             }
             _ => unreachable!(),
         }
@@ -1376,21 +2019,20 @@ impl Cpu {
         }
     }
 
+    fn pending_interrupts(memory_bus: &mut MemoryBus) -> u8 {
+        memory_bus.read_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS)
+            & memory_bus.read_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS)
+            & INTERRUPT_BITS_MASK
+    }
+
     fn should_service_interrupt(&self, memory_bus: &mut MemoryBus) -> bool {
-        if !self.interrupts_enabled {
-            return false;
-        }
-        // Check if interrupts are enabled
-        let enabled_interrupts_bitmask = memory_bus.read_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS);
-        if enabled_interrupts_bitmask == 0 {
-            return false;
-        }
-        // Check if any interrupts are waiting
-        (memory_bus.read_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS) & enabled_interrupts_bitmask) != 0
+        // Only interrupts that are both requested in IF and enabled in IE
+        // are serviced
+        self.ime && Self::pending_interrupts(memory_bus) != 0
     }
 
-    #[allow(dead_code)]
-    fn interrupt_number_to_string(number: u8) -> &'static str {
+    #[must_use]
+    pub fn interrupt_number_to_string(number: u8) -> &'static str {
         match number {
             0 => "vblank",
             1 => "lcd stat",
@@ -1401,36 +2043,52 @@ impl Cpu {
         }
     }
 
-    fn service_interrupt(&mut self, memory_bus: &mut MemoryBus) {
+    // Returns the interrupt serviced, None if the dispatch was cancelled
+    fn service_interrupt(&mut self, memory_bus: &mut MemoryBus) -> Option<u8> {
         // Actual hardware process (from https://gbdev.io/pandocs/Interrupts.html):
-        // Clear the bit corresponding to the interrupt
         // disable interrupts
         // 2 cycles of nop
-        // Push PC onto stack
+        // Push PC onto stack, high byte first
+        // Clear the bit corresponding to the interrupt
         // The PC is set to the interrupt handler
+        self.ime = false;
+
+        Self::internal_cycle(memory_bus);
+        Self::internal_cycle(memory_bus);
+        let return_address = self.pc;
+        let [pc_low, pc_high] = return_address.to_le_bytes();
+        self.sp = self.sp.wrapping_sub(1);
+        Self::write_cycle(memory_bus, self.sp, pc_high);
+
+        // The interrupt is only picked after the high byte is pushed, so if
+        // that write lands on IE and disables the pending interrupts the
+        // dispatch is cancelled and the CPU jumps to 0x0000 instead.
+        // Lower bits are higher priority.
+        let pending = Self::pending_interrupts(memory_bus);
+        self.sp = self.sp.wrapping_sub(1);
+        Self::write_cycle(memory_bus, self.sp, pc_low);
+        if pending == 0 {
+            self.pc = 0x0000;
+            self.enter_call_frame(CallKind::Interrupt(None), return_address);
+            return None;
+        }
 
-        // Determine which interrupt this is. Lower bits in the interrupt flags register
-        // are higher priority
-        let mut interrupt_flags = memory_bus.read_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS)
-            & memory_bus.read_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS);
         #[allow(clippy::cast_possible_truncation)]
-        let interrupt_number = interrupt_flags.trailing_zeros() as u8;
-        assert!(interrupt_number < 5);
+        let interrupt_number = pending.trailing_zeros() as u8;
         // println!(
         //     "Servicing interrupt #{} ({})",
         //     interrupt_number,
         //     Self::interrupt_number_to_string(interrupt_number)
         // );
-        // Clear this bit
+        // Clear this bit, leaving any other requests pending
+        let mut interrupt_flags = memory_bus.read_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS);
         Self::reset_bit(interrupt_number, &mut interrupt_flags);
         memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, interrupt_flags);
-        // Disable interrupts
-        self.interrupts_enabled = false;
 
-        // 2 cycles of nop does nothing
-        // Calling the interrupt handler should accomplish the last two steps
         // Interrupt handler addresses are 0x40, 0x48, 0x50, 0x58, 0x60.
-        self.call(memory_bus, u16::from(0x40 + 8 * interrupt_number));
+        self.pc = u16::from(0x40 + 8 * interrupt_number);
+        self.enter_call_frame(CallKind::Interrupt(Some(interrupt_number)), return_address);
+        Some(interrupt_number)
     }
 
     pub fn dump_state(&self) {
@@ -1439,6 +2097,17 @@ impl Cpu {
         println!("\tde = {} hl = {}", self.de, self.hl);
         println!("\tpc = {:04x} sp = {:04x}", self.pc, self.sp);
         println!("\t\tFlags: {}", self.dump_flags_to_string());
+        println!(
+            "\tIME: {}",
+            match (self.ime, self.ime_scheduled) {
+                (true, _) => "enabled",
+                (false, true) => "enabled after next instruction",
+                (false, false) => "disabled",
+            }
+        );
+        if let Some(dispatch) = &self.last_interrupt_dispatch {
+            println!("\tLast interrupt: {}", dispatch);
+        }
     }
 
     fn dump_flags_to_string(&self) -> String {
@@ -1475,17 +2144,269 @@ mod tests {
     }
 
     #[test]
-    #[should_panic]
+    fn test_get_registers() {
+        let mut cpu = Cpu::default();
+        cpu.af.set_u16(0x12b0);
+        cpu.bc.set_u16(0x3456);
+        cpu.de.set_u16(0x789a);
+        cpu.hl.set_u16(0xbcde);
+        cpu.sp = 0xfff0;
+        cpu.pc = 0x0150;
+        cpu.ime = true;
+        cpu.state = State::Halted;
+        assert_eq!(
+            cpu.get_registers(),
+            Registers {
+                a: 0x12,
+                f: 0xb0,
+                b: 0x34,
+                c: 0x56,
+                d: 0x78,
+                e: 0x9a,
+                h: 0xbc,
+                l: 0xde,
+                sp: 0xfff0,
+                pc: 0x0150,
+                ime: true,
+                state: State::Halted,
+            }
+        );
+        let registers = cpu.get_registers();
+        assert_eq!(serde_round_trip(&registers), registers);
+    }
+
+    /// A field recorded by `FieldSerializer`, enough for `Registers`
+    #[derive(Debug)]
+    enum Field {
+        U8(u8),
+        U16(u16),
+        Bool(bool),
+        Variant(&'static str),
+    }
+
+    /// Flattens a struct of plain fields into name/value pairs, so serde
+    /// derives can be round tripped without a format crate
+    #[derive(Default)]
+    struct FieldSerializer {
+        fields: Vec<(&'static str, Field)>,
+        key: &'static str,
+    }
+
+    fn serde_round_trip<T: Serialize + serde::de::DeserializeOwned>(value: &T) -> T {
+        use serde::de::value::MapDeserializer;
+
+        let mut serializer = FieldSerializer::default();
+        value.serialize(&mut serializer).unwrap();
+        let deserializer: MapDeserializer<_, serde::de::value::Error> =
+            MapDeserializer::new(serializer.fields.into_iter());
+        T::deserialize(deserializer).unwrap()
+    }
+
+    impl serde::Serializer for &mut FieldSerializer {
+        type Ok = ();
+        type Error = serde::de::value::Error;
+        type SerializeSeq = serde::ser::Impossible<(), Self::Error>;
+        type SerializeTuple = serde::ser::Impossible<(), Self::Error>;
+        type SerializeTupleStruct = serde::ser::Impossible<(), Self::Error>;
+        type SerializeTupleVariant = serde::ser::Impossible<(), Self::Error>;
+        type SerializeMap = serde::ser::Impossible<(), Self::Error>;
+        type SerializeStruct = Self;
+        type SerializeStructVariant = serde::ser::Impossible<(), Self::Error>;
+
+        fn serialize_u8(self, v: u8) -> Result<(), Self::Error> {
+            self.fields.push((self.key, Field::U8(v)));
+            Ok(())
+        }
+
+        fn serialize_u16(self, v: u16) -> Result<(), Self::Error> {
+            self.fields.push((self.key, Field::U16(v)));
+            Ok(())
+        }
+
+        fn serialize_bool(self, v: bool) -> Result<(), Self::Error> {
+            self.fields.push((self.key, Field::Bool(v)));
+            Ok(())
+        }
+
+        fn serialize_unit_variant(
+            self,
+            _name: &'static str,
+            _index: u32,
+            variant: &'static str,
+        ) -> Result<(), Self::Error> {
+            self.fields.push((self.key, Field::Variant(variant)));
+            Ok(())
+        }
+
+        fn serialize_struct(self, _name: &'static str, _len: usize) -> Result<Self, Self::Error> {
+            Ok(self)
+        }
+
+        fn serialize_i8(self, _v: i8) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_i16(self, _v: i16) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_i32(self, _v: i32) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_i64(self, _v: i64) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_u32(self, _v: u32) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_u64(self, _v: u64) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_f32(self, _v: f32) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_f64(self, _v: f64) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_char(self, _v: char) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_str(self, _v: &str) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_bytes(self, _v: &[u8]) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_none(self) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_some<T: ?Sized + Serialize>(self, _value: &T) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_unit(self) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_unit_struct(self, _name: &'static str) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_newtype_struct<T: ?Sized + Serialize>(
+            self,
+            _name: &'static str,
+            _value: &T,
+        ) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_newtype_variant<T: ?Sized + Serialize>(
+            self,
+            _name: &'static str,
+            _index: u32,
+            _variant: &'static str,
+            _value: &T,
+        ) -> Result<(), Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_seq(self, _len: Option<usize>) -> Result<Self::SerializeSeq, Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_tuple(self, _len: usize) -> Result<Self::SerializeTuple, Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_tuple_struct(
+            self,
+            _name: &'static str,
+            _len: usize,
+        ) -> Result<Self::SerializeTupleStruct, Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_tuple_variant(
+            self,
+            _name: &'static str,
+            _index: u32,
+            _variant: &'static str,
+            _len: usize,
+        ) -> Result<Self::SerializeTupleVariant, Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_map(self, _len: Option<usize>) -> Result<Self::SerializeMap, Self::Error> {
+            unimplemented!()
+        }
+        fn serialize_struct_variant(
+            self,
+            _name: &'static str,
+            _index: u32,
+            _variant: &'static str,
+            _len: usize,
+        ) -> Result<Self::SerializeStructVariant, Self::Error> {
+            unimplemented!()
+        }
+    }
+
+    impl serde::ser::SerializeStruct for &mut FieldSerializer {
+        type Ok = ();
+        type Error = serde::de::value::Error;
+
+        fn serialize_field<T: ?Sized + Serialize>(
+            &mut self,
+            key: &'static str,
+            value: &T,
+        ) -> Result<(), Self::Error> {
+            self.key = key;
+            value.serialize(&mut **self)
+        }
+
+        fn end(self) -> Result<(), Self::Error> {
+            Ok(())
+        }
+    }
+
+    impl<'de> serde::de::IntoDeserializer<'de> for Field {
+        type Deserializer = Self;
+
+        fn into_deserializer(self) -> Self {
+            self
+        }
+    }
+
+    impl<'de> serde::Deserializer<'de> for Field {
+        type Error = serde::de::value::Error;
+
+        fn deserialize_any<V: serde::de::Visitor<'de>>(
+            self,
+            visitor: V,
+        ) -> Result<V::Value, Self::Error> {
+            use serde::de::IntoDeserializer;
+
+            match self {
+                Self::U8(v) => visitor.visit_u8(v),
+                Self::U16(v) => visitor.visit_u16(v),
+                Self::Bool(v) => visitor.visit_bool(v),
+                Self::Variant(variant) => visitor.visit_enum(variant.into_deserializer()),
+            }
+        }
+
+        serde::forward_to_deserialize_any! {
+            bool i8 i16 i32 i64 i128 u8 u16 u32 u64 u128 f32 f64 char str string
+            bytes byte_buf option unit unit_struct newtype_struct seq tuple
+            tuple_struct map struct enum identifier ignored_any
+        }
+    }
+
+    #[test]
     fn test_unknown_instruction() {
         let mut cpu = Cpu::default();
         let mut memory_bus = create_default_memory_bus();
-        cpu.execute_instruction(
+        let result = cpu.execute_instruction(
             &mut memory_bus,
             Instruction {
-                address: 0,
-                op: Opcode::Unknown { opcode: 0 },
+                address: 0x1234,
+                op: Opcode::Unknown { opcode: 0xd3 },
             },
         );
+        assert_eq!(
+            result,
+            Err(CpuError::IllegalOpcode {
+                pc: 0x1234,
+                opcode: 0xd3
+            })
+        );
     }
 
     #[test]
@@ -1571,7 +2492,7 @@ mod tests {
             },
         };
         cpu.sp = 0x8000;
-        cpu.execute_instruction(&mut memory_bus, insn);
+        cpu.execute_instruction(&mut memory_bus, insn).unwrap();
         assert_eq!(cpu.sp, 0x7fff);
         assert!(!cpu.get_zero_flag());
         assert!(!cpu.get_subtraction_flag());
@@ -1586,7 +2507,7 @@ mod tests {
             },
         };
         cpu.sp = 0x8000;
-        cpu.execute_instruction(&mut memory_bus, insn);
+        cpu.execute_instruction(&mut memory_bus, insn).unwrap();
         assert_eq!(cpu.sp, 0x7ffe);
         assert!(!cpu.get_zero_flag());
         assert!(!cpu.get_subtraction_flag());
@@ -1601,7 +2522,7 @@ mod tests {
             },
         };
         cpu.sp = 0x8000;
-        cpu.execute_instruction(&mut memory_bus, insn);
+        cpu.execute_instruction(&mut memory_bus, insn).unwrap();
         assert_eq!(cpu.sp, 0x8002);
         assert!(!cpu.get_zero_flag());
         assert!(!cpu.get_subtraction_flag());
@@ -1623,6 +2544,286 @@ mod tests {
         assert_eq!(cpu.sp, 0xfffe);
     }
 
+    #[test]
+    fn test_execution_wraps_at_top_of_memory() {
+        let mut cpu = Cpu::default();
+        let mut memory_bus = create_default_memory_bus();
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x00);
+
+        // ld a,0x42 with its operand in IE
+        cpu.pc = 0xfffe;
+        memory_bus.write_u8(0xfffe, 0x3e);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x42);
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(cpu.get_a(), 0x42);
+
+        // jr +2 lands relative to the wrapped PC
+        cpu.pc = 0xfffe;
+        memory_bus.write_u8(0xfffe, 0x18);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x02);
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.pc, 0x0002);
+
+        // A nop in IE
+        cpu.pc = 0xffff;
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x00);
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.pc, 0x0000);
+    }
+
+    #[test]
+    fn test_stack_and_hl_wrap() {
+        let mut cpu = Cpu::default();
+        let mut memory_bus = create_default_memory_bus();
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x00);
+
+        cpu.sp = 0x0000;
+        cpu.push(&mut memory_bus, 0x1234);
+        assert_eq!(cpu.sp, 0xfffe);
+        assert_eq!(cpu.pop(&mut memory_bus), 0x1234);
+        assert_eq!(cpu.sp, 0x0000);
+
+        // ld (hl+),a, ld (hl-),a, ld a,(hl+), ld a,(hl-)
+        for (i, &opcode) in [0x22, 0x32, 0x2a, 0x3a].iter().enumerate() {
+            memory_bus.write_u8(0xc000 + i as u16, opcode);
+        }
+        cpu.pc = 0xc000;
+        cpu.set_a(0x00);
+        cpu.hl.set_u16(0xffff);
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.hl.get_u16(), 0x0000);
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.hl.get_u16(), 0xffff);
+        cpu.hl.set_u16(0xffff);
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.hl.get_u16(), 0x0000);
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.hl.get_u16(), 0xffff);
+    }
+
+    // Cycles for every opcode from the Pan Docs tables, with conditional
+    // branches taken. STOP is listed as 4, but its second byte is fetched
+    // too. 0 marks illegal opcodes and the 0xcb prefix.
+    #[rustfmt::skip]
+    const REFERENCE_CYCLES: [u64; 256] = [
+        //  x0  x1  x2  x3  x4  x5  x6  x7  x8  x9  xa  xb  xc  xd  xe  xf
+             4, 12,  8,  8,  4,  4,  8,  4, 20,  8,  8,  8,  4,  4,  8,  4, // 0x
+             8, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8,  8,  4,  4,  8,  4, // 1x
+            12, 12,  8,  8,  4,  4,  8,  4, 12,  8,  8,  8,  4,  4,  8,  4, // 2x
+            12, 12,  8,  8, 12, 12, 12,  4, 12,  8,  8,  8,  4,  4,  8,  4, // 3x
+             4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 4x
+             4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 5x
+             4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 6x
+             8,  8,  8,  8,  8,  8,  4,  8,  4,  4,  4,  4,  4,  4,  8,  4, // 7x
+             4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 8x
+             4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // 9x
+             4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // ax
+             4,  4,  4,  4,  4,  4,  8,  4,  4,  4,  4,  4,  4,  4,  8,  4, // bx
+            20, 12, 16, 16, 24, 16,  8, 16, 20, 16, 16,  0, 24, 24,  8, 16, // cx
+            20, 12, 16,  0, 24, 16,  8, 16, 20, 16, 16,  0, 24,  0,  8, 16, // dx
+            12, 12,  8,  0,  0, 16,  8, 16, 16,  4, 16,  0,  0,  0,  8, 16, // ex
+            12, 12,  8,  4,  0, 16,  8, 16, 12,  8, 16,  4,  0,  0,  8, 16, // fx
+    ];
+
+    // Cycles for every 0xcb prefixed opcode, prefix included
+    #[rustfmt::skip]
+    const REFERENCE_CB_CYCLES: [u64; 256] = [
+        //  x0  x1  x2  x3  x4  x5  x6  x7  x8  x9  xa  xb  xc  xd  xe  xf
+             8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 0x
+             8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 1x
+             8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 2x
+             8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 3x
+             8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8, // 4x
+             8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8, // 5x
+             8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8, // 6x
+             8,  8,  8,  8,  8,  8, 12,  8,  8,  8,  8,  8,  8,  8, 12,  8, // 7x
+             8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 8x
+             8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // 9x
+             8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // ax
+             8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // bx
+             8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // cx
+             8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // dx
+             8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // ex
+             8,  8,  8,  8,  8,  8, 16,  8,  8,  8,  8,  8,  8,  8, 16,  8, // fx
+    ];
+
+    // Cycles for a conditional branch that isn't taken, None for any
+    // other opcode
+    fn reference_not_taken_cycles(opcode: u8) -> Option<u64> {
+        match opcode {
+            0x20 | 0x28 | 0x30 | 0x38 => Some(8),  // jr cc
+            0xc0 | 0xc8 | 0xd0 | 0xd8 => Some(8),  // ret cc
+            0xc2 | 0xca | 0xd2 | 0xda => Some(12), // jp cc
+            0xc4 | 0xcc | 0xd4 | 0xdc => Some(12), // call cc
+            _ => None,
+        }
+    }
+
+    // Runs the instruction made of `bytes` from WRAM, with every register
+    // pointing somewhere harmless and no interrupts to get in the way
+    fn step_cycles(bytes: &[u8], flags: u8) -> Result<u64, CpuError> {
+        let mut memory_bus = create_default_memory_bus();
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x00);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x00);
+        let mut cpu = Cpu {
+            pc: 0xc000,
+            sp: 0xd000,
+            ..Cpu::default()
+        };
+        cpu.set_flags(flags);
+        cpu.bc.set_u16(0xc100);
+        cpu.de.set_u16(0xc100);
+        cpu.hl.set_u16(0xc100);
+        for (address, &byte) in (0xc000..).zip(bytes) {
+            memory_bus.write_u8(address, byte);
+        }
+        cpu.single_step(&mut memory_bus)
+    }
+
+    #[test]
+    fn test_cycles_match_reference_table() {
+        for opcode in 0..=0xff_u8 {
+            if opcode == 0xcb {
+                continue;
+            }
+            // The condition codes with bit 3 set (Z, C) are taken when the
+            // flags are all set, the others (NZ, NC) when they are clear
+            for &flags in &[0x00, 0xf0] {
+                let taken = (opcode & 0x08 != 0) == (flags == 0xf0);
+                let expected = match reference_not_taken_cycles(opcode) {
+                    Some(cycles) if !taken => cycles,
+                    _ => REFERENCE_CYCLES[opcode as usize],
+                };
+                let result = step_cycles(&[opcode, 0x00, 0x00], flags);
+                if expected == 0 {
+                    assert!(result.is_err(), "opcode {:#04x}", opcode);
+                } else {
+                    assert_eq!(
+                        result,
+                        Ok(expected),
+                        "opcode {:#04x}, flags {:#04x}",
+                        opcode,
+                        flags
+                    );
+                }
+            }
+        }
+        for opcode in 0..=0xff_u8 {
+            let expected = REFERENCE_CB_CYCLES[opcode as usize];
+            assert_eq!(
+                step_cycles(&[0xcb, opcode], 0x00),
+                Ok(expected),
+                "opcode 0xcb {:#04x}",
+                opcode
+            );
+        }
+    }
+
+    // DAA as SameBoy implements it, which is structured differently enough
+    // to catch mistakes in ours. Returns the new A and flags.
+    fn reference_daa(a: u8, flags: u8) -> (u8, u8) {
+        let mut result = i16::from(a);
+        let mut new_flags = flags & (SUBTRACTION_BIT_MASK | CARRY_BIT_MASK);
+        if flags & SUBTRACTION_BIT_MASK != 0 {
+            if flags & HALF_CARRY_BIT_MASK != 0 {
+                result = (result - 0x06) & 0xff;
+            }
+            if flags & CARRY_BIT_MASK != 0 {
+                result -= 0x60;
+            }
+        } else {
+            if flags & HALF_CARRY_BIT_MASK != 0 || (result & 0x0f) > 0x09 {
+                result += 0x06;
+            }
+            if flags & CARRY_BIT_MASK != 0 || result > 0x9f {
+                result += 0x60;
+            }
+        }
+        if result & 0xff == 0 {
+            new_flags |= ZERO_BIT_MASK;
+        }
+        if result & 0x100 != 0 {
+            new_flags |= CARRY_BIT_MASK;
+        }
+        (result.to_le_bytes()[0], new_flags)
+    }
+
+    #[test]
+    fn test_daa_matches_reference() {
+        let mut memory_bus = create_default_memory_bus();
+        for a in 0..=0xff_u8 {
+            // Every combination of Z, N, H and C going in
+            for flags in (0..=0xf0_u8).step_by(0x10) {
+                let mut cpu = Cpu::default();
+                cpu.set_a(a);
+                cpu.set_flags(flags);
+                cpu.execute_instruction(
+                    &mut memory_bus,
+                    Instruction {
+                        address: 0,
+                        op: Opcode::Daa,
+                    },
+                )
+                .unwrap();
+                assert_eq!(
+                    (cpu.get_a(), cpu.get_flags()),
+                    reference_daa(a, flags),
+                    "DAA with A = {:02x}, F = {:02x}",
+                    a,
+                    flags
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_daa_bcd_arithmetic() {
+        let mut memory_bus = create_default_memory_bus();
+        let daa = |cpu: &mut Cpu, memory_bus: &mut MemoryBus| {
+            cpu.execute_instruction(
+                memory_bus,
+                Instruction {
+                    address: 0,
+                    op: Opcode::Daa,
+                },
+            )
+            .unwrap();
+        };
+        for x in 0..100_u8 {
+            for y in 0..100_u8 {
+                let bcd = |v: u8| ((v / 10) << 4) | (v % 10);
+                let mut cpu = Cpu::default();
+                cpu.set_a(bcd(x));
+                cpu.add8_with_carry(bcd(y), false, false);
+                daa(&mut cpu, &mut memory_bus);
+                assert_eq!(cpu.get_a(), bcd((x + y) % 100), "{} + {}", x, y);
+                assert_eq!(cpu.get_carry_flag(), x + y >= 100, "{} + {}", x, y);
+
+                cpu.set_a(bcd(x));
+                cpu.add8_with_carry(bcd(y), false, true);
+                daa(&mut cpu, &mut memory_bus);
+                assert_eq!(cpu.get_a(), bcd((100 + x - y) % 100), "{} - {}", x, y);
+                assert_eq!(cpu.get_carry_flag(), x < y, "{} - {}", x, y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_flags_low_bits_read_as_zero() {
+        let mut cpu = Cpu::default();
+        cpu.set_flags(0xff);
+        assert_eq!(cpu.get_flags(), 0xf0);
+
+        let mut writer = StateWriter::default();
+        cpu.af.set_u16(0x12ff);
+        cpu.save_state(&mut writer);
+        let state = writer.into_inner();
+        let mut cpu = Cpu::default();
+        cpu.load_state(&mut StateReader::new(&state)).unwrap();
+        assert_eq!(cpu.af.get_u16(), 0x12f0);
+    }
+
     #[test]
     fn test_pop_af() {
         let mut cpu = Cpu::default();
@@ -1636,7 +2837,7 @@ mod tests {
             },
         };
         assert_eq!(cpu.af.get_u16(), 0x01b0);
-        cpu.execute_instruction(&mut memory_bus, insn);
+        cpu.execute_instruction(&mut memory_bus, insn).unwrap();
         assert_eq!(cpu.af.get_u16(), 0xfff0);
     }
 
@@ -1655,7 +2856,8 @@ mod tests {
                     address: 0,
                     op: Opcode::Rlca,
                 },
-            );
+            )
+            .unwrap();
             let a = cpu.get_a();
             if a != byte.rotate_left(1) {
                 println!(
@@ -1696,7 +2898,7 @@ mod tests {
                 };
 
                 cpu.set_a(a);
-                cpu.execute_instruction(&mut memory_bus, insn);
+                cpu.execute_instruction(&mut memory_bus, insn).unwrap();
                 let real_res = a.wrapping_sub(imm);
                 let res = cpu.get_a();
                 if res != real_res {
@@ -1755,7 +2957,7 @@ mod tests {
 
                     cpu.set_a(a);
                     cpu.set_carry_flag_from_bool(carry);
-                    cpu.execute_instruction(&mut memory_bus, insn);
+                    cpu.execute_instruction(&mut memory_bus, insn).unwrap();
                     let real_res = a.wrapping_sub(imm).wrapping_sub(carry as u8);
                     let res = cpu.get_a();
                     if res != real_res {
@@ -1796,4 +2998,872 @@ mod tests {
             panic!("FAILED");
         }
     }
+
+    // (opcode, immediate, size, cycles) for the loads between A and the
+    // 0xff00 page, checked against the decoder, size() and the executor
+    const HIGH_PAGE_LOADS: [(u8, u8, u8, u64); 4] = [
+        (0xe0, 0x80, 2, 12), // ld (0xff00+n),A
+        (0xe2, 0x00, 1, 8),  // ld (0xff00+C),A
+        (0xf0, 0x80, 2, 12), // ld A,(0xff00+n)
+        (0xf2, 0x00, 1, 8),  // ld A,(0xff00+C)
+    ];
+
+    #[test]
+    fn test_high_page_load_sizes() {
+        let mut memory_bus = create_default_memory_bus();
+        for &(opcode, immediate, size, _) in &HIGH_PAGE_LOADS {
+            memory_bus.write_u8(0xc000, opcode);
+            memory_bus.write_u8(0xc001, immediate);
+            let insn = Cpu::get_instruction_at_address(&mut memory_bus, 0xc000);
+            assert_eq!(insn.size(), size, "size of {:#04x}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_high_page_load_timing() {
+        for &(opcode, immediate, size, cycles) in &HIGH_PAGE_LOADS {
+            let mut cpu = Cpu::default();
+            let mut memory_bus = create_default_memory_bus();
+            memory_bus.write_u8(0xc000, opcode);
+            memory_bus.write_u8(0xc001, immediate);
+            cpu.pc = 0xc000;
+            let insn = cpu.get_next_instruction(&mut memory_bus);
+            assert_eq!(
+                cpu.execute_instruction(&mut memory_bus, insn),
+                Ok(cycles),
+                "cycles of {:#04x}",
+                opcode
+            );
+            assert_eq!(cpu.pc, 0xc000 + u16::from(size), "pc after {:#04x}", opcode);
+        }
+    }
+
+    #[test]
+    fn test_high_page_load_data() {
+        let mut cpu = Cpu::default();
+        let mut memory_bus = create_default_memory_bus();
+
+        // ld (0xff00+n),A
+        cpu.set_a(0x12);
+        memory_bus.write_u8(0xc000, 0xe0);
+        memory_bus.write_u8(0xc001, 0x80);
+        cpu.pc = 0xc000;
+        let insn = cpu.get_next_instruction(&mut memory_bus);
+        cpu.execute_instruction(&mut memory_bus, insn).unwrap();
+        assert_eq!(memory_bus.read_u8(0xff80), 0x12);
+
+        // ld (0xff00+C),A
+        cpu.set_a(0x34);
+        cpu.bc.set_low(0x81);
+        memory_bus.write_u8(0xc000, 0xe2);
+        cpu.pc = 0xc000;
+        let insn = cpu.get_next_instruction(&mut memory_bus);
+        cpu.execute_instruction(&mut memory_bus, insn).unwrap();
+        assert_eq!(memory_bus.read_u8(0xff81), 0x34);
+
+        // ld A,(0xff00+n)
+        memory_bus.write_u8(0xc000, 0xf0);
+        memory_bus.write_u8(0xc001, 0x81);
+        cpu.pc = 0xc000;
+        let insn = cpu.get_next_instruction(&mut memory_bus);
+        cpu.execute_instruction(&mut memory_bus, insn).unwrap();
+        assert_eq!(cpu.get_a(), 0x34);
+
+        // ld A,(0xff00+C)
+        cpu.bc.set_low(0x80);
+        memory_bus.write_u8(0xc000, 0xf2);
+        cpu.pc = 0xc000;
+        let insn = cpu.get_next_instruction(&mut memory_bus);
+        cpu.execute_instruction(&mut memory_bus, insn).unwrap();
+        assert_eq!(cpu.get_a(), 0x12);
+    }
+
+    fn create_interrupt_test_cpu(memory_bus: &mut MemoryBus) -> Cpu {
+        let cpu = Cpu {
+            ime: true,
+            pc: 0xc000,
+            sp: 0xd000,
+            ..Cpu::default()
+        };
+        // A NOP to run if no interrupt is dispatched
+        memory_bus.write_u8(0xc000, 0x00);
+        cpu
+    }
+
+    #[test]
+    fn test_interrupt_requires_ie_and_if() {
+        let mut memory_bus = create_default_memory_bus();
+        let mut cpu = create_interrupt_test_cpu(&mut memory_bus);
+        // Timer requested, but only vblank enabled
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x04);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x01);
+        assert_eq!(cpu.single_step(&mut memory_bus), Ok(4));
+        assert_eq!(cpu.pc, 0xc001);
+        assert!(cpu.ime);
+
+        // The unused upper bits don't count as interrupts either
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0xe0);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0xe0);
+        assert_eq!(cpu.single_step(&mut memory_bus), Ok(4));
+        assert_eq!(cpu.pc, 0xc002);
+
+        // Nothing is dispatched with IME off
+        cpu.ime = false;
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x01);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x01);
+        assert_eq!(cpu.single_step(&mut memory_bus), Ok(4));
+        assert_eq!(cpu.pc, 0xc003);
+    }
+
+    #[test]
+    fn test_interrupt_dispatch() {
+        let mut memory_bus = create_default_memory_bus();
+        let mut cpu = create_interrupt_test_cpu(&mut memory_bus);
+        // Joypad is requested but not enabled, timer and serial are both
+        // enabled
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x1c);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x0c);
+        assert_eq!(cpu.single_step(&mut memory_bus), Ok(20));
+        // Timer has priority over serial
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(cpu.sp, 0xcffe);
+        assert_eq!(memory_bus.read_u8(0xcfff), 0xc0);
+        assert_eq!(memory_bus.read_u8(0xcffe), 0x00);
+        assert!(!cpu.ime);
+        // Only the serviced request is acknowledged
+        assert_eq!(memory_bus.read_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS), 0x18);
+    }
+
+    // Mirrors mooneye-gb's acceptance/interrupts/ie_push
+    #[test]
+    fn test_ie_push_cancels_interrupt() {
+        let mut memory_bus = create_default_memory_bus();
+        let mut cpu = create_interrupt_test_cpu(&mut memory_bus);
+        // The high byte of the PC is pushed to IE
+        cpu.sp = 0x0000;
+        cpu.pc = 0x0200;
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x04);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x04);
+        assert_eq!(cpu.single_step(&mut memory_bus), Ok(20));
+        assert_eq!(cpu.pc, 0x0000);
+        assert_eq!(cpu.sp, 0xfffe);
+        assert_eq!(memory_bus.read_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS), 0x02);
+        assert_eq!(memory_bus.read_u8(0xfffe), 0x00);
+        assert!(!cpu.ime);
+        // The cancelled interrupt is still requested
+        assert_eq!(memory_bus.read_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS), 0x04);
+    }
+
+    #[test]
+    fn test_ie_push_changes_interrupt() {
+        let mut memory_bus = create_default_memory_bus();
+        let mut cpu = create_interrupt_test_cpu(&mut memory_bus);
+        // Vblank would win, but the push leaves only timer enabled
+        cpu.sp = 0x0000;
+        cpu.pc = 0x0400;
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x05);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x05);
+        assert_eq!(cpu.single_step(&mut memory_bus), Ok(20));
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(memory_bus.read_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS), 0x01);
+    }
+
+    #[test]
+    fn test_halt_bug_repeats_next_instruction() {
+        let mut memory_bus = create_default_memory_bus();
+        let mut cpu = create_interrupt_test_cpu(&mut memory_bus);
+        cpu.ime = false;
+        cpu.af.set_high(0);
+        memory_bus.write_mem(0xc000, &[0x76, 0x3c, 0x00]); // HALT, INC A, NOP
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x01);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x01);
+
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.state, State::Running);
+        assert_eq!(cpu.pc, 0xc001);
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.af.get_high(), 1);
+        assert_eq!(cpu.pc, 0xc001);
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.af.get_high(), 2);
+        assert_eq!(cpu.pc, 0xc002);
+        // The interrupt is still pending, nothing was serviced
+        assert_eq!(memory_bus.read_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS), 0x01);
+    }
+
+    #[test]
+    fn test_halt_bug_reads_opcode_as_operand() {
+        let mut memory_bus = create_default_memory_bus();
+        let mut cpu = create_interrupt_test_cpu(&mut memory_bus);
+        cpu.ime = false;
+        // HALT, LD A,0x14, which becomes LD A,0x3e followed by INC D
+        memory_bus.write_mem(0xc000, &[0x76, 0x3e, 0x14]);
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x04);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x04);
+
+        cpu.single_step(&mut memory_bus).unwrap();
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.af.get_high(), 0x3e);
+        assert_eq!(cpu.pc, 0xc002);
+        let d = cpu.de.get_high();
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.de.get_high(), d.wrapping_add(1));
+        assert_eq!(cpu.pc, 0xc003);
+    }
+
+    #[test]
+    fn test_set_register() {
+        let mut cpu = Cpu::default();
+        cpu.set_register(CpuRegister::Hl, 0xc123);
+        cpu.set_register(CpuRegister::L, 0x1245);
+        assert_eq!(cpu.hl.get_u16(), 0xc145);
+        assert_eq!(cpu.get_registers().get(CpuRegister::Hl), 0xc145);
+        assert_eq!(cpu.get_registers().get(CpuRegister::H), 0xc1);
+        cpu.set_register(CpuRegister::Af, 0x12ff);
+        assert_eq!(cpu.af.get_u16(), 0x12f0);
+        cpu.set_register(CpuRegister::F, 0x0f);
+        assert_eq!(cpu.get_registers().f, 0x00);
+        cpu.set_register(CpuRegister::Pc, 0x4000);
+        assert_eq!(cpu.get_program_counter(), 0x4000);
+        assert_eq!("SP".parse::<CpuRegister>(), Ok(CpuRegister::Sp));
+        assert!("ix".parse::<CpuRegister>().is_err());
+    }
+
+    #[test]
+    fn test_history_records_instructions_and_interrupts() {
+        let mut memory_bus = create_default_memory_bus();
+        let mut cpu = create_interrupt_test_cpu(&mut memory_bus);
+        memory_bus.write_mem(0xc000, &[0x3c, 0x00]); // INC A, NOP
+        cpu.ime = false;
+        cpu.single_step(&mut memory_bus).unwrap();
+        cpu.ime = true;
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x01);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x01);
+        cpu.single_step(&mut memory_bus).unwrap();
+
+        let history = cpu.get_history();
+        assert_eq!(history.len(), 2);
+        match &history[0] {
+            HistoryEntry::Instruction {
+                instruction,
+                registers,
+            } => {
+                assert_eq!(instruction.address, 0xc000);
+                assert_eq!(registers.pc, 0xc000);
+            }
+            HistoryEntry::Interrupt(_) => panic!("expected an instruction"),
+        }
+        assert!(matches!(
+            history[1],
+            HistoryEntry::Interrupt(InterruptDispatch {
+                interrupt: Some(0),
+                ..
+            })
+        ));
+    }
+
+    #[test]
+    fn test_call_stack_tracking() {
+        let mut memory_bus = create_default_memory_bus();
+        let mut cpu = create_interrupt_test_cpu(&mut memory_bus);
+        cpu.ime = false;
+        memory_bus.write_mem(0xc000, &[0xcd, 0x10, 0xc0]); // CALL 0xc010
+        memory_bus.write_mem(0xc010, &[0xcd, 0x20, 0xc0]); // CALL 0xc020
+        memory_bus.write_mem(0xc020, &[0x00, 0xc9]); // NOP, RET
+
+        cpu.single_step(&mut memory_bus).unwrap();
+        cpu.single_step(&mut memory_bus).unwrap();
+        let call_stack = cpu.get_call_stack();
+        assert_eq!(call_stack.len(), 2);
+        assert_eq!(call_stack[0].target, 0xc010);
+        assert_eq!(call_stack[0].return_address, 0xc003);
+        assert_eq!(call_stack[1].target, 0xc020);
+        assert_eq!(call_stack[1].return_address, 0xc013);
+        assert_eq!(call_stack[1].stack_pointer, 0xcffc);
+
+        // An interrupt in the middle of the innermost call
+        cpu.ime = true;
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x04);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x04);
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.pc, 0x0050);
+        let frame = cpu.get_call_stack()[2];
+        assert_eq!(frame.kind, CallKind::Interrupt(Some(2)));
+        assert_eq!(frame.return_address, 0xc020);
+
+        // Dropping the interrupt's frame by moving SP leaves it out
+        cpu.sp = 0xcffc;
+        assert_eq!(cpu.get_call_stack().len(), 2);
+        cpu.pc = 0xc020;
+        cpu.single_step(&mut memory_bus).unwrap();
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.pc, 0xc013);
+        assert_eq!(cpu.get_call_stack().len(), 1);
+        assert_eq!(cpu.get_call_stack()[0].target, 0xc010);
+    }
+
+    #[test]
+    fn test_halt_without_pending_interrupt() {
+        let mut memory_bus = create_default_memory_bus();
+        let mut cpu = create_interrupt_test_cpu(&mut memory_bus);
+        cpu.ime = false;
+        memory_bus.write_mem(0xc000, &[0x76, 0x3c]);
+        // Requested but not enabled doesn't count as pending
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x01);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x00);
+
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.state, State::Halted);
+        assert_eq!(cpu.pc, 0xc001);
+        assert!(!cpu.halt_bug);
+    }
+
+    #[test]
+    fn test_halt_wakes_without_ime() {
+        let mut memory_bus = create_default_memory_bus();
+        let mut cpu = create_interrupt_test_cpu(&mut memory_bus);
+        cpu.ime = false;
+        cpu.af.set_high(0);
+        memory_bus.write_mem(0xc000, &[0x76, 0x3c]); // HALT, INC A
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x01);
+
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.state, State::Halted);
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.state, State::Halted);
+        assert_eq!(cpu.pc, 0xc001);
+
+        // Vblank arrives, which wakes the CPU but isn't serviced
+        cpu.request_interrupts(
+            &mut memory_bus,
+            &InterruptRequest {
+                vblank: true.into(),
+                ..InterruptRequest::default()
+            },
+        );
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.state, State::Running);
+        assert_eq!(cpu.af.get_high(), 1);
+        assert_eq!(cpu.pc, 0xc002);
+        assert_eq!(memory_bus.read_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS), 0x01);
+    }
+
+    #[test]
+    fn test_halt_wakes_when_ie_changes() {
+        let mut memory_bus = create_default_memory_bus();
+        let mut cpu = create_interrupt_test_cpu(&mut memory_bus);
+        memory_bus.write_mem(0xc000, &[0x76, 0x00]); // HALT, NOP
+        // Timer was requested while disabled, so it doesn't stop the HALT
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x04);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x00);
+
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.state, State::Halted);
+
+        // With IME on, enabling it wakes the CPU straight into the handler,
+        // a cycle later than from a running CPU
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x04);
+        assert_eq!(cpu.single_step(&mut memory_bus), Ok(24));
+        assert_eq!(cpu.state, State::Running);
+        assert_eq!(cpu.pc, 0x50);
+        assert_eq!(memory_bus.read_u8(0xcffe), 0x01);
+        assert_eq!(
+            cpu.get_last_interrupt_dispatch(),
+            Some(InterruptDispatch {
+                interrupt: Some(2),
+                from_halt: true,
+                cycles: 24,
+            })
+        );
+    }
+
+    #[test]
+    fn test_interrupt_dispatch_from_running_cpu() {
+        let mut memory_bus = create_default_memory_bus();
+        let mut cpu = create_interrupt_test_cpu(&mut memory_bus);
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x01);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x01);
+
+        assert_eq!(cpu.single_step(&mut memory_bus), Ok(20));
+        assert_eq!(cpu.pc, 0x40);
+        assert_eq!(
+            cpu.get_last_interrupt_dispatch(),
+            Some(InterruptDispatch {
+                interrupt: Some(0),
+                from_halt: false,
+                cycles: 20,
+            })
+        );
+    }
+
+    #[test]
+    fn test_stop_waits_for_joypad() {
+        let mut memory_bus = create_default_memory_bus();
+        let mut cpu = create_interrupt_test_cpu(&mut memory_bus);
+        memory_bus.write_mem(0xc000, &[0x10, 0x00, 0x00]); // STOP, NOP
+        memory_bus.joypad.write_u8(0x10); // Select the action buttons
+
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert!(cpu.is_stopped());
+        assert_eq!(cpu.pc, 0xc002);
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert!(cpu.is_stopped());
+
+        // A direction isn't on the selected line
+        memory_bus.joypad.set_input_state(&crate::gbc::InputState {
+            up_pressed: true,
+            ..crate::gbc::InputState::default()
+        });
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert!(cpu.is_stopped());
+
+        memory_bus.joypad.set_input_state(&crate::gbc::InputState {
+            a_pressed: true,
+            ..crate::gbc::InputState::default()
+        });
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert!(!cpu.is_stopped());
+        assert_eq!(cpu.pc, 0xc003);
+    }
+
+    #[test]
+    fn test_joypad_matrix_and_interrupt() {
+        let mut memory_bus = create_default_memory_bus();
+        let a_and_up = crate::gbc::InputState {
+            a_pressed: true,
+            up_pressed: true,
+            ..crate::gbc::InputState::default()
+        };
+        let take_joypad_interrupt = |memory_bus: &mut MemoryBus| {
+            memory_bus.interrupt_flags = 0;
+            memory_bus.tick(4, false);
+            memory_bus.interrupt_flags & 0x10 != 0
+        };
+
+        // Pressing buttons on lines nothing selects is invisible
+        memory_bus.write_u8(0xff00, 0x30);
+        memory_bus.joypad.set_input_state(&a_and_up);
+        assert_eq!(memory_bus.read_u8(0xff00), 0xff);
+        assert!(!take_joypad_interrupt(&mut memory_bus));
+
+        // Selecting a line with a button held pulls it low
+        memory_bus.write_u8(0xff00, 0x20);
+        assert_eq!(memory_bus.read_u8(0xff00), 0xeb);
+        assert!(take_joypad_interrupt(&mut memory_bus));
+        memory_bus.write_u8(0xff00, 0x10);
+        assert_eq!(memory_bus.read_u8(0xff00), 0xde);
+        assert!(take_joypad_interrupt(&mut memory_bus));
+        // With both selected, either button pulls its line low
+        memory_bus.write_u8(0xff00, 0x00);
+        assert_eq!(memory_bus.read_u8(0xff00), 0xca);
+        assert!(take_joypad_interrupt(&mut memory_bus));
+
+        // Releasing is a rising edge, and pressing again a falling one
+        memory_bus
+            .joypad
+            .set_input_state(&crate::gbc::InputState::default());
+        assert_eq!(memory_bus.read_u8(0xff00), 0xcf);
+        assert!(!take_joypad_interrupt(&mut memory_bus));
+        memory_bus.joypad.set_input_state(&a_and_up);
+        assert!(take_joypad_interrupt(&mut memory_bus));
+        // Holding them doesn't request it again
+        memory_bus.joypad.set_input_state(&a_and_up);
+        assert!(!take_joypad_interrupt(&mut memory_bus));
+    }
+
+    #[test]
+    fn test_stop_switches_speed() {
+        let mut memory_bus = create_default_memory_bus();
+        let mut cpu = create_interrupt_test_cpu(&mut memory_bus);
+        memory_bus.write_mem(0xc000, &[0x10, 0x00, 0x10, 0x00]); // STOP, STOP
+        memory_bus.cgb_mode = true;
+        memory_bus.write_u8(0xff4d, 0x01);
+        assert_eq!(memory_bus.read_u8(0xff4d), 0x7f);
+
+        assert_eq!(cpu.single_step(&mut memory_bus), Ok(SPEED_SWITCH_CYCLES));
+        assert!(!cpu.is_stopped());
+        assert!(memory_bus.double_speed);
+        assert_eq!(memory_bus.read_u8(0xff4d), 0xfe);
+
+        // Without arming KEY1 again, STOP just stops
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert!(cpu.is_stopped());
+        assert!(memory_bus.double_speed);
+    }
+
+    #[test]
+    fn test_oam_dma_blocks_bus() {
+        let mut memory_bus = create_default_memory_bus();
+        // With the LCD off, OAM can be read back without the PPU blocking it
+        memory_bus.write_u8(0xff40, 0x00);
+        for i in 0..0xa0_u16 {
+            #[allow(clippy::cast_possible_truncation)]
+            memory_bus.write_u8(0xc000 + i, i as u8 + 1);
+        }
+        memory_bus.write_u8(0xff80, 0x42);
+        memory_bus.write_u8(0xff46, 0xc0);
+
+        // Halfway through, everything below the registers reads as the last
+        // byte copied
+        memory_bus.tick(4 * 80, false);
+        assert_eq!(memory_bus.read_u8(0xc000), 80);
+        assert_eq!(memory_bus.read_u8(0x0100), 80);
+        assert_eq!(memory_bus.read_u8(0xff80), 0x42);
+        memory_bus.write_u8(0xc000, 0xff);
+        memory_bus.write_u8(0xff81, 0x24);
+        assert_eq!(memory_bus.read_u8(0xff81), 0x24);
+
+        // The whole transfer takes 160 machine cycles
+        memory_bus.tick(4 * 80 - 1, false);
+        assert_eq!(memory_bus.read_u8(0xc000), 159);
+        memory_bus.tick(1, false);
+        assert_eq!(memory_bus.read_u8(0xc000), 1);
+        assert_eq!(memory_bus.read_u8(0xfe00), 1);
+        assert_eq!(memory_bus.read_u8(0xfe9c), 157);
+    }
+
+    #[test]
+    fn test_interrupt_during_oam_dma() {
+        let mut memory_bus = create_default_memory_bus();
+        memory_bus.write_u8(0xff40, 0x00);
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x00);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x01);
+        // Waiting in HRAM, like a DMA routine does
+        memory_bus.write_u8(0xff80, 0x00);
+        memory_bus.write_u8(0xff81, 0x00);
+        let mut cpu = Cpu {
+            pc: 0xff80,
+            sp: 0xfffe,
+            ime: true,
+            ..Cpu::default()
+        };
+        memory_bus.write_u8(0xff46, 0xc0);
+        memory_bus.tick(4, false);
+        cpu.single_step(&mut memory_bus).unwrap();
+
+        // The request is latched in IF and dispatched with DMA still running
+        cpu.interrupt(&mut memory_bus, 0);
+        assert_eq!(memory_bus.read_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS) & 0x01, 0x01);
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert!(memory_bus.lcd.get_dma_running());
+        assert_eq!(cpu.pc, 0x0040);
+        assert_eq!(cpu.sp, 0xfffc);
+        assert_eq!(memory_bus.read_u16(0xfffc), 0xff81);
+        assert_eq!(memory_bus.read_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS) & 0x01, 0x00);
+    }
+
+    /// Runs a whole OAM DMA from `source` and reads back what landed in OAM
+    fn run_oam_dma(memory_bus: &mut MemoryBus, source: u8) -> Vec<u8> {
+        memory_bus.write_u8(0xff46, source);
+        memory_bus.tick(4 * 160, false);
+        memory_bus.read_mem(0xfe00, 0xa0)
+    }
+
+    #[test]
+    fn test_oam_dma_sources() {
+        use crate::gbc::cartridge::Cartridge;
+        let pattern = |seed: u8| -> Vec<u8> {
+            #[allow(clippy::cast_possible_truncation)]
+            (0..0xa0_u16)
+                .map(|i| seed.wrapping_add((i as u8).wrapping_mul(7)))
+                .collect()
+        };
+        // MBC1 with 8KiB of RAM, the switchable bank holds the first pattern
+        let mut rom = vec![0; 0x8000];
+        rom[0x147] = 0x02;
+        rom[0x149] = 0x02;
+        rom[0x4000..0x40a0].copy_from_slice(&pattern(1));
+        let mut memory_bus = MemoryBus::new(Cartridge::from_bytes(&rom).unwrap());
+        memory_bus.write_u8(0xff40, 0x00);
+        memory_bus.write_u8(0x0000, 0x0a); // enable external RAM
+        memory_bus.write_mem(0xa000, &pattern(2));
+        memory_bus.write_mem(0xc100, &pattern(3));
+        memory_bus.write_mem(0xde00, &pattern(4));
+        memory_bus.write_mem(0x8000, &pattern(5));
+
+        assert_eq!(run_oam_dma(&mut memory_bus, 0x40), pattern(1));
+        assert_eq!(run_oam_dma(&mut memory_bus, 0xa0), pattern(2));
+        assert_eq!(run_oam_dma(&mut memory_bus, 0xc1), pattern(3));
+        assert_eq!(run_oam_dma(&mut memory_bus, 0x80), pattern(5));
+        // Echo RAM, and past it where OAM and the registers would be
+        assert_eq!(run_oam_dma(&mut memory_bus, 0xe1), pattern(3));
+        assert_eq!(run_oam_dma(&mut memory_bus, 0xfe), pattern(4));
+        assert_eq!(run_oam_dma(&mut memory_bus, 0xff), vec![0; 0xa0]);
+    }
+
+    #[test]
+    fn test_trace_skips_boot_rom() {
+        let mut memory_bus = create_default_memory_bus();
+        let mut cpu = Cpu {
+            pc: 0x0000,
+            ..Cpu::default()
+        };
+        let path = std::env::temp_dir().join(format!("test_trace_{}.log", std::process::id()));
+        cpu.start_trace(&path).unwrap();
+        cpu.single_step(&mut memory_bus).unwrap();
+        memory_bus.write_u8(0xff50, 1);
+        cpu.pc = 0x0100;
+        cpu.single_step(&mut memory_bus).unwrap();
+        cpu.stop_trace();
+        let trace = std::fs::read_to_string(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        let lines: Vec<&str> = trace.lines().collect();
+        assert_eq!(lines.len(), 1);
+        assert!(lines[0].contains("PC:0100"));
+    }
+
+    #[test]
+    fn test_peek_and_poke_get_past_blocking() {
+        let mut memory_bus = create_default_memory_bus();
+        memory_bus.write_u8(0xff40, 0x00);
+        memory_bus.write_u8(0xc000, 0x12);
+        memory_bus.write_u8(0xff46, 0xc0);
+        memory_bus.tick(4, false);
+        assert_eq!(memory_bus.peek_u8(0xc000), 0x12);
+        memory_bus.poke_u8(0xc001, 0x34);
+        assert_eq!(memory_bus.peek_u8(0xc001), 0x34);
+        memory_bus.tick(4 * 160, false);
+
+        // VRAM and OAM while the PPU is drawing
+        memory_bus.write_u8(0xff40, 0x91);
+        while memory_bus.lcd.get_mode() != 3 {
+            memory_bus.tick(1, false);
+        }
+        assert_eq!(memory_bus.read_u8(0x8000), 0xff);
+        memory_bus.poke_u8(0x8000, 0x56);
+        memory_bus.poke_u8(0xfe00, 0x78);
+        assert_eq!(memory_bus.peek_u8(0x8000), 0x56);
+        assert_eq!(memory_bus.peek_u8(0xfe00), 0x78);
+    }
+
+    #[test]
+    fn test_timer_falling_edge() {
+        let mut memory_bus = create_default_memory_bus();
+        // Counting every 16 cycles, off bit 3 of the system counter
+        memory_bus.write_u8(0xff07, 0x05);
+        memory_bus.tick(15, false);
+        assert_eq!(memory_bus.read_u8(0xff05), 0);
+        memory_bus.tick(1, false);
+        assert_eq!(memory_bus.read_u8(0xff05), 1);
+        memory_bus.tick(256 - 16, false);
+        assert_eq!(memory_bus.read_u8(0xff04), 1);
+        assert_eq!(memory_bus.read_u8(0xff05), 16);
+
+        // Resetting DIV while bit 3 is set is a falling edge
+        memory_bus.tick(8, false);
+        memory_bus.write_u8(0xff04, 0x12);
+        assert_eq!(memory_bus.read_u8(0xff04), 0);
+        assert_eq!(memory_bus.read_u8(0xff05), 17);
+        // but not while it's clear
+        memory_bus.tick(4, false);
+        memory_bus.write_u8(0xff04, 0x12);
+        assert_eq!(memory_bus.read_u8(0xff05), 17);
+
+        // Switching to a bit that's clear, or turning the timer off, while
+        // the selected bit is set is one too
+        memory_bus.tick(8, false);
+        memory_bus.write_u8(0xff07, 0x04);
+        assert_eq!(memory_bus.read_u8(0xff05), 18);
+        memory_bus.write_u8(0xff07, 0x05);
+        memory_bus.write_u8(0xff07, 0x01);
+        assert_eq!(memory_bus.read_u8(0xff05), 19);
+
+        // An overflow caused by a write still requests the interrupt
+        memory_bus.write_u8(0xff05, 0xff);
+        memory_bus.write_u8(0xff06, 0x80);
+        memory_bus.write_u8(0xff07, 0x05);
+        memory_bus.write_u8(0xff04, 0x00);
+        memory_bus.interrupt_flags = 0;
+        memory_bus.tick(4, false);
+        assert_eq!(memory_bus.read_u8(0xff05), 0x80);
+        assert_eq!(memory_bus.interrupt_flags & 0x04, 0x04);
+    }
+
+    /// Overflows TIMA on a fresh bus, returning with TMA loaded with 0x80
+    /// and the reload still 4 cycles away
+    fn overflow_timer() -> MemoryBus {
+        let mut memory_bus = create_default_memory_bus();
+        memory_bus.write_u8(0xff06, 0x80);
+        memory_bus.write_u8(0xff05, 0xff);
+        memory_bus.write_u8(0xff07, 0x05);
+        memory_bus.tick(16, false);
+        memory_bus.interrupt_flags = 0;
+        memory_bus
+    }
+
+    #[test]
+    fn test_timer_overflow_reload_delay() {
+        // TIMA reads 0 for a machine cycle before being reloaded
+        let mut memory_bus = overflow_timer();
+        memory_bus.tick(3, false);
+        assert_eq!(memory_bus.read_u8(0xff05), 0);
+        assert_eq!(memory_bus.interrupt_flags & 0x04, 0);
+        memory_bus.tick(1, false);
+        assert_eq!(memory_bus.read_u8(0xff05), 0x80);
+        assert_eq!(memory_bus.interrupt_flags & 0x04, 0x04);
+
+        // Writing TIMA in that cycle cancels the reload and the interrupt
+        let mut memory_bus = overflow_timer();
+        memory_bus.tick(2, false);
+        memory_bus.write_u8(0xff05, 0x33);
+        memory_bus.tick(8, false);
+        assert_eq!(memory_bus.read_u8(0xff05), 0x33);
+        assert_eq!(memory_bus.interrupt_flags & 0x04, 0);
+
+        // In the cycle after the reload TIMA writes are ignored, and TMA
+        // writes go through to TIMA
+        let mut memory_bus = overflow_timer();
+        memory_bus.tick(4, false);
+        memory_bus.write_u8(0xff05, 0x44);
+        assert_eq!(memory_bus.read_u8(0xff05), 0x80);
+        memory_bus.write_u8(0xff06, 0x55);
+        assert_eq!(memory_bus.read_u8(0xff05), 0x55);
+        // After that, things are back to normal
+        memory_bus.tick(4, false);
+        memory_bus.write_u8(0xff06, 0x66);
+        assert_eq!(memory_bus.read_u8(0xff05), 0x55);
+        memory_bus.write_u8(0xff05, 0x44);
+        assert_eq!(memory_bus.read_u8(0xff05), 0x44);
+    }
+
+    #[test]
+    fn test_memory_accesses_fit_in_instruction_cycles() {
+        for opcode in 0..=0xff_u8 {
+            let second_bytes = if opcode == 0xcb { 0..=0xff } else { 0..=0 };
+            for second_byte in second_bytes {
+                let mut memory_bus = create_default_memory_bus();
+                let mut cpu = create_interrupt_test_cpu(&mut memory_bus);
+                // Any address operand points into work RAM
+                cpu.hl.set_u16(0xc800);
+                memory_bus.write_mem(0xc000, &[opcode, second_byte, 0xc8]);
+                memory_bus.take_cycles_ticked();
+                if let Ok(cycles) = cpu.single_step(&mut memory_bus) {
+                    let ticked = memory_bus.take_cycles_ticked();
+                    assert!(
+                        ticked <= cycles,
+                        "{:02x} {:02x} ticked {} cycles but takes {}",
+                        opcode,
+                        second_byte,
+                        ticked,
+                        cycles
+                    );
+                }
+            }
+        }
+    }
+
+    #[test]
+    fn test_assembler_round_trips_disassembly() {
+        for opcode in 0..=0xff_u8 {
+            let second_bytes = if opcode == 0xcb { 0..=0xff } else { 0..=0 };
+            for second_byte in second_bytes {
+                let mut memory_bus = create_default_memory_bus();
+                let bytes = [opcode, second_byte, 0xc8];
+                memory_bus.write_mem(0xc000, &bytes);
+                let insn = Instruction::new(0xc000, &mut memory_bus);
+                if let Opcode::Unknown { .. } = insn.op {
+                    continue;
+                }
+                // Strip the address and size around the mnemonic
+                let text = insn.to_string();
+                let text = &text[text.find(" - ").unwrap() + 3..text.rfind(" (size").unwrap()];
+                let size = usize::from(insn.size());
+                assert_eq!(
+                    assembler::assemble(0xc000, text),
+                    Ok(bytes[..size].to_vec()),
+                    "{}",
+                    text
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_memory_breakpoints() {
+        use crate::gbc::debug::{AccessType, BreakReason, Breakpoint};
+        let mut memory_bus = create_default_memory_bus();
+        let bp = |address, access_type| Breakpoint::new(address, access_type, 2, BreakReason::User);
+        memory_bus.set_breakpoints(vec![
+            (3, bp(0xc000, AccessType::Write)),
+            (5, bp(0xc100, AccessType::Read)),
+        ]);
+
+        // Reads don't trip write breakpoints and writes don't trip read ones
+        let _ = memory_bus.read_u8(0xc001);
+        memory_bus.write_u8(0xc100, 0);
+        memory_bus.write_u8(0xc002, 0);
+        assert_eq!(memory_bus.take_breakpoint_hit(), None);
+
+        memory_bus.write_u8(0xc001, 0);
+        let _ = memory_bus.read_u8(0xc100);
+        assert_eq!(memory_bus.take_breakpoint_hit(), Some(3));
+        assert_eq!(memory_bus.take_breakpoint_hit(), None);
+        let _ = memory_bus.read_u8(0xc100);
+        assert_eq!(memory_bus.take_breakpoint_hit(), Some(5));
+    }
+
+    #[test]
+    fn test_value_watchpoints() {
+        use crate::gbc::debug::{AccessType, BreakReason, Breakpoint, WatchCondition};
+        let mut memory_bus = create_default_memory_bus();
+        let bp = Breakpoint::new(0xc123, AccessType::Write, 1, BreakReason::User);
+        memory_bus.set_breakpoints(vec![
+            (0, bp.with_condition(WatchCondition::Equals(0x07))),
+            (1, bp.with_condition(WatchCondition::Changes)),
+        ]);
+
+        memory_bus.write_u8(0xc123, 0x00);
+        assert_eq!(memory_bus.take_breakpoint_hit(), None);
+        memory_bus.write_u8(0xc123, 0x07);
+        assert_eq!(memory_bus.take_breakpoint_hit(), Some(0));
+        memory_bus.write_u8(0xc123, 0x08);
+        assert_eq!(memory_bus.take_breakpoint_hit(), Some(1));
+        memory_bus.write_u8(0xc123, 0x08);
+        assert_eq!(memory_bus.take_breakpoint_hit(), None);
+    }
+
+    // Loads `program` into WRAM with a vblank interrupt pending and enabled
+    fn interrupt_pending_cpu(program: &[u8]) -> (Cpu, MemoryBus) {
+        let mut memory_bus = create_default_memory_bus();
+        memory_bus.write_u8(INTERRUPT_FLAGS_REGISTER_ADDRESS, 0x01);
+        memory_bus.write_u8(INTERRUPT_ENABLE_REGISTER_ADDRESS, 0x01);
+        for (address, &byte) in (0xc000..).zip(program) {
+            memory_bus.write_u8(address, byte);
+        }
+        let cpu = Cpu {
+            pc: 0xc000,
+            sp: 0xd000,
+            ..Cpu::default()
+        };
+        (cpu, memory_bus)
+    }
+
+    #[test]
+    fn test_ei_takes_effect_after_next_instruction() {
+        // ei, nop, nop
+        let (mut cpu, mut memory_bus) = interrupt_pending_cpu(&[0xfb, 0x00, 0x00]);
+
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert!(!cpu.ime);
+        // The instruction after EI still runs before the interrupt
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.pc, 0xc002);
+        assert!(cpu.ime);
+
+        cpu.single_step(&mut memory_bus).unwrap();
+        assert_eq!(cpu.pc, 0x0040);
+        assert!(!cpu.ime);
+    }
+
+    #[test]
+    fn test_di_after_ei_cancels_it() {
+        // ei, di, nop
+        let (mut cpu, mut memory_bus) = interrupt_pending_cpu(&[0xfb, 0xf3, 0x00]);
+
+        for _ in 0..3 {
+            cpu.single_step(&mut memory_bus).unwrap();
+        }
+        assert_eq!(cpu.pc, 0xc003);
+        assert!(!cpu.ime);
+    }
 }
@@ -0,0 +1,214 @@
+use std::collections::VecDeque;
+use std::fmt;
+use std::fs::File;
+use std::io::{self, BufRead, BufReader};
+use std::path::Path;
+
+// Matching lines kept to show before a divergence
+const CONTEXT_LINES: usize = 8;
+
+// The field names in a gameboy-doctor line, for saying which ones differ
+const FIELD_NAMES: [&str; 11] = ["A", "F", "B", "C", "D", "E", "H", "L", "SP", "PC", "PCMEM"];
+
+/// Why a trace comparison stopped
+#[derive(Debug)]
+pub enum TraceComparisonStop {
+    /// The CPU's line didn't match line `line_number` of the reference
+    Diverged {
+        line_number: usize,
+        // The matching lines before it, with their line numbers
+        context: Vec<(usize, String)>,
+        expected: String,
+        actual: String,
+    },
+    /// Every line of the reference matched
+    ReferenceEnded {
+        lines: usize,
+    },
+    ReadError {
+        line_number: usize,
+        message: String,
+    },
+}
+
+impl TraceComparisonStop {
+    /// The fields that differ between two gameboy-doctor lines. A field
+    /// missing from both lines doesn't count.
+    fn differing_fields(expected: &str, actual: &str) -> Vec<&'static str> {
+        let field_value = |line: &str, name: &str| -> Option<String> {
+            line.split_whitespace()
+                .find_map(|field| field.strip_prefix(name)?.strip_prefix(':'))
+                .map(str::to_uppercase)
+        };
+        FIELD_NAMES
+            .iter()
+            .filter(|&&name| field_value(expected, name) != field_value(actual, name))
+            .copied()
+            .collect()
+    }
+}
+
+impl fmt::Display for TraceComparisonStop {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Diverged {
+                line_number,
+                context,
+                expected,
+                actual,
+            } => {
+                writeln!(
+                    f,
+                    "Trace diverged from the reference at line {}",
+                    line_number
+                )?;
+                for (number, line) in context {
+                    writeln!(f, "  {:>8} {}", number, line)?;
+                }
+                writeln!(f, "- {:>8} {}", line_number, expected)?;
+                writeln!(f, "+ {:>8} {}", line_number, actual)?;
+                let fields = Self::differing_fields(expected, actual);
+                write!(f, "Differing: {}", fields.join(" "))
+            }
+            Self::ReferenceEnded { lines } => {
+                write!(f, "Reference trace ended, all {} lines matched", lines)
+            }
+            Self::ReadError {
+                line_number,
+                message,
+            } => write!(
+                f,
+                "Error reading line {} of the reference trace: {}",
+                line_number, message
+            ),
+        }
+    }
+}
+
+/// Checks the CPU's trace lines against a reference log, one line per
+/// executed instruction, see `Cpu::start_trace` for the format
+#[derive(Debug)]
+pub struct TraceComparison {
+    reference: BufReader<File>,
+    line_number: usize,
+    context: VecDeque<(usize, String)>,
+}
+
+impl TraceComparison {
+    /// Opens the reference trace at `path`
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be opened
+    pub fn open<P: AsRef<Path>>(path: P) -> io::Result<Self> {
+        Ok(Self {
+            reference: BufReader::new(File::open(path)?),
+            line_number: 0,
+            context: VecDeque::with_capacity(CONTEXT_LINES),
+        })
+    }
+
+    /// Compares the next reference line with `actual`. Case and surrounding
+    /// whitespace are ignored. Returns why comparing has to stop, if it does.
+    pub fn check(&mut self, actual: &str) -> Option<TraceComparisonStop> {
+        let mut expected = String::new();
+        self.line_number += 1;
+        match self.reference.read_line(&mut expected) {
+            Ok(0) => {
+                return Some(TraceComparisonStop::ReferenceEnded {
+                    lines: self.line_number - 1,
+                })
+            }
+            Ok(_) => {}
+            Err(e) => {
+                return Some(TraceComparisonStop::ReadError {
+                    line_number: self.line_number,
+                    message: e.to_string(),
+                })
+            }
+        }
+        let expected = expected.trim();
+        if !expected.eq_ignore_ascii_case(actual.trim()) {
+            return Some(TraceComparisonStop::Diverged {
+                line_number: self.line_number,
+                context: self.context.drain(..).collect(),
+                expected: expected.to_string(),
+                actual: actual.to_string(),
+            });
+        }
+        if self.context.len() == CONTEXT_LINES {
+            self.context.pop_front();
+        }
+        self.context
+            .push_back((self.line_number, actual.to_string()));
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    const LINES: [&str; 3] = [
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0100 PCMEM:00,C3,13,02",
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0101 PCMEM:C3,13,02,CE",
+        "A:01 F:B0 B:00 C:13 D:00 E:D8 H:01 L:4D SP:FFFE PC:0213 PCMEM:21,00,C0,0E",
+    ];
+
+    // Opens a comparison against `reference`, written to a file named after
+    // the test
+    fn comparison(name: &str, reference: &str) -> TraceComparison {
+        let path = std::env::temp_dir().join(format!("{}_{}.log", name, std::process::id()));
+        fs::write(&path, reference).unwrap();
+        let comparison = TraceComparison::open(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        comparison
+    }
+
+    #[test]
+    fn test_trace_matches() {
+        // Case and surrounding whitespace don't matter
+        let mut comparison = comparison("test_trace_matches", &LINES.join("\r\n").to_lowercase());
+        for line in &LINES {
+            assert!(comparison.check(line).is_none());
+        }
+    }
+
+    #[test]
+    fn test_trace_diverges() {
+        let mut comparison = comparison("test_trace_diverges", &LINES.join("\n"));
+        assert!(comparison.check(LINES[0]).is_none());
+        let actual = LINES[1]
+            .replace("A:01 F:B0", "A:02 F:B0")
+            .replace("PC:0101", "PC:0102");
+        match comparison.check(&actual) {
+            Some(TraceComparisonStop::Diverged {
+                line_number,
+                context,
+                expected,
+                actual: found,
+            }) => {
+                assert_eq!(line_number, 2);
+                assert_eq!(context, [(1, LINES[0].to_string())]);
+                assert_eq!(expected, LINES[1]);
+                assert_eq!(found, actual);
+                assert_eq!(
+                    TraceComparisonStop::differing_fields(&expected, &found),
+                    ["A", "PC"]
+                );
+            }
+            stop => panic!("expected a divergence, got {:?}", stop),
+        }
+    }
+
+    #[test]
+    fn test_trace_reference_ends() {
+        let mut comparison = comparison("test_trace_reference_ends", &LINES[..2].join("\n"));
+        assert!(comparison.check(LINES[0]).is_none());
+        assert!(comparison.check(LINES[1]).is_none());
+        assert!(matches!(
+            comparison.check(LINES[2]),
+            Some(TraceComparisonStop::ReferenceEnded { lines: 2 })
+        ));
+    }
+}
@@ -1,4 +1,13 @@
+use std::cmp::Reverse;
+use std::collections::HashMap;
 use std::fmt::{self, Display};
+use std::str::FromStr;
+use std::{fs, io, path::Path};
+
+use parse_int::parse;
+
+use super::cpu::Cpu;
+use super::mapper::MbcRegister;
 
 #[derive(Copy, Clone, Debug)]
 pub enum AccessType {
@@ -40,15 +49,26 @@ impl AccessType {
     }
 }
 
-#[derive(Copy, Clone, Debug)]
+/// What a breakpoint stops on. Interrupt and RST breakpoints are execute
+/// breakpoints on the handler's address that only stop when it was
+/// reached by dispatching that interrupt or running that RST.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum BreakReason {
     User,
+    /// The interrupt number, 0 for vblank up to 4 for joypad
+    Interrupt(u8),
+    /// The RST vector
+    Rst(u8),
 }
 
 impl Display for BreakReason {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
         match self {
             Self::User => write!(f, "USER"),
+            Self::Interrupt(number) => {
+                write!(f, "INTERRUPT {}", Cpu::interrupt_number_to_string(*number))
+            }
+            Self::Rst(vector) => write!(f, "RST {:#04x}", vector),
         }
     }
 }
@@ -70,12 +90,52 @@ impl AccessType {
     }
 }
 
+/// Narrows a read or write breakpoint down to certain values
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WatchCondition {
+    /// The value read or written is this one
+    Equals(u8),
+    /// A write leaves the byte different from what it was. Reads never
+    /// change anything, so they never match.
+    Changes,
+}
+
+impl WatchCondition {
+    /// Whether an access of `value` matches, `old` being what was there
+    /// before a write
+    #[must_use]
+    pub fn matches(self, value: u8, old: Option<u8>) -> bool {
+        match self {
+            Self::Equals(v) => value == v,
+            Self::Changes => matches!(old, Some(old) if old != value),
+        }
+    }
+}
+
+impl Display for WatchCondition {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Equals(v) => write!(f, "== {:#04x}", v),
+            Self::Changes => write!(f, "changes"),
+        }
+    }
+}
+
 #[derive(Copy, Clone, Debug)]
 pub struct Breakpoint {
     pub address: u16,
     pub access_type: AccessType,
     pub length: u16,
     pub reason: BreakReason,
+    pub enabled: bool,
+    /// Times it has been hit, including the ignored ones
+    pub hits: u64,
+    /// Hits left to let through before it stops the emulator again
+    pub ignore_count: u64,
+    /// Only checked for reads and writes
+    pub condition: Option<WatchCondition>,
+    /// Removed once it stops the emulator
+    pub temporary: bool,
 }
 
 impl Breakpoint {
@@ -86,9 +146,20 @@ impl Breakpoint {
             access_type,
             length,
             reason,
+            enabled: true,
+            hits: 0,
+            ignore_count: 0,
+            condition: None,
+            temporary: false,
         }
     }
 
+    #[must_use]
+    pub fn with_condition(mut self, condition: WatchCondition) -> Self {
+        self.condition = Some(condition);
+        self
+    }
+
     #[must_use]
     pub fn matches_address(&self, address: u16) -> bool {
         self.address <= address && address < (self.address + self.length)
@@ -97,6 +168,637 @@ impl Breakpoint {
 
 impl Display for Breakpoint {
     fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        write!(f, "{:#04x} {:#04x} bytes {} {}", self.address, self.length, self.access_type, self.reason)
+        write!(f, "{:#04x} {:#04x} bytes {} {}", self.address, self.length, self.access_type, self.reason)?;
+        if let Some(condition) = self.condition {
+            write!(f, " when {}", condition)?;
+        }
+        write!(f, ", hit {} times", self.hits)?;
+        if self.ignore_count > 0 {
+            write!(f, ", ignoring the next {}", self.ignore_count)?;
+        }
+        if self.temporary {
+            write!(f, " (temporary)")?;
+        }
+        if !self.enabled {
+            write!(f, " (disabled)")?;
+        }
+        Ok(())
+    }
+}
+
+/// A write to one of the cartridge's bank controller registers, along with
+/// the banks that ended up selected because of it
+#[derive(Copy, Clone, Debug)]
+pub struct MbcWrite {
+    pub pc: u16,
+    pub cycle: u64,
+    pub address: u16,
+    pub value: u8,
+    pub register: MbcRegister,
+    pub rom_bank: u16,
+    pub ram_bank: u8,
+}
+
+impl Display for MbcWrite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "[{:>12}] pc {:04x}: {:02x} -> {:04x} ({}), rom bank {:03x}, ram bank {:02x}",
+            self.cycle, self.pc, self.value, self.address, self.register, self.rom_bank, self.ram_bank
+        )
+    }
+}
+
+/// A memory location holding a game's random number generator state. Every
+/// read is counted, and the value the game sees can be pinned for practice.
+#[derive(Copy, Clone, Debug)]
+pub struct RngWatch {
+    pub address: u16,
+    pub reads: u64,
+    pub last_value: u8,
+    pub locked_value: Option<u8>,
+}
+
+impl RngWatch {
+    #[must_use]
+    pub fn new(address: u16) -> Self {
+        RngWatch {
+            address,
+            reads: 0,
+            last_value: 0,
+            locked_value: None,
+        }
+    }
+}
+
+impl Display for RngWatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:04x}: {:02x}, read {} times",
+            self.address, self.last_value, self.reads
+        )?;
+        if let Some(v) = self.locked_value {
+            write!(f, ", locked to {:02x}", v)?;
+        }
+        Ok(())
+    }
+}
+
+/// Captures a savestate every time the byte at `address` changes, e.g. a
+/// room or level id, to build up practice points as the game is played
+#[derive(Clone, Debug)]
+pub struct Savepoint {
+    pub address: u16,
+    pub label: String,
+    pub last_value: Option<u8>,
+    pub captured: u64,
+}
+
+impl Savepoint {
+    #[must_use]
+    pub fn new(address: u16, label: String) -> Self {
+        Savepoint {
+            address,
+            label,
+            last_value: None,
+            captured: 0,
+        }
+    }
+
+    /// Name of the savestate file for the given value of the watched byte.
+    /// Anything in the label that isn't a letter, digit, `-` or `_` becomes
+    /// `_`, so the file always lands in the savepoint directory.
+    #[must_use]
+    pub fn file_name(&self, value: u8) -> String {
+        let label: String = self
+            .label
+            .chars()
+            .map(|c| {
+                if c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+                    c
+                } else {
+                    '_'
+                }
+            })
+            .collect();
+        format!("{}_{:02x}.state", label, value)
+    }
+}
+
+impl Display for Savepoint {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:04x} {}: ", self.address, self.label)?;
+        match self.last_value {
+            Some(v) => write!(f, "{:02x}", v)?,
+            None => write!(f, "--")?,
+        }
+        write!(f, ", {} states captured", self.captured)
+    }
+}
+
+/// Where the CPU's time went during one frame, counted from the start of
+/// one vblank to the next, along with accesses the PPU would have blocked.
+/// A frame the game never waited in means it had more work than fits in a
+/// frame, though games that poll LY instead of halting always look busy.
+#[derive(Copy, Clone, Debug, Default)]
+pub struct FrameBudget {
+    pub frame: u64,
+    /// Cycles spent running instructions during vblank
+    pub vblank_cycles: u64,
+    /// Cycles spent running instructions while the screen was being drawn
+    pub active_cycles: u64,
+    /// Cycles spent in HALT or STOP
+    pub idle_cycles: u64,
+    pub vram_writes_while_drawing: u32,
+    pub oam_writes_while_drawing: u32,
+    pub dma_outside_vblank: u32,
+}
+
+impl FrameBudget {
+    #[must_use]
+    pub fn new(frame: u64) -> Self {
+        FrameBudget {
+            frame,
+            ..FrameBudget::default()
+        }
+    }
+
+    #[must_use]
+    pub fn is_over_budget(&self) -> bool {
+        self.idle_cycles == 0
+    }
+
+    #[must_use]
+    pub fn has_blocked_accesses(&self) -> bool {
+        self.vram_writes_while_drawing != 0
+            || self.oam_writes_while_drawing != 0
+            || self.dma_outside_vblank != 0
+    }
+}
+
+impl Display for FrameBudget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let total = self.vblank_cycles + self.active_cycles + self.idle_cycles;
+        #[allow(clippy::cast_precision_loss)]
+        let idle_percent = 100.0 * self.idle_cycles as f64 / total.max(1) as f64;
+        write!(
+            f,
+            "frame {:>6}: {:>6} cycles in vblank, {:>6} while drawing, {:>5.1}% idle",
+            self.frame, self.vblank_cycles, self.active_cycles, idle_percent
+        )?;
+        if self.has_blocked_accesses() {
+            write!(
+                f,
+                ", {} VRAM/{} OAM writes while drawing, {} DMAs outside vblank",
+                self.vram_writes_while_drawing,
+                self.oam_writes_while_drawing,
+                self.dma_outside_vblank
+            )?;
+        }
+        if self.is_over_budget() {
+            write!(f, " OVER BUDGET")?;
+        }
+        Ok(())
+    }
+}
+
+/// A named change to the ROM that can be switched on and off while the game
+/// runs. The original bytes are checked against the ROM when the patch is
+/// added, so a patch made for one revision of a game isn't applied to
+/// another.
+#[derive(Clone, Debug)]
+pub struct RomPatch {
+    pub name: String,
+    pub bank: u16,
+    pub address: u16,
+    pub original: Vec<u8>,
+    pub replacement: Vec<u8>,
+    pub enabled: bool,
+}
+
+fn parse_hex_bytes(text: &str) -> Result<Vec<u8>, String> {
+    if text.is_empty() || text.len() % 2 == 1 || !text.is_ascii() {
+        return Err(format!("Invalid bytes {}, expected pairs of hex digits", text));
+    }
+    (0..text.len())
+        .step_by(2)
+        .map(|i| {
+            u8::from_str_radix(&text[i..i + 2], 16).map_err(|_| format!("Invalid bytes {}", text))
+        })
+        .collect()
+}
+
+fn write_hex_bytes(f: &mut fmt::Formatter<'_>, bytes: &[u8]) -> fmt::Result {
+    for byte in bytes {
+        write!(f, "{:02x}", byte)?;
+    }
+    Ok(())
+}
+
+/// Parses `<name> [bank:]<address> <original> <replacement>`, the bytes
+/// being written as hex digits, e.g. `infinite_lives 0x1:0x4a3c 3d 00`.
+/// Without a bank, 0x0000-0x3fff is bank 0 and 0x4000-0x7fff bank 1.
+impl FromStr for RomPatch {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let parts: Vec<&str> = s.split_whitespace().collect();
+        let (name, location, original, replacement) = match parts[..] {
+            [name, location, original, replacement] => (name, location, original, replacement),
+            _ => {
+                return Err(format!(
+                    "Invalid patch {}, expected <name> [bank:]<address> <original> <replacement>",
+                    s
+                ))
+            }
+        };
+        let (bank, address) = match location.split_once(':') {
+            Some((bank, address)) => (Some(bank), address),
+            None => (None, location),
+        };
+        let address: u16 = parse(address).map_err(|e| format!("Invalid address: {}", e))?;
+        if address >= 0x8000 {
+            return Err(format!("{:#06x} is not a ROM address", address));
+        }
+        let bank = match bank {
+            Some(bank) => parse(bank).map_err(|e| format!("Invalid bank: {}", e))?,
+            None => u16::from(address >= 0x4000),
+        };
+        let original = parse_hex_bytes(original)?;
+        let replacement = parse_hex_bytes(replacement)?;
+        if original.len() != replacement.len() {
+            return Err(format!(
+                "Patch {} replaces {} bytes with {} bytes",
+                name,
+                original.len(),
+                replacement.len()
+            ));
+        }
+        Ok(RomPatch {
+            name: name.to_string(),
+            bank,
+            address,
+            original,
+            replacement,
+            enabled: true,
+        })
+    }
+}
+
+/// Writes the patch in the form `from_str` reads
+impl Display for RomPatch {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} {:#x}:{:#06x} ", self.name, self.bank, self.address)?;
+        write_hex_bytes(f, &self.original)?;
+        write!(f, " ")?;
+        write_hex_bytes(f, &self.replacement)
+    }
+}
+
+/// A label read from a symbol file
+#[derive(Clone, Debug)]
+pub struct Symbol {
+    pub name: String,
+    pub bank: u16,
+    pub address: u16,
+}
+
+/// The start of the memory region an address is in, so a label isn't used
+/// to name addresses past the end of its region
+fn region_start(address: u16) -> u16 {
+    match address {
+        0x0000..=0x3fff => 0x0000,
+        0x4000..=0x7fff => 0x4000,
+        0x8000..=0x9fff => 0x8000,
+        0xa000..=0xbfff => 0xa000,
+        0xc000..=0xcfff => 0xc000,
+        0xd000..=0xdfff => 0xd000,
+        0xe000..=0xfdff => 0xe000,
+        0xfe00..=0xfeff => 0xfe00,
+        0xff00..=0xff7f => 0xff00,
+        _ => 0xff80,
+    }
+}
+
+/// Switchable ROM and cartridge RAM, the only places banks are tracked
+fn is_banked(address: u16) -> bool {
+    (0x4000..0x8000).contains(&address) || (0xa000..0xc000).contains(&address)
+}
+
+/// Labels for addresses, read from the `.sym` files RGBDS and other
+/// assemblers write, one `bank:address name` per line in hex:
+///
+/// ```text
+/// ; File generated by rgblink
+/// 00:0150 Start
+/// 01:4a2a Main::vblank_handler
+/// ```
+///
+/// Banks are only used for switchable ROM and cartridge RAM, anywhere else
+/// a label is found by its address alone.
+#[derive(Clone, Debug, Default)]
+pub struct SymbolTable {
+    // Sorted by address, then bank
+    symbols: Vec<Symbol>,
+}
+
+impl SymbolTable {
+    /// Parses a `bank:address name` line
+    fn parse_symbol(line: &str) -> Result<Symbol, String> {
+        let (location, name) = match line.split_once(char::is_whitespace) {
+            Some((location, name)) => (location, name.trim()),
+            None => return Err("expected bank:address name".to_string()),
+        };
+        let (bank, address) = match location.split_once(':') {
+            Some((bank, address)) => (bank, address),
+            None => return Err(format!("Expected bank:address, got {}", location)),
+        };
+        let bank = u16::from_str_radix(bank, 16).map_err(|_| format!("Invalid bank {}", bank))?;
+        let address =
+            u16::from_str_radix(address, 16).map_err(|_| format!("Invalid address {}", address))?;
+        Ok(Symbol {
+            name: name.to_string(),
+            bank,
+            address,
+        })
+    }
+
+    /// Replaces the table with the symbols in a file, returning how many
+    /// there were. Lines that can't be parsed are reported and skipped.
+    pub fn load<P: AsRef<Path>>(&mut self, path: P) -> io::Result<usize> {
+        let path = path.as_ref();
+        let contents = fs::read_to_string(path)?;
+        Ok(self.load_contents(&contents, path))
+    }
+
+    fn load_contents(&mut self, contents: &str, path: &Path) -> usize {
+        self.symbols.clear();
+        for (line_number, line) in contents.lines().enumerate() {
+            // Everything after a ; is a comment
+            let line = line.find(';').map_or(line, |i| &line[..i]).trim();
+            if line.is_empty() {
+                continue;
+            }
+            match Self::parse_symbol(line) {
+                Ok(symbol) => self.symbols.push(symbol),
+                Err(e) => eprintln!("{}:{}: {}", path.display(), line_number + 1, e),
+            }
+        }
+        self.symbols
+            .sort_by_key(|symbol| (symbol.address, symbol.bank));
+        self.symbols.len()
+    }
+
+    pub fn clear(&mut self) {
+        self.symbols.clear();
+    }
+
+    #[must_use]
+    pub fn len(&self) -> usize {
+        self.symbols.len()
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.symbols.is_empty()
+    }
+
+    #[must_use]
+    pub fn find(&self, name: &str) -> Option<&Symbol> {
+        self.symbols.iter().find(|symbol| symbol.name == name)
+    }
+
+    /// The closest label at or before `address` in the same memory region,
+    /// with how far past it the address is. `bank` is the bank mapped at
+    /// the address.
+    #[must_use]
+    pub fn find_address(&self, bank: u16, address: u16) -> Option<(&Symbol, u16)> {
+        let start = region_start(address);
+        let end = self
+            .symbols
+            .partition_point(|symbol| symbol.address <= address);
+        self.symbols[..end]
+            .iter()
+            .rev()
+            .take_while(|symbol| symbol.address >= start)
+            .find(|symbol| !is_banked(address) || symbol.bank == bank)
+            .map(|symbol| (symbol, address - symbol.address))
+    }
+
+    /// Names an address like `Main::vblank_handler+0x12`, see
+    /// `find_address`
+    #[must_use]
+    pub fn describe(&self, bank: u16, address: u16) -> Option<String> {
+        self.find_address(bank, address)
+            .map(|(symbol, offset)| match offset {
+                0 => symbol.name.clone(),
+                _ => format!("{}+{:#x}", symbol.name, offset),
+            })
+    }
+}
+
+/// Time spent at one address, or in one function, while profiling
+#[derive(Copy, Clone, Debug, Default)]
+pub struct ProfileSample {
+    pub cycles: u64,
+    /// Instructions run, interrupt dispatches and steps spent halted
+    pub steps: u64,
+}
+
+impl ProfileSample {
+    fn add(&mut self, other: ProfileSample) {
+        self.cycles += other.cycles;
+        self.steps += other.steps;
+    }
+}
+
+/// Cycles spent at each address while profiling, keyed by the bank mapped
+/// there and the address
+#[derive(Clone, Debug, Default)]
+pub struct Profile {
+    samples: HashMap<(u16, u16), ProfileSample>,
+    total_cycles: u64,
+}
+
+impl Profile {
+    pub fn record(&mut self, bank: u16, address: u16, cycles: u64) {
+        let sample = self.samples.entry((bank, address)).or_default();
+        sample.cycles += cycles;
+        sample.steps += 1;
+        self.total_cycles += cycles;
+    }
+
+    #[must_use]
+    pub fn total_cycles(&self) -> u64 {
+        self.total_cycles
+    }
+
+    /// Every address run, as `bank:address`, hottest first. Ties are in
+    /// address order.
+    #[must_use]
+    pub fn by_address(&self) -> Vec<(String, ProfileSample)> {
+        let mut samples: Vec<_> = self.samples.iter().collect();
+        samples.sort_by_key(|&(&(bank, address), sample)| (Reverse(sample.cycles), address, bank));
+        samples
+            .into_iter()
+            .map(|((bank, address), sample)| (format!("{:03x}:{:04x}", bank, address), *sample))
+            .collect()
+    }
+
+    /// The time spent at each address added up under the symbol it's in,
+    /// hottest first. Addresses without one are grouped by region. Ties are
+    /// in address order.
+    #[must_use]
+    pub fn by_symbol(&self, symbols: &SymbolTable) -> Vec<(String, ProfileSample)> {
+        // Keyed by name, with the address the function starts at
+        let mut functions: HashMap<String, (u16, ProfileSample)> = HashMap::new();
+        for (&(bank, address), sample) in &self.samples {
+            let (name, start) = match symbols.find_address(bank, address) {
+                Some((symbol, _)) => (symbol.name.clone(), symbol.address),
+                None => {
+                    let start = region_start(address);
+                    (format!("<no symbol, {:#06x}>", start), start)
+                }
+            };
+            let (_, total) = functions
+                .entry(name)
+                .or_insert((start, ProfileSample::default()));
+            total.add(*sample);
+        }
+        let mut functions: Vec<_> = functions.into_iter().collect();
+        functions.sort_by(|(a_name, (a_start, a)), (b_name, (b_start, b))| {
+            (Reverse(a.cycles), a_start, a_name).cmp(&(Reverse(b.cycles), b_start, b_name))
+        });
+        functions
+            .into_iter()
+            .map(|(name, (_, sample))| (name, sample))
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SYMBOLS: &str = "; File generated by rgblink
+00:0150 Start
+00:0200 Helper
+01:4a2a Main::vblank_handler ; comment
+02:4a00 Other::bank_two
+00:c000 wRAM
+bad line
+";
+
+    fn load(symbols: &mut SymbolTable, contents: &str) -> usize {
+        symbols.load_contents(contents, Path::new("test.sym"))
+    }
+
+    fn symbol_table() -> SymbolTable {
+        let mut symbols = SymbolTable::default();
+        assert_eq!(load(&mut symbols, SYMBOLS), 5);
+        symbols
+    }
+
+    #[test]
+    fn test_symbol_table_reload_replaces() {
+        let mut symbols = symbol_table();
+        assert_eq!(load(&mut symbols, SYMBOLS), 5);
+        assert_eq!(symbols.len(), 5);
+        assert_eq!(load(&mut symbols, "00:0100 Entry"), 1);
+        assert!(symbols.find("Start").is_none());
+    }
+
+    #[test]
+    fn test_symbol_table_find() {
+        let symbols = symbol_table();
+        let symbol = symbols.find("Main::vblank_handler").unwrap();
+        assert_eq!((symbol.bank, symbol.address), (1, 0x4a2a));
+        assert!(symbols.find("Missing").is_none());
+    }
+
+    #[test]
+    fn test_symbol_table_find_address() {
+        let symbols = symbol_table();
+        let name = |bank, address| {
+            symbols
+                .find_address(bank, address)
+                .map(|(symbol, offset)| (symbol.name.as_str(), offset))
+        };
+        assert_eq!(name(0, 0x0150), Some(("Start", 0)));
+        assert_eq!(name(0, 0x01ff), Some(("Start", 0xaf)));
+        assert_eq!(name(0, 0x3fff), Some(("Helper", 0x3dff)));
+        // Nothing before the first label, or in another region
+        assert_eq!(name(0, 0x0100), None);
+        assert_eq!(name(1, 0x8000), None);
+        // Switchable ROM only matches labels in the mapped bank
+        assert_eq!(name(1, 0x4a30), Some(("Main::vblank_handler", 6)));
+        assert_eq!(name(2, 0x4a30), Some(("Other::bank_two", 0x30)));
+        assert_eq!(name(3, 0x4a30), None);
+        // Work RAM isn't banked here, whatever bank is passed
+        assert_eq!(name(5, 0xc001), Some(("wRAM", 1)));
+    }
+
+    #[test]
+    fn test_symbol_table_describe() {
+        let symbols = symbol_table();
+        assert_eq!(symbols.describe(0, 0x0150).as_deref(), Some("Start"));
+        assert_eq!(
+            symbols.describe(1, 0x4a3c).as_deref(),
+            Some("Main::vblank_handler+0x12")
+        );
+        assert_eq!(symbols.describe(0, 0x0000), None);
+    }
+
+    #[test]
+    fn test_profile_by_address() {
+        let mut profile = Profile::default();
+        profile.record(0, 0x0200, 8);
+        profile.record(1, 0x4000, 4);
+        profile.record(0, 0x0150, 4);
+        profile.record(0, 0x0150, 4);
+        profile.record(0, 0x0100, 8);
+        assert_eq!(profile.total_cycles(), 28);
+        let rows: Vec<_> = profile
+            .by_address()
+            .into_iter()
+            .map(|(name, sample)| (name, sample.cycles, sample.steps))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                ("000:0100".to_string(), 8, 1),
+                ("000:0150".to_string(), 8, 2),
+                ("000:0200".to_string(), 8, 1),
+                ("001:4000".to_string(), 4, 1),
+            ]
+        );
+    }
+
+    #[test]
+    fn test_profile_by_symbol() {
+        let symbols = symbol_table();
+        let mut profile = Profile::default();
+        profile.record(0, 0x0150, 4);
+        profile.record(0, 0x0160, 8);
+        profile.record(0, 0x0200, 12);
+        profile.record(1, 0x4a2a, 4);
+        profile.record(3, 0x4a2a, 4);
+        profile.record(0, 0x0000, 4);
+        let rows: Vec<_> = profile
+            .by_symbol(&symbols)
+            .into_iter()
+            .map(|(name, sample)| (name, sample.cycles))
+            .collect();
+        assert_eq!(
+            rows,
+            [
+                ("Start".to_string(), 12),
+                ("Helper".to_string(), 12),
+                ("<no symbol, 0x0000>".to_string(), 4),
+                ("<no symbol, 0x4000>".to_string(), 4),
+                ("Main::vblank_handler".to_string(), 4),
+            ]
+        );
     }
 }
@@ -0,0 +1,73 @@
+use std::fmt;
+use std::path::PathBuf;
+
+use super::debug::{Breakpoint, MbcWrite};
+
+/// Things that happen inside the emulator that features outside the core
+/// (overlays, recording, scripting...) may want to react to
+#[derive(Clone, Debug)]
+pub enum Event {
+    /// The PPU entered vertical blank
+    VBlank,
+    /// A finished frame was handed to the frontend
+    FrameComplete {
+        frame: u64,
+    },
+    /// A byte was shifted out of the serial port
+    SerialByte(u8),
+    BreakpointHit(Breakpoint),
+    /// The game wrote to the cartridge's bank controller
+    BankSwitch(MbcWrite),
+    SavestateTaken(PathBuf),
+    /// A movie being played back ran out of frames
+    MovieFinished {
+        frames: usize,
+    },
+}
+
+pub type Subscriber = Box<dyn FnMut(&Event) + Send>;
+
+/// Hands every published event to all subscribers, in the order they
+/// subscribed. Subscribers run on the emulation thread, so they should be
+/// quick, e.g. forwarding the event over a channel.
+#[derive(Default)]
+pub struct EventBus {
+    subscribers: Vec<(usize, Subscriber)>,
+    next_id: usize,
+}
+
+impl fmt::Debug for EventBus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EventBus")
+            .field("subscribers", &self.subscribers.len())
+            .finish()
+    }
+}
+
+impl EventBus {
+    /// Registers a subscriber, returning an id that can be passed to
+    /// `unsubscribe`
+    pub fn subscribe(&mut self, subscriber: Subscriber) -> usize {
+        let id = self.next_id;
+        self.next_id += 1;
+        self.subscribers.push((id, subscriber));
+        id
+    }
+
+    pub fn unsubscribe(&mut self, id: usize) -> bool {
+        let count = self.subscribers.len();
+        self.subscribers.retain(|(i, _)| *i != id);
+        self.subscribers.len() != count
+    }
+
+    #[must_use]
+    pub fn has_subscribers(&self) -> bool {
+        !self.subscribers.is_empty()
+    }
+
+    pub fn publish(&mut self, event: &Event) {
+        for (_, subscriber) in &mut self.subscribers {
+            subscriber(event);
+        }
+    }
+}
@@ -0,0 +1,98 @@
+use std::sync::{Arc, Mutex};
+
+use super::mmio::serial::SerialDevice;
+
+#[derive(Debug, Default)]
+struct Wire {
+    // The byte each side is waiting to send while on the external clock
+    data: [u8; 2],
+    // A byte the other side clocked over, waiting for this side to listen
+    incoming: [Option<u8>; 2],
+}
+
+/// One end of a link cable between two cores running in the same process.
+/// It plugs into the serial port, so the game sees the other Game Boy
+/// instead of an unplugged port.
+///
+/// The side using the internal clock drives the transfer: once its 8 bits
+/// have been shifted it swaps bytes with the other side, which finishes its
+/// transfer the next time it ticks while waiting on the external clock.
+#[derive(Debug)]
+pub struct LinkPort {
+    side: usize,
+    wire: Arc<Mutex<Wire>>,
+}
+
+impl LinkPort {
+    /// Creates both ends of a cable
+    #[must_use]
+    pub fn pair() -> (Self, Self) {
+        let wire = Arc::new(Mutex::new(Wire::default()));
+        (Self::new(0, wire.clone()), Self::new(1, wire))
+    }
+
+    fn new(side: usize, wire: Arc<Mutex<Wire>>) -> Self {
+        Self { side, wire }
+    }
+
+    fn other_side(&self) -> usize {
+        1 - self.side
+    }
+}
+
+impl SerialDevice for LinkPort {
+    fn name(&self) -> &str {
+        "link cable"
+    }
+
+    fn exchange(&mut self, out: u8) -> u8 {
+        let mut wire = self.wire.lock().unwrap();
+        let other_side = self.other_side();
+        wire.incoming[other_side] = Some(out);
+        wire.data[other_side]
+    }
+
+    fn drives_clock(&self) -> bool {
+        true
+    }
+
+    fn receive(&mut self, out: u8) -> Option<u8> {
+        let mut wire = self.wire.lock().unwrap();
+        wire.data[self.side] = out;
+        wire.incoming[self.side].take()
+    }
+
+    fn reset(&mut self) {
+        let mut wire = self.wire.lock().unwrap();
+        wire.data[self.side] = 0;
+        wire.incoming[self.side] = None;
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gbc::mmio::serial::Comms;
+
+    #[test]
+    fn test_link_cable_swaps_bytes() {
+        let (first, second) = LinkPort::pair();
+        let mut master = Comms::default();
+        let mut slave = Comms::default();
+        master.set_device(Box::new(first));
+        slave.set_device(Box::new(second));
+
+        // The slave waits on the external clock, and never finishes alone
+        slave.write_u8(0, 0x22);
+        slave.write_u8(1, 0x80);
+        assert!(!slave.tick(10_000));
+        master.write_u8(0, 0x11);
+        master.write_u8(1, 0x81);
+        assert!(master.tick(8 * 512 + 1));
+        assert_eq!(master.read_u8(0), 0x22);
+
+        assert!(slave.tick(4));
+        assert_eq!(slave.read_u8(0), 0x11);
+        assert_eq!(slave.read_u8(1) & 0x80, 0);
+    }
+}
@@ -0,0 +1,161 @@
+use super::{ram_enable_value, ram_index, read_rom_bank, Mapper, MbcRegister, ROM_BANK_SIZE};
+use crate::gbc::cartridge::NINTENDO_LOGO_BYTES;
+use crate::gbc::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+const MULTICART_ROM_SIZE: usize = 1024 * 1024;
+
+/// MBC1: up to 2MiB of ROM and 32KiB of RAM. The 2 bit bank register is
+/// either the upper ROM bank bits or the RAM bank, depending on the mode.
+#[derive(Debug)]
+pub struct Mbc1 {
+    rom_banks: u16,
+    ram_enabled: bool,
+    // 5 bit register at 0x2000-0x3fff, never 0
+    bank_low: u8,
+    // 2 bit register at 0x4000-0x5fff
+    bank_high: u8,
+    // In advanced mode, bank_high also switches bank 0 and the RAM bank
+    advanced_banking_mode: bool,
+    // MBC1M multicarts don't connect the top bit of bank_low, so bank_high
+    // selects which of the 16 bank games is mapped
+    multicart: bool,
+}
+
+impl Mbc1 {
+    #[must_use]
+    pub fn new(rom_banks: u16, multicart: bool) -> Self {
+        Self {
+            rom_banks,
+            ram_enabled: false,
+            bank_low: 1,
+            bank_high: 0,
+            advanced_banking_mode: false,
+            multicart,
+        }
+    }
+
+    /// Guesses whether a ROM is an MBC1M multicart, e.g. Bomberman
+    /// Collection or Mortal Kombat I & II. These are always 1MiB, and each
+    /// game in them starts with its own header, so the Nintendo logo shows
+    /// up again at the start of the second game in bank 0x10.
+    #[must_use]
+    pub fn is_multicart(rom: &[u8]) -> bool {
+        if rom.len() != MULTICART_ROM_SIZE {
+            return false;
+        }
+        let logo_start = 0x10 * ROM_BANK_SIZE + 0x104;
+        rom[logo_start..logo_start + NINTENDO_LOGO_BYTES.len()] == NINTENDO_LOGO_BYTES
+    }
+
+    fn bank_high_shift(&self) -> u16 {
+        if self.multicart {
+            4
+        } else {
+            5
+        }
+    }
+
+    fn rom_bank_0(&self) -> u16 {
+        if self.advanced_banking_mode {
+            (u16::from(self.bank_high) << self.bank_high_shift()) & (self.rom_banks - 1)
+        } else {
+            0
+        }
+    }
+
+    fn ram_bank_selected(&self) -> u8 {
+        if self.advanced_banking_mode {
+            self.bank_high
+        } else {
+            0
+        }
+    }
+}
+
+impl Mapper for Mbc1 {
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let bank = if address < 0x4000 {
+            self.rom_bank_0()
+        } else {
+            self.rom_bank()
+        };
+        read_rom_bank(rom, bank, address)
+    }
+
+    fn write_rom(&mut self, address: u16, byte: u8) {
+        match address {
+            0x0000..=0x1fff => self.ram_enabled = ram_enable_value(byte),
+            0x2000..=0x3fff => {
+                self.bank_low = byte & 0x1f;
+                if self.bank_low == 0 {
+                    self.bank_low = 1; // Don't select bank 0 again
+                }
+            }
+            0x4000..=0x5fff => self.bank_high = byte & 0x3,
+            0x6000..=0x7fff => self.advanced_banking_mode = (byte & 1) != 0,
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], offset: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xff;
+        }
+        ram_index(ram, self.ram_bank_selected(), offset).map_or(0xff, |index| ram[index])
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], offset: u16, byte: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        match ram_index(ram, self.ram_bank_selected(), offset) {
+            Some(index) => {
+                ram[index] = byte;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn register_at(&self, address: u16) -> MbcRegister {
+        match address {
+            0x0000..=0x1fff => MbcRegister::RamEnable,
+            0x2000..=0x3fff => MbcRegister::RomBank,
+            0x4000..=0x5fff => MbcRegister::RamBank,
+            _ => MbcRegister::BankingMode,
+        }
+    }
+
+    fn rom_bank(&self) -> u16 {
+        let shift = self.bank_high_shift();
+        // The zero check happens on all 5 bits, so multicarts can map bank
+        // 0x10 (bank 0 of the second game) here
+        let bank_low = u16::from(self.bank_low) & ((1 << shift) - 1);
+        ((u16::from(self.bank_high) << shift) | bank_low) & (self.rom_banks - 1)
+    }
+
+    fn ram_bank(&self) -> u8 {
+        self.ram_bank_selected()
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+}
+
+impl SaveState for Mbc1 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enabled);
+        writer.write_u8(self.bank_low);
+        writer.write_u8(self.bank_high);
+        writer.write_bool(self.advanced_banking_mode);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        self.ram_enabled = reader.read_bool()?;
+        self.bank_low = reader.read_u8()?;
+        self.bank_high = reader.read_u8()?;
+        self.advanced_banking_mode = reader.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,96 @@
+use super::{ram_enable_value, read_rom_bank, Mapper, MbcRegister};
+use crate::gbc::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+/// MBC2: up to 256KiB of ROM and 512 half-bytes of RAM built into the
+/// controller
+#[derive(Debug)]
+pub struct Mbc2 {
+    rom_banks: u16,
+    ram_enabled: bool,
+    rom_bank: u16,
+}
+
+impl Mbc2 {
+    #[must_use]
+    pub fn new(rom_banks: u16) -> Self {
+        Self {
+            rom_banks,
+            ram_enabled: false,
+            rom_bank: 1,
+        }
+    }
+}
+
+impl Mapper for Mbc2 {
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let bank = if address < 0x4000 { 0 } else { self.rom_bank };
+        read_rom_bank(rom, bank, address)
+    }
+
+    fn write_rom(&mut self, address: u16, byte: u8) {
+        if address >= 0x4000 {
+            return; // MBC2 has no registers in this range
+        }
+        // MBC2 only decodes A8 for its registers: if it is clear the write
+        // goes to the RAM enable register, otherwise it selects the ROM bank
+        if address & 0x100 == 0 {
+            self.ram_enabled = ram_enable_value(byte);
+        } else {
+            self.rom_bank = u16::from(byte & 0xf);
+            if self.rom_bank == 0 {
+                self.rom_bank = 1; // Don't select bank 0 again
+            }
+            self.rom_bank &= self.rom_banks - 1;
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], offset: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xff;
+        }
+        // Only the low 9 bits are decoded, so the 512 bytes are echoed
+        // across the whole window. The upper nibble is open bus.
+        0xf0 | (ram[(offset & 0x1ff) as usize] & 0xf)
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], offset: u16, byte: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        ram[(offset & 0x1ff) as usize] = byte & 0xf;
+        true
+    }
+
+    fn register_at(&self, address: u16) -> MbcRegister {
+        match address {
+            0x0000..=0x3fff if address & 0x100 == 0 => MbcRegister::RamEnable,
+            0x0000..=0x3fff => MbcRegister::RomBank,
+            _ => MbcRegister::Unmapped,
+        }
+    }
+
+    fn rom_bank(&self) -> u16 {
+        self.rom_bank
+    }
+
+    fn ram_bank(&self) -> u8 {
+        0
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+}
+
+impl SaveState for Mbc2 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enabled);
+        writer.write_u16(self.rom_bank);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_bank = reader.read_u16()?;
+        Ok(())
+    }
+}
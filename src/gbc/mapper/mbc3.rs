@@ -0,0 +1,155 @@
+use super::{ram_enable_value, ram_index, read_rom_bank, Mapper, MbcRegister};
+use crate::gbc::rtc::RealTimeClock;
+use crate::gbc::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+/// MBC3: up to 2MiB of ROM, 32KiB of RAM and optionally a real time clock,
+/// whose registers are mapped in place of a RAM bank
+#[derive(Debug)]
+pub struct Mbc3 {
+    rom_banks: u16,
+    // Enables both the external RAM and the clock registers
+    ram_enabled: bool,
+    rom_bank: u16,
+    // 0x00-0x03 select a RAM bank, 0x08-0x0c map a clock register
+    ram_bank: u8,
+    rtc: Option<RealTimeClock>,
+}
+
+impl Mbc3 {
+    #[must_use]
+    pub fn new(rom_banks: u16, has_rtc: bool) -> Self {
+        Self {
+            rom_banks,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            rtc: if has_rtc {
+                Some(RealTimeClock::default())
+            } else {
+                None
+            },
+        }
+    }
+
+    fn rtc_register_selected(&self) -> Option<u8> {
+        if self.rtc.is_some() && (0x08..=0x0c).contains(&self.ram_bank) {
+            Some(self.ram_bank)
+        } else {
+            None
+        }
+    }
+}
+
+impl Mapper for Mbc3 {
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let bank = if address < 0x4000 { 0 } else { self.rom_bank };
+        read_rom_bank(rom, bank, address)
+    }
+
+    fn write_rom(&mut self, address: u16, byte: u8) {
+        match address {
+            0x0000..=0x1fff => self.ram_enabled = ram_enable_value(byte),
+            0x2000..=0x3fff => {
+                self.rom_bank = u16::from(byte & 0x7f);
+                if self.rom_bank == 0 {
+                    self.rom_bank = 1; // Don't select bank 0 again
+                }
+                self.rom_bank &= self.rom_banks - 1;
+            }
+            0x4000..=0x5fff => self.ram_bank = byte,
+            0x6000..=0x7fff => {
+                if let Some(rtc) = &mut self.rtc {
+                    rtc.write_latch(byte);
+                }
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], offset: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xff;
+        }
+        if let (Some(register), Some(rtc)) = (self.rtc_register_selected(), &self.rtc) {
+            return rtc.read_register(register);
+        }
+        ram_index(ram, self.ram_bank & 0x3, offset).map_or(0xff, |index| ram[index])
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], offset: u16, byte: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        if let Some(register) = self.rtc_register_selected() {
+            if let Some(rtc) = &mut self.rtc {
+                rtc.write_register(register, byte);
+            }
+            return true;
+        }
+        match ram_index(ram, self.ram_bank & 0x3, offset) {
+            Some(index) => {
+                ram[index] = byte;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn tick(&mut self, cycles: u64) {
+        if let Some(rtc) = &mut self.rtc {
+            rtc.tick(cycles);
+        }
+    }
+
+    fn register_at(&self, address: u16) -> MbcRegister {
+        match address {
+            0x0000..=0x1fff => MbcRegister::RamEnable,
+            0x2000..=0x3fff => MbcRegister::RomBank,
+            0x4000..=0x5fff => MbcRegister::RamBank,
+            _ => MbcRegister::RtcLatch,
+        }
+    }
+
+    fn rom_bank(&self) -> u16 {
+        self.rom_bank
+    }
+
+    fn ram_bank(&self) -> u8 {
+        self.ram_bank
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn rtc(&self) -> Option<&RealTimeClock> {
+        self.rtc.as_ref()
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut RealTimeClock> {
+        self.rtc.as_mut()
+    }
+}
+
+impl SaveState for Mbc3 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enabled);
+        writer.write_u16(self.rom_bank);
+        writer.write_u8(self.ram_bank);
+        // Whether there is a clock comes from the header, which is the same
+        // for any state that passed the ROM check
+        if let Some(rtc) = &self.rtc {
+            rtc.save_state(writer);
+        }
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_bank = reader.read_u16()?;
+        self.ram_bank = reader.read_u8()?;
+        if let Some(rtc) = &mut self.rtc {
+            rtc.load_state(reader)?;
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,137 @@
+use super::{ram_enable_value, ram_index, read_rom_bank, Mapper, MbcRegister};
+use crate::gbc::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+/// MBC5: up to 8MiB of ROM and 128KiB of RAM, optionally with a rumble
+/// motor
+#[derive(Debug)]
+pub struct Mbc5 {
+    rom_banks: u16,
+    ram_enabled: bool,
+    // 9 bits, and unlike the older controllers bank 0 can be selected
+    rom_bank: u16,
+    ram_bank: u8,
+    has_rumble: bool,
+    rumble_active: bool,
+    rumble_changed: bool,
+}
+
+impl Mbc5 {
+    #[must_use]
+    pub fn new(rom_banks: u16, has_rumble: bool) -> Self {
+        Self {
+            rom_banks,
+            ram_enabled: false,
+            rom_bank: 1,
+            ram_bank: 0,
+            has_rumble,
+            rumble_active: false,
+            rumble_changed: false,
+        }
+    }
+}
+
+impl Mapper for Mbc5 {
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        let bank = if address < 0x4000 { 0 } else { self.rom_bank };
+        read_rom_bank(rom, bank, address)
+    }
+
+    fn write_rom(&mut self, address: u16, byte: u8) {
+        match address {
+            0x0000..=0x1fff => self.ram_enabled = ram_enable_value(byte),
+            0x2000..=0x2fff => {
+                self.rom_bank = (self.rom_bank & 0x100) | u16::from(byte);
+                self.rom_bank &= self.rom_banks - 1;
+            }
+            0x3000..=0x3fff => {
+                self.rom_bank = (u16::from(byte & 1) << 8) | (self.rom_bank & 0xff);
+                self.rom_bank &= self.rom_banks - 1;
+            }
+            0x4000..=0x5fff => {
+                if self.has_rumble {
+                    // The motor is wired to bit 3, leaving 3 bits for the ram bank
+                    let rumble = byte & 0x8 != 0;
+                    if rumble != self.rumble_active {
+                        self.rumble_active = rumble;
+                        self.rumble_changed = true;
+                    }
+                    self.ram_bank = byte & 0x7;
+                } else {
+                    self.ram_bank = byte & 0xf;
+                }
+            }
+            0x6000..=0x7fff => (),
+            _ => unreachable!(),
+        }
+    }
+
+    fn read_ram(&self, ram: &[u8], offset: u16) -> u8 {
+        if !self.ram_enabled {
+            return 0xff;
+        }
+        ram_index(ram, self.ram_bank, offset).map_or(0xff, |index| ram[index])
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], offset: u16, byte: u8) -> bool {
+        if !self.ram_enabled {
+            return false;
+        }
+        match ram_index(ram, self.ram_bank, offset) {
+            Some(index) => {
+                ram[index] = byte;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn register_at(&self, address: u16) -> MbcRegister {
+        match address {
+            0x0000..=0x1fff => MbcRegister::RamEnable,
+            0x2000..=0x2fff => MbcRegister::RomBank,
+            0x3000..=0x3fff => MbcRegister::RomBankHigh,
+            0x4000..=0x5fff => MbcRegister::RamBank,
+            _ => MbcRegister::Unmapped,
+        }
+    }
+
+    fn rom_bank(&self) -> u16 {
+        self.rom_bank
+    }
+
+    fn ram_bank(&self) -> u8 {
+        self.ram_bank
+    }
+
+    fn ram_enabled(&self) -> bool {
+        self.ram_enabled
+    }
+
+    fn take_rumble_change(&mut self) -> Option<bool> {
+        if self.rumble_changed {
+            self.rumble_changed = false;
+            Some(self.rumble_active)
+        } else {
+            None
+        }
+    }
+}
+
+impl SaveState for Mbc5 {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_bool(self.ram_enabled);
+        writer.write_u16(self.rom_bank);
+        writer.write_u8(self.ram_bank);
+        writer.write_bool(self.rumble_active);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        self.ram_enabled = reader.read_bool()?;
+        self.rom_bank = reader.read_u16()?;
+        self.ram_bank = reader.read_u8()?;
+        let rumble_active = reader.read_bool()?;
+        self.rumble_changed = rumble_active != self.rumble_active;
+        self.rumble_active = rumble_active;
+        Ok(())
+    }
+}
@@ -0,0 +1,138 @@
+pub mod mbc1;
+pub mod mbc2;
+pub mod mbc3;
+pub mod mbc5;
+pub mod no_mbc;
+
+use std::fmt;
+
+use super::cartridge::Type;
+use super::rtc::RealTimeClock;
+use super::savestate::SaveState;
+
+use mbc1::Mbc1;
+use mbc2::Mbc2;
+use mbc3::Mbc3;
+use mbc5::Mbc5;
+use no_mbc::NoMbc;
+
+pub const ROM_BANK_SIZE: usize = 0x4000;
+pub const RAM_BANK_SIZE: usize = 0x2000;
+
+/// The control register a write to the ROM area lands in
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum MbcRegister {
+    RamEnable,
+    RomBank,
+    RomBankHigh,
+    RamBank,
+    BankingMode,
+    RtcLatch,
+    Unmapped,
+}
+
+impl fmt::Display for MbcRegister {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::RamEnable => write!(f, "RAM enable"),
+            Self::RomBank => write!(f, "ROM bank"),
+            Self::RomBankHigh => write!(f, "ROM bank high"),
+            Self::RamBank => write!(f, "RAM bank"),
+            Self::BankingMode => write!(f, "banking mode"),
+            Self::RtcLatch => write!(f, "RTC latch"),
+            Self::Unmapped => write!(f, "unmapped"),
+        }
+    }
+}
+
+/// The memory bank controller on a cartridge. It decides what the CPU sees
+/// in the ROM (0x0000-0x7fff) and external RAM (0xa000-0xbfff) windows, and
+/// turns writes to the ROM window into register writes. The ROM and RAM
+/// themselves belong to the cartridge and are passed in.
+pub trait Mapper: SaveState + fmt::Debug + Send {
+    /// Reads from the ROM window, `address` is 0x0000-0x7fff
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8;
+
+    /// Writes to the ROM window, `address` is 0x0000-0x7fff
+    fn write_rom(&mut self, address: u16, byte: u8);
+
+    /// Reads from the external RAM window, `offset` is from 0xa000
+    fn read_ram(&self, ram: &[u8], offset: u16) -> u8;
+
+    /// Writes to the external RAM window, `offset` is from 0xa000. Returns
+    /// whether `ram` or other battery backed state, like a clock, was
+    /// changed, so the cartridge knows to save it.
+    fn write_ram(&mut self, ram: &mut [u8], offset: u16, byte: u8) -> bool;
+
+    /// Advances anything on the cartridge that runs on its own, like a clock
+    fn tick(&mut self, _cycles: u64) {}
+
+    /// Decodes which control register a write to the given address
+    /// (0x0000-0x7fff) would hit
+    fn register_at(&self, address: u16) -> MbcRegister;
+
+    /// The ROM bank currently mapped at 0x4000-0x7fff
+    fn rom_bank(&self) -> u16;
+
+    /// The value of the RAM bank register
+    fn ram_bank(&self) -> u8;
+
+    fn ram_enabled(&self) -> bool;
+
+    fn rtc(&self) -> Option<&RealTimeClock> {
+        None
+    }
+
+    fn rtc_mut(&mut self) -> Option<&mut RealTimeClock> {
+        None
+    }
+
+    /// Returns the new state of the rumble motor if the game changed it
+    /// since the last call
+    fn take_rumble_change(&mut self) -> Option<bool> {
+        None
+    }
+}
+
+impl Default for Box<dyn Mapper> {
+    fn default() -> Self {
+        Box::new(NoMbc)
+    }
+}
+
+/// Picks the mapper for the cartridge type in the header. Controllers that
+/// aren't emulated yet get no mapper at all, which at least runs the first
+/// 32KiB of the game. Some cartridges are wired differently than their
+/// header says, so the ROM itself is looked at too.
+#[must_use]
+pub fn for_cartridge(cartridge_type: &Type, rom: &[u8]) -> Box<dyn Mapper> {
+    #[allow(clippy::cast_possible_truncation)]
+    let rom_banks = (rom.len() / ROM_BANK_SIZE) as u16;
+    match cartridge_type.mbc_type() {
+        1 => Box::new(Mbc1::new(rom_banks, Mbc1::is_multicart(rom))),
+        2 => Box::new(Mbc2::new(rom_banks)),
+        3 => Box::new(Mbc3::new(rom_banks, cartridge_type.has_rtc())),
+        5 => Box::new(Mbc5::new(rom_banks, cartridge_type.has_rumble())),
+        _ => Box::new(NoMbc),
+    }
+}
+
+fn ram_enable_value(byte: u8) -> bool {
+    (byte & 0xf) == 0x0a
+}
+
+fn read_rom_bank(rom: &[u8], bank: u16, address: u16) -> u8 {
+    let index = usize::from(bank) * ROM_BANK_SIZE + (usize::from(address) % ROM_BANK_SIZE);
+    rom.get(index).copied().unwrap_or(0xff)
+}
+
+/// Index into the external RAM for the given bank. RAM sizes are powers of
+/// two and the unused address lines aren't connected, so smaller RAMs are
+/// mirrored across the banks.
+fn ram_index(ram: &[u8], bank: u8, offset: u16) -> Option<usize> {
+    if ram.is_empty() {
+        return None;
+    }
+    let index = usize::from(bank) * RAM_BANK_SIZE + usize::from(offset);
+    Some(index & (ram.len() - 1))
+}
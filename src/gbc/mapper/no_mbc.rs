@@ -0,0 +1,53 @@
+use super::{ram_index, read_rom_bank, Mapper, MbcRegister};
+use crate::gbc::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+/// 32KiB ROM cartridges, optionally with up to 8KiB of RAM that is always
+/// accessible
+#[derive(Debug, Default)]
+pub struct NoMbc;
+
+impl Mapper for NoMbc {
+    fn read_rom(&self, rom: &[u8], address: u16) -> u8 {
+        read_rom_bank(rom, address / 0x4000, address)
+    }
+
+    fn write_rom(&mut self, _address: u16, _byte: u8) {}
+
+    fn read_ram(&self, ram: &[u8], offset: u16) -> u8 {
+        ram_index(ram, 0, offset).map_or(0xff, |index| ram[index])
+    }
+
+    fn write_ram(&mut self, ram: &mut [u8], offset: u16, byte: u8) -> bool {
+        match ram_index(ram, 0, offset) {
+            Some(index) => {
+                ram[index] = byte;
+                true
+            }
+            None => false,
+        }
+    }
+
+    fn register_at(&self, _address: u16) -> MbcRegister {
+        MbcRegister::Unmapped
+    }
+
+    fn rom_bank(&self) -> u16 {
+        1
+    }
+
+    fn ram_bank(&self) -> u8 {
+        0
+    }
+
+    fn ram_enabled(&self) -> bool {
+        true
+    }
+}
+
+impl SaveState for NoMbc {
+    fn save_state(&self, _writer: &mut StateWriter) {}
+
+    fn load_state(&mut self, _reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        Ok(())
+    }
+}
@@ -1,11 +1,13 @@
 use super::cartridge::Cartridge;
-use super::debug::{AccessType, Breakpoint};
+use super::debug::{Breakpoint, FrameBudget, MbcWrite, RngWatch, WatchCondition};
+use super::cpu::InterruptRequest;
 use super::mmio::{apu::Sound, joypad::Joypad, lcd::Lcd, serial::Comms, timer::Timer};
+use super::peripheral::Peripheral;
 use super::ppu::PictureProcessingUnit;
+use super::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 
 enum MemoryRegion {
-    CartridgeBank0(u16),
-    CartridgeBankSelectable(u16),
+    CartridgeRom(u16),
     VideoRam(u16),
     ExternalRam(u16),
     WorkRam(u16),
@@ -22,14 +24,14 @@ enum MemoryRegion {
     BootRomDisable,
     HighRam(u16),
     InterruptEnable,
+    Peripheral(usize),
 }
 
 impl From<u16> for MemoryRegion {
     fn from(address: u16) -> Self {
         #![allow(clippy::match_same_arms)]
         match address {
-            0x0000..=0x3fff => MemoryRegion::CartridgeBank0(address),
-            0x4000..=0x7fff => MemoryRegion::CartridgeBankSelectable(address - 0x4000),
+            0x0000..=0x7fff => MemoryRegion::CartridgeRom(address),
             0x8000..=0x9fff => MemoryRegion::VideoRam(address - 0x8000),
             0xa000..=0xbfff => MemoryRegion::ExternalRam(address - 0xa000),
             0xc000..=0xdfff => MemoryRegion::WorkRam(address - 0xc000),
@@ -71,10 +73,29 @@ pub struct MemoryBus {
     pub interrupt_flags: u8,
     pub high_ram: [u8; 127],
     pub interrupt_enable: u8,
+    // KEY1 and double speed mode only exist on the CGB, which isn't
+    // emulated yet, so this stays off and KEY1 reads as unmapped
+    pub cgb_mode: bool,
+    pub double_speed: bool,
+    pub speed_switch_armed: bool,
+    // Accuracy option, off by default since only test ROMs rely on it
+    pub emulate_oam_corruption: bool,
+    // The frame being tracked when the vblank budget analyzer is on
+    pub frame_budget: Option<FrameBudget>,
     boot_rom: &'static [u8; 256],
     last_bus_value: u8,
-    memory_breakpoints: Vec<Breakpoint>,
-    break_reason: Option<Breakpoint>,
+    // Enabled read and write breakpoints, with their index in the Gbc's list
+    memory_breakpoints: Vec<(usize, Breakpoint)>,
+    breakpoint_hit: Option<usize>,
+    mbc_writes: Vec<MbcWrite>,
+    rng_watches: Vec<RngWatch>,
+    write_watches: Vec<u16>,
+    watched_writes: Vec<(u16, u8)>,
+    peripherals: Vec<Box<dyn Peripheral>>,
+    // Cycles the hardware was ticked by the CPU during the current step,
+    // and the interrupts that raised
+    cycles_ticked: u64,
+    ticked_interrupts: InterruptRequest,
 }
 
 impl MemoryBus {
@@ -98,71 +119,337 @@ impl MemoryBus {
             interrupt_flags: 0,
             high_ram: [0; 127],
             interrupt_enable: 0,
+            cgb_mode: false,
+            double_speed: false,
+            speed_switch_armed: false,
+            emulate_oam_corruption: false,
+            frame_budget: None,
             boot_rom: include_bytes!("../../dmg_boot.bin"),
             last_bus_value: 0,
             memory_breakpoints: Vec::new(),
-            break_reason: None,
+            breakpoint_hit: None,
+            mbc_writes: Vec::new(),
+            rng_watches: Vec::new(),
+            write_watches: Vec::new(),
+            watched_writes: Vec::new(),
+            peripherals: Vec::new(),
+            cycles_ticked: 0,
+            ticked_interrupts: InterruptRequest::default(),
         }
     }
 
+    /// Resets the hardware to its power on state. The cartridge, plugged
+    /// in peripherals and debugging aids like breakpoints and watches are
+    /// kept.
     pub fn reset(&mut self) {
         let c = std::mem::take(&mut self.cartridge);
+        let mut peripherals = std::mem::take(&mut self.peripherals);
+        for peripheral in &mut peripherals {
+            peripheral.reset();
+        }
+        let memory_breakpoints = std::mem::take(&mut self.memory_breakpoints);
+        let rng_watches = std::mem::take(&mut self.rng_watches);
+        let write_watches = std::mem::take(&mut self.write_watches);
+        let cgb_mode = self.cgb_mode;
+        let emulate_oam_corruption = self.emulate_oam_corruption;
+        let renderer = self.ppu.get_renderer();
+        let batch_scanlines = self.ppu.get_batch_scanlines();
+        let rgba_layout = self.ppu.get_rgba_layout();
+        let frame_budget = self.frame_budget.map(|budget| FrameBudget::new(budget.frame));
+        let mut serial_device = self.serial.take_device();
+        serial_device.reset();
         *self = Self::new(c);
+        self.serial.set_device(serial_device);
+        self.cgb_mode = cgb_mode;
+        self.emulate_oam_corruption = emulate_oam_corruption;
+        self.ppu.set_renderer(renderer);
+        self.ppu.set_batch_scanlines(batch_scanlines);
+        self.ppu.set_rgba_layout(rgba_layout);
+        self.frame_budget = frame_budget;
+        self.peripherals = peripherals;
+        self.memory_breakpoints = memory_breakpoints;
+        self.rng_watches = rng_watches;
+        self.write_watches = write_watches;
+    }
+
+    /// Swaps in a different cartridge and resets. Watches belong to the old
+    /// game, so they are dropped, breakpoints are kept.
+    /// Whether the boot ROM is still mapped over the start of the cartridge
+    #[must_use]
+    pub fn is_boot_rom_mapped(&self) -> bool {
+        self.boot_rom_disable == 0
     }
 
-    pub fn add_breakpoint(&mut self, bp: Breakpoint) {
-        // Only add it if it isn't just an execute breakpoint
-        if matches!(bp.access_type, AccessType::Execute) {
+    pub fn replace_cartridge(&mut self, cartridge: Cartridge) {
+        self.cartridge = cartridge;
+        self.rng_watches.clear();
+        self.write_watches.clear();
+        self.reset();
+    }
+
+    /// 16 bit INC and DEC put their register on the address bus. On the DMG,
+    /// if that points into OAM while the PPU is scanning it, the row being
+    /// scanned gets corrupted.
+    pub fn trigger_oam_corruption(&mut self, address: u16) {
+        if !self.emulate_oam_corruption || !(0xfe00..=0xfeff).contains(&address) {
             return;
         }
-        self.memory_breakpoints.push(bp);
+        if let Some(row) = self.lcd.get_oam_scan_row() {
+            self.ppu.corrupt_object_attribute_memory(row);
+        }
+    }
+
+    /// Performs the CGB speed switch if the game armed it through KEY1,
+    /// returning whether it did
+    pub fn switch_speed(&mut self) -> bool {
+        if !self.cgb_mode || !self.speed_switch_armed {
+            return false;
+        }
+        self.double_speed = !self.double_speed;
+        self.speed_switch_armed = false;
+        true
+    }
+
+    /// Plugs in a peripheral. If more than one claims an address, the one
+    /// added first wins.
+    pub fn add_peripheral(&mut self, peripheral: Box<dyn Peripheral>) {
+        self.peripherals.push(peripheral);
     }
 
     #[must_use]
-    pub fn check_breakpoints(&self, address: u16, write: bool) -> Option<Breakpoint> {
-        for bp in &self.memory_breakpoints {
-            if bp.matches_address(address) && (write && bp.access_type.on_write())
-                || (!write && bp.access_type.on_read())
-            {
-                return Some(*bp);
+    pub fn get_peripherals(&self) -> &[Box<dyn Peripheral>] {
+        &self.peripherals
+    }
+
+    /// Advances everything on the bus by `cycles` CPU cycles and requests
+    /// the interrupts they raise in IF. While the CPU is stopped the LCD
+    /// and timer don't run.
+    pub fn tick(&mut self, cycles: u64, cpu_stopped: bool) {
+        self.cycles_ticked += cycles;
+        // The PPU and the cartridge's clock don't speed up in double speed
+        // mode
+        let real_cycles = if self.double_speed {
+            cycles / 2
+        } else {
+            cycles
+        };
+        let mut interrupts = InterruptRequest {
+            serial: self.serial.tick(cycles).into(),
+            joypad: self.joypad.take_interrupt().into(),
+            ..InterruptRequest::default()
+        };
+        if !cpu_stopped {
+            let (vblank, stat) = self.ppu.tick(real_cycles, &mut self.lcd);
+            interrupts.vblank = vblank.into();
+            interrupts.stat = stat.into();
+            interrupts.timer = self.timer_control.tick(cycles).into();
+        }
+        self.cartridge.tick(real_cycles);
+        interrupts.merge(&self.tick_peripherals(cycles));
+        self.run_dma(cycles);
+
+        self.interrupt_flags |= interrupts.bits();
+        self.ticked_interrupts.merge(&interrupts);
+    }
+
+    /// How many cycles `tick` advanced the hardware since the last call
+    pub fn take_cycles_ticked(&mut self) -> u64 {
+        std::mem::take(&mut self.cycles_ticked)
+    }
+
+    /// The interrupts raised by `tick` since the last call
+    pub fn take_ticked_interrupts(&mut self) -> InterruptRequest {
+        std::mem::take(&mut self.ticked_interrupts)
+    }
+
+    pub fn tick_peripherals(&mut self, cycles: u64) -> InterruptRequest {
+        let mut interrupts = InterruptRequest::default();
+        for peripheral in &mut self.peripherals {
+            interrupts.merge(&peripheral.tick(cycles));
+        }
+        interrupts
+    }
+
+    fn region_for(&self, address: u16) -> MemoryRegion {
+        match self.peripherals.iter().position(|p| p.claims(address)) {
+            Some(index) => MemoryRegion::Peripheral(index),
+            None => MemoryRegion::from(address),
+        }
+    }
+
+    /// Replaces the breakpoints checked on reads and writes. Each comes
+    /// with the index reported by `take_breakpoint_hit` when it's hit.
+    pub fn set_breakpoints(&mut self, breakpoints: Vec<(usize, Breakpoint)>) {
+        self.memory_breakpoints = breakpoints;
+        self.breakpoint_hit = None;
+    }
+
+    /// Finds the first breakpoint an access of `value` trips. For writes,
+    /// `old` is what was there before, if a breakpoint needs it.
+    #[must_use]
+    pub fn check_breakpoints(
+        &self,
+        address: u16,
+        write: bool,
+        value: u8,
+        old: Option<u8>,
+    ) -> Option<usize> {
+        self.memory_breakpoints
+            .iter()
+            .find(|(_, bp)| {
+                let access = if write {
+                    bp.access_type.on_write()
+                } else {
+                    bp.access_type.on_read()
+                };
+                let condition_met = match bp.condition {
+                    Some(condition) => condition.matches(value, old),
+                    None => true,
+                };
+                access && condition_met && bp.matches_address(address)
+            })
+            .map(|&(index, _)| index)
+    }
+
+    /// Whether a write to `address` needs the old value for a `Changes`
+    /// watch
+    fn watching_for_change(&self, address: u16) -> bool {
+        self.memory_breakpoints.iter().any(|(_, bp)| {
+            bp.condition == Some(WatchCondition::Changes) && bp.matches_address(address)
+        })
+    }
+
+    /// Returns every cartridge control write since the last call. The pc and
+    /// cycle are left for the caller to fill in, the bus doesn't know them.
+    pub fn take_mbc_writes(&mut self) -> Vec<MbcWrite> {
+        std::mem::take(&mut self.mbc_writes)
+    }
+
+    fn record_mbc_write(&mut self, address: u16, value: u8) {
+        self.mbc_writes.push(MbcWrite {
+            pc: 0,
+            cycle: 0,
+            address,
+            value,
+            register: self.cartridge.mbc_register_at(address),
+            rom_bank: self.cartridge.get_rom_bank_selected(),
+            ram_bank: self.cartridge.get_ram_bank_selected(),
+        });
+    }
+
+    /// Remembers every value written to the given address until the next
+    /// `take_watched_writes`
+    pub fn add_write_watch(&mut self, address: u16) {
+        if !self.write_watches.contains(&address) {
+            self.write_watches.push(address);
+        }
+    }
+
+    pub fn remove_write_watch(&mut self, address: u16) {
+        self.write_watches.retain(|&a| a != address);
+    }
+
+    /// Returns the (address, value) of every write to a watched address
+    /// since the last call
+    pub fn take_watched_writes(&mut self) -> Vec<(u16, u8)> {
+        std::mem::take(&mut self.watched_writes)
+    }
+
+    pub fn add_rng_watch(&mut self, address: u16) {
+        if !self.rng_watches.iter().any(|w| w.address == address) {
+            self.rng_watches.push(RngWatch::new(address));
+        }
+    }
+
+    /// Returns false if the address wasn't being watched
+    pub fn remove_rng_watch(&mut self, address: u16) -> bool {
+        let count = self.rng_watches.len();
+        self.rng_watches.retain(|w| w.address != address);
+        self.rng_watches.len() != count
+    }
+
+    /// Makes every read of a watched address return the given value, or the
+    /// real value again if None. Returns false if the address isn't watched.
+    pub fn lock_rng_watch(&mut self, address: u16, value: Option<u8>) -> bool {
+        match self.rng_watches.iter_mut().find(|w| w.address == address) {
+            Some(watch) => {
+                watch.locked_value = value;
+                true
             }
+            None => false,
         }
-        None
     }
 
     #[must_use]
-    pub fn get_break_reason(&mut self) -> Option<Breakpoint> {
-        self.break_reason.take()
+    pub fn get_rng_watches(&self) -> &[RngWatch] {
+        &self.rng_watches[..]
+    }
+
+    fn observe_rng_read(&mut self, address: u16, value: u8) -> u8 {
+        match self.rng_watches.iter_mut().find(|w| w.address == address) {
+            Some(watch) => {
+                watch.reads += 1;
+                watch.last_value = watch.locked_value.unwrap_or(value);
+                watch.last_value
+            }
+            None => value,
+        }
+    }
+
+    /// The index of the first breakpoint hit since the last call
+    #[must_use]
+    pub fn take_breakpoint_hit(&mut self) -> Option<usize> {
+        self.breakpoint_hit.take()
     }
 
     #[must_use]
     pub fn read_u8(&mut self, address: u16) -> u8 {
-        let region = MemoryRegion::from(address);
-        // Technically there are more is one bus and this is complicated
-        if self.lcd.get_dma_running() && !matches!(region, MemoryRegion::HighRam(_)) {
+        // While DMA has the bus, only the registers, HRAM and IE can be
+        // reached and everything else reads as the byte being copied
+        if self.lcd.get_dma_running() && address < 0xff00 {
             return self.last_bus_value;
         }
-        self.break_reason = self
-            .break_reason
-            .or_else(|| self.check_breakpoints(address, false));
-        // I'd like to overwrite self.last_bus_value here, but I also don't want to make this
-        // a &mut self function...
-        self.last_bus_value = match region {
-            MemoryRegion::CartridgeBank0(offset) => {
-                if self.boot_rom_disable == 0 && offset < 0x100 {
+        let region = self.region_for(address);
+        self.last_bus_value = self.read_region(address, region);
+        if self.breakpoint_hit.is_none() && !self.memory_breakpoints.is_empty() {
+            let value = self.last_bus_value;
+            self.breakpoint_hit = self.check_breakpoints(address, false, value, None);
+        }
+        if !self.rng_watches.is_empty() {
+            self.last_bus_value = self.observe_rng_read(address, self.last_bus_value);
+        }
+        self.last_bus_value
+    }
+
+    /// Reads a byte the way the CPU would see it with DMA off and VRAM and
+    /// OAM unblocked, without triggering breakpoints, RNG watches or
+    /// changing the last bus value. Peripherals are still read through
+    /// `Peripheral::read_u8`.
+    #[must_use]
+    pub fn peek_u8(&mut self, address: u16) -> u8 {
+        match self.region_for(address) {
+            MemoryRegion::VideoRam(offset) => self.ppu.peek_video_ram(offset),
+            MemoryRegion::ObjectAttributeMemory(offset) => {
+                self.ppu.peek_object_attribute_memory(offset)
+            }
+            region => self.read_region(address, region),
+        }
+    }
+
+    fn read_region(&mut self, address: u16, region: MemoryRegion) -> u8 {
+        match region {
+            MemoryRegion::CartridgeRom(offset) => {
+                if self.is_boot_rom_mapped() && offset < 0x100 {
                     self.boot_rom[offset as usize]
                 } else {
-                    self.cartridge.read_rom_bank_0(offset)
+                    self.cartridge.read_rom(offset)
                 }
             }
-            MemoryRegion::CartridgeBankSelectable(offset) => {
-                self.cartridge.read_rom_selected_bank(offset)
-            }
-            MemoryRegion::VideoRam(offset) => self.ppu.read_video_ram(offset),
+            MemoryRegion::VideoRam(offset) => self.ppu.read_video_ram(&self.lcd, offset),
             MemoryRegion::ExternalRam(offset) => self.cartridge.read_from_external_ram(offset),
             MemoryRegion::WorkRam(offset) => self.ram[offset as usize],
             MemoryRegion::ObjectAttributeMemory(offset) => {
-                self.ppu.read_object_attribute_memory(offset)
+                self.ppu.read_object_attribute_memory(&self.lcd, offset)
             }
             MemoryRegion::Unused => {
                 // Use Color Game Boy Revision E behavior I guess?
@@ -178,11 +465,17 @@ impl MemoryBus {
             MemoryRegion::WaveformRam(offset) => self.sound.read_u8_from_waveform(offset),
             MemoryRegion::Lcd(offset) => self.lcd.read_u8(offset),
             MemoryRegion::BootRomDisable => self.boot_rom_disable,
-            MemoryRegion::Key1Flag => 0xff, // Undocumented flag, KEY1 in CGB
+            MemoryRegion::Key1Flag => {
+                if self.cgb_mode {
+                    (u8::from(self.double_speed) << 7) | 0x7e | u8::from(self.speed_switch_armed)
+                } else {
+                    0xff // Undocumented flag, KEY1 in CGB
+                }
+            }
             MemoryRegion::HighRam(offset) => self.high_ram[offset as usize],
             MemoryRegion::InterruptEnable => self.interrupt_enable as u8,
-        };
-        self.last_bus_value
+            MemoryRegion::Peripheral(index) => self.peripherals[index].read_u8(address),
+        }
     }
 
     #[must_use]
@@ -196,34 +489,82 @@ impl MemoryBus {
     pub fn read_mem(&mut self, address: u16, length: u16) -> Vec<u8> {
         let mut vec = Vec::with_capacity(length as usize);
 
-        for addr in address..address + length {
-            let byte = self.read_u8(addr);
+        for offset in 0..length {
+            let byte = self.read_u8(address.wrapping_add(offset));
             vec.push(byte);
         }
 
         vec
     }
 
+    // Counts writes the PPU would block, or that are only safe in vblank
+    fn record_frame_budget_write(&mut self, address: u16) {
+        let mode = self.lcd.get_mode();
+        if let Some(budget) = &mut self.frame_budget {
+            match address {
+                0x8000..=0x9fff if mode == 3 => budget.vram_writes_while_drawing += 1,
+                0xfe00..=0xfe9f if mode == 2 || mode == 3 => budget.oam_writes_while_drawing += 1,
+                0xff46 if mode != 1 => budget.dma_outside_vblank += 1,
+                _ => {}
+            }
+        }
+    }
+
     pub fn write_u8(&mut self, address: u16, byte: u8) {
-        #![allow(clippy::match_same_arms)]
-        let region = MemoryRegion::from(address);
-        // Technically there are more is one bus and this is complicated
-        if self.lcd.get_dma_running() && !matches!(region, MemoryRegion::HighRam(_)) {
+        // Writes below the registers go nowhere while DMA has the bus
+        if self.lcd.get_dma_running() && address < 0xff00 {
             return;
         }
-        self.break_reason = self
-            .break_reason
-            .or_else(|| self.check_breakpoints(address, true));
+        let region = self.region_for(address);
+        if self.breakpoint_hit.is_none() && !self.memory_breakpoints.is_empty() {
+            let old = if self.watching_for_change(address) {
+                Some(self.peek_u8(address))
+            } else {
+                None
+            };
+            self.breakpoint_hit = self.check_breakpoints(address, true, byte, old);
+        }
+        if self.frame_budget.is_some() {
+            self.record_frame_budget_write(address);
+        }
+        self.write_region(address, region, byte);
+        if self.write_watches.contains(&address) {
+            self.watched_writes.push((address, byte));
+        }
+        self.last_bus_value = byte;
+    }
+
+    /// Writes a byte the way the CPU would with DMA off and VRAM and OAM
+    /// unblocked, without triggering breakpoints or write watches. IO
+    /// registers still react and ROM writes still go to the MBC.
+    pub fn poke_u8(&mut self, address: u16, byte: u8) {
+        match self.region_for(address) {
+            MemoryRegion::VideoRam(offset) => {
+                self.ppu.catch_up(&mut self.lcd);
+                self.ppu.poke_video_ram(offset, byte);
+            }
+            MemoryRegion::ObjectAttributeMemory(offset) => {
+                self.ppu.poke_object_attribute_memory(offset, byte);
+            }
+            region => self.write_region(address, region, byte),
+        }
+    }
+
+    fn write_region(&mut self, address: u16, region: MemoryRegion, byte: u8) {
+        #![allow(clippy::match_same_arms)]
         match region {
-            MemoryRegion::CartridgeBank0(offset) => self.cartridge.write_rom_bank_0(offset, byte),
-            MemoryRegion::CartridgeBankSelectable(offset) => {
-                self.cartridge.write_rom_selected_bank(offset, byte);
+            MemoryRegion::CartridgeRom(offset) => {
+                self.cartridge.write_rom(offset, byte);
+                self.record_mbc_write(address, byte);
+            }
+            MemoryRegion::VideoRam(offset) => {
+                self.ppu.catch_up(&mut self.lcd);
+                self.ppu.write_video_ram(&self.lcd, offset, byte);
             }
-            MemoryRegion::VideoRam(offset) => self.ppu.write_video_ram(offset, byte),
             MemoryRegion::ExternalRam(offset) => self.cartridge.write_to_external_ram(offset, byte),
             MemoryRegion::WorkRam(offset) => self.ram[offset as usize] = byte,
             MemoryRegion::ObjectAttributeMemory(offset) => {
-                self.ppu.write_object_attribute_memory(offset, byte);
+                self.ppu.write_object_attribute_memory(&self.lcd, offset, byte);
             }
             MemoryRegion::Unused => (),
             MemoryRegion::Joypad => self.joypad.write_u8(byte),
@@ -232,13 +573,20 @@ impl MemoryBus {
             MemoryRegion::InterruptFlags => self.interrupt_flags = byte,
             MemoryRegion::Sound(offset) => self.sound.write_u8(offset, byte),
             MemoryRegion::WaveformRam(offset) => self.sound.write_u8_from_waveform(offset, byte),
-            MemoryRegion::Lcd(offset) => self.lcd.write_u8(offset, byte),
+            MemoryRegion::Lcd(offset) => {
+                self.ppu.catch_up(&mut self.lcd);
+                self.lcd.write_u8(offset, byte);
+            }
             MemoryRegion::BootRomDisable => self.boot_rom_disable = byte,
-            MemoryRegion::Key1Flag => (),
+            MemoryRegion::Key1Flag => {
+                if self.cgb_mode {
+                    self.speed_switch_armed = byte & 0x1 != 0;
+                }
+            }
             MemoryRegion::HighRam(offset) => self.high_ram[offset as usize] = byte,
             MemoryRegion::InterruptEnable => self.interrupt_enable = byte,
+            MemoryRegion::Peripheral(index) => self.peripherals[index].write_u8(address, byte),
         }
-        self.last_bus_value = byte;
     }
 
     pub fn write_u16(&mut self, address: u16, v: u16) {
@@ -252,22 +600,79 @@ impl MemoryBus {
         }
     }
 
+    /// Copies the bytes OAM DMA gets to in `cycles` CPU cycles, one per
+    /// machine cycle. The copy goes around the CPU's view of the bus, which
+    /// is blocked until it finishes.
     pub fn run_dma(&mut self, cycles: u64) {
-        if !self.lcd.get_dma_running() {
-            return;
-        }
-
-        for _ in 0..cycles {
+        for _ in 0..self.lcd.advance_dma(cycles) {
             let (source, destination) = match self.lcd.get_dma_addresses() {
                 Some(v) => v,
                 None => break,
             };
 
-            let v = self.read_u8(source);
+            // Sources from 0xe000 up all read work RAM, like echo RAM but
+            // running on past its end
+            let source = if source >= 0xe000 {
+                source - 0x2000
+            } else {
+                source
+            };
+            let region = self.region_for(source);
+            let v = self.read_region(source, region);
             self.last_bus_value = v;
-            self.write_u8(destination, v);
+            self.ppu
+                .dma_write_object_attribute_memory(destination - 0xfe00, v);
 
             self.lcd.tick_dma();
         }
     }
 }
+
+impl SaveState for MemoryBus {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.cartridge.save_state(writer);
+        writer.write_bytes(&self.ram);
+        self.ppu.save_state(writer);
+        self.joypad.save_state(writer);
+        self.serial.save_state(writer);
+        self.timer_control.save_state(writer);
+        self.sound.save_state(writer);
+        self.lcd.save_state(writer);
+        writer.write_u8(self.boot_rom_disable);
+        writer.write_u8(self.vram_select);
+        writer.write_bool(self.disable_boot_rom);
+        writer.write_bytes(&self.vram_dma);
+        writer.write_bytes(&self.color_palettes);
+        writer.write_u8(self.wram_bank_select);
+        writer.write_u8(self.interrupt_flags);
+        writer.write_bytes(&self.high_ram);
+        writer.write_u8(self.interrupt_enable);
+        writer.write_bool(self.double_speed);
+        writer.write_bool(self.speed_switch_armed);
+        writer.write_u8(self.last_bus_value);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        self.cartridge.load_state(reader)?;
+        reader.read_bytes(&mut self.ram)?;
+        self.ppu.load_state(reader)?;
+        self.joypad.load_state(reader)?;
+        self.serial.load_state(reader)?;
+        self.timer_control.load_state(reader)?;
+        self.sound.load_state(reader)?;
+        self.lcd.load_state(reader)?;
+        self.boot_rom_disable = reader.read_u8()?;
+        self.vram_select = reader.read_u8()?;
+        self.disable_boot_rom = reader.read_bool()?;
+        reader.read_bytes(&mut self.vram_dma)?;
+        reader.read_bytes(&mut self.color_palettes)?;
+        self.wram_bank_select = reader.read_u8()?;
+        self.interrupt_flags = reader.read_u8()?;
+        reader.read_bytes(&mut self.high_ram)?;
+        self.interrupt_enable = reader.read_u8()?;
+        self.double_speed = reader.read_bool()?;
+        self.speed_switch_armed = reader.read_bool()?;
+        self.last_bus_value = reader.read_u8()?;
+        Ok(())
+    }
+}
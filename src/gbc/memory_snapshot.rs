@@ -0,0 +1,62 @@
+use super::memory_bus::MemoryBus;
+
+const ADDRESS_SPACE_SIZE: usize = 0x10000;
+
+/// A copy of the whole address space, taken at the start of vertical blank.
+/// Debug UIs on other threads read this instead of the live bus, so they
+/// never see a value the game is halfway through updating and never hold
+/// up the emulation thread for more than a swap.
+///
+/// Every value in a snapshot comes from the same instant, so multi byte
+/// values are consistent with each other. The snapshot is taken with
+/// `MemoryBus::peek_u8`, so it doesn't trigger breakpoints or watches.
+#[derive(Debug, Clone)]
+pub struct MemorySnapshot {
+    memory: Vec<u8>,
+    frame: u64,
+}
+
+impl Default for MemorySnapshot {
+    fn default() -> Self {
+        Self {
+            memory: vec![0xff; ADDRESS_SPACE_SIZE],
+            frame: 0,
+        }
+    }
+}
+
+impl MemorySnapshot {
+    /// Copies the current contents of the bus, outside of any lock
+    pub fn capture(memory_bus: &mut MemoryBus, frame: u64) -> Self {
+        let memory = (0..=u16::MAX).map(|a| memory_bus.peek_u8(a)).collect();
+        Self { memory, frame }
+    }
+
+    /// The frame the snapshot was taken at the end of, 0 if none was
+    /// taken yet
+    #[must_use]
+    pub fn frame(&self) -> u64 {
+        self.frame
+    }
+
+    #[must_use]
+    pub fn peek_u8(&self, address: u16) -> u8 {
+        self.memory[address as usize]
+    }
+
+    #[must_use]
+    pub fn peek_u16(&self, address: u16) -> u16 {
+        let byte1 = self.peek_u8(address);
+        let byte2 = self.peek_u8(address.wrapping_add(1));
+        (u16::from(byte2) << 8) | u16::from(byte1)
+    }
+
+    /// Copies `length` bytes starting at `address`, wrapping around the
+    /// end of the address space like the bus does
+    #[must_use]
+    pub fn peek_range(&self, address: u16, length: u16) -> Vec<u8> {
+        (0..length)
+            .map(|i| self.peek_u8(address.wrapping_add(i)))
+            .collect()
+    }
+}
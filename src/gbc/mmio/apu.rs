@@ -1,3 +1,7 @@
+use std::fmt;
+
+use crate::gbc::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
 #[derive(Debug, Default)]
 pub struct SoundChannel1 {
     pub sweep_control: u8,     // NR10
@@ -44,7 +48,183 @@ pub struct Sound {
     pub sound_on_off_control: u8, // NR52
 }
 
+/// Volume envelope settings from an NRx2 register
+#[derive(Debug, Clone, Copy)]
+pub struct Envelope {
+    pub initial_volume: u8,
+    pub increasing: bool,
+    // Steps of 1/64s between volume changes, 0 leaves the volume alone
+    pub pace: u8,
+}
+
+impl Envelope {
+    fn from_register(byte: u8) -> Self {
+        Self {
+            initial_volume: byte >> 4,
+            increasing: byte & 0x08 != 0,
+            pace: byte & 0x07,
+        }
+    }
+}
+
+impl fmt::Display for Envelope {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        if self.pace == 0 {
+            write!(f, "volume {:2}, constant", self.initial_volume)
+        } else {
+            write!(
+                f,
+                "volume {:2}, {} every {}/64s",
+                self.initial_volume,
+                if self.increasing { "up" } else { "down" },
+                self.pace
+            )
+        }
+    }
+}
+
+/// One channel's settings, decoded from its registers for the debugger
+#[derive(Debug, Clone)]
+pub struct ChannelStatus {
+    pub name: &'static str,
+    // Whether the channel's DAC is powered, without which it is silent
+    pub dac_enabled: bool,
+    pub frequency_hz: f64,
+    pub envelope: Option<Envelope>,
+    // Only the wave channel has a fixed volume, as a percentage
+    pub volume_percent: Option<u8>,
+    // Length timer in 1/256s ticks, and whether it stops the channel
+    pub length: u16,
+    pub length_enabled: bool,
+    // Duty cycle as a percentage, for the square wave channels
+    pub duty_percent: Option<f64>,
+}
+
+impl fmt::Display for ChannelStatus {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{:<8} DAC {:3} {:>9.1}Hz",
+            self.name,
+            if self.dac_enabled { "on" } else { "off" },
+            self.frequency_hz
+        )?;
+        if let Some(envelope) = &self.envelope {
+            write!(f, " {}", envelope)?;
+        }
+        if let Some(volume) = self.volume_percent {
+            write!(f, " volume {}%", volume)?;
+        }
+        write!(
+            f,
+            " length {}{}",
+            self.length,
+            if self.length_enabled { "" } else { " (off)" }
+        )?;
+        if let Some(duty) = self.duty_percent {
+            write!(f, " duty {}%", duty)?;
+        }
+        Ok(())
+    }
+}
+
+/// Snapshot of the sound hardware for the debugger
+#[derive(Debug, Clone)]
+pub struct SoundStatus {
+    pub powered: bool,
+    pub channels: [ChannelStatus; 4],
+    // None until the frame sequencer is emulated
+    pub frame_sequencer_step: Option<u8>,
+}
+
+fn square_channel_status(
+    name: &'static str,
+    sound_length_duty: u8,
+    volume_envelope: u8,
+    frequency_low: u8,
+    frequency_high: u8,
+) -> ChannelStatus {
+    let period = (u16::from(frequency_high & 0x7) << 8) | u16::from(frequency_low);
+    ChannelStatus {
+        name,
+        dac_enabled: volume_envelope & 0xf8 != 0,
+        frequency_hz: 131_072.0 / f64::from(2048 - period),
+        envelope: Some(Envelope::from_register(volume_envelope)),
+        volume_percent: None,
+        length: 64 - u16::from(sound_length_duty & 0x3f),
+        length_enabled: frequency_high & 0x40 != 0,
+        duty_percent: Some(match sound_length_duty >> 6 {
+            0 => 12.5,
+            1 => 25.0,
+            2 => 50.0,
+            _ => 75.0,
+        }),
+    }
+}
+
 impl Sound {
+    /// Decodes the registers of every channel
+    #[must_use]
+    pub fn status(&self) -> SoundStatus {
+        let wave = &self.digital_channel;
+        let wave_period =
+            (u16::from(wave.frequency_high & 0x7) << 8) | u16::from(wave.frequency_low);
+        let noise = &self.noise_channel;
+        // 262144Hz divided by the divisor (with 0 meaning 0.5) and shifted
+        let divisor = match noise.polynomial_counter & 0x7 {
+            0 => 0.5,
+            divisor => f64::from(divisor),
+        };
+        let noise_frequency =
+            262_144.0 / divisor / f64::from(1_u32 << (noise.polynomial_counter >> 4));
+
+        SoundStatus {
+            powered: self.sound_on_off_control & 0x80 != 0,
+            channels: [
+                square_channel_status(
+                    "Pulse 1",
+                    self.channel1.sound_length_duty,
+                    self.channel1.volume_envelope,
+                    self.channel1.frequency_low,
+                    self.channel1.frequency_high,
+                ),
+                square_channel_status(
+                    "Pulse 2",
+                    self.channel2.sound_length_duty,
+                    self.channel2.volume_envelope,
+                    self.channel2.frequency_low,
+                    self.channel2.frequency_high,
+                ),
+                ChannelStatus {
+                    name: "Wave",
+                    dac_enabled: wave.is_on,
+                    frequency_hz: 65_536.0 / f64::from(2048 - wave_period),
+                    envelope: None,
+                    volume_percent: Some(match (wave.volume >> 5) & 0x3 {
+                        0 => 0,
+                        1 => 100,
+                        2 => 50,
+                        _ => 25,
+                    }),
+                    length: 256 - u16::from(wave.length),
+                    length_enabled: wave.frequency_high & 0x40 != 0,
+                    duty_percent: None,
+                },
+                ChannelStatus {
+                    name: "Noise",
+                    dac_enabled: noise.volume & 0xf8 != 0,
+                    frequency_hz: noise_frequency,
+                    envelope: Some(Envelope::from_register(noise.volume)),
+                    volume_percent: None,
+                    length: 64 - u16::from(noise.length & 0x3f),
+                    length_enabled: noise.counter & 0x40 != 0,
+                    duty_percent: None,
+                },
+            ],
+            frame_sequencer_step: None,
+        }
+    }
+
     #[must_use]
     pub fn read_u8(&self, offset: u16) -> u8 {
         #![allow(clippy::match_same_arms)]
@@ -120,3 +300,33 @@ impl Sound {
         self.digital_channel.wave_ram[offset as usize] = byte;
     }
 }
+
+impl SaveState for Sound {
+    fn save_state(&self, writer: &mut StateWriter) {
+        // Every register is plain data, so reuse the register file layout
+        for offset in 0..=0x16 {
+            if offset == 0x3 || offset == 0x8 {
+                // write only registers read back as 0
+                continue;
+            }
+            writer.write_u8(self.read_u8(offset));
+        }
+        writer.write_u8(self.channel1.frequency_low);
+        writer.write_u8(self.channel2.frequency_low);
+        writer.write_bytes(&self.digital_channel.wave_ram);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        for offset in 0..=0x16 {
+            if offset == 0x3 || offset == 0x8 {
+                continue;
+            }
+            let byte = reader.read_u8()?;
+            self.write_u8(offset, byte);
+        }
+        self.channel1.frequency_low = reader.read_u8()?;
+        self.channel2.frequency_low = reader.read_u8()?;
+        reader.read_bytes(&mut self.digital_channel.wave_ram)?;
+        Ok(())
+    }
+}
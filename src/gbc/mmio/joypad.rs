@@ -1,9 +1,18 @@
+use crate::gbc::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 use crate::gbc::InputState;
 
+/// P1, the buttons are wired up as a 2x4 matrix. Pulling P14 low connects
+/// the direction buttons to the 4 input lines and P15 the action buttons,
+/// and a pressed button pulls its line low. With both selected, a line is
+/// low if either button on it is pressed.
+///
+/// Any input line going from high to low requests the joypad interrupt,
+/// whether a button was pressed or a line with one held was selected.
 #[derive(Debug)]
 pub struct Joypad {
-    input: u8, // (action << 4) | directions, a 0 means pressed
+    input: u8, // (directions << 4) | action, a 0 means pressed
     selected: u8,
+    interrupt_requested: bool,
 }
 
 impl Default for Joypad {
@@ -11,27 +20,54 @@ impl Default for Joypad {
         Self {
             input: 0xff,
             selected: 0,
+            interrupt_requested: false,
         }
     }
 }
 
 impl Joypad {
     pub fn write_u8(&mut self, byte: u8) {
+        let lines = self.input_lines();
         let byte = byte & 0x30; // mask out only the select bits;
         self.selected = byte;
+        self.check_falling_edges(lines);
     }
 
     #[must_use]
     pub fn read_u8(&self) -> u8 {
-        let key_selection = (self.selected & 0b00110000) >> 4;
-        match key_selection {
-            0 | 3 => 0b11000000 | self.selected | 0b1111, // No selection or both selected
-            1 => 0b11000000 | self.selected | (self.input & 0b1111), // Action buttons
-            2 => 0b11000000 | self.selected | ((self.input >> 4) & 0b1111), // direction buttons
-            _ => unreachable!()
+        0b11000000 | self.selected | self.input_lines()
+    }
+
+    /// The 4 input lines, as the selected buttons pull them
+    fn input_lines(&self) -> u8 {
+        let mut lines = 0b1111;
+        if self.selected & 0x10 == 0 {
+            lines &= self.input >> 4; // direction buttons
+        }
+        if self.selected & 0x20 == 0 {
+            lines &= self.input & 0b1111; // action buttons
+        }
+        lines
+    }
+
+    fn check_falling_edges(&mut self, previous_lines: u8) {
+        if previous_lines & !self.input_lines() != 0 {
+            self.interrupt_requested = true;
         }
     }
 
+    /// Whether a line went low since the last call
+    pub fn take_interrupt(&mut self) -> bool {
+        std::mem::take(&mut self.interrupt_requested)
+    }
+
+    /// Whether a pressed button pulls one of the selected lines low, which
+    /// is what wakes the CPU from STOP
+    #[must_use]
+    pub fn any_line_low(&self) -> bool {
+        self.read_u8() & 0b1111 != 0b1111
+    }
+
     pub fn set_input_state(&mut self, input_state: &InputState) {
         let mut joypad_state = 0u8;
         if !input_state.a_pressed {
@@ -58,6 +94,23 @@ impl Joypad {
         if !input_state.down_pressed {
             joypad_state |= 0x80;
         }
-        self.input = joypad_state
+        let lines = self.input_lines();
+        self.input = joypad_state;
+        self.check_falling_edges(lines);
+    }
+}
+
+impl SaveState for Joypad {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.input);
+        writer.write_u8(self.selected);
+        writer.write_bool(self.interrupt_requested);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        self.input = reader.read_u8()?;
+        self.selected = reader.read_u8()? & 0x30;
+        self.interrupt_requested = reader.version() >= 17 && reader.read_bool()?;
+        Ok(())
     }
 }
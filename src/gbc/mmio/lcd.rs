@@ -1,9 +1,17 @@
 use std::convert::From;
 
 use crate::gbc::ppu::{ColorIndex, TileAddressingMethod};
+use crate::gbc::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 use crate::gbc::utils::Flag;
 
-#[derive(Copy, Clone, Debug)]
+/// Dots (cycles at normal speed) the LCD spends on each line, hblank included
+pub const DOTS_PER_LINE: u64 = 456;
+/// 144 visible lines and 10 lines of vblank
+pub const DOTS_PER_FRAME: u64 = DOTS_PER_LINE * 154;
+/// Mode 3 with no scrolling, window or sprites to slow it down
+pub const MIN_MODE_3_LENGTH: i16 = 172;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
 pub enum Color {
     White,
     LightGray,
@@ -38,6 +46,23 @@ impl Color {
             Color::Black => 3,
         }
     }
+
+    /// The shade shown on screen, as red, green and blue
+    #[must_use]
+    pub fn to_rgb(self) -> [u8; 3] {
+        match self {
+            Color::White => [0xff, 0xff, 0xff],
+            Color::LightGray => [0xaa, 0xaa, 0xaa],
+            Color::DarkGray => [0x77, 0x77, 0x77],
+            Color::Black => [0x00, 0x00, 0x00],
+        }
+    }
+}
+
+impl From<Color> for u8 {
+    fn from(c: Color) -> Self {
+        c.to_u8()
+    }
 }
 
 #[derive(Copy, Clone, Debug, Default)]
@@ -283,6 +308,21 @@ impl From<Status> for u8 {
     }
 }
 
+/// The raw register values that decide how a scanline is colored and
+/// positioned, so frontends can recolor frames without working backwards
+/// from the pixels
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct ScanlineRegisters {
+    pub control: u8,
+    pub scroll_x: u8,
+    pub scroll_y: u8,
+    pub window_x: u8,
+    pub window_y: u8,
+    pub background_palette: u8,
+    pub object_palette_0: u8,
+    pub object_palette_1: u8,
+}
+
 #[derive(Debug)]
 pub struct Lcd {
     control: Control,
@@ -300,9 +340,19 @@ pub struct Lcd {
     lx: i16,
     window_was_rendered: bool,
     window_ly: u8,
-    last_stat_interrupt: bool,
+    // Set once LY has matched WY at the start of a line this frame, the
+    // window can't show up before that, however WY changes afterwards
+    window_y_triggered: bool,
+    // Where mode 3 ends on this line, None while the renderer is still
+    // working it out
+    mode_3_end: Option<i16>,
+    // Level of the STAT interrupt line, which all the enabled conditions
+    // share. An interrupt is only requested when it goes from low to high.
+    stat_line: bool,
     dma_running: bool,
     dma_low_byte: u8,
+    // Cycles into copying the current byte, each one takes a machine cycle
+    dma_cycles: u8,
     dot_clock: u64,
 }
 
@@ -324,9 +374,12 @@ impl Default for Lcd {
             lx: -80,
             window_was_rendered: false,
             window_ly: 0,
-            last_stat_interrupt: true,
+            window_y_triggered: false,
+            mode_3_end: Some(MIN_MODE_3_LENGTH),
+            stat_line: true,
             dma_running: false,
             dma_low_byte: 0,
+            dma_cycles: 0,
             dot_clock: 0,
         }
     }
@@ -340,7 +393,7 @@ impl Lcd {
             0x1 => self.status.into(),
             0x2 => self.scroll_y,
             0x3 => self.scroll_x,
-            0x4 => self.ly,
+            0x4 => self.get_ly_register(),
             0x5 => self.ly_compare,
             0x6 => self.dma_start_high_byte,
             0x7 => self.background_palette.to_u8(),
@@ -354,7 +407,12 @@ impl Lcd {
 
     pub fn write_u8(&mut self, offset: u16, byte: u8) {
         match offset {
-            0x0 => self.control = byte.into(),
+            0x0 => {
+                self.control = byte.into();
+                if !self.control.enable.to_bool() {
+                    self.turn_off();
+                }
+            }
             0x1 => self.status.update(byte),
             0x2 => self.scroll_y = byte,
             0x3 => self.scroll_x = byte,
@@ -363,6 +421,7 @@ impl Lcd {
             0x6 => {
                 self.dma_start_high_byte = byte;
                 self.dma_low_byte = 0;
+                self.dma_cycles = 0;
                 self.dma_running = true;
             }
             0x7 => self.background_palette = byte.into(),
@@ -374,14 +433,64 @@ impl Lcd {
         }
     }
 
+    /// Turning the LCD off stops it at LY 0, in mode 0, and turning it back
+    /// on starts over from the beginning of line 0
+    fn turn_off(&mut self) {
+        self.lx = -80;
+        self.ly = 0;
+        self.window_ly = 0;
+        self.window_was_rendered = false;
+        self.window_y_triggered = false;
+        self.mode_3_end = Some(MIN_MODE_3_LENGTH);
+        self.status.mode = LcdStatusMode::InHBlank;
+        self.stat_line = false;
+        self.dot_clock = 0;
+    }
+
+    /// What LY reads as. Line 153 only shows up for its first 4 dots, LY
+    /// reads 0 for the rest of it.
+    fn get_ly_register(&self) -> u8 {
+        if self.ly == 153 && self.lx >= -76 {
+            0
+        } else {
+            self.ly
+        }
+    }
+
+    /// The LY value the LY=LYC comparison sees, None for the 4 dots after
+    /// LY changes while it catches up. On line 153 it sees 153 for 4 dots,
+    /// then 0 from dot 12, and line 0 carries on without a gap.
+    fn get_ly_for_compare(&self) -> Option<u8> {
+        let dot = self.lx + 80;
+        match self.ly {
+            0 => Some(0),
+            153 if (4..8).contains(&dot) => Some(153),
+            153 if dot >= 12 => Some(0),
+            _ if dot < 4 || self.ly == 153 => None,
+            ly => Some(ly),
+        }
+    }
+
+    /// Whether any of the enabled STAT conditions hold. While one does,
+    /// another one becoming true doesn't request a new interrupt, which
+    /// some games rely on.
+    fn get_stat_line(&self) -> bool {
+        let mode = u8::from(self.status.mode);
+        (self.status.interrupt_on_hblank.to_bool() && mode == 0)
+            || (self.status.interrupt_on_vblank.to_bool() && mode == 1)
+            || (self.status.interrupt_on_oam.to_bool() && mode == 2)
+            || (self.status.interrupt_on_lyc.to_bool() && self.status.ly_equal_lyc.to_bool())
+    }
+
     #[must_use]
     pub fn tick(&mut self) -> (bool, bool) {
         let mut vblank_interrupt = false;
-        let mut stat_interrupt = false;
         if !self.control.enable.to_bool() {
-            self.lx = -80;
-            self.ly = 0;
-            return (vblank_interrupt, stat_interrupt);
+            self.turn_off();
+            return (vblank_interrupt, false);
+        }
+        if self.lx == -80 && self.ly == self.window_y {
+            self.window_y_triggered = true;
         }
         self.dot_clock += 1;
 
@@ -389,41 +498,30 @@ impl Lcd {
         if self.lx > 375 {
             self.ly += 1;
             self.lx = -80;
+            self.mode_3_end = Some(MIN_MODE_3_LENGTH);
 
             if self.window_was_rendered {
                 self.window_was_rendered = false;
                 self.window_ly += 1;
             }
 
-            if self.status.interrupt_on_vblank.to_bool() && self.ly == 144 {
-                stat_interrupt = true;
-            }
-            self.status.ly_equal_lyc = (self.ly == self.ly_compare).into();
-            // TODO OAM stat interrupt
-            if self.status.interrupt_on_lyc.to_bool() && self.status.ly_equal_lyc.to_bool() {
-                stat_interrupt = true;
-            }
-
             if self.ly == 144 {
                 vblank_interrupt = true;
             } else if self.ly == 154 {
                 self.window_ly = 0;
+                self.window_y_triggered = false;
                 self.ly = 0;
             }
         }
 
-        if self.status.interrupt_on_hblank.to_bool() && self.lx == 160 {
-            stat_interrupt = true;
-        }
-
-        // TODO Deal with modes
-
-        // only interrupt on rising edge
-        let should_stat_interrupt = !self.last_stat_interrupt && stat_interrupt;
-        self.last_stat_interrupt = stat_interrupt;
+        self.status.mode = self.mode_at_position().into();
+        self.status.ly_equal_lyc = (self.get_ly_for_compare() == Some(self.ly_compare)).into();
+        let stat_line = self.get_stat_line();
+        let should_stat_interrupt = !self.stat_line && stat_line;
+        self.stat_line = stat_line;
 
         if vblank_interrupt {
-            if self.dot_clock != 70224 {
+            if self.dot_clock != DOTS_PER_FRAME {
                 println!(
                     "ERROR: vblank timing incorrect! Took {} dots",
                     self.dot_clock
@@ -439,6 +537,8 @@ impl Lcd {
         self.control.enable.to_bool()
     }
 
+    /// The line being drawn, which unlike the LY register stays 153 for
+    /// all of the last line
     #[must_use]
     pub fn get_ly(&self) -> u8 {
         self.ly
@@ -449,6 +549,60 @@ impl Lcd {
         self.lx
     }
 
+    /// The mode in STAT, which is also what decides whether the CPU can
+    /// get at VRAM and OAM
+    #[must_use]
+    pub fn get_mode(&self) -> u8 {
+        self.status.mode.into()
+    }
+
+    /// The mode for the current position. Mode 3 is taken to be its
+    /// shortest length of 172 dots unless the renderer says otherwise.
+    fn mode_at_position(&self) -> u8 {
+        if !self.control.enable.to_bool() {
+            0
+        } else if self.ly >= 144 {
+            1
+        } else if self.lx < 0 {
+            2
+        } else if self.mode_3_end.filter(|&end| self.lx >= end).is_none() {
+            3
+        } else {
+            0
+        }
+    }
+
+    /// Lets a renderer that models mode 3's length report where it ends on
+    /// this line, None meaning it is still drawing
+    pub fn set_mode_3_end(&mut self, end: Option<i16>) {
+        self.mode_3_end = end;
+    }
+
+    /// The row of OAM (8 bytes, 2 sprites) the PPU is reading during mode 2,
+    /// it gets through one every 4 dots
+    #[must_use]
+    pub fn get_oam_scan_row(&self) -> Option<u16> {
+        if !self.control.enable.to_bool() || self.ly >= 144 || self.lx >= 0 {
+            return None;
+        }
+        #[allow(clippy::cast_sign_loss)]
+        Some(((self.lx + 80) / 4) as u16)
+    }
+
+    #[must_use]
+    pub fn get_scanline_registers(&self) -> ScanlineRegisters {
+        ScanlineRegisters {
+            control: self.control.into(),
+            scroll_x: self.scroll_x,
+            scroll_y: self.scroll_y,
+            window_x: self.window_x,
+            window_y: self.window_y,
+            background_palette: self.background_palette.to_u8(),
+            object_palette_0: self.object_palette_0.to_u8(),
+            object_palette_1: self.object_pallete_1.to_u8(),
+        }
+    }
+
     #[must_use]
     pub fn get_scroll_offsets(&self) -> (u8, u8) {
         (self.scroll_x, self.scroll_y)
@@ -504,6 +658,13 @@ impl Lcd {
         self.control.window_tile_map
     }
 
+    #[must_use]
+    pub fn get_window_y_triggered(&self) -> bool {
+        self.window_y_triggered
+    }
+
+    /// The window's own line counter, which only moves on lines the window
+    /// was drawn on
     #[must_use]
     pub fn get_window_line(&self) -> u8 {
         self.window_ly
@@ -529,6 +690,20 @@ impl Lcd {
         }
     }
 
+    /// Counts `cycles` CPU cycles towards the transfer and returns how many
+    /// bytes are due to be copied. Copying all 160 takes 160 machine cycles.
+    pub fn advance_dma(&mut self, cycles: u64) -> u64 {
+        if !self.dma_running {
+            return 0;
+        }
+        let cycles = u64::from(self.dma_cycles) + cycles;
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            self.dma_cycles = (cycles % 4) as u8;
+        }
+        cycles / 4
+    }
+
     pub fn tick_dma(&mut self) {
         self.dma_low_byte += 1;
         if self.dma_low_byte == 0xa0 {
@@ -536,3 +711,74 @@ impl Lcd {
         }
     }
 }
+
+impl SaveState for Lcd {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.control.into());
+        writer.write_u8(self.status.into());
+        writer.write_u8(self.scroll_y);
+        writer.write_u8(self.scroll_x);
+        writer.write_u8(self.ly);
+        writer.write_u8(self.ly_compare);
+        writer.write_u8(self.dma_start_high_byte);
+        writer.write_u8(self.background_palette.to_u8());
+        writer.write_u8(self.object_palette_0.to_u8());
+        writer.write_u8(self.object_pallete_1.to_u8());
+        writer.write_u8(self.window_y);
+        writer.write_u8(self.window_x);
+        #[allow(clippy::cast_sign_loss)]
+        writer.write_u16(self.lx as u16);
+        writer.write_bool(self.window_was_rendered);
+        writer.write_u8(self.window_ly);
+        writer.write_bool(self.window_y_triggered);
+        writer.write_bool(self.mode_3_end.is_some());
+        #[allow(clippy::cast_sign_loss)]
+        writer.write_u16(self.mode_3_end.unwrap_or(0) as u16);
+        writer.write_bool(self.stat_line);
+        writer.write_bool(self.dma_running);
+        writer.write_u8(self.dma_low_byte);
+        writer.write_u8(self.dma_cycles);
+        writer.write_u64(self.dot_clock);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        self.control = reader.read_u8()?.into();
+        self.status = reader.read_u8()?.into();
+        self.scroll_y = reader.read_u8()?;
+        self.scroll_x = reader.read_u8()?;
+        self.ly = reader.read_u8()?;
+        self.ly_compare = reader.read_u8()?;
+        self.dma_start_high_byte = reader.read_u8()?;
+        self.background_palette = reader.read_u8()?.into();
+        self.object_palette_0 = reader.read_u8()?.into();
+        self.object_pallete_1 = reader.read_u8()?.into();
+        self.window_y = reader.read_u8()?;
+        self.window_x = reader.read_u8()?;
+        #[allow(clippy::cast_possible_wrap)]
+        {
+            self.lx = reader.read_u16()? as i16;
+        }
+        self.window_was_rendered = reader.read_bool()?;
+        self.window_ly = reader.read_u8()?;
+        self.window_y_triggered = reader.version() >= 7 && reader.read_bool()?;
+        self.mode_3_end = None;
+        if reader.version() >= 9 {
+            let has_mode_3_end = reader.read_bool()?;
+            #[allow(clippy::cast_possible_wrap)]
+            let mode_3_end = reader.read_u16()? as i16;
+            if has_mode_3_end {
+                self.mode_3_end = Some(mode_3_end);
+            }
+        }
+        self.stat_line = reader.read_bool()?;
+        self.dma_running = reader.read_bool()?;
+        self.dma_low_byte = reader.read_u8()?;
+        self.dma_cycles = if reader.version() >= 14 {
+            reader.read_u8()? % 4
+        } else {
+            0
+        };
+        self.dot_clock = reader.read_u64()?;
+        Ok(())
+    }
+}
@@ -1,7 +1,139 @@
+use std::fmt::{self, Display};
 use std::{fs::File, io::Write};
 
+use crate::gbc::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
 const CYCLES_PER_BYTE: u64 = 4_194_304 / 8192; // CPU speed (4194304 HZ) divided by internal clock (8192 HZ)
 
+/// Whatever is plugged into the other end of the serial port. Once the
+/// Game Boy has clocked a whole byte out, the device hands back the byte
+/// that was shifted in at the same time.
+///
+/// Devices are not part of savestates, and stay plugged in across resets.
+pub trait SerialDevice: fmt::Debug + Send {
+    /// Short name, shown by the debugger
+    fn name(&self) -> &str;
+
+    /// Trades the byte the Game Boy sent for the one it receives
+    fn exchange(&mut self, out: u8) -> u8;
+
+    /// Whether the device drives the clock itself, like another Game Boy.
+    /// If not, transfers waiting on the external clock run at the internal
+    /// clock's rate, as if the device answered straight away.
+    fn drives_clock(&self) -> bool {
+        false
+    }
+
+    /// Called while a transfer waits on the external clock, with the byte
+    /// the Game Boy is sending. Returns the byte received once the device
+    /// has clocked a transfer. Only called if `drives_clock` is true.
+    fn receive(&mut self, _out: u8) -> Option<u8> {
+        None
+    }
+
+    /// Called when the Game Boy is reset. The device stays plugged in.
+    fn reset(&mut self) {}
+
+    /// Whether the device already writes every byte it is sent to stdout,
+    /// so the serial console doesn't print them a second time
+    fn prints_to_stdout(&self) -> bool {
+        false
+    }
+}
+
+/// Nothing plugged in, the line floats high so every byte received is 0xff
+#[derive(Debug, Default)]
+pub struct Disconnected;
+
+impl SerialDevice for Disconnected {
+    fn name(&self) -> &str {
+        "disconnected"
+    }
+
+    fn exchange(&mut self, _out: u8) -> u8 {
+        0xff
+    }
+}
+
+/// The output wired back to the input, every byte comes straight back
+#[derive(Debug, Default)]
+pub struct Loopback;
+
+impl SerialDevice for Loopback {
+    fn name(&self) -> &str {
+        "loopback"
+    }
+
+    fn exchange(&mut self, out: u8) -> u8 {
+        out
+    }
+}
+
+/// Writes every byte sent to stdout as it is, like test ROMs that print
+/// their results over serial expect. Receives 0xff like nothing is there.
+#[derive(Debug, Default)]
+pub struct StdoutPrinter;
+
+impl SerialDevice for StdoutPrinter {
+    fn name(&self) -> &str {
+        "stdout"
+    }
+
+    fn exchange(&mut self, out: u8) -> u8 {
+        let mut stdout = std::io::stdout();
+        // Nowhere to report a closed stdout to, the game carries on
+        let _ = stdout.write_all(&[out]).and_then(|()| stdout.flush());
+        0xff
+    }
+
+    fn prints_to_stdout(&self) -> bool {
+        true
+    }
+}
+
+/// The serial devices that can be picked by name
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum SerialDeviceKind {
+    #[default]
+    Disconnected,
+    Loopback,
+    Stdout,
+}
+
+impl std::str::FromStr for SerialDeviceKind {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "disconnected" | "none" => Ok(Self::Disconnected),
+            "loopback" => Ok(Self::Loopback),
+            "stdout" | "printer" => Ok(Self::Stdout),
+            _ => Err(format!("Unknown serial device {}", s)),
+        }
+    }
+}
+
+impl Display for SerialDeviceKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Disconnected => write!(f, "disconnected"),
+            Self::Loopback => write!(f, "loopback"),
+            Self::Stdout => write!(f, "stdout"),
+        }
+    }
+}
+
+impl SerialDeviceKind {
+    #[must_use]
+    pub fn create(self) -> Box<dyn SerialDevice> {
+        match self {
+            Self::Disconnected => Box::new(Disconnected),
+            Self::Loopback => Box::new(Loopback),
+            Self::Stdout => Box::new(StdoutPrinter),
+        }
+    }
+}
+
 #[derive(Debug)]
 pub struct Comms {
     pub io_register: u8,
@@ -10,6 +142,8 @@ pub struct Comms {
     bits_written: u8,
     out_byte: u8,
     out_file: File,
+    sent_bytes: Vec<u8>,
+    device: Box<dyn SerialDevice>,
 }
 
 impl Comms {
@@ -30,10 +164,54 @@ impl Comms {
         }
     }
 
+    pub fn set_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.device = device;
+    }
+
+    /// Unplugs the device, leaving the port disconnected
+    pub fn take_device(&mut self) -> Box<dyn SerialDevice> {
+        std::mem::replace(&mut self.device, Box::new(Disconnected))
+    }
+
+    #[must_use]
+    pub fn get_device(&self) -> &dyn SerialDevice {
+        self.device.as_ref()
+    }
+
+    /// Returns every byte that finished shifting out since the last call
+    pub fn take_sent_bytes(&mut self) -> Vec<u8> {
+        std::mem::take(&mut self.sent_bytes)
+    }
+
+    fn finish_transfer(&mut self, out: u8, received: u8) {
+        self.out_file
+            .write_all(&[out])
+            .expect("can't write to file!");
+        self.out_file.flush().expect("Could not flush");
+        self.sent_bytes.push(out);
+        self.io_register = received;
+        self.out_byte = 0;
+        self.bits_written = 0;
+        self.control &= 0x7f;
+    }
+
     pub fn tick(&mut self, cycles: u64) -> bool {
-        // Can't use external clock, so check both
-        // enable and clock source
-        if self.control & 0x81 == 0 {
+        // A device that drives the clock says when a transfer started on
+        // the external clock is done
+        if self.control & 0x81 == 0x80 && self.device.drives_clock() {
+            self.ticks = 0;
+            return match self.device.receive(self.io_register) {
+                Some(received) => {
+                    self.finish_transfer(self.io_register, received);
+                    true
+                }
+                None => false,
+            };
+        }
+
+        // Otherwise the external clock is treated like the internal one, so
+        // only whether a transfer is running matters
+        if self.control & 0x80 == 0 {
             self.ticks = 0;
             return false;
         }
@@ -45,21 +223,19 @@ impl Comms {
             self.out_byte = (self.out_byte << 1) | (self.io_register >> 7);
             self.io_register <<= 1;
 
-            // Per https://gbdev.io/pandocs/Interrupt_Sources.html#int-58---serial-interrupt
-            // 0xff should be received if there is no other game boy present
+            // Ones are shifted in until the device answers with the whole
+            // byte at the end
             self.io_register |= 1;
 
             self.bits_written += 1;
             if self.bits_written == 8 {
-                self.out_file
-                    .write_all(&[self.out_byte])
-                    .expect("can't write to file!");
-                self.out_file.flush().expect("Could not flush");
-                self.out_byte = 0;
-                self.bits_written = 0;
-                self.control &= 0x7f;
+                let received = self.device.exchange(self.out_byte);
+                self.finish_transfer(self.out_byte, received);
 
+                // Nothing shifts until the next transfer is started
+                self.ticks = 0;
                 interrupt_required = true;
+                break;
             } else {
                 interrupt_required = false;
             }
@@ -77,6 +253,76 @@ impl Default for Comms {
             bits_written: 0,
             out_byte: 0,
             out_file: File::create("serial_out.dat").expect("Can't open serial_out.dat!"),
+            sent_bytes: Vec::new(),
+            device: Box::new(Disconnected),
         }
     }
 }
+
+impl SaveState for Comms {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.io_register);
+        writer.write_u8(self.control);
+        writer.write_u64(self.ticks);
+        writer.write_u8(self.bits_written);
+        writer.write_u8(self.out_byte);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        self.io_register = reader.read_u8()?;
+        self.control = reader.read_u8()?;
+        self.ticks = reader.read_u64()?;
+        self.bits_written = reader.read_u8()?;
+        self.out_byte = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const SB: u16 = 0;
+    const SC: u16 = 1;
+
+    // Sends `byte` on the internal clock, returning what came back and
+    // whether the transfer finished with an interrupt
+    fn transfer(comms: &mut Comms, byte: u8) -> (u8, bool) {
+        comms.write_u8(SB, byte);
+        comms.write_u8(SC, 0x81);
+        assert!(!comms.tick(4 * CYCLES_PER_BYTE));
+        assert_eq!(comms.read_u8(SC) & 0x80, 0x80);
+        let interrupt = comms.tick(4 * CYCLES_PER_BYTE + 1);
+        (comms.read_u8(SB), interrupt)
+    }
+
+    #[test]
+    fn test_disconnected_receives_ff() {
+        let mut comms = Comms::default();
+        assert_eq!(transfer(&mut comms, 0x5a), (0xff, true));
+        assert_eq!(comms.read_u8(SC) & 0x80, 0);
+        assert_eq!(comms.take_sent_bytes(), vec![0x5a]);
+    }
+
+    #[test]
+    fn test_loopback_returns_byte_sent() {
+        let mut comms = Comms::default();
+        comms.set_device(Box::new(Loopback));
+        assert_eq!(transfer(&mut comms, 0x5a), (0x5a, true));
+        assert_eq!(transfer(&mut comms, 0x00), (0x00, true));
+        assert_eq!(comms.take_sent_bytes(), vec![0x5a, 0x00]);
+    }
+
+    #[test]
+    fn test_nothing_shifts_after_transfer() {
+        let mut comms = Comms::default();
+        comms.set_device(Box::new(Loopback));
+        comms.write_u8(SB, 0x5a);
+        comms.write_u8(SC, 0x81);
+        assert!(comms.tick(20 * CYCLES_PER_BYTE));
+        assert_eq!(comms.read_u8(SB), 0x5a);
+        assert!(!comms.tick(20 * CYCLES_PER_BYTE));
+        assert_eq!(comms.read_u8(SB), 0x5a);
+        assert_eq!(comms.take_sent_bytes(), vec![0x5a]);
+    }
+}
@@ -1,27 +1,42 @@
-// TODO support other clock speeds
-const DIV_TICK_RATE: u64 = 16384;
-const CPU_FREQ: u64 = 4_194_304;
-const CYCLES_PER_DIV_TICK: u64 = CPU_FREQ / DIV_TICK_RATE; // 256
+use crate::gbc::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
 
+// The bit of the system counter each TAC clock select follows. TIMA counts
+// when the selected bit (ANDed with the enable bit) goes from 1 to 0.
+const TIMER_INPUT_BITS: [u16; 4] = [9, 3, 5, 7];
+
+// TIMA reads as 0 for a machine cycle after overflowing, before it's
+// reloaded from TMA and the interrupt is requested
+const RELOAD_DELAY: u8 = 4;
+
+/// DIV, TIMA, TMA and TAC. DIV is the top half of a 16 bit counter that
+/// goes up every cycle, and TIMA is clocked by a falling edge detector
+/// watching one of that counter's bits, so writing DIV or TAC can bump
+/// TIMA just like the counter moving on does.
+///
+/// Writing TIMA while the reload is pending cancels it, interrupt and all.
+/// For the machine cycle after the reload, TIMA ignores writes and TMA
+/// writes go through to it as well.
 #[derive(Debug)]
 pub struct Timer {
-    divider: u8,
+    system_counter: u16,
     timer_counter: u8,
     timer_reset_value: u8,
     control: u8,
-    div_cycles_counter: u64,
-    tma_cycles_counter: u64,
+    // Cycles until TIMA is reloaded after overflowing
+    reload_cycles: Option<u8>,
+    // Cycles left in the machine cycle TIMA was reloaded in
+    reloaded_cycles: u8,
 }
 
 impl Default for Timer {
     fn default() -> Self {
         Self {
-            divider: 0,
+            system_counter: 0,
             timer_counter: 0,
             timer_reset_value: 0,
             control: 0,
-            div_cycles_counter: 0,
-            tma_cycles_counter: 0,
+            reload_cycles: None,
+            reloaded_cycles: 0,
         }
     }
 }
@@ -30,7 +45,8 @@ impl Timer {
     #[must_use]
     pub fn read_u8(&self, offset: u16) -> u8 {
         match offset {
-            0 => self.divider,
+            #[allow(clippy::cast_possible_truncation)]
+            0 => (self.system_counter >> 8) as u8,
             1 => self.timer_counter,
             2 => self.timer_reset_value,
             3 => self.control,
@@ -39,49 +55,127 @@ impl Timer {
     }
 
     pub fn write_u8(&mut self, offset: u16, byte: u8) {
+        let timer_input = self.timer_input();
         match offset {
-            0 => self.divider = 0, // writes reset the divider to zero
-            1 => self.timer_counter = byte,
-            2 => self.timer_reset_value = byte,
+            0 => self.system_counter = 0, // writes reset the whole counter
+            1 => {
+                if self.reloaded_cycles == 0 {
+                    self.reload_cycles = None;
+                    self.timer_counter = byte;
+                }
+            }
+            2 => {
+                self.timer_reset_value = byte;
+                if self.reloaded_cycles > 0 {
+                    self.timer_counter = byte;
+                }
+            }
             3 => self.control = byte & 0x7, // 3 bit register
             _ => unreachable!(),
         }
+        // Resetting the counter or switching to another bit (or turning the
+        // timer off) can look like a falling edge
+        if timer_input && !self.timer_input() {
+            self.increment_timer_counter();
+        }
     }
 
-    pub fn tick(&mut self, cycles: u64) -> bool {
-        // check if div should be ticked
-        self.div_cycles_counter += cycles;
-        if self.div_cycles_counter > CYCLES_PER_DIV_TICK {
-            self.divider = self.divider.wrapping_add(1);
-            self.div_cycles_counter -= CYCLES_PER_DIV_TICK;
-        }
+    /// What the falling edge detector sees
+    fn timer_input(&self) -> bool {
+        let bit = TIMER_INPUT_BITS[usize::from(self.control & 0x3)];
+        self.control & 0x4 != 0 && self.system_counter & (1 << bit) != 0
+    }
 
-        // check if TIMA should be ticked
-        if self.control & 0x4 == 0 {
-            false
+    fn increment_timer_counter(&mut self) {
+        if let Some(v) = self.timer_counter.checked_add(1) {
+            self.timer_counter = v;
         } else {
-            self.tma_cycles_counter += cycles;
-            let cycles_per_tma_tick = match self.control & 0x3 {
-                0 => 1024,
-                1 => 16,
-                2 => 64,
-                3 => 256,
-                _ => unreachable!(),
-            };
-            if self.tma_cycles_counter > cycles_per_tma_tick {
-                self.tma_cycles_counter -= cycles_per_tma_tick;
-                let res = self.timer_counter.checked_add(1);
-                #[allow(clippy::option_if_let_else)]
-                if let Some(v) = res {
-                    self.timer_counter = v;
-                    false
-                } else {
+            self.timer_counter = 0;
+            self.reload_cycles = Some(RELOAD_DELAY);
+        }
+    }
+
+    /// Returns whether TIMA was reloaded from TMA, which requests the timer
+    /// interrupt
+    pub fn tick(&mut self, cycles: u64) -> bool {
+        let mut interrupt = false;
+        for _ in 0..cycles {
+            self.reloaded_cycles = self.reloaded_cycles.saturating_sub(1);
+            if let Some(reload_cycles) = self.reload_cycles {
+                if reload_cycles == 1 {
+                    self.reload_cycles = None;
+                    self.reloaded_cycles = RELOAD_DELAY;
                     self.timer_counter = self.timer_reset_value;
-                    true
+                    interrupt = true;
+                } else {
+                    self.reload_cycles = Some(reload_cycles - 1);
                 }
-            } else {
-                false
             }
+
+            let timer_input = self.timer_input();
+            self.system_counter = self.system_counter.wrapping_add(1);
+            if timer_input && !self.timer_input() {
+                self.increment_timer_counter();
+            }
+        }
+        interrupt
+    }
+}
+
+impl SaveState for Timer {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u16(self.system_counter);
+        writer.write_u8(self.timer_counter);
+        writer.write_u8(self.timer_reset_value);
+        writer.write_u8(self.control);
+        writer.write_bool(self.reload_cycles.is_some());
+        writer.write_u8(self.reload_cycles.unwrap_or(0));
+        writer.write_u8(self.reloaded_cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        if reader.version() < 15 {
+            return self.load_state_v14(reader);
+        }
+        self.system_counter = reader.read_u16()?;
+        self.timer_counter = reader.read_u8()?;
+        self.timer_reset_value = reader.read_u8()?;
+        self.control = reader.read_u8()? & 0x7;
+        if reader.version() < 16 {
+            // An overflow whose interrupt hadn't been requested yet, TIMA
+            // was already reloaded by then
+            self.reload_cycles = if reader.read_bool()? { Some(1) } else { None };
+            self.reloaded_cycles = 0;
+            return Ok(());
+        }
+        let reload_pending = reader.read_bool()?;
+        let reload_cycles = reader.read_u8()?.clamp(1, RELOAD_DELAY);
+        self.reload_cycles = if reload_pending {
+            Some(reload_cycles)
+        } else {
+            None
+        };
+        self.reloaded_cycles = reader.read_u8()?.min(RELOAD_DELAY);
+        Ok(())
+    }
+}
+
+impl Timer {
+    // Before version 15 DIV was kept on its own, with separate cycle
+    // counters for it and TIMA
+    fn load_state_v14(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        let divider = reader.read_u8()?;
+        self.timer_counter = reader.read_u8()?;
+        self.timer_reset_value = reader.read_u8()?;
+        self.control = reader.read_u8()? & 0x7;
+        let div_cycles = reader.read_u64()?;
+        let _tima_cycles = reader.read_u64()?;
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            self.system_counter = u16::from(divider) << 8 | div_cycles.min(0xff) as u16;
         }
+        self.reload_cycles = None;
+        self.reloaded_cycles = 0;
+        Ok(())
     }
 }
@@ -1,24 +1,78 @@
 pub mod cartridge;
 pub mod cpu;
 pub mod debug;
+pub mod events;
+pub mod link;
+pub mod mapper;
 pub mod memory_bus;
+pub mod memory_snapshot;
 pub mod mmio;
+pub mod movie;
+pub mod peripheral;
+pub mod png;
 pub mod ppu;
+pub mod rtc;
+pub mod savestate;
 pub mod utils;
 
+use std::collections::VecDeque;
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{Sender, SyncSender};
 use std::sync::{Arc, Mutex};
 use std::time::{Duration, Instant};
-use std::{io, path::Path};
+use std::{
+    fs,
+    io::{self, IsTerminal, Write},
+    path::{Path, PathBuf},
+};
 
-use cartridge::Cartridge;
-use cpu::Cpu;
-use debug::{AccessType, BreakReason, Breakpoint};
+use cartridge::{Cartridge, CartridgeError};
+use cpu::{
+    instruction::{Instruction, Opcode},
+    trace::TraceComparisonStop,
+    CallFrame, Cpu, CpuError, CpuRegister, HistoryEntry, InterruptDispatch, Registers,
+};
+use debug::{
+    AccessType, BreakReason, Breakpoint, FrameBudget, MbcWrite, Profile, RngWatch, RomPatch,
+    Savepoint, SymbolTable, WatchCondition,
+};
+use events::{Event, EventBus, Subscriber};
 use memory_bus::MemoryBus;
-use mmio::lcd::Color;
+use memory_snapshot::MemorySnapshot;
+use mmio::apu::SoundStatus;
+use mmio::lcd::Palette;
+use mmio::serial::SerialDevice;
+use movie::{Movie, MovieStart};
+use peripheral::Peripheral;
+use savestate::{FileHeader, FileKind, SaveState, SaveStateError, StateReader, StateWriter};
 
 use self::cpu::InterruptRequest;
-use self::ppu::{Tile, TileAddressingMethod};
+use self::ppu::{
+    FinishedFrame, Frame, FrameMetadata, PpuDebugState, Renderer, RgbaLayout, ScannedSprite,
+    Sprite, Tile, TileAddressingMethod,
+};
+
+/// Close enough to the real 59.7 frames per second for save intervals
+const FRAMES_PER_SECOND: u64 = 60;
+
+/// With `SaveRamFlush::OnChange`, save RAM is written once the game has
+/// left it alone for this many frames, so a save that takes several frames
+/// to write isn't flushed half done
+const SAVE_RAM_SETTLE_FRAMES: u64 = 30;
+
+/// Games that write save RAM every frame never settle, so they are flushed
+/// at least this often
+const SAVE_RAM_MAX_UNFLUSHED_FRAMES: u64 = 5 * FRAMES_PER_SECOND;
+
+/// RET, RETI and the conditional RETs, for noticing when `finish` is done
+const RETURN_OPCODES: [u8; 6] = [0xc9, 0xd9, 0xc0, 0xc8, 0xd0, 0xd8];
+
+/// How many cartridge control writes are kept for `banks history`
+const MBC_WRITE_HISTORY_LENGTH: usize = 256;
+/// Frames of vblank budget reports kept, 10 seconds worth
+const FRAME_BUDGET_HISTORY_LENGTH: usize = 600;
+/// The coverage flag for ROM bytes that were part of an executed instruction
+const COVERAGE_EXECUTED: u8 = 0x01;
 
 #[derive(Debug, Default)]
 pub struct InputState{
@@ -32,42 +86,302 @@ pub struct InputState{
     pub b_pressed: bool,
 }
 
+impl InputState {
+    /// Packs the buttons into a byte, a set bit meaning pressed, in the
+    /// joypad's order: A, B, Select, Start, Right, Left, Up, Down
+    #[must_use]
+    pub fn to_byte(&self) -> u8 {
+        [
+            self.a_pressed,
+            self.b_pressed,
+            self.select_pressed,
+            self.start_pressed,
+            self.right_pressed,
+            self.left_pressed,
+            self.up_pressed,
+            self.down_pressed,
+        ]
+        .iter()
+        .rev()
+        .fold(0, |byte, &pressed| (byte << 1) | u8::from(pressed))
+    }
+
+    #[must_use]
+    pub fn from_byte(byte: u8) -> Self {
+        let pressed = |bit: u8| byte & (1 << bit) != 0;
+        Self {
+            a_pressed: pressed(0),
+            b_pressed: pressed(1),
+            select_pressed: pressed(2),
+            start_pressed: pressed(3),
+            right_pressed: pressed(4),
+            left_pressed: pressed(5),
+            up_pressed: pressed(6),
+            down_pressed: pressed(7),
+        }
+    }
+
+    /// A+B+Start+Select, which many games treat as a soft reset
+    #[must_use]
+    pub fn soft_reset_combo_pressed(&self) -> bool {
+        self.a_pressed && self.b_pressed && self.start_pressed && self.select_pressed
+    }
+}
+
+/// What to do when the player holds A+B+Start+Select, games get to see it
+/// by default
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SoftResetCombo {
+    /// Let the game see the buttons and handle it itself
+    PassToGame,
+    /// Reset the whole emulated machine, for games that don't support it
+    ResetEmulator,
+}
+
+impl std::str::FromStr for SoftResetCombo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "pass" | "game" => Ok(Self::PassToGame),
+            "reset" | "emulator" => Ok(Self::ResetEmulator),
+            _ => Err(format!("Unknown soft reset combo action {}", s)),
+        }
+    }
+}
+
+/// When battery backed RAM (and the clock) is written to the .sav file
+/// while the game is running. It is always written on exit.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum SaveRamFlush {
+    /// Shortly after the game stops writing to it
+    OnChange,
+    /// Every so many seconds of emulated time, if it changed
+    EverySeconds(u64),
+    /// Only when the emulator exits
+    OnExit,
+}
+
+impl std::str::FromStr for SaveRamFlush {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "change" | "onchange" => Ok(Self::OnChange),
+            "exit" | "onexit" => Ok(Self::OnExit),
+            seconds => match seconds.trim_end_matches('s').parse() {
+                Ok(0) => Err("Save interval must be at least 1 second".to_string()),
+                Ok(seconds) => Ok(Self::EverySeconds(seconds)),
+                Err(_) => Err(format!(
+                    "Unknown save interval {}, expected change, exit or a number of seconds",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// A point in emulated time, counted from power on, to fast forward to
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum WarpTarget {
+    Frames(u64),
+    Seconds(u64),
+}
+
+impl std::str::FromStr for WarpTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.to_lowercase();
+        let parsed = if let Some(seconds) = s.strip_suffix('s') {
+            seconds.parse().map(Self::Seconds)
+        } else {
+            s.trim_end_matches('f').parse().map(Self::Frames)
+        };
+        parsed.map_err(|_| {
+            format!(
+                "Unknown warp target {}, expected a number of frames or seconds, e.g. 600 or 10s",
+                s
+            )
+        })
+    }
+}
+
+/// A movie being recorded to `path`, or played back
+#[derive(Debug)]
+enum MovieMode {
+    Recording { movie: Movie, path: PathBuf },
+    Playing { movie: Movie, next_frame: usize },
+}
+
+/// How long `run_until` runs for, counted from when it's called
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum StopCondition {
+    /// Until this many more frames have been finished, i.e. vblanks
+    Frames(u64),
+    Cycles(u64),
+    /// Instructions executed, a halted CPU waiting counts as one each step
+    Instructions(u64),
+}
+
+/// Where `step_over`, `finish` and `run_until` stop. The first two watch
+/// the stack pointer, so recursion and interrupts don't stop them early.
+#[derive(Copy, Clone, Debug)]
+enum StepTarget {
+    /// The PC reaching `address` with the stack back up to `stack_pointer`
+    ReturnAddress { address: u16, stack_pointer: u16 },
+    /// A return popping the stack above `stack_pointer`
+    Return { stack_pointer: u16 },
+    /// `frame_count` reaching the given value
+    Frame(u64),
+    /// `cycle_count` reaching the given value
+    Cycle(u64),
+    /// The given number of instructions still to run
+    Instructions(u64),
+}
+
 #[derive(Debug)]
 pub struct Gbc {
     running: Arc<AtomicBool>,
     turbo: bool,
-    framebuffer: Arc<Mutex<[[Color; 160]; 144]>>,
+    warp_target: Option<WarpTarget>,
+    frame_sender: SyncSender<FinishedFrame>,
     clock_speed: u64, // HZ
     cpu: Cpu,
     cycle_count: u64,
+    frame_count: u64,
+    save_ram_flush: SaveRamFlush,
+    frames_since_save_ram_flush: u64,
+    frames_since_save_ram_write: u64,
     breakpoints: Vec<Breakpoint>,
     break_reason: Option<Breakpoint>,
+    step_target: Option<StepTarget>,
     memory_bus: MemoryBus,
     input_state: Arc<Mutex<InputState>>,
+    frame_produced_probe: Option<Arc<Mutex<Option<Instant>>>>,
+    memory_snapshot: Option<Arc<Mutex<MemorySnapshot>>>,
+    frame_metadata: Option<Arc<Mutex<FrameMetadata>>>,
+    rumble_sender: Option<Sender<bool>>,
+    trace_mbc_writes: bool,
+    mbc_write_history: VecDeque<MbcWrite>,
+    frame_budget_history: VecDeque<FrameBudget>,
+    soft_reset_combo: SoftResetCombo,
+    soft_reset_combo_held: bool,
+    soft_reset_pending: bool,
+    savepoints: Vec<Savepoint>,
+    savepoint_directory: Option<PathBuf>,
+    patches: Vec<RomPatch>,
+    symbols: SymbolTable,
+    // Kept after profiling stops, until it starts again
+    profile: Option<Profile>,
+    profiling: bool,
+    // One byte per ROM byte, see `save_coverage`
+    coverage: Vec<u8>,
+    recording_coverage: bool,
+    // Why comparing against a reference trace stopped the emulator
+    trace_comparison_stop: Option<TraceComparisonStop>,
+    // Everything sent over serial, once capturing is turned on
+    serial_output: Option<String>,
+    echo_serial_output: bool,
+    movie: Option<MovieMode>,
+    // Set once a movie starts playing. Playback replaces the save RAM, so
+    // it isn't written to disk again until another cartridge is loaded.
+    movie_played: bool,
+    events: EventBus,
 }
 
 impl Gbc {
     pub fn new<P: AsRef<Path>>(
         rom_path: P,
-        framebuffer: Arc<Mutex<[[Color; 160]; 144]>>,
+        frame_sender: SyncSender<FinishedFrame>,
         running: Arc<AtomicBool>,
         turbo: bool,
         show_instructions: bool,
         input_state: Arc<Mutex<InputState>>,
-    ) -> io::Result<Self> {
+    ) -> Result<Self, CartridgeError> {
         let cartridge = Cartridge::new(rom_path)?;
-        Ok(Gbc {
+        Ok(Self::with_cartridge(
+            cartridge,
+            frame_sender,
             running,
             turbo,
-            framebuffer,
+            show_instructions,
+            input_state,
+        ))
+    }
+
+    /// Like `new`, but takes the ROM data directly instead of reading it
+    /// from a file
+    pub fn from_rom_bytes(
+        rom: &[u8],
+        frame_sender: SyncSender<FinishedFrame>,
+        running: Arc<AtomicBool>,
+        turbo: bool,
+        show_instructions: bool,
+        input_state: Arc<Mutex<InputState>>,
+    ) -> Result<Self, CartridgeError> {
+        let cartridge = Cartridge::from_bytes(rom)?;
+        Ok(Self::with_cartridge(
+            cartridge,
+            frame_sender,
+            running,
+            turbo,
+            show_instructions,
+            input_state,
+        ))
+    }
+
+    fn with_cartridge(
+        cartridge: Cartridge,
+        frame_sender: SyncSender<FinishedFrame>,
+        running: Arc<AtomicBool>,
+        turbo: bool,
+        show_instructions: bool,
+        input_state: Arc<Mutex<InputState>>,
+    ) -> Self {
+        let coverage = vec![0; cartridge.rom.len()];
+        Gbc {
+            running,
+            turbo,
+            warp_target: None,
+            frame_sender,
             clock_speed: 4_194_304, // TODO switch based on detected cartridge / config
             cpu: Cpu::new(show_instructions),
             cycle_count: 0,
+            frame_count: 0,
+            save_ram_flush: SaveRamFlush::EverySeconds(1),
+            frames_since_save_ram_flush: 0,
+            frames_since_save_ram_write: 0,
             breakpoints: Vec::new(),
             break_reason: None,
+            step_target: None,
             memory_bus: MemoryBus::new(cartridge),
             input_state,
-        })
+            frame_produced_probe: None,
+            memory_snapshot: None,
+            frame_metadata: None,
+            rumble_sender: None,
+            trace_mbc_writes: false,
+            mbc_write_history: VecDeque::with_capacity(MBC_WRITE_HISTORY_LENGTH),
+            frame_budget_history: VecDeque::new(),
+            soft_reset_combo: SoftResetCombo::PassToGame,
+            soft_reset_combo_held: false,
+            soft_reset_pending: false,
+            savepoints: Vec::new(),
+            savepoint_directory: None,
+            patches: Vec::new(),
+            symbols: SymbolTable::default(),
+            profile: None,
+            profiling: false,
+            coverage,
+            recording_coverage: false,
+            trace_comparison_stop: None,
+            serial_output: None,
+            echo_serial_output: false,
+            movie: None,
+            movie_played: false,
+            events: EventBus::default(),
+        }
     }
 
     #[must_use]
@@ -75,138 +389,1195 @@ impl Gbc {
         self.clock_speed
     }
 
-    pub fn add_breakpoint(
+    /// Number of machine cycles run since power on
+    #[must_use]
+    pub fn cycle_count(&self) -> u64 {
+        self.cycle_count
+    }
+
+    /// Number of frames the PPU has completed since power on
+    #[must_use]
+    pub fn frame_count(&self) -> u64 {
+        self.frame_count
+    }
+
+    /// How much time has passed inside the emulated machine since power on,
+    /// independent of how fast the host is actually running it
+    #[must_use]
+    pub fn emulated_time(&self) -> Duration {
+        let seconds = self.cycle_count / self.clock_speed;
+        let remaining_cycles = self.cycle_count % self.clock_speed;
+        #[allow(clippy::cast_possible_truncation)]
+        let nanos = (remaining_cycles * 1_000_000_000 / self.clock_speed) as u32;
+        Duration::new(seconds, nanos)
+    }
+
+    /// Runs at full speed without handing frames to the frontend until the
+    /// target is reached, then carries on at normal speed. Reaching the
+    /// target is checked once per frame.
+    pub fn warp_to(&mut self, target: WarpTarget) {
+        self.warp_target = Some(target);
+    }
+
+    #[must_use]
+    pub fn is_warping(&self) -> bool {
+        self.warp_target.is_some()
+    }
+
+    fn update_warp(&mut self) {
+        let reached = match self.warp_target {
+            Some(WarpTarget::Frames(frames)) => self.frame_count >= frames,
+            Some(WarpTarget::Seconds(seconds)) => self.emulated_time().as_secs() >= seconds,
+            None => false,
+        };
+        if reached {
+            self.warp_target = None;
+        }
+    }
+
+    /// Stamps the given slot with the current time whenever a frame is
+    /// handed to the frontend, for latency measurements
+    pub fn set_frame_produced_probe(&mut self, probe: Arc<Mutex<Option<Instant>>>) {
+        self.frame_produced_probe = Some(probe);
+    }
+
+    /// Fills in the registers each line of the framebuffer was drawn with
+    /// whenever a frame is handed to the frontend, for palette aware
+    /// colorization filters
+    pub fn set_frame_metadata(&mut self, metadata: Arc<Mutex<FrameMetadata>>) {
+        self.frame_metadata = Some(metadata);
+    }
+
+    /// Refreshes the given snapshot with the whole address space at the
+    /// start of every vertical blank, for debug UIs on other threads
+    pub fn set_memory_snapshot(&mut self, snapshot: Arc<Mutex<MemorySnapshot>>) {
+        self.memory_snapshot = Some(snapshot);
+    }
+
+    /// Sends the new motor state every time a rumble cartridge toggles it
+    pub fn set_rumble_sender(&mut self, sender: Sender<bool>) {
+        self.rumble_sender = Some(sender);
+    }
+
+    /// Calls `subscriber` with every event the emulator publishes, see
+    /// `Event`. Returns an id for `unsubscribe`.
+    pub fn subscribe(&mut self, subscriber: Subscriber) -> usize {
+        self.events.subscribe(subscriber)
+    }
+
+    pub fn unsubscribe(&mut self, id: usize) -> bool {
+        self.events.unsubscribe(id)
+    }
+
+    /// Plugs extra hardware into the bus, see `Peripheral`
+    pub fn add_peripheral(&mut self, peripheral: Box<dyn Peripheral>) {
+        self.memory_bus.add_peripheral(peripheral);
+    }
+
+    /// Plugs a device into the serial port in place of the one there, see
+    /// `SerialDevice`
+    pub fn set_serial_device(&mut self, device: Box<dyn SerialDevice>) {
+        self.memory_bus.serial.set_device(device);
+    }
+
+    #[must_use]
+    pub fn get_serial_device(&self) -> &dyn SerialDevice {
+        self.memory_bus.serial.get_device()
+    }
+
+    /// Starts collecting every byte sent over serial into `serial_output`,
+    /// which is how test ROMs like blargg's report their results. With
+    /// `echo` the bytes are also written to stdout as they are sent, unless
+    /// the serial device already prints them there.
+    pub fn capture_serial_output(&mut self, echo: bool) {
+        self.serial_output.get_or_insert_with(String::new);
+        self.echo_serial_output = echo;
+    }
+
+    /// What was sent over serial since capturing started, each byte as
+    /// the character with that code. Empty if not capturing.
+    #[must_use]
+    pub fn serial_output(&self) -> &str {
+        self.serial_output.as_deref().unwrap_or("")
+    }
+
+    /// Starts recording the buttons latched each frame, to be written to
+    /// `path` by `stop_recording_movie` or when the emulator is dropped.
+    /// Recording from power on, with no save RAM or clock to carry over,
+    /// starts the movie from power on; anywhere else it starts from a
+    /// savestate of the machine as it is now.
+    pub fn record_movie<P: AsRef<Path>>(&mut self, path: P) {
+        let cartridge = &self.memory_bus.cartridge;
+        let blank_save_ram = cartridge.external_ram.iter().all(|&b| b == 0);
+        let has_clock = cartridge.cartridge_type.has_rtc();
+        let start = if self.cycle_count == 0 && blank_save_ram && !has_clock {
+            MovieStart::PowerOn
+        } else {
+            MovieStart::SaveState(self.save_state_bytes())
+        };
+        self.movie = Some(MovieMode::Recording {
+            movie: Movie::new(self.rom_identity(), start),
+            path: path.as_ref().to_owned(),
+        });
+    }
+
+    /// Writes out the movie being recorded, if there is one, and stops
+    /// recording
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be written
+    pub fn stop_recording_movie(&mut self) -> Result<(), SaveStateError> {
+        if let Some(MovieMode::Recording { movie, path }) = &self.movie {
+            movie.save(path)?;
+            self.movie = None;
+        }
+        Ok(())
+    }
+
+    /// Puts the machine where the movie starts and feeds it the recorded
+    /// buttons instead of the frontend's, one set per frame. Once the
+    /// movie runs out the frontend's input takes over again. Save RAM isn't
+    /// written to disk from then on, even after the movie runs out, so
+    /// replaying can't clobber a save.
+    ///
+    /// # Errors
+    /// Returns an error if the movie was made with another ROM or its
+    /// savestate can't be loaded
+    pub fn play_movie(&mut self, movie: Movie) -> Result<(), SaveStateError> {
+        let expected = self.rom_identity();
+        if movie.rom_identity != expected {
+            return Err(SaveStateError::RomMismatch {
+                expected,
+                found: movie.rom_identity,
+            });
+        }
+        match &movie.start {
+            MovieStart::PowerOn => {
+                self.reset();
+                for byte in &mut self.memory_bus.cartridge.external_ram {
+                    *byte = 0;
+                }
+            }
+            MovieStart::SaveState(state) => self.load_state_bytes(state)?,
+        }
+        self.movie = Some(MovieMode::Playing {
+            movie,
+            next_frame: 0,
+        });
+        self.movie_played = true;
+        Ok(())
+    }
+
+    #[must_use]
+    pub fn is_playing_movie(&self) -> bool {
+        matches!(self.movie, Some(MovieMode::Playing { .. }))
+    }
+
+    /// The buttons the movie being played has for this frame. Playback
+    /// stops when it runs out.
+    fn next_movie_input(&mut self) -> Option<InputState> {
+        let (movie, next_frame) = match &mut self.movie {
+            Some(MovieMode::Playing { movie, next_frame }) => (movie, next_frame),
+            _ => return None,
+        };
+        let input = movie.get_frame(*next_frame);
+        if input.is_some() {
+            *next_frame += 1;
+        } else {
+            let frames = movie.frames.len();
+            self.movie = None;
+            self.events.publish(&Event::MovieFinished { frames });
+        }
+        input
+    }
+
+    pub fn set_soft_reset_combo(&mut self, action: SoftResetCombo) {
+        self.soft_reset_combo = action;
+    }
+
+    /// Emulates the DMG bug where 16 bit INC/DEC of a register pointing at
+    /// OAM during mode 2 corrupts it. Only some test ROMs depend on this.
+    pub fn set_emulate_oam_corruption(&mut self, enabled: bool) {
+        self.memory_bus.emulate_oam_corruption = enabled;
+    }
+
+    /// Switches to the slower pixel FIFO renderer, for games that depend on
+    /// how long mode 3 takes
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.memory_bus.ppu.set_renderer(renderer);
+    }
+
+    /// Has the PPU draw frames as RGBA bytes too, in the layout of the
+    /// frontend's textures, so they can be copied in without converting
+    pub fn set_rgba_layout(&mut self, layout: RgbaLayout) {
+        self.memory_bus.ppu.set_rgba_layout(Some(layout));
+    }
+
+    /// Draws lines all at once when nothing changes in the middle of them,
+    /// which is on by default. Turning it off draws every line dot by dot,
+    /// for checking the two agree.
+    pub fn set_batch_scanlines(&mut self, batch: bool) {
+        self.memory_bus.ppu.set_batch_scanlines(batch);
+    }
+
+    /// Prints every write to the cartridge's bank controller as it happens
+    pub fn set_trace_mbc_writes(&mut self, trace: bool) {
+        self.trace_mbc_writes = trace;
+    }
+
+    /// The most recent cartridge control writes, oldest first
+    #[must_use]
+    pub fn get_mbc_write_history(&self) -> &VecDeque<MbcWrite> {
+        &self.mbc_write_history
+    }
+
+    fn record_mbc_writes(&mut self, pc: u16) {
+        for mut write in self.memory_bus.take_mbc_writes() {
+            write.pc = pc;
+            write.cycle = self.cycle_count;
+            if self.trace_mbc_writes {
+                println!("MBC: {}", write);
+            }
+            if self.mbc_write_history.len() == MBC_WRITE_HISTORY_LENGTH {
+                self.mbc_write_history.pop_front();
+            }
+            self.mbc_write_history.push_back(write);
+            if self.events.has_subscribers() {
+                self.events.publish(&Event::BankSwitch(write));
+            }
+        }
+    }
+
+    /// Turns the vblank budget analyzer on or off, which reports where the
+    /// CPU's time went in each frame
+    pub fn set_track_frame_budget(&mut self, track: bool) {
+        self.memory_bus.frame_budget = if track {
+            Some(FrameBudget::new(self.frame_count))
+        } else {
+            None
+        };
+        self.frame_budget_history.clear();
+    }
+
+    #[must_use]
+    pub fn is_tracking_frame_budget(&self) -> bool {
+        self.memory_bus.frame_budget.is_some()
+    }
+
+    /// Reports for the most recent complete frames, oldest first
+    #[must_use]
+    pub fn get_frame_budget_history(&self) -> &VecDeque<FrameBudget> {
+        &self.frame_budget_history
+    }
+
+    fn record_frame_budget(&mut self, cpu_was_idle: bool, in_vblank: bool, cycles: u64) {
+        if let Some(budget) = &mut self.memory_bus.frame_budget {
+            if cpu_was_idle {
+                budget.idle_cycles += cycles;
+            } else if in_vblank {
+                budget.vblank_cycles += cycles;
+            } else {
+                budget.active_cycles += cycles;
+            }
+        }
+    }
+
+    /// Captures a savestate named after `label` and the new value whenever
+    /// the byte at `address` changes
+    pub fn add_savepoint(&mut self, address: u16, label: String) {
+        self.remove_savepoint(address);
+        self.memory_bus.add_write_watch(address);
+        self.savepoints.push(Savepoint::new(address, label));
+    }
+
+    pub fn remove_savepoint(&mut self, address: u16) -> bool {
+        let count = self.savepoints.len();
+        self.savepoints.retain(|s| s.address != address);
+        self.memory_bus.remove_write_watch(address);
+        self.savepoints.len() != count
+    }
+
+    #[must_use]
+    pub fn get_savepoints(&self) -> &[Savepoint] {
+        &self.savepoints[..]
+    }
+
+    /// Where savepoint states are written, defaults to a `<rom>.savepoints`
+    /// directory next to the ROM
+    pub fn set_savepoint_directory(&mut self, directory: PathBuf) {
+        self.savepoint_directory = Some(directory);
+    }
+
+    fn get_savepoint_directory(&self) -> Option<PathBuf> {
+        self.savepoint_directory.clone().or_else(|| {
+            self.memory_bus
+                .cartridge
+                .rom_path
+                .as_ref()
+                .map(|path| path.with_extension("savepoints"))
+        })
+    }
+
+    // The watched bytes may hold anything after a reset or a loaded state
+    fn forget_savepoint_values(&mut self) {
+        for savepoint in &mut self.savepoints {
+            savepoint.last_value = None;
+        }
+    }
+
+    fn check_savepoints(&mut self) {
+        for (address, value) in self.memory_bus.take_watched_writes() {
+            let index = match self.savepoints.iter().position(|s| s.address == address) {
+                Some(index) => index,
+                None => continue,
+            };
+            if self.savepoints[index].last_value == Some(value) {
+                continue;
+            }
+            self.savepoints[index].last_value = Some(value);
+
+            let directory = match self.get_savepoint_directory() {
+                Some(directory) => directory,
+                None => {
+                    eprintln!("No savepoint directory set, not capturing savepoint");
+                    continue;
+                }
+            };
+            let path = directory.join(self.savepoints[index].file_name(value));
+            let result = fs::create_dir_all(&directory)
+                .map_err(SaveStateError::from)
+                .and_then(|_| self.save_state(&path));
+            match result {
+                Ok(()) => {
+                    self.savepoints[index].captured += 1;
+                    println!("Captured savepoint {}", path.display());
+                }
+                Err(e) => eprintln!("Error capturing savepoint {}: {}", path.display(), e),
+            }
+        }
+    }
+
+    /// Adds a ROM patch, replacing any with the same name, and applies it
+    /// if it is enabled. Fails if the ROM doesn't contain the original bytes.
+    pub fn add_patch(&mut self, patch: RomPatch) -> Result<(), String> {
+        self.remove_patch(&patch.name);
+        let found = self
+            .memory_bus
+            .cartridge
+            .read_rom_bytes(patch.bank, patch.address, patch.original.len())
+            .map(<[u8]>::to_vec);
+        if found.as_ref() != Some(&patch.original) {
+            return Err(format!(
+                "Patch {} doesn't match the ROM at {:03x}:{:04x}",
+                patch.name, patch.bank, patch.address
+            ));
+        }
+        if patch.enabled {
+            self.memory_bus
+                .cartridge
+                .patch_rom(patch.bank, patch.address, &patch.replacement);
+        }
+        self.patches.push(patch);
+        Ok(())
+    }
+
+    /// Removes a ROM patch, putting the original bytes back
+    pub fn remove_patch(&mut self, name: &str) -> bool {
+        if !self.set_patch_enabled(name, false) {
+            return false;
+        }
+        self.patches.retain(|p| p.name != name);
+        true
+    }
+
+    pub fn set_patch_enabled(&mut self, name: &str, enabled: bool) -> bool {
+        let patch = match self.patches.iter_mut().find(|p| p.name == name) {
+            Some(patch) => patch,
+            None => return false,
+        };
+        patch.enabled = enabled;
+        let bytes = if enabled {
+            &patch.replacement
+        } else {
+            &patch.original
+        };
+        self.memory_bus
+            .cartridge
+            .patch_rom(patch.bank, patch.address, bytes);
+        true
+    }
+
+    #[must_use]
+    pub fn get_patches(&self) -> &[RomPatch] {
+        &self.patches[..]
+    }
+
+    /// Replaces the labels with those in a symbol file, see `SymbolTable`
+    pub fn load_symbols<P: AsRef<Path>>(&mut self, path: P) -> io::Result<usize> {
+        self.symbols.load(path)
+    }
+
+    #[must_use]
+    pub fn get_symbols(&self) -> &SymbolTable {
+        &self.symbols
+    }
+
+    /// Where an address is in the ROM with the banks currently mapped, None
+    /// for addresses outside of it, including the boot ROM while it's mapped
+    fn rom_offset(&self, address: u16) -> Option<usize> {
+        if address >= 0x8000 || (address < 0x100 && self.memory_bus.is_boot_rom_mapped()) {
+            return None;
+        }
+        let bank = usize::from(self.bank_at(address));
+        let offset = if address < 0x4000 {
+            usize::from(address)
+        } else {
+            bank * 0x4000 + usize::from(address - 0x4000)
+        };
+        if offset < self.coverage.len() {
+            Some(offset)
+        } else {
+            None
+        }
+    }
+
+    /// Marks the bytes of the instruction just run at `pc`, which starts
+    /// at `offset` in the ROM, as executed
+    fn record_coverage(&mut self, pc: u16, offset: usize) {
+        if let Some(HistoryEntry::Instruction { instruction, .. }) = self.cpu.get_history().back() {
+            if instruction.address == pc {
+                let end = (offset + usize::from(instruction.size())).min(self.coverage.len());
+                for flags in &mut self.coverage[offset..end] {
+                    *flags |= COVERAGE_EXECUTED;
+                }
+            }
+        }
+    }
+
+    /// Whether the instruction at an address in ROM has ever been run, None
+    /// for addresses outside of the ROM
+    #[must_use]
+    pub fn was_executed(&self, address: u16) -> Option<bool> {
+        self.rom_offset(address)
+            .map(|offset| self.coverage[offset] & COVERAGE_EXECUTED != 0)
+    }
+
+    /// How many bytes of the ROM have been run, and how big it is
+    #[must_use]
+    pub fn get_coverage_summary(&self) -> (usize, usize) {
+        let executed = self
+            .coverage
+            .iter()
+            .filter(|&&flags| flags & COVERAGE_EXECUTED != 0)
+            .count();
+        (executed, self.coverage.len())
+    }
+
+    /// Starts marking the ROM bytes of every instruction run. What was
+    /// recorded before is kept.
+    pub fn start_coverage(&mut self) {
+        self.recording_coverage = true;
+    }
+
+    pub fn stop_coverage(&mut self) {
+        self.recording_coverage = false;
+    }
+
+    #[must_use]
+    pub fn is_recording_coverage(&self) -> bool {
+        self.recording_coverage
+    }
+
+    pub fn clear_coverage(&mut self) {
+        self.coverage.iter_mut().for_each(|flags| *flags = 0);
+    }
+
+    /// Writes which ROM bytes have been run as one byte per ROM byte, with
+    /// bit 0 set for the bytes of every instruction executed, like the code
+    /// flag in the .cdl files other emulators write
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be written
+    pub fn save_coverage<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        fs::write(path, &self.coverage)
+    }
+
+    /// Starts counting the cycles spent at each address in `run`, throwing
+    /// away any earlier profile
+    pub fn start_profiling(&mut self) {
+        self.profile = Some(Profile::default());
+        self.profiling = true;
+    }
+
+    /// Stops profiling, keeping what was collected
+    pub fn stop_profiling(&mut self) {
+        self.profiling = false;
+    }
+
+    #[must_use]
+    pub fn is_profiling(&self) -> bool {
+        self.profiling
+    }
+
+    /// The current or last profile, if profiling was ever started
+    #[must_use]
+    pub fn get_profile(&self) -> Option<&Profile> {
+        self.profile.as_ref()
+    }
+
+    /// The bank mapped at an address, for looking up symbols
+    fn bank_at(&self, address: u16) -> u16 {
+        let cartridge = &self.memory_bus.cartridge;
+        match address {
+            0x4000..=0x7fff => cartridge.get_rom_bank_selected(),
+            0xa000..=0xbfff => u16::from(cartridge.get_ram_bank_selected()),
+            _ => 0,
+        }
+    }
+
+    /// Names an address by the closest symbol before it in the banks
+    /// currently mapped, like `Main::vblank_handler+0x12`
+    #[must_use]
+    pub fn describe_address(&self, address: u16) -> Option<String> {
+        self.symbols.describe(self.bank_at(address), address)
+    }
+
+    /// Starts counting reads of an address holding a game's RNG state
+    pub fn add_rng_watch(&mut self, address: u16) {
+        self.memory_bus.add_rng_watch(address);
+    }
+
+    pub fn remove_rng_watch(&mut self, address: u16) -> bool {
+        self.memory_bus.remove_rng_watch(address)
+    }
+
+    /// Pins the RNG value the game reads from a watched address, or
+    /// releases it with None
+    pub fn lock_rng_watch(&mut self, address: u16, value: Option<u8>) -> bool {
+        self.memory_bus.lock_rng_watch(address, value)
+    }
+
+    #[must_use]
+    pub fn get_rng_watches(&self) -> &[RngWatch] {
+        self.memory_bus.get_rng_watches()
+    }
+
+    pub fn add_breakpoint(
+        &mut self,
+        address: u16,
+        access_type: AccessType,
+        length: u16,
+        reason: BreakReason,
+    ) {
+        let bp = Breakpoint::new(address, access_type, length, reason);
+        self.breakpoints.push(bp);
+        self.sync_memory_breakpoints();
+    }
+
+    /// Adds an execute breakpoint that removes itself the first time it
+    /// stops the emulator
+    pub fn add_temporary_breakpoint(&mut self, address: u16) {
+        let mut bp = Breakpoint::new(address, AccessType::Execute, 1, BreakReason::User);
+        bp.temporary = true;
+        self.breakpoints.push(bp);
+    }
+
+    /// Removes a temporary breakpoint at `address` that hasn't been hit.
+    /// Returns false if there isn't one.
+    pub fn remove_temporary_breakpoint(&mut self, address: u16) -> bool {
+        match self
+            .breakpoints
+            .iter()
+            .rposition(|bp| bp.temporary && bp.address == address)
+        {
+            Some(index) => self.remove_breakpoint(index),
+            None => false,
+        }
+    }
+
+    /// Adds a read and/or write breakpoint that only stops the emulator
+    /// when the access matches `condition`
+    pub fn add_watchpoint(
         &mut self,
         address: u16,
         access_type: AccessType,
         length: u16,
-        reason: BreakReason,
+        condition: WatchCondition,
     ) {
-        let bp = Breakpoint::new(address, access_type, length, reason);
-        if access_type.on_execute() {
-            self.breakpoints.push(bp);
-        }
-        if access_type.on_read() || access_type.on_write() {
-            self.memory_bus.add_breakpoint(bp);
-        }
+        let bp = Breakpoint::new(address, access_type, length, BreakReason::User);
+        self.breakpoints.push(bp.with_condition(condition));
+        self.sync_memory_breakpoints();
     }
 
+    /// Every breakpoint, whatever it breaks on. Their positions are the
+    /// indices the other breakpoint functions take.
     #[must_use]
     pub fn list_breakpoints(&self) -> &[Breakpoint] {
         &self.breakpoints[..]
     }
 
-    pub fn remove_breakpoint(&mut self, index: usize) {
-        if index < self.breakpoints.len() {
-            self.breakpoints.remove(index);
-        } else {
-            println!("Unknown index {}", index);
+    /// Returns false if there is no breakpoint with that index. The ones
+    /// after it move down to fill the gap.
+    pub fn remove_breakpoint(&mut self, index: usize) -> bool {
+        if index >= self.breakpoints.len() {
+            return false;
+        }
+        self.breakpoints.remove(index);
+        self.sync_memory_breakpoints();
+        true
+    }
+
+    /// Returns false if there is no breakpoint with that index
+    pub fn set_breakpoint_enabled(&mut self, index: usize, enabled: bool) -> bool {
+        match self.breakpoints.get_mut(index) {
+            Some(bp) => bp.enabled = enabled,
+            None => return false,
+        }
+        self.sync_memory_breakpoints();
+        true
+    }
+
+    /// Lets the breakpoint's next `count` hits through without stopping.
+    /// Returns false if there is no breakpoint with that index.
+    pub fn set_breakpoint_ignore_count(&mut self, index: usize, count: u64) -> bool {
+        match self.breakpoints.get_mut(index) {
+            Some(bp) => {
+                bp.ignore_count = count;
+                true
+            }
+            None => false,
         }
     }
 
+    /// The memory bus checks reads and writes itself, so it keeps its own
+    /// copy of the enabled breakpoints that care about them
+    fn sync_memory_breakpoints(&mut self) {
+        let memory_breakpoints = self
+            .breakpoints
+            .iter()
+            .enumerate()
+            .filter(|(_, bp)| bp.enabled && (bp.access_type.on_read() || bp.access_type.on_write()))
+            .map(|(index, bp)| (index, *bp))
+            .collect();
+        self.memory_bus.set_breakpoints(memory_breakpoints);
+    }
+
     #[must_use]
     pub fn get_last_breakpoint(&self) -> Option<Breakpoint> {
         self.break_reason
     }
 
-    fn check_execute_breakpoints(&mut self) {
+    fn check_execute_breakpoints(&self) -> Option<usize> {
         let pc = self.cpu.get_program_counter();
-        for bp in &self.breakpoints {
-            if !bp.access_type.on_execute() {
-                continue;
-            }
-
-            if !bp.matches_address(pc) {
-                continue;
-            }
-
-            self.break_reason = Some(*bp);
-            return;
-        }
+        // What got the CPU to the PC, to tell an interrupt or RST reaching a
+        // handler from a jump there
+        let last = self.cpu.get_history().back();
+        let interrupt = match last {
+            Some(HistoryEntry::Interrupt(dispatch)) => dispatch.interrupt,
+            _ => None,
+        };
+        let rst = match last {
+            Some(HistoryEntry::Instruction {
+                instruction:
+                    Instruction {
+                        op: Opcode::Rst { vector },
+                        ..
+                    },
+                ..
+            }) => Some(*vector),
+            _ => None,
+        };
+        self.breakpoints.iter().position(|bp| {
+            bp.enabled
+                && bp.access_type.on_execute()
+                && bp.matches_address(pc)
+                && match bp.reason {
+                    BreakReason::User => true,
+                    BreakReason::Interrupt(number) => interrupt == Some(number),
+                    BreakReason::Rst(vector) => rst == Some(vector),
+                }
+        })
     }
 
     fn check_breakpoints(&mut self) {
-        self.check_execute_breakpoints();
-        if self.break_reason.is_none() {
-            self.break_reason = self.memory_bus.get_break_reason();
+        let memory_hit = self.memory_bus.take_breakpoint_hit();
+        if let Some(index) = self.check_execute_breakpoints().or(memory_hit) {
+            let bp = &mut self.breakpoints[index];
+            bp.hits += 1;
+            if bp.ignore_count > 0 {
+                bp.ignore_count -= 1;
+            } else if self.break_reason.is_none() {
+                self.break_reason = Some(*bp);
+                if bp.temporary {
+                    self.remove_breakpoint(index);
+                }
+            }
         }
-        if self.break_reason.is_some() {
+        if let Some(bp) = self.break_reason {
+            self.events.publish(&Event::BreakpointHit(bp));
             self.running.store(false, Ordering::Relaxed);
         }
     }
 
-    pub fn run(&mut self) -> (u64, bool) {
+    /// Runs until stopped, a breakpoint is hit or the CPU can't carry on.
+    /// Returns the cycles run and the error that stopped the CPU, if any.
+    pub fn run(&mut self) -> (u64, Option<CpuError>) {
         self.break_reason = None;
         self.running.store(true, Ordering::Relaxed);
         let mut cycles_in_this_run = 0;
-        let mut encountered_problem = false;
+        let mut error = None;
         let mut start = Instant::now();
         while self.running.load(Ordering::Relaxed) {
+            let pc = self.cpu.get_program_counter();
+            let bank = if self.profiling { self.bank_at(pc) } else { 0 };
             let cycles = match self.single_step() {
-                Some(cycles) => cycles,
-                None => {
-                    encountered_problem = true;
+                Ok(cycles) => cycles,
+                Err(e) => {
+                    error = Some(e);
                     self.running.store(false, Ordering::Relaxed);
                     break;
                 }
             };
             self.cycle_count += cycles;
             cycles_in_this_run += cycles;
-
-            {
-                let input_state = self.input_state.lock().unwrap();
-                self.memory_bus.joypad.set_input_state(&*input_state);
+            if self.profiling {
+                if let Some(profile) = &mut self.profile {
+                    profile.record(bank, pc, cycles);
+                }
             }
 
             self.check_breakpoints();
+            if self.step_target.is_some() {
+                self.check_step_target(pc);
+            }
+            // In double speed mode the CPU gets through twice the cycles in
+            // the same time
+            let real_cycles = if self.memory_bus.double_speed {
+                cycles / 2
+            } else {
+                cycles
+            };
             let desired_iteration_time =
-                Duration::from_nanos(cycles * (1_000_000_000_u64 / self.clock_speed));
+                Duration::from_nanos(real_cycles * (1_000_000_000_u64 / self.clock_speed));
             let next_cycle_time = start + desired_iteration_time;
-            if !self.turbo {
+            if !self.turbo && self.warp_target.is_none() {
                 while Instant::now() < next_cycle_time {}
             }
             start = Instant::now();
         }
-        (cycles_in_this_run, encountered_problem)
+        (cycles_in_this_run, error)
     }
 
-    pub fn single_step(&mut self) -> Option<u64> {
-        match self.cpu.single_step(&mut self.memory_bus) {
-            Some(cycles) => {
-                let interrupts = self.tick_hardware(cycles);
-                self.cpu
-                    .request_interrupts(&mut self.memory_bus, &interrupts);
-                Some(cycles)
+    /// Runs the next instruction, except that a call or RST is run until it
+    /// returns to the instruction after it. Breakpoints still stop it.
+    pub fn step_over(&mut self) -> (u64, Option<CpuError>) {
+        let pc = self.cpu.get_program_counter();
+        let insn = Cpu::get_instruction_at_address(&mut self.memory_bus, pc);
+        if !matches!(
+            insn.op,
+            Opcode::Call { .. } | Opcode::CallCond { .. } | Opcode::Rst { .. }
+        ) {
+            return match self.single_step() {
+                Ok(cycles) => {
+                    self.cycle_count += cycles;
+                    (cycles, None)
+                }
+                Err(e) => (0, Some(e)),
+            };
+        }
+        self.run_to(StepTarget::ReturnAddress {
+            address: pc.wrapping_add(u16::from(insn.size())),
+            stack_pointer: self.cpu.get_registers().sp,
+        })
+    }
+
+    /// Runs until the current function returns. Breakpoints still stop it.
+    pub fn finish(&mut self) -> (u64, Option<CpuError>) {
+        self.run_to(StepTarget::Return {
+            stack_pointer: self.cpu.get_registers().sp,
+        })
+    }
+
+    /// Runs until `condition` is met. Breakpoints still stop it.
+    pub fn run_until(&mut self, condition: StopCondition) -> (u64, Option<CpuError>) {
+        let target = match condition {
+            StopCondition::Frames(frames) => StepTarget::Frame(self.frame_count + frames),
+            StopCondition::Cycles(cycles) => StepTarget::Cycle(self.cycle_count + cycles),
+            StopCondition::Instructions(count) => StepTarget::Instructions(count),
+        };
+        self.run_to(target)
+    }
+
+    fn run_to(&mut self, target: StepTarget) -> (u64, Option<CpuError>) {
+        self.step_target = Some(target);
+        let result = self.run();
+        self.step_target = None;
+        result
+    }
+
+    /// Stops the emulator if the instruction at `previous_pc` got it to the
+    /// step target
+    fn check_step_target(&mut self, previous_pc: u16) {
+        let registers = self.cpu.get_registers();
+        let reached = match &mut self.step_target {
+            Some(StepTarget::ReturnAddress {
+                address,
+                stack_pointer,
+            }) => registers.pc == *address && registers.sp >= *stack_pointer,
+            Some(StepTarget::Return { stack_pointer }) => {
+                registers.sp > *stack_pointer
+                    && RETURN_OPCODES.contains(&self.memory_bus.peek_u8(previous_pc))
             }
-            None => None,
+            Some(StepTarget::Frame(frame)) => self.frame_count >= *frame,
+            Some(StepTarget::Cycle(cycle)) => self.cycle_count >= *cycle,
+            Some(StepTarget::Instructions(remaining)) => {
+                *remaining = remaining.saturating_sub(1);
+                *remaining == 0
+            }
+            None => false,
+        };
+        if reached {
+            self.step_target = None;
+            self.running.store(false, Ordering::Relaxed);
         }
     }
 
-    pub fn tick_hardware(&mut self, cycles: u64) -> InterruptRequest {
-        let mut interrupts = InterruptRequest {
-            serial: self.memory_bus.serial.tick(cycles).into(),
-            ..InterruptRequest::default()
+    pub fn single_step(&mut self) -> Result<u64, CpuError> {
+        let pc = self.cpu.get_program_counter();
+        let rom_offset = if self.recording_coverage {
+            self.rom_offset(pc)
+        } else {
+            None
         };
+        let cpu_was_idle = self.cpu.is_halted() || self.cpu.is_stopped();
+        let in_vblank = self.memory_bus.lcd.get_mode() == 1;
+        match self.cpu.single_step(&mut self.memory_bus) {
+            Ok(cycles) => {
+                if let Some(offset) = rom_offset {
+                    self.record_coverage(pc, offset);
+                }
+                if let Some(stop) = self.cpu.take_trace_comparison_stop() {
+                    self.trace_comparison_stop = Some(stop);
+                    self.running.store(false, Ordering::Relaxed);
+                }
+                self.record_mbc_writes(pc);
+                self.record_frame_budget(cpu_was_idle, in_vblank, cycles);
+                self.tick_hardware(cycles);
+                if !self.savepoints.is_empty() {
+                    self.check_savepoints();
+                }
+                if self.soft_reset_pending {
+                    self.soft_reset_pending = false;
+                    self.reset();
+                }
+                Ok(cycles)
+            }
+            Err(e) => {
+                // The bad opcode was still fetched, but the step never
+                // finished, so don't count that towards the next one
+                self.memory_bus.take_cycles_ticked();
+                Err(e)
+            }
+        }
+    }
+
+    /// Steps until the PC reaches `address`, taking at least one step.
+    /// Returns the cycles that took, or None if it didn't happen within
+    /// `cycle_limit` cycles. Breakpoints are not checked.
+    pub fn run_until_pc(
+        &mut self,
+        address: u16,
+        cycle_limit: u64,
+    ) -> Result<Option<u64>, CpuError> {
+        let mut cycles = 0;
+        loop {
+            let step_cycles = self.single_step()?;
+            self.cycle_count += step_cycles;
+            cycles += step_cycles;
+            if self.cpu.get_program_counter() == address {
+                return Ok(Some(cycles));
+            }
+            if cycles >= cycle_limit {
+                return Ok(None);
+            }
+        }
+    }
+
+    #[must_use]
+    pub fn get_program_counter(&self) -> u16 {
+        self.cpu.get_program_counter()
+    }
+
+    #[must_use]
+    pub fn get_registers(&self) -> Registers {
+        self.cpu.get_registers()
+    }
+
+    /// Logs every instruction to a file, see `Cpu::start_trace`
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be created
+    pub fn start_trace<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.cpu.start_trace(path)
+    }
+
+    pub fn stop_trace(&mut self) {
+        self.cpu.stop_trace();
+    }
+
+    #[must_use]
+    pub fn is_tracing(&self) -> bool {
+        self.cpu.is_tracing()
+    }
+
+    /// Stops the emulator at the first instruction whose trace line differs
+    /// from the reference log at `path`, see `Cpu::start_trace_comparison`
+    ///
+    /// # Errors
+    /// Returns an error if the file can't be opened
+    pub fn start_trace_comparison<P: AsRef<Path>>(&mut self, path: P) -> io::Result<()> {
+        self.trace_comparison_stop = None;
+        self.cpu.start_trace_comparison(path)
+    }
+
+    #[must_use]
+    pub fn is_comparing_trace(&self) -> bool {
+        self.cpu.is_comparing_trace()
+    }
+
+    /// Why the trace comparison stopped the emulator, if it did since this
+    /// was last called
+    pub fn take_trace_comparison_stop(&mut self) -> Option<TraceComparisonStop> {
+        self.trace_comparison_stop.take()
+    }
+
+    /// Changes a register while the emulator is paused, see
+    /// `Cpu::set_register`
+    pub fn set_register(&mut self, register: CpuRegister, value: u16) {
+        self.cpu.set_register(register, value);
+    }
+
+    /// Which interrupt was last serviced and how long getting to its handler
+    /// took
+    #[must_use]
+    pub fn get_last_interrupt_dispatch(&self) -> Option<InterruptDispatch> {
+        self.cpu.get_last_interrupt_dispatch()
+    }
+
+    /// The last few thousand instructions run and interrupts dispatched,
+    /// oldest first
+    #[must_use]
+    pub fn get_history(&self) -> &VecDeque<HistoryEntry> {
+        self.cpu.get_history()
+    }
+
+    /// The calls, RSTs and interrupts that haven't returned yet, outermost
+    /// first
+    #[must_use]
+    pub fn get_call_stack(&self) -> &[CallFrame] {
+        self.cpu.get_call_stack()
+    }
+
+    #[must_use]
+    pub fn get_sprites(&self) -> &[Sprite; 40] {
+        self.memory_bus.ppu.get_sprites()
+    }
+
+    /// The sprites the OAM scan picked for the current line
+    #[must_use]
+    pub fn get_sprites_this_line(&self) -> &[ScannedSprite] {
+        self.memory_bus.ppu.get_sprites_this_line()
+    }
+
+    #[must_use]
+    pub fn get_ppu_debug_state(&self) -> PpuDebugState {
+        self.memory_bus.ppu.debug_state(&self.memory_bus.lcd)
+    }
+
+    #[must_use]
+    pub fn is_double_speed(&self) -> bool {
+        self.memory_bus.double_speed
+    }
 
-        let lcd = &mut self.memory_bus.lcd;
-        let vblank_and_stat = self.memory_bus.ppu.tick(cycles, lcd);
-        interrupts.vblank = vblank_and_stat.0.into();
-        interrupts.stat = vblank_and_stat.1.into();
-        interrupts.timer = self.memory_bus.timer_control.tick(cycles).into();
+    /// Finishes a step of `cycles` cycles: ticks the hardware through
+    /// whatever part of the step the CPU didn't already tick through for
+    /// its memory accesses, then deals with what happened during the step.
+    /// Returns the interrupts raised, which are already requested in IF.
+    pub fn tick_hardware(&mut self, cycles: u64) -> InterruptRequest {
+        let remaining_cycles = cycles.saturating_sub(self.memory_bus.take_cycles_ticked());
+        let stopped = self.cpu.is_stopped();
+        if stopped {
+            // STOP freezes the LCD and timer. Nothing latches input while
+            // the LCD is frozen, so refresh it here to notice the button
+            // that wakes the CPU.
+            self.refresh_stopped_input();
+        }
+        self.memory_bus.tick(remaining_cycles, stopped);
+        // Those cycles were part of this step, the next one starts from 0
+        self.memory_bus.take_cycles_ticked();
+        let interrupts = self.memory_bus.take_ticked_interrupts();
 
-        self.memory_bus.run_dma(cycles);
+        let echo = self.echo_serial_output && !self.get_serial_device().prints_to_stdout();
+        for byte in self.memory_bus.serial.take_sent_bytes() {
+            if let Some(output) = &mut self.serial_output {
+                output.push(char::from(byte));
+                if echo {
+                    let mut stdout = std::io::stdout();
+                    let _ = stdout.write_all(&[byte]).and_then(|()| stdout.flush());
+                }
+            }
+            self.events.publish(&Event::SerialByte(byte));
+        }
+        if let Some(rumble) = self.memory_bus.cartridge.take_rumble_change() {
+            if let Some(sender) = &self.rumble_sender {
+                // The receiver going away just means nobody cares anymore
+                let _ = sender.send(rumble);
+            }
+        }
 
-        // Update framebuffer on vblank
         if interrupts.vblank.to_bool() {
-            let mut f = self.framebuffer.lock().unwrap();
-            *f = *self.memory_bus.ppu.get_current_framebuffer();
+            self.events.publish(&Event::VBlank);
+        }
+        // Update framebuffer and pick up the freshest host input once a
+        // frame, which keeps happening with blank frames while the LCD is off
+        if self.memory_bus.ppu.take_frame_ready() {
+            self.frame_count += 1;
+            if let Some(budget) = self.memory_bus.frame_budget.take() {
+                if self.frame_budget_history.len() == FRAME_BUDGET_HISTORY_LENGTH {
+                    self.frame_budget_history.pop_front();
+                }
+                self.frame_budget_history.push_back(budget);
+                self.memory_bus.frame_budget = Some(FrameBudget::new(self.frame_count));
+            }
+            self.autosave_save_ram();
+            self.latch_input();
+            self.update_warp();
+            if let Some(snapshot) = &self.memory_snapshot {
+                // Copy before taking the lock so readers only wait for the swap
+                let mut fresh = MemorySnapshot::capture(&mut self.memory_bus, self.frame_count);
+                std::mem::swap(&mut *snapshot.lock().unwrap(), &mut fresh);
+            }
+            if self.warp_target.is_some() {
+                // Nobody is watching yet
+                return interrupts;
+            }
+            // Stamp before sending so the frontend never gets a frame
+            // without its timestamp
+            if let Some(probe) = &self.frame_produced_probe {
+                *probe.lock().unwrap() = Some(Instant::now());
+            }
+            // A full channel means the frontend is behind, so skip this frame
+            // rather than queue up more, and it going away means nobody is
+            // watching
+            let _ = self
+                .frame_sender
+                .try_send(self.memory_bus.ppu.get_finished_frame_with_rgba());
+            if let Some(metadata) = &self.frame_metadata {
+                *metadata.lock().unwrap() = FrameMetadata {
+                    frame: self.frame_count,
+                    lines: *self.memory_bus.ppu.get_scanline_registers(),
+                };
+            }
+            self.events.publish(&Event::FrameComplete {
+                frame: self.frame_count,
+            });
         }
 
         interrupts
     }
 
     #[must_use]
-    pub fn get_current_framebuffer(&self) -> [[Color; 160]; 144] {
+    pub fn get_external_ram(&self) -> &[u8] {
+        &self.memory_bus.cartridge.external_ram
+    }
+
+    /// Copies `data` into the cartridge's external RAM, returning how many
+    /// bytes fit
+    pub fn import_external_ram(&mut self, data: &[u8]) -> usize {
+        self.memory_bus.cartridge.import_external_ram(data)
+    }
+
+    pub fn set_save_ram_flush(&mut self, save_ram_flush: SaveRamFlush) {
+        self.save_ram_flush = save_ram_flush;
+    }
+
+    /// Called once per frame to write out save RAM according to
+    /// `save_ram_flush`
+    fn autosave_save_ram(&mut self) {
+        self.frames_since_save_ram_flush += 1;
+        if self.memory_bus.cartridge.take_save_ram_written() {
+            self.frames_since_save_ram_write = 0;
+        } else {
+            self.frames_since_save_ram_write += 1;
+        }
+        // The clock doesn't need to be flushed periodically: the footer
+        // records when it was written, and loading catches up from there
+        let should_flush = match self.save_ram_flush {
+            SaveRamFlush::OnChange => {
+                self.frames_since_save_ram_write == SAVE_RAM_SETTLE_FRAMES
+                    || self.frames_since_save_ram_flush >= SAVE_RAM_MAX_UNFLUSHED_FRAMES
+            }
+            SaveRamFlush::EverySeconds(seconds) => {
+                self.frames_since_save_ram_flush >= seconds * FRAMES_PER_SECOND
+            }
+            SaveRamFlush::OnExit => false,
+        };
+        if should_flush {
+            self.frames_since_save_ram_flush = 0;
+            self.flush_save_ram(false);
+        }
+    }
+
+    /// Writes battery backed cartridge RAM to disk if it changed, or
+    /// unconditionally for cartridges with a clock if `include_clock` is set
+    pub fn flush_save_ram(&mut self, include_clock: bool) {
+        if self.movie_played {
+            return;
+        }
+        if let Err(e) = self.memory_bus.cartridge.flush_save_ram(include_clock) {
+            eprintln!("Error writing save RAM: {}", e);
+        }
+    }
+
+    /// Copies the frontend's input state into the joypad register. This is
+    /// done once per frame, right as vblank starts, since that is when games
+    /// read their input for the upcoming frame.
+    fn latch_input(&mut self) {
+        let movie_input = self.next_movie_input();
+        let live_input = self.input_state.lock().unwrap();
+        let input_state = movie_input.as_ref().unwrap_or(&*live_input);
+        if let Some(MovieMode::Recording { movie, .. }) = &mut self.movie {
+            movie.push_frame(input_state);
+        }
+        let combo_pressed = input_state.soft_reset_combo_pressed();
+        if self.soft_reset_combo == SoftResetCombo::ResetEmulator
+            && combo_pressed
+            && !self.soft_reset_combo_held
+        {
+            // Reset at the end of this step, not in the middle of ticking
+            // the hardware
+            self.soft_reset_pending = true;
+        }
+        self.soft_reset_combo_held = combo_pressed;
+        self.memory_bus.joypad.set_input_state(input_state);
+    }
+
+    /// Copies the input into the joypad register while STOP has the LCD
+    /// frozen. No frame starts, so a movie neither records nor advances;
+    /// playback shows the buttons of the frame it latches next, which is
+    /// what was held when the recording woke up.
+    fn refresh_stopped_input(&mut self) {
+        let movie_input = match &self.movie {
+            Some(MovieMode::Playing { movie, next_frame }) => movie.get_frame(*next_frame),
+            _ => None,
+        };
+        let live_input = self.input_state.lock().unwrap();
+        let input_state = movie_input.as_ref().unwrap_or(&*live_input);
+        self.memory_bus.joypad.set_input_state(input_state);
+    }
+
+    #[must_use]
+    pub fn get_current_framebuffer(&self) -> Frame {
         *self.memory_bus.ppu.get_current_framebuffer()
     }
 
@@ -225,12 +1596,14 @@ impl Gbc {
         );
         self.cpu.dump_state();
         println!("\tCycles run: {}", self.cycle_count);
+        println!("\tFrames run: {}", self.frame_count);
+        println!("\tEmulated time: {:.06}s", self.emulated_time().as_secs_f64());
         println!("\tBreakpoints: ");
         if self.breakpoints.is_empty() {
             println!("\t\tNone");
         } else {
             for bp in &self.breakpoints {
-                println!("\t\t{:04x} {} {}", bp.address, bp.access_type, bp.reason);
+                println!("\t\t{}", bp);
             }
         }
         print!("\tBreak Reason: ");
@@ -244,21 +1617,69 @@ impl Gbc {
     pub fn print_instructions(&mut self, address: Option<u16>, length: u16) {
         let mut address = address.map_or_else(|| self.cpu.get_program_counter(), |address| address);
 
-        for _ in 0..length {
+        for i in 0..length {
             let insn = Cpu::get_instruction_at_address(&mut self.memory_bus, address);
-            println!("{}", insn);
+            self.print_instruction(&insn, i == 0);
             address += u16::from(insn.size());
         }
     }
 
     pub fn print_next_instruction(&mut self) {
         let insn = self.cpu.get_next_instruction(&mut self.memory_bus);
-        println!("{}", insn);
+        self.print_instruction(&insn, true);
+    }
+
+    /// Prints an instruction under the label it starts at, if there is one,
+    /// or under the label it's in if `first` is set, naming where it jumps
+    fn print_instruction(&self, insn: &Instruction, first: bool) {
+        let bank = self.bank_at(insn.address);
+        match self.symbols.find_address(bank, insn.address) {
+            Some((symbol, 0)) => println!("{}:", symbol.name),
+            Some((symbol, offset)) if first => println!("{}+{:#x}:", symbol.name, offset),
+            _ => {}
+        }
+        let mut line = insn.to_string();
+        if let Some(name) = insn.jump_target().and_then(|t| self.describe_address(t)) {
+            line = format!("{} ; {}", line, name);
+        }
+        // Code that has run is green, so unexplored code stands out
+        let executed = self.was_executed(insn.address) == Some(true);
+        if executed && io::stdout().is_terminal() {
+            println!("\x1b[32m{}\x1b[0m", line);
+        } else {
+            println!("{}", line);
+        }
     }
 
+    /// Reads `length` bytes like `peek_memory`
     #[must_use]
     pub fn read_memory(&mut self, address: u16, length: u16) -> Vec<u8> {
-        self.memory_bus.read_mem(address, length)
+        (0..length)
+            .map(|offset| self.memory_bus.peek_u8(address.wrapping_add(offset)))
+            .collect()
+    }
+
+    /// Reads a byte without triggering watchpoints or any of a read's side
+    /// effects. VRAM and OAM are read whatever mode the PPU is in and DMA
+    /// doesn't get in the way, see `MemoryBus::peek_u8`.
+    #[must_use]
+    pub fn peek_memory(&mut self, address: u16) -> u8 {
+        self.memory_bus.peek_u8(address)
+    }
+
+    /// Writes through the bus as the CPU would, so IO registers react and
+    /// writes to ROM go to the MBC, but ignoring DMA and the PPU blocking
+    /// VRAM and OAM. Watchpoints aren't triggered.
+    pub fn write_memory(&mut self, address: u16, bytes: &[u8]) {
+        for (i, &byte) in bytes.iter().enumerate() {
+            #[allow(clippy::cast_possible_truncation)]
+            self.memory_bus.poke_u8(address.wrapping_add(i as u16), byte);
+        }
+    }
+
+    /// Patches the cartridge ROM, see `Cartridge::patch_rom`
+    pub fn patch_rom(&mut self, bank: u16, address: u16, bytes: &[u8]) -> bool {
+        self.memory_bus.cartridge.patch_rom(bank, address, bytes)
     }
 
     #[must_use]
@@ -266,6 +1687,11 @@ impl Gbc {
         &self.memory_bus.cartridge
     }
 
+    #[must_use]
+    pub fn get_sound_status(&self) -> SoundStatus {
+        self.memory_bus.sound.status()
+    }
+
     #[must_use]
     pub fn get_tile_map(&self, map_number: u8) -> Option<&[u8]> {
         match map_number {
@@ -280,9 +1706,350 @@ impl Gbc {
         self.memory_bus.ppu.video_ram.read_tile(tile_address)
     }
 
+    /// Draws an 8x8 tile into a row by row RGB image `width` pixels wide
+    fn draw_tile(
+        pixels: &mut [u8],
+        width: usize,
+        x: usize,
+        y: usize,
+        tile: &Tile,
+        palette: Palette,
+    ) {
+        for row in 0..8_u8 {
+            for column in 0..8_u8 {
+                let color = palette.get_color(&tile.get_color(column, row));
+                let i = ((y + usize::from(row)) * width + x + usize::from(column)) * 3;
+                pixels[i..i + 3].copy_from_slice(&color.to_rgb());
+            }
+        }
+    }
+
+    /// Renders the whole 256x256 pixel background map `map_number` as RGB
+    /// bytes, with the tile addressing mode and palette LCDC and BGP select
+    /// right now
+    #[must_use]
+    pub fn render_tile_map(&self, map_number: u8) -> Option<Vec<u8>> {
+        let map = self.get_tile_map(map_number)?;
+        let lcd = &self.memory_bus.lcd;
+        let palette = lcd.get_background_palette();
+        let addressing_mode = lcd.get_addressing_mode();
+        let mut pixels = vec![0; 256 * 256 * 3];
+        for (entry, &index) in map.iter().enumerate() {
+            let tile_address = match addressing_mode {
+                TileAddressingMethod::From8000(_) => TileAddressingMethod::From8000(index),
+                #[allow(clippy::cast_possible_wrap)]
+                TileAddressingMethod::From9000(_) => TileAddressingMethod::From9000(index as i8),
+            };
+            let tile = self.get_tile(tile_address);
+            let x = 8 * (entry % 32);
+            let y = 8 * (entry / 32);
+            Self::draw_tile(&mut pixels, 256, x, y, &tile, palette);
+        }
+        Some(pixels)
+    }
+
+    /// Renders all 384 tiles in VRAM, 16 to a row in address order, as a
+    /// 128x192 pixel RGB image using the current background palette
+    #[must_use]
+    pub fn render_tiles(&self) -> Vec<u8> {
+        let palette = self.memory_bus.lcd.get_background_palette();
+        let mut pixels = vec![0; 128 * 192 * 3];
+        for tile_number in 0..384_usize {
+            #[allow(clippy::cast_possible_truncation)]
+            let tile_address = if tile_number < 256 {
+                TileAddressingMethod::From8000(tile_number as u8)
+            } else {
+                TileAddressingMethod::From9000((tile_number - 256) as i8)
+            };
+            let tile = self.get_tile(tile_address);
+            let x = 8 * (tile_number % 16);
+            let y = 8 * (tile_number / 16);
+            Self::draw_tile(&mut pixels, 128, x, y, &tile, palette);
+        }
+        pixels
+    }
+
+    /// Describes the loaded ROM well enough to tell savestates apart
+    #[must_use]
+    pub fn rom_identity(&self) -> String {
+        let cartridge = &self.memory_bus.cartridge;
+        format!("{} ({:04x})", cartridge.title, cartridge.global_checksum)
+    }
+
+    /// The complete machine state in the savestate file format, for
+    /// keeping in memory, e.g. as a rewind snapshot or a movie's start
+    #[must_use]
+    pub fn save_state_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        FileHeader::new(FileKind::SaveState, self.rom_identity()).write(&mut writer);
+        self.cpu.save_state(&mut writer);
+        self.memory_bus.save_state(&mut writer);
+        writer.write_u64(self.cycle_count);
+        writer.write_u64(self.frame_count);
+        writer.into_inner()
+    }
+
+    /// Writes the complete machine state to the given file
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be written
+    pub fn save_state<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SaveStateError> {
+        fs::write(&path, self.save_state_bytes())?;
+        self.events.publish(&Event::SavestateTaken(path.as_ref().to_owned()));
+        Ok(())
+    }
+
+    /// Restores the machine state from a file written by `save_state`
+    ///
+    /// # Errors
+    /// Returns an error if the file cannot be read, is not a savestate, was
+    /// made by an incompatible version, or belongs to a different ROM. The
+    /// machine may be partially overwritten if the file is corrupt.
+    pub fn load_state<P: AsRef<Path>>(&mut self, path: P) -> Result<(), SaveStateError> {
+        let data = fs::read(path)?;
+        self.load_state_bytes(&data)
+    }
+
+    /// Restores the machine state from bytes made by `save_state_bytes`
+    ///
+    /// # Errors
+    /// The same as `load_state`, apart from file errors
+    pub fn load_state_bytes(&mut self, data: &[u8]) -> Result<(), SaveStateError> {
+        let mut reader = StateReader::new(data);
+        let header = FileHeader::read(FileKind::SaveState, &mut reader)?;
+        let expected = self.rom_identity();
+        if header.rom_identity != expected {
+            return Err(SaveStateError::RomMismatch {
+                expected,
+                found: header.rom_identity,
+            });
+        }
+
+        self.cpu.load_state(&mut reader)?;
+        self.memory_bus.load_state(&mut reader)?;
+        self.cycle_count = reader.read_u64()?;
+        self.frame_count = reader.read_u64()?;
+        if !reader.is_empty() {
+            return Err(SaveStateError::Invalid("trailing data"));
+        }
+        self.forget_savepoint_values();
+        Ok(())
+    }
+
+    /// Switches to a different game, as if the cartridge was swapped with
+    /// the power off. The old game's save RAM is written out first. Things
+    /// tied to the old game, like savepoints, patches and RNG watches, are
+    /// dropped, breakpoints are kept.
+    ///
+    /// # Errors
+    /// Returns an error if the new ROM can't be loaded, in which case the
+    /// old game keeps running
+    pub fn load_rom<P: AsRef<Path>>(&mut self, rom_path: P) -> Result<(), CartridgeError> {
+        let cartridge = Cartridge::new(rom_path)?;
+        self.replace_cartridge(cartridge);
+        Ok(())
+    }
+
+    /// Like `load_rom`, but takes the ROM data directly
+    ///
+    /// # Errors
+    /// Returns an error if the ROM can't be loaded, in which case the old
+    /// game keeps running
+    pub fn load_rom_bytes(&mut self, rom: &[u8]) -> Result<(), CartridgeError> {
+        let cartridge = Cartridge::from_bytes(rom)?;
+        self.replace_cartridge(cartridge);
+        Ok(())
+    }
+
+    fn replace_cartridge(&mut self, cartridge: Cartridge) {
+        self.flush_save_ram(true);
+        self.memory_bus.replace_cartridge(cartridge);
+        self.coverage = vec![0; self.memory_bus.cartridge.rom.len()];
+        self.movie_played = false;
+        self.patches.clear();
+        self.savepoints.clear();
+        self.savepoint_directory = None;
+        self.reset();
+    }
+
     pub fn reset(&mut self) {
         self.cycle_count = 0;
+        self.frame_count = 0;
+        self.frames_since_save_ram_flush = 0;
+        self.frames_since_save_ram_write = 0;
+        self.soft_reset_pending = false;
+        self.mbc_write_history.clear();
+        self.forget_savepoint_values();
         self.cpu.reset();
         self.memory_bus.reset();
     }
 }
+
+impl Drop for Gbc {
+    fn drop(&mut self) {
+        if let Err(e) = self.stop_recording_movie() {
+            eprintln!("Error writing movie: {}", e);
+        }
+        self.flush_save_ram(true);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::sync::mpsc::sync_channel;
+
+    fn create_gbc() -> Gbc {
+        let (frame_sender, _) = sync_channel(1);
+        Gbc::from_rom_bytes(
+            &[0; 0x8000],
+            frame_sender,
+            Arc::new(AtomicBool::new(true)),
+            false,
+            false,
+            Arc::new(Mutex::new(InputState::default())),
+        )
+        .unwrap()
+    }
+
+    fn send_serial_byte(gbc: &mut Gbc, byte: u8) {
+        gbc.memory_bus.write_u8(0xff01, byte);
+        gbc.memory_bus.write_u8(0xff02, 0x81);
+        // A bit shifts out every 512 cycles
+        gbc.tick_hardware(9 * 512);
+    }
+
+    #[test]
+    fn test_capture_serial_output() {
+        let mut gbc = create_gbc();
+        send_serial_byte(&mut gbc, b'x');
+        assert_eq!(gbc.serial_output(), "");
+
+        gbc.capture_serial_output(false);
+        for &byte in b"Passed" {
+            send_serial_byte(&mut gbc, byte);
+        }
+        assert_eq!(gbc.serial_output(), "Passed");
+    }
+
+    #[test]
+    fn test_coverage() {
+        let mut gbc = create_gbc();
+        gbc.start_coverage();
+        // The boot ROM isn't part of the cartridge
+        gbc.single_step().unwrap();
+        assert_eq!(gbc.get_coverage_summary(), (0, 0x8000));
+
+        gbc.write_memory(0xff50, &[1]);
+        gbc.set_register(CpuRegister::Pc, 0x150);
+        gbc.single_step().unwrap();
+        assert_eq!(gbc.was_executed(0x150), Some(true));
+        gbc.stop_coverage();
+        gbc.single_step().unwrap();
+        assert_eq!(gbc.was_executed(0x151), Some(false));
+        assert_eq!(gbc.get_coverage_summary(), (1, 0x8000));
+
+        // 64 KiB of ROM, according to the header
+        let mut rom = vec![0; 0x10000];
+        rom[0x148] = 1;
+        gbc.replace_cartridge(Cartridge::from_bytes(&rom).unwrap());
+        assert_eq!(gbc.get_coverage_summary(), (0, 0x10000));
+    }
+
+    #[test]
+    fn test_remove_temporary_breakpoint() {
+        let mut gbc = create_gbc();
+        gbc.add_breakpoint(0x150, AccessType::Execute, 1, BreakReason::User);
+        gbc.add_temporary_breakpoint(0x150);
+        assert!(gbc.remove_temporary_breakpoint(0x150));
+        assert!(!gbc.remove_temporary_breakpoint(0x150));
+        assert_eq!(gbc.list_breakpoints().len(), 1);
+        assert!(!gbc.list_breakpoints()[0].temporary);
+    }
+
+    #[test]
+    fn test_movie_record_and_playback() {
+        let mut gbc = create_gbc();
+        let path = std::env::temp_dir().join(format!("test_movie_{}.gbm", std::process::id()));
+        let frames = [0x01, 0x00, 0x81, 0x42];
+
+        gbc.record_movie(&path);
+        for &frame in &frames {
+            *gbc.input_state.lock().unwrap() = InputState::from_byte(frame);
+            gbc.latch_input();
+        }
+        gbc.stop_recording_movie().unwrap();
+        let movie = Movie::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(movie.start, MovieStart::PowerOn);
+        assert_eq!(movie.frames, frames);
+
+        // The movie's buttons win over the frontend's until it runs out
+        *gbc.input_state.lock().unwrap() = InputState::from_byte(0xff);
+        gbc.play_movie(movie).unwrap();
+        assert!(gbc.is_playing_movie());
+        for &frame in &frames {
+            let input = gbc.next_movie_input().map(|input| input.to_byte());
+            assert_eq!(input, Some(frame));
+        }
+        assert!(gbc.next_movie_input().is_none());
+        assert!(!gbc.is_playing_movie());
+        // Save RAM stays off the disk once playback replaced it
+        assert!(gbc.movie_played);
+    }
+
+    /// Executes a STOP from WRAM with both joypad lines selected
+    fn enter_stop(gbc: &mut Gbc) {
+        gbc.write_memory(0xc000, &[0x10, 0x00]);
+        gbc.write_memory(0xff00, &[0x00]);
+        gbc.set_register(CpuRegister::Pc, 0xc000);
+        gbc.single_step().unwrap();
+        assert!(gbc.cpu.is_stopped());
+    }
+
+    fn step_until_awake(gbc: &mut Gbc) {
+        for _ in 0..10 {
+            if !gbc.cpu.is_stopped() {
+                return;
+            }
+            gbc.single_step().unwrap();
+        }
+        panic!("the CPU didn't wake from STOP");
+    }
+
+    #[test]
+    fn test_movie_across_stop() {
+        let mut gbc = create_gbc();
+        let path = std::env::temp_dir().join(format!("test_stop_movie_{}.gbm", std::process::id()));
+
+        gbc.record_movie(&path);
+        gbc.latch_input();
+        enter_stop(&mut gbc);
+        for _ in 0..100 {
+            gbc.single_step().unwrap();
+        }
+        assert!(gbc.cpu.is_stopped());
+        *gbc.input_state.lock().unwrap() = InputState::from_byte(0x01);
+        step_until_awake(&mut gbc);
+        gbc.latch_input();
+        gbc.latch_input();
+        gbc.stop_recording_movie().unwrap();
+        let movie = Movie::load(&path).unwrap();
+        std::fs::remove_file(&path).unwrap();
+        // Only the frames latched were recorded, not the steps spent stopped
+        assert_eq!(movie.frames, [0x00, 0x01, 0x01]);
+
+        // The movie wakes the CPU, not the frontend
+        *gbc.input_state.lock().unwrap() = InputState::default();
+        gbc.play_movie(movie).unwrap();
+        gbc.latch_input();
+        enter_stop(&mut gbc);
+        step_until_awake(&mut gbc);
+        gbc.latch_input();
+        gbc.latch_input();
+        assert!(matches!(
+            &gbc.movie,
+            Some(MovieMode::Playing { movie, next_frame }) if *next_frame == movie.frames.len()
+        ));
+    }
+}
@@ -0,0 +1,112 @@
+use std::fs;
+use std::path::Path;
+
+use serde::{Deserialize, Serialize};
+
+use super::savestate::{FileHeader, FileKind, SaveStateError, StateReader, StateWriter};
+use super::InputState;
+
+const START_POWER_ON: u8 = 0;
+const START_SAVESTATE: u8 = 1;
+
+/// Where playback of a movie begins
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MovieStart {
+    /// A fresh power on, with cleared save RAM
+    PowerOn,
+    /// A complete savestate file, header and all
+    SaveState(Vec<u8>),
+}
+
+/// A recording of the buttons held on every frame. After the `FileHeader`,
+/// version 1 of the file is laid out as:
+///
+/// | bytes | contents                                                  |
+/// |-------|-----------------------------------------------------------|
+/// | 1     | start, 0 for power on or 1 for a savestate                |
+/// | 4 + n | length prefixed savestate, only when starting from one    |
+/// | 4 + n | length prefixed buttons, one byte per frame               |
+///
+/// The serde schema mirrors this, minus the header's magic and version.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Movie {
+    pub rom_identity: String,
+    pub start: MovieStart,
+    /// The buttons held on each frame, `InputState::to_byte` packed
+    pub frames: Vec<u8>,
+}
+
+impl Movie {
+    #[must_use]
+    pub fn new(rom_identity: String, start: MovieStart) -> Self {
+        Self {
+            rom_identity,
+            start,
+            frames: Vec::new(),
+        }
+    }
+
+    pub fn push_frame(&mut self, input: &InputState) {
+        self.frames.push(input.to_byte());
+    }
+
+    #[must_use]
+    pub fn get_frame(&self, frame: usize) -> Option<InputState> {
+        self.frames
+            .get(frame)
+            .map(|&byte| InputState::from_byte(byte))
+    }
+
+    #[must_use]
+    pub fn to_bytes(&self) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        FileHeader::new(FileKind::Movie, self.rom_identity.clone()).write(&mut writer);
+        match &self.start {
+            MovieStart::PowerOn => writer.write_u8(START_POWER_ON),
+            MovieStart::SaveState(state) => {
+                writer.write_u8(START_SAVESTATE);
+                writer.write_vec(state);
+            }
+        }
+        writer.write_vec(&self.frames);
+        writer.into_inner()
+    }
+
+    /// Reads a movie made by `to_bytes`
+    ///
+    /// # Errors
+    /// Returns an error if the data isn't a movie of a supported version,
+    /// or is corrupt
+    pub fn from_bytes(data: &[u8]) -> Result<Self, SaveStateError> {
+        let mut reader = StateReader::new(data);
+        let header = FileHeader::read(FileKind::Movie, &mut reader)?;
+        let start = match reader.read_u8()? {
+            START_POWER_ON => MovieStart::PowerOn,
+            START_SAVESTATE => MovieStart::SaveState(reader.read_vec()?),
+            _ => return Err(SaveStateError::Invalid("movie start")),
+        };
+        let frames = reader.read_vec()?;
+        if !reader.is_empty() {
+            return Err(SaveStateError::Invalid("trailing data"));
+        }
+        Ok(Self {
+            rom_identity: header.rom_identity,
+            start,
+            frames,
+        })
+    }
+
+    /// # Errors
+    /// Returns an error if the file cannot be written
+    pub fn save<P: AsRef<Path>>(&self, path: P) -> Result<(), SaveStateError> {
+        fs::write(path, self.to_bytes())?;
+        Ok(())
+    }
+
+    /// # Errors
+    /// Returns an error if the file cannot be read or isn't a valid movie
+    pub fn load<P: AsRef<Path>>(path: P) -> Result<Self, SaveStateError> {
+        Self::from_bytes(&fs::read(path)?)
+    }
+}
@@ -0,0 +1,30 @@
+use std::fmt;
+
+use super::cpu::InterruptRequest;
+
+/// Extra hardware hanging off the bus, like a printer, IR sensor or an
+/// accessory built into the cartridge. A peripheral claims the addresses
+/// it answers to, and reads and writes to those addresses go to it instead
+/// of whatever the bus would normally map there.
+///
+/// Peripherals are not part of savestates, they are plugged in again by
+/// whoever created the emulator.
+pub trait Peripheral: fmt::Debug + Send {
+    /// Short name, shown by the debugger
+    fn name(&self) -> &str;
+
+    /// Whether reads and writes to this address should go to the peripheral
+    fn claims(&self, address: u16) -> bool;
+
+    fn read_u8(&mut self, address: u16) -> u8;
+
+    fn write_u8(&mut self, address: u16, byte: u8);
+
+    /// Advances the peripheral, returning any interrupts it wants raised
+    fn tick(&mut self, _cycles: u64) -> InterruptRequest {
+        InterruptRequest::default()
+    }
+
+    /// Called when the Game Boy is reset. The peripheral stays plugged in.
+    fn reset(&mut self) {}
+}
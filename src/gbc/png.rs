@@ -0,0 +1,71 @@
+use std::fs::File;
+use std::io::{self, BufWriter, Write};
+use std::path::Path;
+
+use flate2::write::ZlibEncoder;
+use flate2::{Compression, Crc};
+
+const SIGNATURE: [u8; 8] = [0x89, b'P', b'N', b'G', b'\r', b'\n', 0x1a, b'\n'];
+
+// 8 bits per channel, truecolor without alpha
+const BIT_DEPTH: u8 = 8;
+const COLOR_TYPE_RGB: u8 = 2;
+
+fn write_chunk<W: Write>(writer: &mut W, kind: &[u8; 4], data: &[u8]) -> io::Result<()> {
+    #[allow(clippy::cast_possible_truncation)]
+    let length = data.len() as u32;
+    writer.write_all(&length.to_be_bytes())?;
+    writer.write_all(kind)?;
+    writer.write_all(data)?;
+    let mut crc = Crc::new();
+    crc.update(kind);
+    crc.update(data);
+    writer.write_all(&crc.sum().to_be_bytes())
+}
+
+/// Encodes `pixels`, `width` by `height` pixels of red, green and blue
+/// bytes row by row, as a PNG image
+///
+/// # Errors
+/// Returns an error if writing fails
+///
+/// # Panics
+/// Panics if `pixels` isn't exactly `width * height * 3` bytes
+pub fn write_rgb<W: Write>(
+    writer: &mut W,
+    width: u32,
+    height: u32,
+    pixels: &[u8],
+) -> io::Result<()> {
+    let row_length = width as usize * 3;
+    assert_eq!(pixels.len(), row_length * height as usize);
+
+    writer.write_all(&SIGNATURE)?;
+
+    let mut header = Vec::with_capacity(13);
+    header.extend_from_slice(&width.to_be_bytes());
+    header.extend_from_slice(&height.to_be_bytes());
+    // Deflate compression, adaptive filtering, no interlacing
+    header.extend_from_slice(&[BIT_DEPTH, COLOR_TYPE_RGB, 0, 0, 0]);
+    write_chunk(writer, b"IHDR", &header)?;
+
+    // Every row starts with its filter type, always 0 (none) here
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::default());
+    for row in pixels.chunks(row_length.max(1)) {
+        encoder.write_all(&[0])?;
+        encoder.write_all(row)?;
+    }
+    write_chunk(writer, b"IDAT", &encoder.finish()?)?;
+
+    write_chunk(writer, b"IEND", &[])
+}
+
+/// Writes `pixels` to the PNG file at `path`, see `write_rgb`
+///
+/// # Errors
+/// Returns an error if the file cannot be written
+pub fn save_rgb<P: AsRef<Path>>(path: P, width: u32, height: u32, pixels: &[u8]) -> io::Result<()> {
+    let mut writer = BufWriter::new(File::create(path)?);
+    write_rgb(&mut writer, width, height, pixels)?;
+    writer.flush()
+}
@@ -0,0 +1,388 @@
+use std::collections::VecDeque;
+
+use super::{
+    object_over_background, ColorIndex, FifoDebugState, ScannedSprite, SpritePaletteNumber,
+    TileAddressingMethod, VideoRam,
+};
+use crate::gbc::mmio::lcd::{Color, Lcd, SpriteSize, TileMap};
+use crate::gbc::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+// Reading the tile number, then the low and high bytes of its data, takes
+// 2 dots each
+const FETCH_DOTS: u8 = 6;
+// A sprite fetch takes as long, once the background fetcher is out of the way
+const SPRITE_FETCH_DOTS: u8 = 6;
+
+#[derive(Copy, Clone, Debug, Default)]
+struct ObjectPixel {
+    color: ColorIndex,
+    palette: SpritePaletteNumber,
+    behind_background: bool,
+}
+
+/// Draws a line the way the hardware does: a fetcher reads 8 pixels of
+/// background or window at a time into a FIFO, which shifts one pixel out
+/// to the LCD every dot. Sprites pause the shifting while their pixels are
+/// fetched and mixed into a second FIFO.
+///
+/// Unlike looking each pixel up directly, this makes mode 3 take as long as
+/// it really does: 12 dots to get going, plus a dot for every pixel of SCX
+/// fine scroll thrown away, 6 when the window starts and 6 to 11 for each
+/// sprite.
+#[derive(Debug, Default)]
+pub struct PixelFifo {
+    background: VecDeque<ColorIndex>,
+    objects: VecDeque<ObjectPixel>,
+    // Fetcher state, fetch_dot reaching FETCH_DOTS means it has a tile
+    // ready and is waiting for the background FIFO to empty
+    fetch_dot: u8,
+    // Tile column, counted from where the background or window started
+    fetch_x: u8,
+    fetching_window: bool,
+    // The first tile fetched on a line is thrown away
+    first_fetch: bool,
+    tile_number: u8,
+    tile_row: u8,
+    tile_low: u8,
+    tile_high: u8,
+    // Pixels to shift out without drawing, for SCX (or WX) fine scroll
+    discard: u8,
+    pixel_x: u8,
+    // Index into the line's sprites, which are sorted by X
+    next_sprite: usize,
+    sprite_fetch_dots: Option<u8>,
+    done: bool,
+}
+
+impl PixelFifo {
+    fn start_line(&mut self, lcd: &mut Lcd) {
+        let (scroll_x, _) = lcd.get_scroll_offsets();
+        self.background.clear();
+        self.objects.clear();
+        self.fetch_dot = 0;
+        self.fetch_x = 0;
+        self.fetching_window = false;
+        self.first_fetch = true;
+        self.discard = scroll_x % 8;
+        self.pixel_x = 0;
+        self.next_sprite = 0;
+        self.sprite_fetch_dots = None;
+        self.done = false;
+        lcd.set_mode_3_end(None);
+    }
+
+    /// Runs one dot of mode 3, doing nothing outside of it. Returns the
+    /// pixel shifted out to the LCD and its X, if there was one.
+    pub fn tick(
+        &mut self,
+        video_ram: &VideoRam,
+        sprites: &[ScannedSprite],
+        lcd: &mut Lcd,
+    ) -> Option<(u8, Color)> {
+        let x = lcd.get_lx();
+        if lcd.get_ly() >= 144 || x < 0 {
+            return None;
+        }
+        if x == 0 {
+            self.start_line(lcd);
+        }
+        if self.done {
+            return None;
+        }
+
+        self.check_window(lcd);
+        if self.sprite_fetch_dots.is_none() {
+            self.check_sprites(sprites, lcd);
+        }
+        self.tick_fetcher(video_ram, lcd);
+
+        if let Some(dots) = self.sprite_fetch_dots {
+            // The sprite fetch has to wait for the background fetcher to
+            // finish the tile it is on, and nothing is shifted out until
+            // it's done
+            if self.fetch_dot == FETCH_DOTS && !self.background.is_empty() {
+                if dots + 1 == SPRITE_FETCH_DOTS {
+                    if let Some(scanned) = sprites.get(self.next_sprite) {
+                        self.fetch_sprite(video_ram, scanned, lcd);
+                    }
+                    self.next_sprite += 1;
+                    self.sprite_fetch_dots = None;
+                } else {
+                    self.sprite_fetch_dots = Some(dots + 1);
+                }
+            }
+            return None;
+        }
+
+        let pixel = self.shift_out_pixel(lcd);
+        if self.pixel_x == 160 {
+            self.done = true;
+            lcd.set_mode_3_end(Some(x + 1));
+        }
+        pixel
+    }
+
+    pub fn debug_state(&self) -> FifoDebugState {
+        FifoDebugState {
+            background: self.background.iter().copied().collect(),
+            objects: self.objects.iter().map(|pixel| pixel.color).collect(),
+            pixel_x: self.pixel_x,
+            fetching_window: self.fetching_window,
+        }
+    }
+
+    fn check_window(&mut self, lcd: &mut Lcd) {
+        if self.fetching_window
+            || !lcd.get_window_enable()
+            || !lcd.get_background_window_priority()
+            || !lcd.get_window_y_triggered()
+        {
+            return;
+        }
+        let (window_x, _) = lcd.get_window_coords();
+        let window_start = i16::from(window_x) - 7;
+        if i16::from(self.pixel_x) != window_start.max(0) {
+            return;
+        }
+        // The fetcher starts over on the window's first tile, and with WX
+        // below 7 the columns left of the screen are thrown away
+        self.fetching_window = true;
+        self.fetch_x = 0;
+        self.fetch_dot = 0;
+        self.background.clear();
+        #[allow(clippy::cast_possible_truncation)]
+        #[allow(clippy::cast_sign_loss)]
+        {
+            self.discard = (-window_start).max(0) as u8;
+        }
+        lcd.set_window_was_rendered();
+    }
+
+    fn check_sprites(&mut self, sprites: &[ScannedSprite], lcd: &Lcd) {
+        if !lcd.get_sprite_enable() {
+            return;
+        }
+        if let Some(scanned) = sprites.get(self.next_sprite) {
+            let sprite_x = i16::from(scanned.sprite.x) - 8;
+            if sprite_x <= i16::from(self.pixel_x) {
+                self.sprite_fetch_dots = Some(0);
+            }
+        }
+    }
+
+    fn tick_fetcher(&mut self, video_ram: &VideoRam, lcd: &Lcd) {
+        if self.fetch_dot == FETCH_DOTS {
+            if !self.background.is_empty() {
+                return;
+            }
+            if self.first_fetch {
+                self.first_fetch = false;
+            } else {
+                for bit in (0..8).rev() {
+                    let color = (((self.tile_high >> bit) & 1) << 1) | ((self.tile_low >> bit) & 1);
+                    self.background.push_back(ColorIndex::new(color));
+                }
+                self.fetch_x = self.fetch_x.wrapping_add(1);
+            }
+            self.fetch_dot = 0;
+        }
+
+        match self.fetch_dot {
+            1 => {
+                let (tile_map, map_x, map_y) = if self.fetching_window {
+                    (
+                        lcd.get_window_tile_map(),
+                        self.fetch_x,
+                        lcd.get_window_line(),
+                    )
+                } else {
+                    let (scroll_x, scroll_y) = lcd.get_scroll_offsets();
+                    (
+                        lcd.get_background_tile_map(),
+                        (scroll_x / 8).wrapping_add(self.fetch_x),
+                        scroll_y.wrapping_add(lcd.get_ly()),
+                    )
+                };
+                let tile_map = match tile_map {
+                    TileMap::From9800 => &video_ram.background_map_0,
+                    TileMap::From9C00 => &video_ram.background_map_1,
+                };
+                self.tile_number = tile_map[32 * usize::from(map_y / 8) + usize::from(map_x % 32)];
+                self.tile_row = map_y % 8;
+            }
+            3 | 5 => {
+                let mut addressing_mode = lcd.get_addressing_mode();
+                addressing_mode.set_offset(self.tile_number);
+                let tile = video_ram.read_tile(addressing_mode);
+                let index = usize::from(2 * self.tile_row);
+                if self.fetch_dot == 3 {
+                    self.tile_low = tile.lines[index];
+                } else {
+                    self.tile_high = tile.lines[index + 1];
+                }
+            }
+            _ => {}
+        }
+        self.fetch_dot += 1;
+    }
+
+    fn fetch_sprite(&mut self, video_ram: &VideoRam, scanned: &ScannedSprite, lcd: &Lcd) {
+        let object = &scanned.sprite;
+        let height = match lcd.get_sprite_size() {
+            SpriteSize::Small => 8,
+            SpriteSize::Large => 16,
+        };
+        // The size could have changed since the OAM scan picked the sprite
+        #[allow(clippy::cast_possible_truncation)]
+        #[allow(clippy::cast_sign_loss)]
+        let mut row = (i16::from(lcd.get_ly()) - (i16::from(object.y) - 16)) as u8 % height;
+        if object.attributes.flip_y {
+            row = height - 1 - row;
+        }
+        let tile_index = if height == 16 {
+            (object.tile_number & !0x1) | (row / 8)
+        } else {
+            object.tile_number
+        };
+        // Sprites always use 0x8000 addressing
+        let tile = video_ram.read_tile(TileAddressingMethod::From8000(tile_index));
+        let index = usize::from(2 * (row % 8));
+        let (low, high) = (tile.lines[index], tile.lines[index + 1]);
+
+        // Columns already shifted out, for sprites hanging off the left
+        let sprite_x = i16::from(object.x) - 8;
+        #[allow(clippy::cast_sign_loss)]
+        let skip = (i16::from(self.pixel_x) - sprite_x).max(0) as usize;
+        while self.objects.len() < 8 {
+            self.objects.push_back(ObjectPixel::default());
+        }
+        for column in skip..8 {
+            let bit = if object.attributes.flip_x {
+                column
+            } else {
+                7 - column
+            };
+            let color = (((high >> bit) & 1) << 1) | ((low >> bit) & 1);
+            // Pixels of sprites fetched earlier have priority, so only
+            // transparent ones get replaced
+            let slot = &mut self.objects[column - skip];
+            if color != 0 && matches!(slot.color, ColorIndex::Color0) {
+                *slot = ObjectPixel {
+                    color: ColorIndex::new(color),
+                    palette: object.attributes.gb_palette_number,
+                    behind_background: object.attributes.behind_background,
+                };
+            }
+        }
+    }
+
+    fn shift_out_pixel(&mut self, lcd: &Lcd) -> Option<(u8, Color)> {
+        let background = self.background.pop_front()?;
+        if self.discard > 0 {
+            self.discard -= 1;
+            return None;
+        }
+        let object = self.objects.pop_front();
+
+        // Registers are read as each pixel is shifted out, like with the
+        // direct renderer
+        let background_enabled = lcd.get_background_window_priority();
+        let mut color = if background_enabled {
+            lcd.get_background_palette().get_color(&background)
+        } else {
+            Color::White
+        };
+        if let Some(object) = object {
+            if !matches!(object.color, ColorIndex::Color0)
+                && lcd.get_sprite_enable()
+                && object_over_background(
+                    background_enabled,
+                    false,
+                    object.behind_background,
+                    background,
+                )
+            {
+                let (obj_pal0, obj_pal1) = lcd.get_object_palettes();
+                let palette = match object.palette {
+                    SpritePaletteNumber::Palette0 => obj_pal0,
+                    SpritePaletteNumber::Palette1 => obj_pal1,
+                };
+                color = palette.get_color(&object.color);
+            }
+        }
+        let x = self.pixel_x;
+        self.pixel_x += 1;
+        Some((x, color))
+    }
+}
+
+impl SaveState for PixelFifo {
+    fn save_state(&self, writer: &mut StateWriter) {
+        #[allow(clippy::cast_possible_truncation)]
+        writer.write_u8(self.background.len() as u8);
+        for &color in &self.background {
+            writer.write_u8(color.into());
+        }
+        #[allow(clippy::cast_possible_truncation)]
+        writer.write_u8(self.objects.len() as u8);
+        for pixel in &self.objects {
+            writer.write_u8(pixel.color.into());
+            writer.write_u8(pixel.palette.into());
+            writer.write_bool(pixel.behind_background);
+        }
+        writer.write_u8(self.fetch_dot);
+        writer.write_u8(self.fetch_x);
+        writer.write_bool(self.fetching_window);
+        writer.write_bool(self.first_fetch);
+        writer.write_u8(self.tile_number);
+        writer.write_u8(self.tile_row);
+        writer.write_u8(self.tile_low);
+        writer.write_u8(self.tile_high);
+        writer.write_u8(self.discard);
+        writer.write_u8(self.pixel_x);
+        #[allow(clippy::cast_possible_truncation)]
+        writer.write_u8(self.next_sprite as u8);
+        writer.write_bool(self.sprite_fetch_dots.is_some());
+        writer.write_u8(self.sprite_fetch_dots.unwrap_or(0));
+        writer.write_bool(self.done);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        self.background.clear();
+        for _ in 0..reader.read_u8()? {
+            let color = reader.read_u8()?;
+            self.background.push_back(ColorIndex::new(color & 3));
+        }
+        self.objects.clear();
+        for _ in 0..reader.read_u8()? {
+            let color = ColorIndex::new(reader.read_u8()? & 3);
+            let palette = SpritePaletteNumber::new(reader.read_u8()? & 1);
+            let behind_background = reader.read_bool()?;
+            self.objects.push_back(ObjectPixel {
+                color,
+                palette,
+                behind_background,
+            });
+        }
+        self.fetch_dot = reader.read_u8()?.min(FETCH_DOTS);
+        self.fetch_x = reader.read_u8()?;
+        self.fetching_window = reader.read_bool()?;
+        self.first_fetch = reader.read_bool()?;
+        self.tile_number = reader.read_u8()?;
+        self.tile_row = reader.read_u8()? % 8;
+        self.tile_low = reader.read_u8()?;
+        self.tile_high = reader.read_u8()?;
+        self.discard = reader.read_u8()?;
+        self.pixel_x = reader.read_u8()?.min(160);
+        self.next_sprite = usize::from(reader.read_u8()?);
+        let sprite_fetching = reader.read_bool()?;
+        let sprite_fetch_dots = reader.read_u8()?;
+        self.sprite_fetch_dots = if sprite_fetching {
+            Some(sprite_fetch_dots)
+        } else {
+            None
+        };
+        self.done = reader.read_bool()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,2239 @@
+mod fifo;
+
+use std::fmt::{self, Display};
+use std::sync::Arc;
+
+use self::fifo::PixelFifo;
+use super::mmio::lcd::{Color, Lcd, ScanlineRegisters, SpriteSize, TileMap, DOTS_PER_FRAME};
+use super::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+const MAX_SPRITES_PER_LINE: usize = 10;
+
+pub type Frame = [[Color; 160]; 144];
+
+pub const RGBA_FRAME_SIZE: usize = 160 * 144 * 4;
+
+pub type RgbaFrame = [u8; RGBA_FRAME_SIZE];
+
+fn blank_frame() -> Arc<Frame> {
+    Arc::new([[Color::White; 160]; 144])
+}
+
+// White with full alpha in any layout
+fn blank_rgba_frame() -> Arc<RgbaFrame> {
+    Arc::new([0xff; RGBA_FRAME_SIZE])
+}
+
+/// The byte each of red, green, blue and alpha goes to within a pixel of
+/// an RGBA frame, so it matches the texture it gets copied into
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct RgbaLayout {
+    pub red: usize,
+    pub green: usize,
+    pub blue: usize,
+    pub alpha: usize,
+}
+
+impl RgbaLayout {
+    fn write_pixel(self, pixel: &mut [u8], color: Color) {
+        let [red, green, blue] = color.to_rgb();
+        pixel[self.red] = red;
+        pixel[self.green] = green;
+        pixel[self.blue] = blue;
+        pixel[self.alpha] = 0xff;
+    }
+
+    /// Converts a whole frame, for when the PPU wasn't drawing RGBA
+    #[must_use]
+    pub fn convert_frame(self, frame: &Frame) -> RgbaFrame {
+        let mut rgba = [0; RGBA_FRAME_SIZE];
+        for (pixel, &color) in rgba.chunks_mut(4).zip(frame.iter().flatten()) {
+            self.write_pixel(pixel, color);
+        }
+        rgba
+    }
+}
+
+// RGBA copies of the framebuffers, kept up to date as pixels are drawn
+#[derive(Debug)]
+struct RgbaOutput {
+    layout: RgbaLayout,
+    framebuffers: [Arc<RgbaFrame>; 2],
+}
+
+impl RgbaOutput {
+    fn new(layout: RgbaLayout, framebuffers: &[Arc<Frame>; 2]) -> Self {
+        Self {
+            layout,
+            framebuffers: [
+                Arc::new(layout.convert_frame(&framebuffers[0])),
+                Arc::new(layout.convert_frame(&framebuffers[1])),
+            ],
+        }
+    }
+}
+
+/// A finished frame as it is handed to the frontend
+#[derive(Clone, Debug)]
+pub struct FinishedFrame {
+    pub colors: Arc<Frame>,
+    // Only there if the PPU was asked for RGBA output
+    pub rgba: Option<Arc<RgbaFrame>>,
+}
+
+/// How the PPU turns VRAM into pixels
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub enum Renderer {
+    /// Looks up each pixel as it is drawn, mode 3 always takes 172 dots
+    #[default]
+    Direct,
+    /// Runs the background and sprite fetchers and the pixel FIFOs, so
+    /// mode 3 gets longer with fine scroll, the window and sprites like on
+    /// hardware. Slower.
+    PixelFifo,
+}
+
+impl std::str::FromStr for Renderer {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_lowercase().as_str() {
+            "direct" => Ok(Self::Direct),
+            "fifo" | "pixel-fifo" => Ok(Self::PixelFifo),
+            _ => Err(format!("Unknown renderer {}", s)),
+        }
+    }
+}
+
+impl Display for Renderer {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Direct => write!(f, "direct"),
+            Self::PixelFifo => write!(f, "fifo"),
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum ColorIndex {
+    Color0,
+    Color1,
+    Color2,
+    Color3,
+}
+
+impl ColorIndex {
+    #[must_use]
+    pub fn new(color: u8) -> Self {
+        match color {
+            0 => Self::Color0,
+            1 => Self::Color1,
+            2 => Self::Color2,
+            3 => Self::Color3,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Default for ColorIndex {
+    fn default() -> Self {
+        Self::Color0
+    }
+}
+
+impl From<ColorIndex> for u8 {
+    fn from(c: ColorIndex) -> Self {
+        match c {
+            ColorIndex::Color0 => 0,
+            ColorIndex::Color1 => 1,
+            ColorIndex::Color2 => 2,
+            ColorIndex::Color3 => 3,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Tile {
+    lines: [u8; 16],
+}
+
+impl Tile {
+    #[must_use]
+    pub fn get_color(&self, x: u8, y: u8) -> ColorIndex {
+        assert!(x < 8);
+        assert!(y < 8);
+
+        let bit_index = 7 - x;
+        let index = 2 * y;
+        let byte1 = self.lines[index as usize];
+        let byte2 = self.lines[(index + 1) as usize];
+        let bit1 = (byte1 >> bit_index) & 1;
+        let bit2 = (byte2 >> bit_index) & 1;
+        ColorIndex::new((bit2 << 1) | bit1)
+    }
+
+    #[must_use]
+    pub fn deinterleave(&self) -> [ColorIndex; 64] {
+        let mut colors = [ColorIndex::default(); 64];
+        for i in 0..8 {
+            let mut byte0 = self.lines[i*2];
+            let mut byte1 = self.lines[i*2 + 1];
+
+            colors[i * 8] = ColorIndex::new(((byte1 & 1) << 1) | (byte0 & 1));
+            byte0 >>= 1;
+            byte1 >>= 1;
+
+            colors[i * 8 + 1] = ColorIndex::new(((byte1 & 1) << 1) | (byte0 & 1));
+            byte0 >>= 1;
+            byte1 >>= 1;
+
+            colors[i * 8 + 2] = ColorIndex::new(((byte1 & 1) << 1) | (byte0 & 1));
+            byte0 >>= 1;
+            byte1 >>= 1;
+
+            colors[i * 8 + 3] = ColorIndex::new(((byte1 & 1) << 1) | (byte0 & 1));
+            byte0 >>= 1;
+            byte1 >>= 1;
+
+            colors[i * 8 + 4] = ColorIndex::new(((byte1 & 1) << 1) | (byte0 & 1));
+            byte0 >>= 1;
+            byte1 >>= 1;
+
+            colors[i * 8 + 5] = ColorIndex::new(((byte1 & 1) << 1) | (byte0 & 1));
+            byte0 >>= 1;
+            byte1 >>= 1;
+
+            colors[i * 8 + 6] = ColorIndex::new(((byte1 & 1) << 1) | (byte0 & 1));
+            byte0 >>= 1;
+            byte1 >>= 1;
+
+            colors[i * 8 + 7] = ColorIndex::new(((byte1 & 1) << 1) | (byte0 & 1));
+        }
+        colors
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum TileAddressingMethod {
+    From8000(u8),
+    From9000(i8),
+}
+
+impl TileAddressingMethod {
+    fn set_offset(&mut self, offset: u8) {
+        match self {
+            TileAddressingMethod::From8000(ref mut o) => *o = offset,
+            #[allow(clippy::cast_possible_wrap)]
+            TileAddressingMethod::From9000(ref mut i) => *i = offset as i8,
+        }
+    }
+}
+
+impl From<bool> for TileAddressingMethod {
+    fn from(v: bool) -> Self {
+        if v {
+            Self::From8000(0)
+        } else {
+            Self::From9000(0)
+        }
+    }
+}
+
+impl From<TileAddressingMethod> for bool {
+    fn from(s: TileAddressingMethod) -> Self {
+        match s {
+            TileAddressingMethod::From8000(_) => false,
+            TileAddressingMethod::From9000(_) => true,
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct VideoRam {
+    pub tile_block_0: [Tile; 128],
+    pub tile_block_1: [Tile; 128],
+    pub tile_block_2: [Tile; 128],
+    pub background_map_0: [u8; 32 * 32],
+    pub background_map_1: [u8; 32 * 32],
+}
+
+impl Default for VideoRam {
+    fn default() -> Self {
+        Self {
+            tile_block_0: [Tile::default(); 128],
+            tile_block_1: [Tile::default(); 128],
+            tile_block_2: [Tile::default(); 128],
+            background_map_0: [0; 32 * 32],
+            background_map_1: [0; 32 * 32],
+        }
+    }
+}
+
+impl VideoRam {
+    fn read(&self, offset: u16) -> u8 {
+        match offset {
+            0..=0x07ff => self.tile_block_0[(offset / 16) as usize].lines[(offset % 16) as usize],
+            0x0800..=0x0fff => {
+                let offset = offset - 0x0800;
+                self.tile_block_1[(offset / 16) as usize].lines[(offset % 16) as usize]
+            }
+            0x1000..=0x17ff => {
+                let offset = offset - 0x1000;
+                self.tile_block_2[(offset / 16) as usize].lines[(offset % 16) as usize]
+            }
+            0x1800..=0x1bff => {
+                let offset = offset - 0x1800;
+                self.background_map_0[offset as usize]
+            }
+            0x1c00..=0x1fff => {
+                let offset = offset - 0x1c00;
+                self.background_map_1[offset as usize]
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, offset: u16, byte: u8) {
+        match offset {
+            0..=0x07ff => {
+                self.tile_block_0[(offset / 16) as usize].lines[(offset % 16) as usize] = byte;
+            }
+            0x0800..=0x0fff => {
+                let offset = offset - 0x0800;
+                self.tile_block_1[(offset / 16) as usize].lines[(offset % 16) as usize] = byte;
+            }
+            0x1000..=0x17ff => {
+                let offset = offset - 0x1000;
+                self.tile_block_2[(offset / 16) as usize].lines[(offset % 16) as usize] = byte;
+            }
+            0x1800..=0x1bff => {
+                let offset = offset - 0x1800;
+                self.background_map_0[offset as usize] = byte;
+            }
+            0x1c00..=0x1fff => {
+                let offset = offset - 0x1c00;
+                self.background_map_1[offset as usize] = byte;
+            }
+            _ => unreachable!(),
+        }
+    }
+
+    #[must_use]
+    pub fn read_tile(&self, addressing_method: TileAddressingMethod) -> Tile {
+        match addressing_method {
+            TileAddressingMethod::From8000(offset) => {
+                if offset > 127 {
+                    self.tile_block_1[(offset - 128) as usize]
+                } else {
+                    self.tile_block_0[offset as usize]
+                }
+            }
+            TileAddressingMethod::From9000(offset) => {
+                if offset < 0 {
+                    #[allow(clippy::cast_sign_loss)]
+                    self.tile_block_1[(128_i16 + i16::from(offset)) as usize]
+                } else {
+                    #[allow(clippy::cast_sign_loss)]
+                    self.tile_block_2[offset as usize]
+                }
+            }
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum SpritePaletteNumber {
+    Palette0,
+    Palette1,
+}
+
+impl Default for SpritePaletteNumber {
+    fn default() -> Self {
+        Self::Palette0
+    }
+}
+
+impl SpritePaletteNumber {
+    fn new(v: u8) -> Self {
+        match v {
+            0 => Self::Palette0,
+            1 => Self::Palette1,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl From<SpritePaletteNumber> for u8 {
+    fn from(bank: SpritePaletteNumber) -> Self {
+        match bank {
+            SpritePaletteNumber::Palette0 => 0,
+            SpritePaletteNumber::Palette1 => 1,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug)]
+pub enum SpriteVideoRamBank {
+    Bank0,
+    Bank1,
+}
+
+impl Default for SpriteVideoRamBank {
+    fn default() -> Self {
+        Self::Bank0
+    }
+}
+
+impl SpriteVideoRamBank {
+    fn new(v: u8) -> Self {
+        match v {
+            0 => Self::Bank0,
+            1 => Self::Bank1,
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl From<SpriteVideoRamBank> for u8 {
+    fn from(bank: SpriteVideoRamBank) -> Self {
+        match bank {
+            SpriteVideoRamBank::Bank0 => 0,
+            SpriteVideoRamBank::Bank1 => 1,
+        }
+    }
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct SpriteAttributes {
+    behind_background: bool,
+    flip_y: bool,
+    flip_x: bool,
+    gb_palette_number: SpritePaletteNumber,
+    cgb_vram_bank: SpriteVideoRamBank,
+    cgb_palette_number: u8,
+}
+
+impl From<u8> for SpriteAttributes {
+    fn from(v: u8) -> Self {
+        Self {
+            behind_background: (v >> 7) != 0,
+            flip_y: ((v >> 6) & 1) != 0,
+            flip_x: ((v >> 5) & 1) != 0,
+            gb_palette_number: SpritePaletteNumber::new((v >> 4) & 1),
+            cgb_vram_bank: SpriteVideoRamBank::new((v >> 3) & 1),
+            cgb_palette_number: v & 7,
+        }
+    }
+}
+
+impl From<SpriteAttributes> for u8 {
+    fn from(attributes: SpriteAttributes) -> Self {
+        ((attributes.behind_background as u8) << 7_u8)
+            | ((attributes.flip_y as u8) << 6_u8)
+            | ((attributes.flip_x as u8) << 5_u8)
+            | (u8::from(attributes.gb_palette_number) << 4_u8)
+            | (u8::from(attributes.cgb_vram_bank) << 3_u8)
+            | attributes.cgb_palette_number
+    }
+}
+
+// Whether an opaque sprite pixel is drawn over the background or window
+// pixel under it, indexed by LCDC bit 0, the BG attribute priority bit, the
+// sprite's behind background bit and whether the background color index is
+// not 0, in that order from the highest bit.
+//
+// With LCDC bit 0 clear the DMG draws the background white and treats it as
+// color 0, and the CGB keeps drawing it but takes away its priority, so
+// either way the sprite is on top. Otherwise background color 0 is always
+// covered, and any other color covers the sprite if either priority bit
+// asks for it.
+const OBJECT_OVER_BACKGROUND: [bool; 16] = [
+    true, true, true, true, true, true, true, true, // LCDC bit 0 clear
+    true, true, true, false, // no BG attribute priority
+    true, false, true, false, // BG attribute priority
+];
+
+/// Resolves a sprite pixel against the background pixel under it. The DMG
+/// has no background attributes, so it always passes `false` for
+/// `background_attribute_priority`.
+fn object_over_background(
+    background_enabled: bool,
+    background_attribute_priority: bool,
+    behind_background: bool,
+    background_color: ColorIndex,
+) -> bool {
+    let index = (usize::from(background_enabled) << 3)
+        | (usize::from(background_attribute_priority) << 2)
+        | (usize::from(behind_background) << 1)
+        | usize::from(!matches!(background_color, ColorIndex::Color0));
+    OBJECT_OVER_BACKGROUND[index]
+}
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct Sprite {
+    y: u8,
+    x: u8,
+    tile_number: u8,
+    attributes: SpriteAttributes,
+}
+
+impl Sprite {
+    fn read(&self, offset: u16) -> u8 {
+        match offset {
+            0 => self.y,
+            1 => self.x,
+            2 => self.tile_number,
+            3 => self.attributes.into(),
+            _ => unreachable!(),
+        }
+    }
+
+    fn write(&mut self, offset: u16, byte: u8) {
+        match offset {
+            0 => self.y = byte,
+            1 => self.x = byte,
+            2 => self.tile_number = byte,
+            3 => self.attributes = byte.into(),
+            _ => unreachable!(),
+        }
+    }
+}
+
+impl Display for Sprite {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "({:4}, {:4}) tile {:02x} attributes {:02x}",
+            i16::from(self.x) - 8,
+            i16::from(self.y) - 16,
+            self.tile_number,
+            u8::from(self.attributes)
+        )
+    }
+}
+
+/// A sprite the OAM scan picked for the current line, copied out of OAM as
+/// it was when scanned
+#[derive(Copy, Clone, Debug)]
+pub struct ScannedSprite {
+    pub oam_index: u8,
+    pub sprite: Sprite,
+}
+
+/// What the pixel FIFO renderer is holding, oldest pixel first
+#[derive(Clone, Debug)]
+pub struct FifoDebugState {
+    pub background: Vec<ColorIndex>,
+    pub objects: Vec<ColorIndex>,
+    // Pixels shifted out to the LCD so far this line
+    pub pixel_x: u8,
+    pub fetching_window: bool,
+}
+
+/// A look at what the PPU is doing right now
+#[derive(Clone, Debug)]
+pub struct PpuDebugState {
+    pub mode: u8,
+    // Dots since the start of the line, 0 to 455
+    pub dot: u16,
+    pub ly: u8,
+    pub lx: i16,
+    pub window_line: u8,
+    pub sprites: Vec<ScannedSprite>,
+    // Only the pixel FIFO renderer has FIFOs
+    pub fifo: Option<FifoDebugState>,
+}
+
+fn format_fifo(pixels: &[ColorIndex]) -> String {
+    let pixels: Vec<String> = pixels.iter().map(|&c| u8::from(c).to_string()).collect();
+    format!("[{}]", pixels.join(" "))
+}
+
+impl Display for PpuDebugState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(
+            f,
+            "Mode {}, line {} dot {} (LX {})",
+            self.mode, self.ly, self.dot, self.lx
+        )?;
+        writeln!(f, "Window line: {}", self.window_line)?;
+        let sprites: Vec<String> = self
+            .sprites
+            .iter()
+            .map(|scanned| scanned.oam_index.to_string())
+            .collect();
+        writeln!(
+            f,
+            "Sprites on this line ({}/{}): {}",
+            self.sprites.len(),
+            MAX_SPRITES_PER_LINE,
+            sprites.join(", ")
+        )?;
+        match &self.fifo {
+            Some(fifo) => {
+                writeln!(
+                    f,
+                    "Pixel X: {}{}",
+                    fifo.pixel_x,
+                    if fifo.fetching_window {
+                        ", fetching the window"
+                    } else {
+                        ""
+                    }
+                )?;
+                writeln!(f, "Background FIFO: {}", format_fifo(&fifo.background))?;
+                write!(f, "Object FIFO: {}", format_fifo(&fifo.objects))
+            }
+            None => write!(f, "FIFOs: not used by the direct renderer"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct ObjectAttributeMemory {
+    sprites: [Sprite; 40],
+}
+
+impl Default for ObjectAttributeMemory {
+    fn default() -> Self {
+        Self {
+            sprites: [Sprite::default(); 40],
+        }
+    }
+}
+
+impl ObjectAttributeMemory {
+    fn read(&self, offset: u16) -> u8 {
+        // Sprites are 4 bytes
+        self.sprites[(offset / 4) as usize].read(offset % 4)
+    }
+
+    fn write(&mut self, offset: u16, byte: u8) {
+        // Sprites are 4 bytes
+        self.sprites[(offset / 4) as usize].write(offset % 4, byte);
+    }
+
+    fn read_word(&self, offset: u16) -> u16 {
+        u16::from_le_bytes([self.read(offset), self.read(offset + 1)])
+    }
+
+    fn write_word(&mut self, offset: u16, word: u16) {
+        let [low, high] = word.to_le_bytes();
+        self.write(offset, low);
+        self.write(offset + 1, high);
+    }
+
+    // See https://gbdev.io/pandocs/OAM_Corruption_Bug.html, OAM is 20 rows
+    // of 4 words and the first row is never corrupted
+    fn corrupt_row_on_write(&mut self, row: u16) {
+        if row == 0 || row >= 20 {
+            return;
+        }
+        let current = row * 8;
+        let previous = current - 8;
+        let a = self.read_word(current);
+        let b = self.read_word(previous);
+        let c = self.read_word(previous + 4);
+        self.write_word(current, ((a ^ c) & (b ^ c)) ^ c);
+        // The rest of the row is copied from the previous one
+        for offset in 2..8 {
+            self.write(current + offset, self.read(previous + offset));
+        }
+    }
+}
+
+/// The registers each line of a finished frame was drawn with, handed to
+/// the frontend alongside the framebuffer. Palettes are applied per pixel,
+/// so a write in the middle of a line only shows up from the next line
+/// here.
+#[derive(Copy, Clone, Debug)]
+pub struct FrameMetadata {
+    pub frame: u64,
+    pub lines: [ScanlineRegisters; 144],
+}
+
+impl Default for FrameMetadata {
+    fn default() -> Self {
+        Self {
+            frame: 0,
+            lines: [ScanlineRegisters::default(); 144],
+        }
+    }
+}
+
+#[derive(Debug)]
+pub struct PictureProcessingUnit {
+    pub video_ram: VideoRam,
+    pub object_attribute_memory: ObjectAttributeMemory,
+    // Drawn into one at a time, the other one holding the last finished
+    // frame. Handing a finished frame to the frontend only shares it, and
+    // it is copied later only if the frontend still holds on to it when
+    // the PPU wants to draw into it again.
+    framebuffers: [Arc<Frame>; 2],
+    // The one being drawn into
+    framebuffer_selector: bool,
+    rgba_output: Option<RgbaOutput>,
+    // In OAM order during the scan, then sorted by priority, highest first
+    sprites_this_line: Vec<ScannedSprite>,
+    // Double buffered along with the framebuffers
+    scanline_registers: [[ScanlineRegisters; 144]; 2],
+    renderer: Renderer,
+    pixel_fifo: PixelFifo,
+    batch_scanlines: bool,
+    // Whether the direct renderer is waiting to draw the current line at
+    // the end of mode 3
+    line_batched: bool,
+    // SCX and SCY as the direct renderer last read them
+    fine_scroll_x: u8,
+    tile_scroll: (u8, u8),
+    lcd_was_enabled: bool,
+    // Dots since the last blank frame while the LCD is off
+    disabled_dots: u64,
+    // Set when there is a frame for the frontend, at vblank or every
+    // frame's worth of dots while the LCD is off
+    frame_ready: bool,
+}
+
+impl Default for PictureProcessingUnit {
+    fn default() -> Self {
+        Self {
+            video_ram: VideoRam::default(),
+            object_attribute_memory: ObjectAttributeMemory::default(),
+            framebuffers: [blank_frame(), blank_frame()],
+            framebuffer_selector: false,
+            rgba_output: None,
+            sprites_this_line: Vec::with_capacity(MAX_SPRITES_PER_LINE),
+            scanline_registers: [[ScanlineRegisters::default(); 144]; 2],
+            renderer: Renderer::default(),
+            pixel_fifo: PixelFifo::default(),
+            batch_scanlines: true,
+            line_batched: false,
+            fine_scroll_x: 0,
+            tile_scroll: (0, 0),
+            lcd_was_enabled: true,
+            disabled_dots: 0,
+            frame_ready: false,
+        }
+    }
+}
+
+impl PictureProcessingUnit {
+    // The PPU has VRAM to itself while drawing, and OAM from the start of
+    // the OAM scan, so the CPU reads 0xff and its writes are dropped
+    fn video_ram_blocked(lcd: &Lcd) -> bool {
+        lcd.get_mode() == 3
+    }
+
+    fn object_attribute_memory_blocked(lcd: &Lcd) -> bool {
+        matches!(lcd.get_mode(), 2 | 3)
+    }
+
+    #[must_use]
+    pub fn read_video_ram(&self, lcd: &Lcd, offset: u16) -> u8 {
+        if Self::video_ram_blocked(lcd) {
+            return 0xff;
+        }
+        self.video_ram.read(offset)
+    }
+
+    pub fn write_video_ram(&mut self, lcd: &Lcd, offset: u16, byte: u8) {
+        if !Self::video_ram_blocked(lcd) {
+            self.video_ram.write(offset, byte);
+        }
+    }
+
+    #[must_use]
+    pub fn read_object_attribute_memory(&self, lcd: &Lcd, offset: u16) -> u8 {
+        if Self::object_attribute_memory_blocked(lcd) {
+            return 0xff;
+        }
+        self.object_attribute_memory.read(offset)
+    }
+
+    pub fn write_object_attribute_memory(&mut self, lcd: &Lcd, offset: u16, byte: u8) {
+        if !Self::object_attribute_memory_blocked(lcd) {
+            self.object_attribute_memory.write(offset, byte);
+        }
+    }
+
+    /// Copies a byte in for OAM DMA, which gets through whatever mode the
+    /// PPU is in
+    pub fn dma_write_object_attribute_memory(&mut self, offset: u16, byte: u8) {
+        self.object_attribute_memory.write(offset, byte);
+    }
+
+    /// Reads VRAM whatever mode the PPU is in, for the debugger
+    #[must_use]
+    pub fn peek_video_ram(&self, offset: u16) -> u8 {
+        self.video_ram.read(offset)
+    }
+
+    /// Writes VRAM whatever mode the PPU is in, for the debugger
+    pub fn poke_video_ram(&mut self, offset: u16, byte: u8) {
+        self.video_ram.write(offset, byte);
+    }
+
+    /// Reads OAM whatever mode the PPU is in, for the debugger
+    #[must_use]
+    pub fn peek_object_attribute_memory(&self, offset: u16) -> u8 {
+        self.object_attribute_memory.read(offset)
+    }
+
+    /// Writes OAM whatever mode the PPU is in, for the debugger
+    pub fn poke_object_attribute_memory(&mut self, offset: u16, byte: u8) {
+        self.object_attribute_memory.write(offset, byte);
+    }
+
+    /// Applies the DMG OAM corruption bug to the row the PPU is scanning
+    pub fn corrupt_object_attribute_memory(&mut self, row: u16) {
+        self.object_attribute_memory.corrupt_row_on_write(row);
+    }
+
+    fn get_color_at_pixel_using_tilemap(
+        &self,
+        x: u8,
+        y: u8,
+        selected_map: TileMap,
+        mut addressing_mode: TileAddressingMethod,
+    ) -> ColorIndex {
+        let tilemap = match selected_map {
+            TileMap::From9800 => &self.video_ram.background_map_0,
+            TileMap::From9C00 => &self.video_ram.background_map_1,
+        };
+        let map_x = x / 8;
+        let map_y = y / 8;
+        let tile_index = tilemap[32 * map_y as usize + map_x as usize];
+
+        addressing_mode.set_offset(tile_index);
+        let tile = self.video_ram.read_tile(addressing_mode);
+
+        let tile_x = x % 8;
+        let tile_y = y % 8;
+        tile.get_color(tile_x, tile_y)
+    }
+
+    fn get_color_at_pixel_for_sprite(
+        &self,
+        x: u8,
+        y: u8,
+        tile_index: u8,
+        flip_x: bool,
+        flip_y: bool,
+    ) -> ColorIndex {
+        // Sprites only use tilemap 1
+        let addressing_mode = TileAddressingMethod::From8000(tile_index);
+        let tile = self.video_ram.read_tile(addressing_mode);
+        let tile_x = if flip_x { 7 - (x % 8) } else { x % 8 };
+        let tile_y = if flip_y { 7 - (y % 8) } else { y % 8 };
+        tile.get_color(tile_x, tile_y)
+    }
+
+    /// The color of `object` at the given screen position, None where it
+    /// doesn't cover that column or is transparent
+    fn get_color_index_for_sprite(
+        &self,
+        object: &Sprite,
+        x: i16,
+        y: u8,
+        sprite_size: SpriteSize,
+    ) -> Option<ColorIndex> {
+        let sprite_x = i16::from(object.x) - 8;
+        if !(sprite_x..(sprite_x + 8)).contains(&x) {
+            return None;
+        }
+        let sprite_y = i16::from(object.y) - 16;
+        #[allow(clippy::cast_possible_truncation)]
+        #[allow(clippy::cast_sign_loss)]
+        let (tile_x, tile_y) = ((x - sprite_x) as u8, (i16::from(y) - sprite_y) as u8);
+        let tile_index = match sprite_size {
+            SpriteSize::Small => object.tile_number,
+            SpriteSize::Large => {
+                // The hardware enforces that, for two tile sprites, the
+                // first sprite has a 0 in the lowest bit, and the second
+                // sprite has a 1
+                let temp_tile_index = if tile_y > 7 {
+                    object.tile_number | 0x1
+                } else {
+                    object.tile_number & !0x1
+                };
+                if object.attributes.flip_y {
+                    temp_tile_index ^ 0x1
+                } else {
+                    temp_tile_index
+                }
+            }
+        };
+        let color_index = self.get_color_at_pixel_for_sprite(
+            tile_x,
+            tile_y,
+            tile_index,
+            object.attributes.flip_x,
+            object.attributes.flip_y,
+        );
+        if matches!(color_index, ColorIndex::Color0) {
+            None
+        } else {
+            Some(color_index)
+        }
+    }
+
+    // Mode 2 checks one OAM entry every 2 dots, picking the first 10 that
+    // cover the line, using the sprite size at the time of the check
+    fn scan_oam_entry(&mut self, index: u8, y: u8, sprite_size: SpriteSize) {
+        if self.sprites_this_line.len() >= MAX_SPRITES_PER_LINE {
+            return;
+        }
+        let object = self.object_attribute_memory.sprites[index as usize];
+        let sprite_y = i16::from(object.y) - 16;
+        let height = match sprite_size {
+            SpriteSize::Small => 8,
+            SpriteSize::Large => 16,
+        };
+        if (sprite_y..(sprite_y + height)).contains(&i16::from(y)) {
+            self.sprites_this_line.push(ScannedSprite {
+                oam_index: index,
+                sprite: object,
+            });
+        }
+    }
+
+    pub fn tick(&mut self, cycles: u64, lcd: &mut Lcd) -> (bool, bool) {
+        let mut vblank_interrupt = false;
+        let mut stat_interrupt = false;
+        let lcd_enable = lcd.get_lcd_enable();
+
+        if lcd_enable {
+            for _ in 0..cycles {
+                let x_i16 = lcd.get_lx();
+                let y = lcd.get_ly();
+                if y < 144 && x_i16 < 0 {
+                    if x_i16 == -80 {
+                        // New line, reset the sprites picked for the last one
+                        self.sprites_this_line.clear();
+                    }
+                    if x_i16 % 2 == 0 {
+                        #[allow(clippy::cast_possible_truncation)]
+                        #[allow(clippy::cast_sign_loss)]
+                        let index = ((x_i16 + 80) / 2) as u8;
+                        self.scan_oam_entry(index, y, lcd.get_sprite_size());
+                    }
+                }
+                if y < 144 && x_i16 == 0 {
+                    // On DMG the sprite with the lowest X is on top, ties
+                    // going to the one earlier in OAM, which the stable sort
+                    // keeps first
+                    self.sprites_this_line
+                        .sort_by_key(|scanned| scanned.sprite.x);
+                    let buffer = usize::from(self.framebuffer_selector);
+                    self.scanline_registers[buffer][y as usize] = lcd.get_scanline_registers();
+                }
+
+                if self.renderer == Renderer::PixelFifo {
+                    let pixel = self
+                        .pixel_fifo
+                        .tick(&self.video_ram, &self.sprites_this_line, lcd);
+                    if let Some((x, color)) = pixel {
+                        self.write_to_framebuffer(usize::from(x), usize::from(y), color);
+                    }
+                } else if y < 144 && (0..=160).contains(&x_i16) {
+                    if x_i16 == 0 {
+                        self.line_batched = self.batch_scanlines;
+                    }
+                    if self.line_batched {
+                        // Nothing changed in the middle of the line, so it
+                        // can all be drawn at once
+                        if x_i16 == 160 {
+                            self.line_batched = false;
+                            self.draw_line(y, lcd);
+                        }
+                    } else if x_i16 < 160 {
+                        #[allow(clippy::cast_possible_truncation)]
+                        #[allow(clippy::cast_sign_loss)]
+                        self.draw_pixel(x_i16 as u8, y, lcd);
+                    }
+                }
+
+                let interrupts = lcd.tick();
+                if interrupts.0 {
+                    self.framebuffer_selector ^= true;
+                    self.frame_ready = true;
+                }
+                vblank_interrupt |= interrupts.0;
+                stat_interrupt |= interrupts.1;
+            }
+            self.lcd_was_enabled = true;
+        } else {
+            if self.lcd_was_enabled {
+                // The screen goes blank as soon as the LCD is turned off
+                self.lcd_was_enabled = false;
+                self.framebuffers = [blank_frame(), blank_frame()];
+                if let Some(output) = &mut self.rgba_output {
+                    output.framebuffers = [blank_rgba_frame(), blank_rgba_frame()];
+                }
+                self.disabled_dots = 0;
+                self.frame_ready = true;
+            }
+            // Without vblank to pace them, keep handing the frontend blank
+            // frames as often as it would normally get frames
+            self.disabled_dots += cycles;
+            if self.disabled_dots >= DOTS_PER_FRAME {
+                self.disabled_dots -= DOTS_PER_FRAME;
+                self.frame_ready = true;
+            }
+        }
+
+        (vblank_interrupt, stat_interrupt)
+    }
+
+    fn draw_pixel(&mut self, x: u8, y: u8, lcd: &mut Lcd) {
+        let x_i16 = i16::from(x);
+        let addressing_mode = lcd.get_addressing_mode();
+        let bg_window_priority = lcd.get_background_window_priority();
+
+        // draw background
+        // The fine part of SCX is only read at the start of the line, the
+        // rest of it and SCY at the start of each tile, like the fetcher
+        // does, so mid line writes take effect on the next tile
+        let (scroll_x, scroll_y) = lcd.get_scroll_offsets();
+        if x == 0 {
+            self.fine_scroll_x = scroll_x % 8;
+        }
+        if x == 0 || (x + self.fine_scroll_x) & 0x7 == 0 {
+            self.tile_scroll = (scroll_x & !0x7, scroll_y);
+        }
+        let bg_x = (self.tile_scroll.0 | self.fine_scroll_x).wrapping_add(x);
+        let bg_y = self.tile_scroll.1.wrapping_add(y);
+        let bg_tile_map = lcd.get_background_tile_map();
+        // Palettes are looked up for every pixel as it is drawn,
+        // so writes between (or even during) lines show up
+        // exactly where they happened, like DMG hi-color demos
+        // rely on
+        let palette = lcd.get_background_palette();
+
+        // If this is not true, the background and window should display as white
+        let mut bg_color = ColorIndex::Color0;
+        if bg_window_priority {
+            bg_color =
+                self.get_color_at_pixel_using_tilemap(bg_x, bg_y, bg_tile_map, addressing_mode);
+            let color = palette.get_color(&bg_color);
+            self.write_to_framebuffer(x as usize, y as usize, color);
+        } else {
+            self.write_to_framebuffer(x as usize, y as usize, Color::White);
+        }
+
+        // draw window
+        let (window_x, _) = lcd.get_window_coords();
+        // WX is the left edge plus 7, so WX below 7 pushes the
+        // window's first columns off the left of the screen
+        let window_start = i16::from(window_x) - 7;
+        let window_tile_map = lcd.get_window_tile_map();
+        let window_enable = lcd.get_window_enable();
+
+        if bg_window_priority
+            && window_enable
+            && lcd.get_window_y_triggered()
+            && x_i16 >= window_start
+        {
+            lcd.set_window_was_rendered();
+            #[allow(clippy::cast_possible_truncation)]
+            #[allow(clippy::cast_sign_loss)]
+            let win_pos_x = (x_i16 - window_start) as u8;
+            let win_pos_y = lcd.get_window_line();
+            bg_color = self.get_color_at_pixel_using_tilemap(
+                win_pos_x,
+                win_pos_y,
+                window_tile_map,
+                addressing_mode,
+            );
+            let color = palette.get_color(&bg_color);
+            self.write_to_framebuffer(x as usize, y as usize, color);
+        }
+
+        // draw objects
+        if lcd.get_sprite_enable() {
+            // The first opaque pixel in priority order wins, and
+            // only then does its priority bit decide whether the
+            // background covers it, so a sprite hidden behind
+            // the background still hides the sprites under it
+            let sprite_size = lcd.get_sprite_size();
+            let pixel = self.sprites_this_line.iter().find_map(|scanned| {
+                let object = &scanned.sprite;
+                self.get_color_index_for_sprite(object, x_i16, y, sprite_size)
+                    .map(|color_index| (object.attributes, color_index))
+            });
+            if let Some((attributes, color_index)) = pixel {
+                if object_over_background(
+                    bg_window_priority,
+                    false,
+                    attributes.behind_background,
+                    bg_color,
+                ) {
+                    let (obj_pal0, obj_pal1) = lcd.get_object_palettes();
+                    let palette = match attributes.gb_palette_number {
+                        SpritePaletteNumber::Palette0 => obj_pal0,
+                        SpritePaletteNumber::Palette1 => obj_pal1,
+                    };
+                    let color = palette.get_color(&color_index);
+                    self.write_to_framebuffer(x as usize, y as usize, color);
+                }
+            }
+        }
+    }
+
+    /// Fills `colors` with a row of the tile map, starting at `map_x`. Each
+    /// tile is only looked up once.
+    fn fill_from_tilemap(
+        &self,
+        colors: &mut [ColorIndex],
+        map_x: u8,
+        map_y: u8,
+        selected_map: TileMap,
+        mut addressing_mode: TileAddressingMethod,
+    ) {
+        let tilemap = match selected_map {
+            TileMap::From9800 => &self.video_ram.background_map_0,
+            TileMap::From9C00 => &self.video_ram.background_map_1,
+        };
+        let mut current: Option<(u8, Tile)> = None;
+        for (i, color) in colors.iter_mut().enumerate() {
+            #[allow(clippy::cast_possible_truncation)]
+            let x = map_x.wrapping_add(i as u8);
+            let tile = match current {
+                Some((column, tile)) if column == x / 8 => tile,
+                _ => {
+                    let tile_index = tilemap[32 * usize::from(map_y / 8) + usize::from(x / 8)];
+                    addressing_mode.set_offset(tile_index);
+                    let tile = self.video_ram.read_tile(addressing_mode);
+                    current = Some((x / 8, tile));
+                    tile
+                }
+            };
+            *color = tile.get_color(x % 8, map_y % 8);
+        }
+    }
+
+    /// Draws a whole line with the registers as they are now, which gives
+    /// the same picture as drawing it dot by dot as long as nothing was
+    /// written to them or VRAM in the middle of it
+    fn draw_line(&mut self, y: u8, lcd: &mut Lcd) {
+        let bg_window_priority = lcd.get_background_window_priority();
+        let addressing_mode = lcd.get_addressing_mode();
+        let mut bg_colors = [ColorIndex::Color0; 160];
+        if bg_window_priority {
+            let (scroll_x, scroll_y) = lcd.get_scroll_offsets();
+            self.fill_from_tilemap(
+                &mut bg_colors,
+                scroll_x,
+                scroll_y.wrapping_add(y),
+                lcd.get_background_tile_map(),
+                addressing_mode,
+            );
+
+            let (window_x, _) = lcd.get_window_coords();
+            let window_start = i16::from(window_x) - 7;
+            if lcd.get_window_enable() && lcd.get_window_y_triggered() && window_start < 160 {
+                lcd.set_window_was_rendered();
+                #[allow(clippy::cast_sign_loss)]
+                let first_x = window_start.max(0) as usize;
+                #[allow(clippy::cast_possible_truncation)]
+                #[allow(clippy::cast_sign_loss)]
+                let map_x = (first_x as i16 - window_start) as u8;
+                self.fill_from_tilemap(
+                    &mut bg_colors[first_x..],
+                    map_x,
+                    lcd.get_window_line(),
+                    lcd.get_window_tile_map(),
+                    addressing_mode,
+                );
+            }
+        }
+
+        // Sprites are already sorted by priority, so the first opaque pixel
+        // for each column is the one that gets drawn
+        let mut sprite_pixels: [Option<(SpriteAttributes, ColorIndex)>; 160] = [None; 160];
+        if lcd.get_sprite_enable() {
+            let sprite_size = lcd.get_sprite_size();
+            for scanned in &self.sprites_this_line {
+                let object = &scanned.sprite;
+                let sprite_x = i16::from(object.x) - 8;
+                for x in sprite_x.max(0)..(sprite_x + 8).min(160) {
+                    #[allow(clippy::cast_sign_loss)]
+                    let pixel = &mut sprite_pixels[x as usize];
+                    if pixel.is_none() {
+                        *pixel = self
+                            .get_color_index_for_sprite(object, x, y, sprite_size)
+                            .map(|color_index| (object.attributes, color_index));
+                    }
+                }
+            }
+        }
+
+        let palette = lcd.get_background_palette();
+        let (obj_pal0, obj_pal1) = lcd.get_object_palettes();
+        for (x, (bg_color, sprite_pixel)) in bg_colors.iter().zip(&sprite_pixels).enumerate() {
+            let mut color = if bg_window_priority {
+                palette.get_color(bg_color)
+            } else {
+                Color::White
+            };
+            if let Some((attributes, color_index)) = sprite_pixel {
+                if object_over_background(
+                    bg_window_priority,
+                    false,
+                    attributes.behind_background,
+                    *bg_color,
+                ) {
+                    let palette = match attributes.gb_palette_number {
+                        SpritePaletteNumber::Palette0 => obj_pal0,
+                        SpritePaletteNumber::Palette1 => obj_pal1,
+                    };
+                    color = palette.get_color(color_index);
+                }
+            }
+            self.write_to_framebuffer(x, y as usize, color);
+        }
+    }
+
+    /// Called before anything that changes how the current line is drawn.
+    /// If it was going to be drawn all at once, the pixels the LCD already
+    /// got to are drawn with the old values and the rest go dot by dot.
+    pub fn catch_up(&mut self, lcd: &mut Lcd) {
+        if !self.line_batched {
+            return;
+        }
+        self.line_batched = false;
+        let y = lcd.get_ly();
+        for x in 0..lcd.get_lx().clamp(0, 160) {
+            #[allow(clippy::cast_possible_truncation)]
+            #[allow(clippy::cast_sign_loss)]
+            self.draw_pixel(x as u8, y, lcd);
+        }
+    }
+
+    fn write_to_framebuffer(&mut self, x: usize, y: usize, color: Color) {
+        let framebuffer = self.get_current_framebuffer_mut();
+        framebuffer[y][x] = color;
+        if let Some(output) = &mut self.rgba_output {
+            let buffer = usize::from(self.framebuffer_selector);
+            let offset = 4 * (160 * y + x);
+            let rgba = Arc::make_mut(&mut output.framebuffers[buffer]);
+            output
+                .layout
+                .write_pixel(&mut rgba[offset..offset + 4], color);
+        }
+    }
+
+    pub fn get_current_framebuffer_mut(&mut self) -> &mut Frame {
+        Arc::make_mut(&mut self.framebuffers[usize::from(self.framebuffer_selector)])
+    }
+
+    /// The last finished frame, shared rather than copied
+    #[must_use]
+    pub fn get_finished_frame(&self) -> Arc<Frame> {
+        self.framebuffers[usize::from(!self.framebuffer_selector)].clone()
+    }
+
+    #[must_use]
+    pub fn debug_state(&self, lcd: &Lcd) -> PpuDebugState {
+        #[allow(clippy::cast_sign_loss)]
+        let dot = (lcd.get_lx() + 80) as u16;
+        PpuDebugState {
+            mode: lcd.get_mode(),
+            dot,
+            ly: lcd.get_ly(),
+            lx: lcd.get_lx(),
+            window_line: lcd.get_window_line(),
+            sprites: self.sprites_this_line.clone(),
+            fifo: if self.renderer == Renderer::PixelFifo {
+                Some(self.pixel_fifo.debug_state())
+            } else {
+                None
+            },
+        }
+    }
+
+    /// The last finished frame and its RGBA copy, if there is one
+    #[must_use]
+    pub fn get_finished_frame_with_rgba(&self) -> FinishedFrame {
+        let buffer = usize::from(!self.framebuffer_selector);
+        FinishedFrame {
+            colors: self.framebuffers[buffer].clone(),
+            rgba: self
+                .rgba_output
+                .as_ref()
+                .map(|output| output.framebuffers[buffer].clone()),
+        }
+    }
+
+    /// Also draws every frame as RGBA bytes in the given layout, ready to
+    /// be copied into a texture, or stops doing that
+    pub fn set_rgba_layout(&mut self, layout: Option<RgbaLayout>) {
+        self.rgba_output = layout.map(|layout| RgbaOutput::new(layout, &self.framebuffers));
+    }
+
+    #[must_use]
+    pub fn get_rgba_layout(&self) -> Option<RgbaLayout> {
+        self.rgba_output.as_ref().map(|output| output.layout)
+    }
+
+    /// The registers each line of the last finished frame started drawing
+    /// with
+    #[must_use]
+    pub fn get_scanline_registers(&self) -> &[ScanlineRegisters; 144] {
+        &self.scanline_registers[usize::from(!self.framebuffer_selector)]
+    }
+
+    /// Whether a frame was finished since the last call, either at vblank
+    /// or a blank one while the LCD is off
+    pub fn take_frame_ready(&mut self) -> bool {
+        std::mem::take(&mut self.frame_ready)
+    }
+
+    #[must_use]
+    pub fn get_renderer(&self) -> Renderer {
+        self.renderer
+    }
+
+    pub fn set_renderer(&mut self, renderer: Renderer) {
+        self.renderer = renderer;
+    }
+
+    #[must_use]
+    pub fn get_batch_scanlines(&self) -> bool {
+        self.batch_scanlines
+    }
+
+    /// Drawing whole lines at once when nothing changes halfway through
+    /// them is much faster, turning it off draws every line dot by dot
+    pub fn set_batch_scanlines(&mut self, batch: bool) {
+        self.batch_scanlines = batch;
+    }
+
+    #[must_use]
+    pub fn get_sprites(&self) -> &[Sprite; 40] {
+        &self.object_attribute_memory.sprites
+    }
+
+    /// The sprites picked for the line being drawn, in OAM order until the
+    /// scan finishes, then in drawing priority
+    #[must_use]
+    pub fn get_sprites_this_line(&self) -> &[ScannedSprite] {
+        &self.sprites_this_line
+    }
+
+    /// The frame being drawn, complete up to the current position
+    #[must_use]
+    pub fn get_current_framebuffer(&self) -> &Frame {
+        &self.framebuffers[usize::from(self.framebuffer_selector)]
+    }
+}
+
+impl SaveState for PictureProcessingUnit {
+    fn save_state(&self, writer: &mut StateWriter) {
+        for offset in 0..0x2000 {
+            writer.write_u8(self.video_ram.read(offset));
+        }
+        for offset in 0..0xa0 {
+            writer.write_u8(self.object_attribute_memory.read(offset));
+        }
+        for framebuffer in &self.framebuffers {
+            for row in framebuffer.iter() {
+                for &color in row {
+                    writer.write_u8(color.into());
+                }
+            }
+        }
+        writer.write_bool(self.framebuffer_selector);
+        #[allow(clippy::cast_possible_truncation)]
+        writer.write_u8(self.sprites_this_line.len() as u8);
+        for scanned in &self.sprites_this_line {
+            writer.write_u8(scanned.oam_index);
+            for offset in 0..4 {
+                writer.write_u8(scanned.sprite.read(offset));
+            }
+        }
+        self.pixel_fifo.save_state(writer);
+        writer.write_bool(self.line_batched);
+        writer.write_u8(self.fine_scroll_x);
+        writer.write_u8(self.tile_scroll.0);
+        writer.write_u8(self.tile_scroll.1);
+        writer.write_bool(self.lcd_was_enabled);
+        writer.write_u64(self.disabled_dots);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        if reader.version() < 13 {
+            // Whether the LCD was using VRAM, which is now worked out from
+            // the mode
+            let _in_use_by_lcd = reader.read_bool()?;
+        }
+        for offset in 0..0x2000 {
+            self.video_ram.write(offset, reader.read_u8()?);
+        }
+        for offset in 0..0xa0 {
+            self.object_attribute_memory
+                .write(offset, reader.read_u8()?);
+        }
+        for framebuffer in &mut self.framebuffers {
+            for row in Arc::make_mut(framebuffer).iter_mut() {
+                for color in row.iter_mut() {
+                    let v = reader.read_u8()?;
+                    if v > 3 {
+                        return Err(SaveStateError::Invalid("color"));
+                    }
+                    *color = v.into();
+                }
+            }
+        }
+        self.framebuffer_selector = reader.read_bool()?;
+        self.set_rgba_layout(self.get_rgba_layout());
+        let sprite_count = reader.read_u8()?;
+        self.sprites_this_line.clear();
+        for index in 0..sprite_count {
+            // Older states kept the sprites in OAM order without their index
+            let oam_index = if reader.version() >= 8 {
+                reader.read_u8()?
+            } else {
+                index
+            };
+            let mut sprite = Sprite::default();
+            for offset in 0..4 {
+                sprite.write(offset, reader.read_u8()?);
+            }
+            self.sprites_this_line
+                .push(ScannedSprite { oam_index, sprite });
+        }
+        self.pixel_fifo = PixelFifo::default();
+        if reader.version() >= 9 {
+            self.pixel_fifo.load_state(reader)?;
+        }
+        self.line_batched = reader.version() >= 10 && reader.read_bool()?;
+        if reader.version() >= 11 {
+            self.fine_scroll_x = reader.read_u8()? % 8;
+            self.tile_scroll = (reader.read_u8()? & !0x7, reader.read_u8()?);
+        } else {
+            self.fine_scroll_x = 0;
+            self.tile_scroll = (0, 0);
+        }
+        if reader.version() >= 12 {
+            self.lcd_was_enabled = reader.read_bool()?;
+            self.disabled_dots = reader.read_u64()?;
+        } else {
+            self.lcd_was_enabled = true;
+            self.disabled_dots = 0;
+        }
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::gbc::mmio::lcd::DOTS_PER_FRAME;
+
+    const LCD_LCDC_OFFSET: u16 = 0x0;
+    const LCD_STAT_OFFSET: u16 = 0x1;
+    const LCD_SCX_OFFSET: u16 = 0x3;
+    const LCD_LY_OFFSET: u16 = 0x4;
+    const LCD_LYC_OFFSET: u16 = 0x5;
+    const LCD_BGP_OFFSET: u16 = 0x7;
+    const LCD_OBP0_OFFSET: u16 = 0x8;
+    const LCD_OBP1_OFFSET: u16 = 0x9;
+    const LCD_WY_OFFSET: u16 = 0xa;
+    const LCD_WX_OFFSET: u16 = 0xb;
+    // LCD, window (using the 0x9c00 map), tiles from 0x8000 and background on
+    const LCDC_WINDOW_ON: u8 = 0xf1;
+    const LCDC_WINDOW_OFF: u8 = 0xd1;
+    const LCDC_ENABLE: u8 = 0x80;
+    const WINDOW_MAP_OFFSET: u16 = 0x1c00;
+    const STAT_LY_EQUAL_LYC: u8 = 0x04;
+    const STAT_HBLANK: u8 = 0x08;
+    const STAT_VBLANK: u8 = 0x10;
+    const STAT_OAM: u8 = 0x20;
+    const STAT_LYC: u8 = 0x40;
+
+    /// Ticks one dot at a time until the LCD is at the given position
+    fn run_until(ppu: &mut PictureProcessingUnit, lcd: &mut Lcd, ly: u8, lx: i16) {
+        while !(lcd.get_ly() == ly && lcd.get_lx() == lx) {
+            let _ = ppu.tick(1, lcd);
+        }
+    }
+
+    #[test]
+    fn test_palette_write_takes_effect_on_next_scanline() {
+        let mut ppu = PictureProcessingUnit::default();
+        let mut lcd = Lcd::default();
+        // Every tile is color 0, so the whole screen is BGP's first color
+        lcd.write_u8(LCD_BGP_OFFSET, 0x00);
+
+        // Change the palette during the hblank of line 71, like a hi-color
+        // demo would
+        run_until(&mut ppu, &mut lcd, 71, 200);
+        lcd.write_u8(LCD_BGP_OFFSET, 0x03);
+        run_until(&mut ppu, &mut lcd, 144, -80);
+
+        // The finished frame is in the buffer that was drawn before the swap
+        let framebuffer = &ppu.framebuffers[0];
+        for (y, row) in framebuffer.iter().enumerate() {
+            let expected = if y < 72 { Color::White } else { Color::Black };
+            for &color in row.iter() {
+                assert_eq!(color, expected, "line {}", y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_object_palette_write_takes_effect_on_next_scanline() {
+        let mut ppu = PictureProcessingUnit::default();
+        let mut lcd = Lcd::default();
+        lcd.write_u8(LCD_BGP_OFFSET, 0x00);
+        lcd.write_u8(LCD_OBP0_OFFSET, 0x04); // color 1 is light gray
+
+        // Tile 1 is solid color 1
+        for line in 0..8 {
+            ppu.video_ram.write(16 + 2 * line, 0xff);
+        }
+        // An 8x8 sprite covering lines 20-27 and columns 0-7
+        ppu.object_attribute_memory.write(0, 20 + 16);
+        ppu.object_attribute_memory.write(1, 8);
+        ppu.object_attribute_memory.write(2, 1);
+        ppu.object_attribute_memory.write(3, 0);
+        lcd.write_u8(0x0, 0x93); // enable objects as well
+
+        run_until(&mut ppu, &mut lcd, 23, 200);
+        lcd.write_u8(LCD_OBP0_OFFSET, 0x08); // color 1 is dark gray
+        run_until(&mut ppu, &mut lcd, 144, -80);
+
+        let framebuffer = &ppu.framebuffers[0];
+        for (y, line) in framebuffer.iter().enumerate().take(28).skip(20) {
+            let expected = if y < 24 {
+                Color::LightGray
+            } else {
+                Color::DarkGray
+            };
+            for (x, &color) in line[..8].iter().enumerate() {
+                assert_eq!(color, expected, "line {} column {}", y, x);
+            }
+            assert_eq!(line[8], Color::White, "line {}", y);
+        }
+    }
+
+    #[test]
+    fn test_oam_corruption_on_write() {
+        let mut ppu = PictureProcessingUnit::default();
+        for offset in 0..0xa0_u16 {
+            #[allow(clippy::cast_possible_truncation)]
+            ppu.object_attribute_memory
+                .write(offset, (offset * 7 + 3) as u8);
+        }
+        let before: Vec<u8> = (0..0xa0)
+            .map(|offset| ppu.object_attribute_memory.read(offset))
+            .collect();
+        let word = |offset: usize| u16::from_le_bytes([before[offset], before[offset + 1]]);
+
+        ppu.corrupt_object_attribute_memory(0);
+        ppu.corrupt_object_attribute_memory(2);
+        let (a, b, c) = (word(16), word(8), word(12));
+        let expected = (((a ^ c) & (b ^ c)) ^ c).to_le_bytes();
+        assert_eq!(ppu.object_attribute_memory.read(16), expected[0]);
+        assert_eq!(ppu.object_attribute_memory.read(17), expected[1]);
+        for offset in 2..8 {
+            assert_eq!(ppu.object_attribute_memory.read(16 + offset), before[8 + offset as usize]);
+        }
+        // Row 0 is never corrupted, and only the scanned row changes
+        for offset in (0..16).chain(24..0xa0) {
+            assert_eq!(ppu.object_attribute_memory.read(offset), before[offset as usize]);
+        }
+    }
+
+    /// Sets up tile 1 as solid color 3 and tile 2 as solid color 1, with
+    /// palettes that show color n as shade n
+    fn setup_window_tiles(ppu: &mut PictureProcessingUnit, lcd: &mut Lcd) {
+        for line in 0..8 {
+            ppu.video_ram.write(16 + 2 * line, 0xff);
+            ppu.video_ram.write(16 + 2 * line + 1, 0xff);
+            ppu.video_ram.write(32 + 2 * line, 0xff);
+        }
+        lcd.write_u8(LCD_BGP_OFFSET, 0xe4);
+        lcd.write_u8(0x0, LCDC_WINDOW_ON);
+    }
+
+    #[test]
+    fn test_window_line_counter_pauses_while_disabled() {
+        let mut ppu = PictureProcessingUnit::default();
+        let mut lcd = Lcd::default();
+        setup_window_tiles(&mut ppu, &mut lcd);
+        // Window rows 0-7 are black, 8-15 light gray and the rest white
+        for column in 0..32 {
+            ppu.video_ram.write(WINDOW_MAP_OFFSET + column, 1);
+            ppu.video_ram.write(WINDOW_MAP_OFFSET + 32 + column, 2);
+        }
+        lcd.write_u8(LCD_WY_OFFSET, 0);
+        lcd.write_u8(LCD_WX_OFFSET, 7);
+
+        run_until(&mut ppu, &mut lcd, 4, 200);
+        lcd.write_u8(0x0, LCDC_WINDOW_OFF);
+        run_until(&mut ppu, &mut lcd, 20, 200);
+        lcd.write_u8(0x0, LCDC_WINDOW_ON);
+        run_until(&mut ppu, &mut lcd, 144, -80);
+
+        // The window picks up from its 6th line when it comes back
+        let framebuffer = &ppu.framebuffers[0];
+        for (y, row) in framebuffer.iter().enumerate() {
+            let expected = match y {
+                0..=4 | 21..=23 => Color::Black,
+                24..=31 => Color::LightGray,
+                _ => Color::White,
+            };
+            for &color in row.iter() {
+                assert_eq!(color, expected, "line {}", y);
+            }
+        }
+    }
+
+    #[test]
+    fn test_window_position_edge_cases() {
+        let mut ppu = PictureProcessingUnit::default();
+        let mut lcd = Lcd::default();
+        setup_window_tiles(&mut ppu, &mut lcd);
+        // The window's first column of tiles is light gray, the rest black
+        for row in 0..32 {
+            ppu.video_ram.write(WINDOW_MAP_OFFSET + 32 * row, 2);
+            for column in 1..32 {
+                ppu.video_ram
+                    .write(WINDOW_MAP_OFFSET + 32 * row + column, 1);
+            }
+        }
+        // WX below 7 hides the window's first 7 - WX columns
+        lcd.write_u8(LCD_WY_OFFSET, 30);
+        lcd.write_u8(LCD_WX_OFFSET, 3);
+
+        // Once LY has matched WY, moving WY doesn't hide the window again
+        run_until(&mut ppu, &mut lcd, 60, 200);
+        lcd.write_u8(LCD_WY_OFFSET, 100);
+        run_until(&mut ppu, &mut lcd, 144, -80);
+
+        let framebuffer = &ppu.framebuffers[0];
+        for (y, row) in framebuffer.iter().enumerate() {
+            for (x, &color) in row.iter().enumerate() {
+                let expected = if y < 30 {
+                    Color::White
+                } else if x < 4 {
+                    Color::LightGray
+                } else {
+                    Color::Black
+                };
+                assert_eq!(color, expected, "line {} column {}", y, x);
+            }
+        }
+    }
+
+    /// Puts an 8x8 sprite at the given screen position
+    fn place_sprite(
+        ppu: &mut PictureProcessingUnit,
+        index: u16,
+        x: u8,
+        y: u8,
+        tile: u8,
+        flags: u8,
+    ) {
+        ppu.object_attribute_memory.write(4 * index, y + 16);
+        ppu.object_attribute_memory.write(4 * index + 1, x + 8);
+        ppu.object_attribute_memory.write(4 * index + 2, tile);
+        ppu.object_attribute_memory.write(4 * index + 3, flags);
+    }
+
+    /// Tile 1 is solid color 1, tile 2 solid color 3 and tile 3 has a
+    /// transparent left half and a color 2 right half. The palettes show
+    /// color n as shade n.
+    fn setup_sprite_tiles(ppu: &mut PictureProcessingUnit, lcd: &mut Lcd) {
+        for line in 0..8 {
+            ppu.video_ram.write(16 + 2 * line, 0xff);
+            ppu.video_ram.write(32 + 2 * line, 0xff);
+            ppu.video_ram.write(32 + 2 * line + 1, 0xff);
+            ppu.video_ram.write(48 + 2 * line + 1, 0x0f);
+        }
+        lcd.write_u8(LCD_BGP_OFFSET, 0xe4);
+        lcd.write_u8(LCD_OBP0_OFFSET, 0xe4);
+        lcd.write_u8(0x0, 0x93); // enable objects as well
+    }
+
+    fn assert_line(framebuffer: &Frame, y: usize, expected: &[(usize, Color)]) {
+        let mut color = Color::White;
+        for (x, &actual) in framebuffer[y].iter().enumerate() {
+            if let Some(&(_, c)) = expected.iter().find(|(start, _)| *start == x) {
+                color = c;
+            }
+            assert_eq!(actual, color, "line {} column {}", y, x);
+        }
+    }
+
+    #[test]
+    fn test_sprite_priority() {
+        let mut ppu = PictureProcessingUnit::default();
+        let mut lcd = Lcd::default();
+        setup_sprite_tiles(&mut ppu, &mut lcd);
+        // The lower X wins, even over a sprite earlier in OAM
+        place_sprite(&mut ppu, 0, 12, 20, 1, 0);
+        place_sprite(&mut ppu, 1, 8, 20, 2, 0);
+        // With the same X the earlier one wins, but only where it's opaque
+        place_sprite(&mut ppu, 2, 42, 40, 3, 0);
+        place_sprite(&mut ppu, 3, 42, 40, 2, 0);
+        run_until(&mut ppu, &mut lcd, 144, -80);
+
+        let framebuffer = &ppu.framebuffers[0];
+        for y in 20..28 {
+            assert_line(
+                framebuffer,
+                y,
+                &[
+                    (8, Color::Black),
+                    (16, Color::LightGray),
+                    (20, Color::White),
+                ],
+            );
+        }
+        for y in 40..48 {
+            assert_line(
+                framebuffer,
+                y,
+                &[
+                    (42, Color::Black),
+                    (46, Color::DarkGray),
+                    (50, Color::White),
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn test_sprite_behind_background_hides_lower_priority_sprites() {
+        let mut ppu = PictureProcessingUnit::default();
+        let mut lcd = Lcd::default();
+        setup_sprite_tiles(&mut ppu, &mut lcd);
+        // OBP1 shows color 1 as dark gray
+        lcd.write_u8(LCD_OBP1_OFFSET, 0x08);
+        // Background line 64-71 is black, with a white hole at columns 8-15
+        for column in 0..32 {
+            ppu.video_ram.write(0x1800 + 32 * 8 + column, 2);
+        }
+        ppu.video_ram.write(0x1800 + 32 * 8 + 1, 0);
+        place_sprite(&mut ppu, 0, 10, 64, 1, 0x80); // behind the background
+        place_sprite(&mut ppu, 1, 14, 64, 1, 0x10); // using OBP1
+        run_until(&mut ppu, &mut lcd, 144, -80);
+
+        // The first sprite shows through the hole, and where the background
+        // covers it, it still keeps the second sprite out
+        let framebuffer = &ppu.framebuffers[0];
+        for y in 64..72 {
+            assert_line(
+                framebuffer,
+                y,
+                &[
+                    (0, Color::Black),
+                    (8, Color::White),
+                    (10, Color::LightGray),
+                    (16, Color::Black),
+                    (18, Color::DarkGray),
+                    (22, Color::Black),
+                ],
+            );
+        }
+    }
+
+    #[test]
+    fn test_background_over_object_priority() {
+        // LCDC, sprite flags, and where the line changes color
+        type Case = (u8, u8, &'static [(usize, Color)]);
+        let cases: [Case; 4] = [
+            (0x93, 0x00, &[(16, Color::Black), (24, Color::White)]),
+            // Only the nonzero half of the background covers the sprite
+            (
+                0x93,
+                0x80,
+                &[
+                    (16, Color::Black),
+                    (20, Color::LightGray),
+                    (24, Color::White),
+                ],
+            ),
+            // With the background off, nothing covers the sprite
+            (0x92, 0x00, &[(16, Color::Black), (24, Color::White)]),
+            (0x92, 0x80, &[(16, Color::Black), (24, Color::White)]),
+        ];
+        for renderer in [Renderer::Direct, Renderer::PixelFifo] {
+            for &(lcdc, flags, expected) in &cases {
+                let mut ppu = PictureProcessingUnit::default();
+                let mut lcd = Lcd::default();
+                ppu.set_renderer(renderer);
+                setup_sprite_tiles(&mut ppu, &mut lcd);
+                // Tile 4 has a color 0 left half and a color 1 right half,
+                // under a solid black sprite at columns 16-23
+                for line in 0..8 {
+                    ppu.video_ram.write(64 + 2 * line, 0x0f);
+                }
+                ppu.video_ram.write(0x1800 + 32 * 10 + 2, 4);
+                place_sprite(&mut ppu, 0, 16, 80, 2, flags);
+                lcd.write_u8(LCD_LCDC_OFFSET, lcdc);
+                run_until(&mut ppu, &mut lcd, 144, -80);
+
+                for y in 80..88 {
+                    assert_line(&ppu.framebuffers[0], y, expected);
+                }
+            }
+        }
+
+        // The CGB's BG attribute priority bit covers sprites like their own
+        // bit does, unless LCDC bit 0 takes priority away from the background
+        let (zero, nonzero) = (ColorIndex::Color0, ColorIndex::Color1);
+        for &behind in &[false, true] {
+            assert!(!object_over_background(true, true, behind, nonzero));
+            assert!(object_over_background(true, true, behind, zero));
+            assert!(object_over_background(false, true, behind, nonzero));
+        }
+    }
+
+    #[test]
+    fn test_oam_scan_picks_first_ten_sprites() {
+        let mut ppu = PictureProcessingUnit::default();
+        let mut lcd = Lcd::default();
+        setup_sprite_tiles(&mut ppu, &mut lcd);
+        // Only on the line above, so it doesn't count towards the limit
+        place_sprite(&mut ppu, 0, 0, 72, 2, 0);
+        for index in 1..=10 {
+            #[allow(clippy::cast_possible_truncation)]
+            place_sprite(&mut ppu, index, 16 * index as u8, 80, 1, 0);
+        }
+        // The 11th sprite is dropped even though it has the lowest X
+        place_sprite(&mut ppu, 11, 0, 80, 2, 0);
+
+        run_until(&mut ppu, &mut lcd, 80, 0);
+        let picked: Vec<u8> = ppu
+            .get_sprites_this_line()
+            .iter()
+            .map(|scanned| scanned.oam_index)
+            .collect();
+        assert_eq!(picked, (1..=10).collect::<Vec<u8>>());
+
+        run_until(&mut ppu, &mut lcd, 144, -80);
+        let framebuffer = &ppu.framebuffers[0];
+        let expected: Vec<(usize, Color)> = (1..=10)
+            .flat_map(|i| vec![(16 * i, Color::LightGray), (16 * i + 8, Color::White)])
+            .collect();
+        for y in 80..88 {
+            assert_line(framebuffer, y, &expected);
+        }
+    }
+
+    /// Background and window tiles with a different pattern on every row,
+    /// scrolled, with sprites overlapping each other and the window
+    fn setup_mixed_scene(ppu: &mut PictureProcessingUnit, lcd: &mut Lcd) {
+        for tile in 0..4_u16 {
+            for line in 0..8_u16 {
+                #[allow(clippy::cast_possible_truncation)]
+                let pattern = (tile * 0x35 + line * 0x17) as u8;
+                ppu.video_ram.write(16 * tile + 2 * line, pattern);
+                ppu.video_ram
+                    .write(16 * tile + 2 * line + 1, pattern.rotate_left(3));
+            }
+        }
+        for offset in 0..0x400_u16 {
+            #[allow(clippy::cast_possible_truncation)]
+            ppu.video_ram.write(0x1800 + offset, (offset % 3) as u8 + 1);
+            #[allow(clippy::cast_possible_truncation)]
+            ppu.video_ram
+                .write(WINDOW_MAP_OFFSET + offset, (offset % 4) as u8);
+        }
+        place_sprite(ppu, 0, 4, 30, 1, 0x20);
+        place_sprite(ppu, 1, 0, 32, 2, 0x10);
+        place_sprite(ppu, 2, 70, 100, 3, 0xc0);
+        place_sprite(ppu, 3, 74, 96, 1, 0);
+        place_sprite(ppu, 4, 155, 50, 2, 0x40);
+        lcd.write_u8(LCD_SCX_OFFSET, 3);
+        lcd.write_u8(0x2, 5); // SCY
+        lcd.write_u8(LCD_BGP_OFFSET, 0xe4);
+        lcd.write_u8(LCD_OBP0_OFFSET, 0xd2);
+        lcd.write_u8(LCD_OBP1_OFFSET, 0x1b);
+        lcd.write_u8(LCD_WY_OFFSET, 90);
+        lcd.write_u8(LCD_WX_OFFSET, 60);
+        lcd.write_u8(0x0, LCDC_WINDOW_ON | 0x02); // objects as well
+    }
+
+    #[test]
+    fn test_pixel_fifo_draws_like_direct_renderer() {
+        let mut direct = PictureProcessingUnit::default();
+        let mut direct_lcd = Lcd::default();
+        setup_mixed_scene(&mut direct, &mut direct_lcd);
+        run_until(&mut direct, &mut direct_lcd, 144, -80);
+
+        let mut fifo = PictureProcessingUnit::default();
+        fifo.set_renderer(Renderer::PixelFifo);
+        let mut fifo_lcd = Lcd::default();
+        setup_mixed_scene(&mut fifo, &mut fifo_lcd);
+        run_until(&mut fifo, &mut fifo_lcd, 144, -80);
+
+        for y in 0..144 {
+            for x in 0..160 {
+                assert_eq!(
+                    fifo.framebuffers[0][y][x], direct.framebuffers[0][y][x],
+                    "line {} column {}",
+                    y, x
+                );
+            }
+        }
+    }
+
+    /// Ticks through mode 3 of the given line, counting its dots
+    fn mode_3_length(ppu: &mut PictureProcessingUnit, lcd: &mut Lcd, ly: u8) -> i16 {
+        run_until(ppu, lcd, ly, 0);
+        let mut length = 0;
+        while lcd.get_mode() == 3 {
+            let _ = ppu.tick(1, lcd);
+            length += 1;
+        }
+        length
+    }
+
+    #[test]
+    fn test_pixel_fifo_mode_3_length() {
+        let mut ppu = PictureProcessingUnit::default();
+        ppu.set_renderer(Renderer::PixelFifo);
+        let mut lcd = Lcd::default();
+        setup_sprite_tiles(&mut ppu, &mut lcd);
+        place_sprite(&mut ppu, 0, 0, 20, 1, 0);
+        place_sprite(&mut ppu, 1, 13, 40, 1, 0);
+
+        assert_eq!(mode_3_length(&mut ppu, &mut lcd, 0), 172);
+        // One dot for each pixel of fine scroll thrown away
+        lcd.write_u8(LCD_SCX_OFFSET, 5);
+        assert_eq!(mode_3_length(&mut ppu, &mut lcd, 10), 177);
+        lcd.write_u8(LCD_SCX_OFFSET, 0);
+        // A sprite waits for the background fetcher to finish its tile
+        assert_eq!(mode_3_length(&mut ppu, &mut lcd, 20), 183);
+        assert_eq!(mode_3_length(&mut ppu, &mut lcd, 40), 178);
+        // The fetcher starts over when the window starts
+        lcd.write_u8(LCD_WY_OFFSET, 50);
+        lcd.write_u8(LCD_WX_OFFSET, 87);
+        lcd.write_u8(0x0, LCDC_WINDOW_ON);
+        assert_eq!(mode_3_length(&mut ppu, &mut lcd, 60), 178);
+
+        ppu.set_renderer(Renderer::Direct);
+        assert_eq!(mode_3_length(&mut ppu, &mut lcd, 70), 172);
+    }
+
+    /// Counts the STAT interrupts requested over a frame, after letting one
+    /// go by so the line starts out where it would be
+    fn count_stat_interrupts(status: u8, ly_compare: u8) -> usize {
+        let mut ppu = PictureProcessingUnit::default();
+        let mut lcd = Lcd::default();
+        lcd.write_u8(LCD_STAT_OFFSET, status);
+        lcd.write_u8(LCD_LYC_OFFSET, ly_compare);
+        for _ in 0..DOTS_PER_FRAME {
+            let _ = ppu.tick(1, &mut lcd);
+        }
+        (0..DOTS_PER_FRAME)
+            .filter(|_| ppu.tick(1, &mut lcd).1)
+            .count()
+    }
+
+    #[test]
+    fn test_stat_interrupt_line_blocks_overlapping_conditions() {
+        assert_eq!(count_stat_interrupts(STAT_HBLANK, 0), 144);
+        assert_eq!(count_stat_interrupts(STAT_VBLANK, 0), 1);
+        assert_eq!(count_stat_interrupts(STAT_OAM, 0), 144);
+        // Vblank follows hblank without the line going low
+        assert_eq!(count_stat_interrupts(STAT_HBLANK | STAT_VBLANK, 0), 144);
+        // Hblank runs into the next line's OAM scan, only line 0's comes
+        // after the line was low
+        assert_eq!(count_stat_interrupts(STAT_HBLANK | STAT_OAM, 0), 145);
+        // Line 10's LYC match holds the line high through its hblank
+        assert_eq!(count_stat_interrupts(STAT_HBLANK | STAT_LYC, 10), 144);
+        assert_eq!(count_stat_interrupts(STAT_LYC, 10), 1);
+    }
+
+    #[test]
+    fn test_lyc_write_mid_line() {
+        let mut ppu = PictureProcessingUnit::default();
+        let mut lcd = Lcd::default();
+        lcd.write_u8(LCD_STAT_OFFSET, STAT_LYC);
+        lcd.write_u8(LCD_LYC_OFFSET, 100);
+        run_until(&mut ppu, &mut lcd, 20, 100);
+        assert_eq!(lcd.read_u8(LCD_STAT_OFFSET) & STAT_LY_EQUAL_LYC, 0);
+
+        // Matching the current line raises the interrupt on the next dot
+        lcd.write_u8(LCD_LYC_OFFSET, 20);
+        assert!(ppu.tick(1, &mut lcd).1);
+        assert_ne!(lcd.read_u8(LCD_STAT_OFFSET) & STAT_LY_EQUAL_LYC, 0);
+        assert!(!ppu.tick(1, &mut lcd).1);
+
+        // Moving it away and back is a new rising edge
+        lcd.write_u8(LCD_LYC_OFFSET, 21);
+        assert!(!ppu.tick(1, &mut lcd).1);
+        assert_eq!(lcd.read_u8(LCD_STAT_OFFSET) & STAT_LY_EQUAL_LYC, 0);
+        lcd.write_u8(LCD_LYC_OFFSET, 20);
+        assert!(ppu.tick(1, &mut lcd).1);
+
+        // The flag follows LY, 4 dots into the next line
+        run_until(&mut ppu, &mut lcd, 21, -80);
+        assert_eq!(lcd.read_u8(LCD_STAT_OFFSET) & STAT_LY_EQUAL_LYC, 0);
+        lcd.write_u8(LCD_LYC_OFFSET, 21);
+        run_until(&mut ppu, &mut lcd, 21, -77);
+        assert!(ppu.tick(1, &mut lcd).1);
+        assert_eq!(lcd.get_lx(), -76);
+    }
+
+    #[test]
+    fn test_line_153_reads_as_line_0() {
+        let mut ppu = PictureProcessingUnit::default();
+        let mut lcd = Lcd::default();
+        lcd.write_u8(LCD_STAT_OFFSET, STAT_LYC);
+        lcd.write_u8(LCD_LYC_OFFSET, 153);
+        run_until(&mut ppu, &mut lcd, 153, -77);
+        assert_eq!(lcd.read_u8(LCD_LY_OFFSET), 153);
+        assert!(ppu.tick(1, &mut lcd).1);
+        assert_eq!(lcd.read_u8(LCD_LY_OFFSET), 0);
+
+        // LYC=0 matches 12 dots into line 153, and line 0 doesn't match
+        // again since LY didn't change
+        lcd.write_u8(LCD_LYC_OFFSET, 0);
+        let mut raised = Vec::new();
+        while !(lcd.get_ly() == 1 && lcd.get_lx() == 0) {
+            if ppu.tick(1, &mut lcd).1 {
+                raised.push((lcd.get_ly(), lcd.get_lx()));
+            }
+        }
+        assert_eq!(raised, vec![(153, -68)]);
+    }
+
+    #[test]
+    fn test_lcd_off_blanks_screen() {
+        let mut ppu = PictureProcessingUnit::default();
+        let mut lcd = Lcd::default();
+        setup_mixed_scene(&mut ppu, &mut lcd);
+        run_until(&mut ppu, &mut lcd, 100, 50);
+        let _ = ppu.take_frame_ready();
+        assert!(ppu.framebuffers[0]
+            .iter()
+            .flatten()
+            .any(|c| *c != Color::White));
+
+        lcd.write_u8(LCD_LCDC_OFFSET, LCDC_WINDOW_OFF & !LCDC_ENABLE);
+        assert_eq!(lcd.read_u8(LCD_LY_OFFSET), 0);
+        let _ = ppu.tick(1, &mut lcd);
+        assert!(ppu.take_frame_ready());
+        for framebuffer in &ppu.framebuffers {
+            assert!(framebuffer.iter().flatten().all(|c| *c == Color::White));
+        }
+
+        // Blank frames keep coming at the usual rate, without vblank
+        for _ in 2..DOTS_PER_FRAME {
+            assert_eq!(ppu.tick(1, &mut lcd), (false, false));
+            assert!(!ppu.take_frame_ready());
+        }
+        let _ = ppu.tick(1, &mut lcd);
+        assert!(ppu.take_frame_ready());
+        assert_eq!(lcd.read_u8(LCD_LY_OFFSET), 0);
+
+        // Turning it back on starts from the top of line 0
+        lcd.write_u8(LCD_LCDC_OFFSET, LCDC_WINDOW_OFF);
+        assert_eq!((lcd.get_ly(), lcd.get_lx()), (0, -80));
+        let mut dots = 0;
+        while !ppu.tick(1, &mut lcd).0 {
+            dots += 1;
+        }
+        assert_eq!(dots, 144 * 456 - 1);
+        assert!(ppu.take_frame_ready());
+    }
+
+    #[test]
+    fn test_access_blocking_matches_stat_mode() {
+        let mut ppu = PictureProcessingUnit::default();
+        let mut lcd = Lcd::default();
+        ppu.video_ram.write(0, 0x42);
+        ppu.object_attribute_memory.write(0, 0x24);
+        let mut modes_seen = [false; 4];
+        for _ in 0..2 * 456 {
+            let _ = ppu.tick(1, &mut lcd);
+            let mode = lcd.read_u8(LCD_STAT_OFFSET) & 0x3;
+            modes_seen[mode as usize] = true;
+            let vram = if mode == 3 { 0xff } else { 0x42 };
+            let oam = if mode >= 2 { 0xff } else { 0x24 };
+            assert_eq!(ppu.read_video_ram(&lcd, 0), vram);
+            assert_eq!(ppu.read_object_attribute_memory(&lcd, 0), oam);
+        }
+        assert_eq!(modes_seen, [true, false, true, true]);
+
+        // Writes are dropped the same way
+        run_until(&mut ppu, &mut lcd, 2, 10);
+        ppu.write_video_ram(&lcd, 0, 0);
+        ppu.write_object_attribute_memory(&lcd, 0, 0);
+        run_until(&mut ppu, &mut lcd, 2, 300);
+        assert_eq!(ppu.read_video_ram(&lcd, 0), 0x42);
+        assert_eq!(ppu.read_object_attribute_memory(&lcd, 0), 0x24);
+        ppu.write_video_ram(&lcd, 0, 0);
+        ppu.write_object_attribute_memory(&lcd, 0, 0);
+        assert_eq!(ppu.read_video_ram(&lcd, 0), 0);
+        assert_eq!(ppu.read_object_attribute_memory(&lcd, 0), 0);
+    }
+
+    #[test]
+    fn test_finished_frames_are_shared() {
+        let mut ppu = PictureProcessingUnit::default();
+        let mut lcd = Lcd::default();
+        setup_mixed_scene(&mut ppu, &mut lcd);
+        let buffers = [
+            Arc::as_ptr(&ppu.framebuffers[0]),
+            Arc::as_ptr(&ppu.framebuffers[1]),
+        ];
+        let _ = ppu.tick(DOTS_PER_FRAME, &mut lcd);
+        let first = ppu.get_finished_frame();
+        assert_eq!(Arc::as_ptr(&first), buffers[0]);
+
+        // Holding on to a frame keeps it as it was while the PPU draws over
+        // its buffer, and anything let go of is drawn into without a copy
+        lcd.write_u8(LCD_BGP_OFFSET, 0x1b);
+        let _ = ppu.tick(DOTS_PER_FRAME, &mut lcd);
+        let second = ppu.get_finished_frame();
+        assert_eq!(Arc::as_ptr(&second), buffers[1]);
+        drop(second);
+        let _ = ppu.tick(DOTS_PER_FRAME, &mut lcd);
+        let third = ppu.get_finished_frame();
+        assert_ne!(Arc::as_ptr(&third), buffers[0]);
+        assert_ne!(*first, *third);
+        let _ = ppu.tick(DOTS_PER_FRAME, &mut lcd);
+        assert_eq!(Arc::as_ptr(&ppu.get_finished_frame()), buffers[1]);
+    }
+
+    #[test]
+    fn test_rgba_output_matches_colors() {
+        let layout = RgbaLayout {
+            red: 2,
+            green: 1,
+            blue: 0,
+            alpha: 3,
+        };
+        for renderer in [Renderer::Direct, Renderer::PixelFifo] {
+            let mut ppu = PictureProcessingUnit::default();
+            ppu.set_renderer(renderer);
+            let mut lcd = Lcd::default();
+            setup_mixed_scene(&mut ppu, &mut lcd);
+            let _ = ppu.tick(DOTS_PER_FRAME, &mut lcd);
+            // Frames drawn before it was turned on are converted too
+            ppu.set_rgba_layout(Some(layout));
+            for _ in 0..2 {
+                let frame = ppu.get_finished_frame_with_rgba();
+                let rgba = frame.rgba.unwrap();
+                for (pixel, color) in rgba.chunks(4).zip(frame.colors.iter().flatten()) {
+                    let [red, green, blue] = color.to_rgb();
+                    assert_eq!(pixel, [blue, green, red, 0xff], "{}", renderer);
+                }
+                lcd.write_u8(LCD_BGP_OFFSET, 0x1b);
+                let _ = ppu.tick(DOTS_PER_FRAME, &mut lcd);
+            }
+        }
+    }
+
+    #[test]
+    fn test_debug_state() {
+        for renderer in [Renderer::Direct, Renderer::PixelFifo] {
+            let mut ppu = PictureProcessingUnit::default();
+            ppu.set_renderer(renderer);
+            let mut lcd = Lcd::default();
+            place_sprite(&mut ppu, 3, 40, 8, 0, 0);
+            place_sprite(&mut ppu, 7, 90, 10, 0, 0);
+            run_until(&mut ppu, &mut lcd, 12, 20);
+            let state = ppu.debug_state(&lcd);
+            assert_eq!(state.mode, 3);
+            assert_eq!((state.ly, state.lx, state.dot), (12, 20, 100));
+            assert_eq!(state.window_line, 0);
+            let sprites: Vec<u8> = state.sprites.iter().map(|s| s.oam_index).collect();
+            assert_eq!(sprites, [3, 7]);
+            match (renderer, state.fifo) {
+                (Renderer::Direct, None) => {}
+                (Renderer::PixelFifo, Some(fifo)) => {
+                    // Nothing but color 0 background so far
+                    assert!(fifo.pixel_x > 0 && fifo.pixel_x < 20);
+                    assert!(fifo.background.len() <= 16);
+                    assert!(fifo
+                        .background
+                        .iter()
+                        .all(|&c| matches!(c, ColorIndex::Color0)));
+                    assert!(fifo.objects.is_empty());
+                    assert!(!fifo.fetching_window);
+                }
+                (_, fifo) => panic!("{} renderer gave {:?}", renderer, fifo),
+            }
+        }
+    }
+
+    /// Writes an LCD register the way the memory bus does
+    fn write_lcd(ppu: &mut PictureProcessingUnit, lcd: &mut Lcd, offset: u16, byte: u8) {
+        ppu.catch_up(lcd);
+        lcd.write_u8(offset, byte);
+    }
+
+    #[test]
+    fn test_batched_lines_match_dot_rendering() {
+        let mut frames = Vec::new();
+        for batch in [false, true] {
+            let mut ppu = PictureProcessingUnit::default();
+            ppu.set_batch_scanlines(batch);
+            let mut lcd = Lcd::default();
+            setup_mixed_scene(&mut ppu, &mut lcd);
+            // Writes in the middle of lines, and during hblank
+            run_until(&mut ppu, &mut lcd, 40, 37);
+            write_lcd(&mut ppu, &mut lcd, LCD_SCX_OFFSET, 40);
+            run_until(&mut ppu, &mut lcd, 97, 100);
+            write_lcd(&mut ppu, &mut lcd, LCD_BGP_OFFSET, 0x1b);
+            run_until(&mut ppu, &mut lcd, 98, 120);
+            write_lcd(&mut ppu, &mut lcd, LCD_WX_OFFSET, 20);
+            run_until(&mut ppu, &mut lcd, 144, -80);
+            frames.push(*ppu.framebuffers[0]);
+        }
+        for (y, (batched, dot_by_dot)) in frames[1].iter().zip(frames[0].iter()).enumerate() {
+            assert_eq!(batched, dot_by_dot, "line {}", y);
+        }
+    }
+
+    #[test]
+    fn test_mid_line_scroll_write_takes_effect_on_next_tile() {
+        let mut ppu = PictureProcessingUnit::default();
+        ppu.set_batch_scanlines(false);
+        let mut lcd = Lcd::default();
+        setup_sprite_tiles(&mut ppu, &mut lcd);
+        // Background columns alternate between light gray and black tiles
+        for offset in 0..0x400 {
+            ppu.video_ram.write(0x1800 + offset, (offset % 2) as u8 + 1);
+        }
+
+        // Halfway through the second tile, move SCX by a tile and 3 pixels
+        run_until(&mut ppu, &mut lcd, 10, 12);
+        lcd.write_u8(LCD_SCX_OFFSET, 11);
+        run_until(&mut ppu, &mut lcd, 144, -80);
+
+        // Only the tile part applies, from the third tile on
+        let mut expected = vec![(0, Color::LightGray), (8, Color::Black)];
+        expected.extend((3..20).map(|tile| (8 * tile, [Color::Black, Color::LightGray][tile % 2])));
+        assert_line(&ppu.framebuffers[0], 10, &expected);
+    }
+
+    #[test]
+    fn test_scanline_registers_follow_mid_frame_writes() {
+        let mut ppu = PictureProcessingUnit::default();
+        let mut lcd = Lcd::default();
+        lcd.write_u8(LCD_SCX_OFFSET, 0x10);
+
+        run_until(&mut ppu, &mut lcd, 99, 200);
+        lcd.write_u8(LCD_SCX_OFFSET, 0x20);
+        lcd.write_u8(LCD_BGP_OFFSET, 0x1b);
+        run_until(&mut ppu, &mut lcd, 144, -80);
+
+        // Recorded alongside the buffer that was drawn before the swap
+        let lines = &ppu.scanline_registers[0];
+        for (y, line) in lines.iter().enumerate() {
+            let (scroll_x, palette) = if y < 100 { (0x10, 0xfc) } else { (0x20, 0x1b) };
+            assert_eq!(line.scroll_x, scroll_x, "line {}", y);
+            assert_eq!(line.background_palette, palette, "line {}", y);
+        }
+    }
+}
@@ -0,0 +1,278 @@
+use std::convert::TryInto;
+
+use super::savestate::{SaveState, SaveStateError, StateReader, StateWriter};
+
+// TODO support other clock speeds
+const CYCLES_PER_SECOND: u64 = 4_194_304;
+
+const DAY_HIGH_BIT_MASK: u8 = 1;
+const HALT_BIT_MASK: u8 = 1 << 6;
+const DAY_CARRY_BIT_MASK: u8 = 1 << 7;
+
+const SECONDS_PER_DAY: u64 = 24 * 60 * 60;
+
+/// Size of the clock footer appended to .sav files: the live and latched
+/// registers as 32 bit words, then a 64 bit unix timestamp. This is the
+/// layout VBA and BGB use, so saves can be moved between emulators. Some
+/// older files only have a 32 bit timestamp, making the footer 44 bytes.
+pub const RTC_FOOTER_SIZE: usize = 48;
+const RTC_FOOTER_SIZE_SHORT_TIMESTAMP: usize = 44;
+
+#[derive(Copy, Clone, Debug, Default)]
+pub struct RtcRegisters {
+    pub seconds: u8,  // RTC S, 0x08
+    pub minutes: u8,  // RTC M, 0x09
+    pub hours: u8,    // RTC H, 0x0a
+    pub day_low: u8,  // RTC DL, 0x0b
+    pub day_high: u8, // RTC DH, 0x0c
+}
+
+impl RtcRegisters {
+    #[must_use]
+    pub fn get_day(&self) -> u16 {
+        (u16::from(self.day_high & DAY_HIGH_BIT_MASK) << 8) | u16::from(self.day_low)
+    }
+
+    fn set_day(&mut self, day: u16) {
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            self.day_low = day as u8;
+            self.day_high = (self.day_high & !DAY_HIGH_BIT_MASK) | ((day >> 8) as u8 & 1);
+        }
+    }
+
+    #[must_use]
+    pub fn is_halted(&self) -> bool {
+        self.day_high & HALT_BIT_MASK != 0
+    }
+
+    fn is_in_range(&self) -> bool {
+        self.seconds < 60 && self.minutes < 60 && self.hours < 24
+    }
+
+    fn advance_seconds(&mut self, mut seconds: u64) {
+        // Out of range values don't wrap like normal time, so step them
+        // one at a time until they have come back into range
+        while seconds > 0 && !self.is_in_range() {
+            self.advance_second();
+            seconds -= 1;
+        }
+        if seconds == 0 {
+            return;
+        }
+        let elapsed = u64::from(self.get_day()) * SECONDS_PER_DAY
+            + u64::from(self.hours) * 60 * 60
+            + u64::from(self.minutes) * 60
+            + u64::from(self.seconds)
+            + seconds;
+        let days = elapsed / SECONDS_PER_DAY;
+        let time_of_day = elapsed % SECONDS_PER_DAY;
+        #[allow(clippy::cast_possible_truncation)]
+        {
+            self.seconds = (time_of_day % 60) as u8;
+            self.minutes = (time_of_day / 60 % 60) as u8;
+            self.hours = (time_of_day / 60 / 60) as u8;
+            if days > 0x1ff {
+                self.day_high |= DAY_CARRY_BIT_MASK;
+            }
+            self.set_day((days & 0x1ff) as u16);
+        }
+    }
+
+    fn write_footer_words(&self, buffer: &mut Vec<u8>) {
+        for register in &[
+            self.seconds,
+            self.minutes,
+            self.hours,
+            self.day_low,
+            self.day_high,
+        ] {
+            buffer.extend_from_slice(&u32::from(*register).to_le_bytes());
+        }
+    }
+
+    fn from_footer_words(words: &[u8]) -> Self {
+        // Each register is the low byte of a little endian 32 bit word
+        Self {
+            seconds: words[0],
+            minutes: words[4],
+            hours: words[8],
+            day_low: words[12],
+            day_high: words[16],
+        }
+    }
+
+    fn advance_second(&mut self) {
+        // The counters are only 6 (or 5) bits wide. Out of range values
+        // written by the game count up to the bit width and wrap to 0
+        // without carrying into the next counter.
+        self.seconds = (self.seconds + 1) & 0x3f;
+        if self.seconds != 60 {
+            return;
+        }
+        self.seconds = 0;
+        self.minutes = (self.minutes + 1) & 0x3f;
+        if self.minutes != 60 {
+            return;
+        }
+        self.minutes = 0;
+        self.hours = (self.hours + 1) & 0x1f;
+        if self.hours != 24 {
+            return;
+        }
+        self.hours = 0;
+        let day = self.get_day() + 1;
+        if day > 0x1ff {
+            self.set_day(0);
+            self.day_high |= DAY_CARRY_BIT_MASK;
+        } else {
+            self.set_day(day);
+        }
+    }
+}
+
+/// The MBC3 real time clock. The game only ever sees the latched copy of
+/// the registers, which is refreshed by writing 0 then 1 to 0x6000-0x7fff.
+#[derive(Debug, Default)]
+pub struct RealTimeClock {
+    live: RtcRegisters,
+    latched: RtcRegisters,
+    latch_armed: bool,
+    cycles: u64,
+}
+
+impl RealTimeClock {
+    pub fn tick(&mut self, cycles: u64) {
+        if self.live.is_halted() {
+            return;
+        }
+        self.cycles += cycles;
+        while self.cycles >= CYCLES_PER_SECOND {
+            self.cycles -= CYCLES_PER_SECOND;
+            self.live.advance_second();
+        }
+    }
+
+    pub fn write_latch(&mut self, byte: u8) {
+        if self.latch_armed && byte == 1 {
+            self.latched = self.live;
+        }
+        self.latch_armed = byte == 0;
+    }
+
+    #[must_use]
+    pub fn read_register(&self, register: u8) -> u8 {
+        match register {
+            0x08 => self.latched.seconds,
+            0x09 => self.latched.minutes,
+            0x0a => self.latched.hours,
+            0x0b => self.latched.day_low,
+            0x0c => self.latched.day_high,
+            _ => 0xff,
+        }
+    }
+
+    pub fn write_register(&mut self, register: u8, byte: u8) {
+        match register {
+            0x08 => {
+                // Writing the seconds resets the sub-second divider
+                self.live.seconds = byte & 0x3f;
+                self.cycles = 0;
+            }
+            0x09 => self.live.minutes = byte & 0x3f,
+            0x0a => self.live.hours = byte & 0x1f,
+            0x0b => self.live.day_low = byte,
+            0x0c => {
+                self.live.day_high = byte & (DAY_CARRY_BIT_MASK | HALT_BIT_MASK | DAY_HIGH_BIT_MASK);
+            }
+            _ => (),
+        }
+        // Writes are visible immediately, even without a new latch
+        self.latched = self.live;
+    }
+
+    /// Moves the live clock forward, e.g. to account for the time the
+    /// emulator was closed. A halted clock does not move.
+    pub fn advance_seconds(&mut self, seconds: u64) {
+        if !self.live.is_halted() {
+            self.live.advance_seconds(seconds);
+        }
+    }
+
+    /// Serializes the clock into the .sav footer, stamped with the given
+    /// unix time
+    #[must_use]
+    pub fn to_footer(&self, unix_time: u64) -> Vec<u8> {
+        let mut footer = Vec::with_capacity(RTC_FOOTER_SIZE);
+        self.live.write_footer_words(&mut footer);
+        self.latched.write_footer_words(&mut footer);
+        footer.extend_from_slice(&unix_time.to_le_bytes());
+        footer
+    }
+
+    /// Parses a .sav footer, returning the clock and the unix time it was
+    /// saved at, or None if the data isn't a footer
+    #[must_use]
+    pub fn from_footer(footer: &[u8]) -> Option<(Self, u64)> {
+        let unix_time = match footer.len() {
+            RTC_FOOTER_SIZE => u64::from_le_bytes(footer[40..48].try_into().unwrap()),
+            RTC_FOOTER_SIZE_SHORT_TIMESTAMP => {
+                u64::from(u32::from_le_bytes(footer[40..44].try_into().unwrap()))
+            }
+            _ => return None,
+        };
+        let rtc = Self {
+            live: RtcRegisters::from_footer_words(&footer[0..20]),
+            latched: RtcRegisters::from_footer_words(&footer[20..40]),
+            latch_armed: false,
+            cycles: 0,
+        };
+        Some((rtc, unix_time))
+    }
+
+    #[must_use]
+    pub fn get_live_registers(&self) -> RtcRegisters {
+        self.live
+    }
+
+    #[must_use]
+    pub fn get_latched_registers(&self) -> RtcRegisters {
+        self.latched
+    }
+}
+
+impl SaveState for RtcRegisters {
+    fn save_state(&self, writer: &mut StateWriter) {
+        writer.write_u8(self.seconds);
+        writer.write_u8(self.minutes);
+        writer.write_u8(self.hours);
+        writer.write_u8(self.day_low);
+        writer.write_u8(self.day_high);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        self.seconds = reader.read_u8()?;
+        self.minutes = reader.read_u8()?;
+        self.hours = reader.read_u8()?;
+        self.day_low = reader.read_u8()?;
+        self.day_high = reader.read_u8()?;
+        Ok(())
+    }
+}
+
+impl SaveState for RealTimeClock {
+    fn save_state(&self, writer: &mut StateWriter) {
+        self.live.save_state(writer);
+        self.latched.save_state(writer);
+        writer.write_bool(self.latch_armed);
+        writer.write_u64(self.cycles);
+    }
+
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError> {
+        self.live.load_state(reader)?;
+        self.latched.load_state(reader)?;
+        self.latch_armed = reader.read_bool()?;
+        self.cycles = reader.read_u64()?;
+        Ok(())
+    }
+}
@@ -0,0 +1,364 @@
+use std::{
+    convert::TryInto,
+    error::Error,
+    fmt::{self, Display},
+    io,
+};
+
+use serde::{Deserialize, Serialize};
+
+pub const SAVESTATE_MAGIC: &[u8; 8] = b"GBCSTATE";
+pub const SAVESTATE_VERSION: u32 = 17;
+/// The oldest savestate version that can still be loaded. Components whose
+/// layout changed since keep reading the old one by checking
+/// `StateReader::version`, so this only moves when that's not possible.
+pub const OLDEST_SAVESTATE_VERSION: u32 = 6;
+pub const MOVIE_MAGIC: &[u8; 8] = b"GBCMOVIE";
+pub const MOVIE_VERSION: u32 = 1;
+pub const OLDEST_MOVIE_VERSION: u32 = 1;
+
+/// The kinds of file sharing the container format described by `FileHeader`
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum FileKind {
+    /// A complete machine state. Rewind snapshots use the same bytes, just
+    /// kept in memory.
+    SaveState,
+    /// A recording of the buttons held on every frame
+    Movie,
+}
+
+impl FileKind {
+    #[must_use]
+    pub fn magic(self) -> &'static [u8; 8] {
+        match self {
+            Self::SaveState => SAVESTATE_MAGIC,
+            Self::Movie => MOVIE_MAGIC,
+        }
+    }
+
+    /// The version this build writes
+    #[must_use]
+    pub fn current_version(self) -> u32 {
+        match self {
+            Self::SaveState => SAVESTATE_VERSION,
+            Self::Movie => MOVIE_VERSION,
+        }
+    }
+
+    /// The oldest version this build can still read
+    #[must_use]
+    pub fn oldest_version(self) -> u32 {
+        match self {
+            Self::SaveState => OLDEST_SAVESTATE_VERSION,
+            Self::Movie => OLDEST_MOVIE_VERSION,
+        }
+    }
+}
+
+impl Display for FileKind {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::SaveState => write!(f, "savestate"),
+            Self::Movie => write!(f, "movie"),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub enum SaveStateError {
+    Io(io::Error),
+    BadMagic(FileKind),
+    UnsupportedVersion { kind: FileKind, version: u32 },
+    RomMismatch { expected: String, found: String },
+    Truncated,
+    Invalid(&'static str),
+}
+
+impl Display for SaveStateError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Io(e) => write!(f, "{}", e),
+            Self::BadMagic(kind) => write!(f, "not a {} file", kind),
+            Self::UnsupportedVersion { kind, version } => write!(
+                f,
+                "{} version {} is not supported (expected {} to {})",
+                kind,
+                version,
+                kind.oldest_version(),
+                kind.current_version()
+            ),
+            Self::RomMismatch { expected, found } => write!(
+                f,
+                "savestate was made with {}, but {} is loaded",
+                found, expected
+            ),
+            Self::Truncated => write!(f, "savestate is truncated"),
+            Self::Invalid(what) => write!(f, "savestate contains an invalid {}", what),
+        }
+    }
+}
+
+impl Error for SaveStateError {}
+
+impl From<io::Error> for SaveStateError {
+    fn from(e: io::Error) -> Self {
+        Self::Io(e)
+    }
+}
+
+/// Implemented by every piece of emulated hardware that has to survive a
+/// save and load. Fields are written in declaration order with no padding,
+/// so any change to what a component saves must bump `SAVESTATE_VERSION`.
+pub trait SaveState {
+    fn save_state(&self, writer: &mut StateWriter);
+    fn load_state(&mut self, reader: &mut StateReader<'_>) -> Result<(), SaveStateError>;
+}
+
+/// The start of every savestate and movie file:
+///
+/// | bytes | contents                                          |
+/// |-------|---------------------------------------------------|
+/// | 8     | magic, `GBCSTATE` or `GBCMOVIE`                   |
+/// | 4     | format version, little endian                     |
+/// | 4 + n | length prefixed name and checksum of the ROM used |
+///
+/// Everything after it depends on the kind of file and its version. All
+/// integers are little endian and blocks of bytes are prefixed with a u32
+/// length, as `StateWriter` writes them.
+///
+/// The serde schema has the same fields, for tools that want the header in
+/// another format such as JSON. The magic is implied by `kind`.
+#[derive(Clone, Debug, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileHeader {
+    pub kind: FileKind,
+    pub version: u32,
+    pub rom_identity: String,
+}
+
+impl FileHeader {
+    /// A header for a file written by this build
+    #[must_use]
+    pub fn new(kind: FileKind, rom_identity: String) -> Self {
+        Self {
+            kind,
+            version: kind.current_version(),
+            rom_identity,
+        }
+    }
+
+    pub fn write(&self, writer: &mut StateWriter) {
+        writer.write_bytes(self.kind.magic());
+        writer.write_u32(self.version);
+        writer.write_vec(self.rom_identity.as_bytes());
+    }
+
+    /// Reads the header of a file that should be of the given kind,
+    /// accepting any version this build still understands. The rest of the
+    /// file is then read as that version.
+    ///
+    /// # Errors
+    /// Returns an error if the file is of another kind or an unsupported
+    /// version
+    pub fn read(kind: FileKind, reader: &mut StateReader<'_>) -> Result<Self, SaveStateError> {
+        let mut magic = [0; 8];
+        reader.read_bytes(&mut magic)?;
+        if &magic != kind.magic() {
+            return Err(SaveStateError::BadMagic(kind));
+        }
+        let version = reader.read_u32()?;
+        if !(kind.oldest_version()..=kind.current_version()).contains(&version) {
+            return Err(SaveStateError::UnsupportedVersion { kind, version });
+        }
+        reader.version = version;
+        let rom_identity = String::from_utf8_lossy(&reader.read_vec()?).into_owned();
+        Ok(Self {
+            kind,
+            version,
+            rom_identity,
+        })
+    }
+}
+
+#[derive(Debug, Default)]
+pub struct StateWriter {
+    buffer: Vec<u8>,
+}
+
+impl StateWriter {
+    #[must_use]
+    pub fn into_inner(self) -> Vec<u8> {
+        self.buffer
+    }
+
+    pub fn write_u8(&mut self, v: u8) {
+        self.buffer.push(v);
+    }
+
+    pub fn write_bool(&mut self, v: bool) {
+        self.write_u8(u8::from(v));
+    }
+
+    pub fn write_u16(&mut self, v: u16) {
+        self.buffer.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_u32(&mut self, v: u32) {
+        self.buffer.extend_from_slice(&v.to_le_bytes());
+    }
+
+    pub fn write_u64(&mut self, v: u64) {
+        self.buffer.extend_from_slice(&v.to_le_bytes());
+    }
+
+    /// Writes a fixed size block, the reader must know the length
+    pub fn write_bytes(&mut self, bytes: &[u8]) {
+        self.buffer.extend_from_slice(bytes);
+    }
+
+    /// Writes a length prefixed block
+    pub fn write_vec(&mut self, bytes: &[u8]) {
+        #[allow(clippy::cast_possible_truncation)]
+        self.write_u32(bytes.len() as u32);
+        self.write_bytes(bytes);
+    }
+}
+
+#[derive(Debug)]
+pub struct StateReader<'a> {
+    data: &'a [u8],
+    version: u32,
+}
+
+impl<'a> StateReader<'a> {
+    /// A reader for data in the current savestate layout, until a header
+    /// says otherwise
+    #[must_use]
+    pub fn new(data: &'a [u8]) -> Self {
+        Self {
+            data,
+            version: SAVESTATE_VERSION,
+        }
+    }
+
+    /// The format version of the file being read, for components whose
+    /// layout changed between versions
+    #[must_use]
+    pub fn version(&self) -> u32 {
+        self.version
+    }
+
+    fn take(&mut self, length: usize) -> Result<&'a [u8], SaveStateError> {
+        if self.data.len() < length {
+            return Err(SaveStateError::Truncated);
+        }
+        let (taken, rest) = self.data.split_at(length);
+        self.data = rest;
+        Ok(taken)
+    }
+
+    pub fn read_u8(&mut self) -> Result<u8, SaveStateError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn read_bool(&mut self) -> Result<bool, SaveStateError> {
+        Ok(self.read_u8()? != 0)
+    }
+
+    pub fn read_u16(&mut self) -> Result<u16, SaveStateError> {
+        Ok(u16::from_le_bytes(self.take(2)?.try_into().unwrap()))
+    }
+
+    pub fn read_u32(&mut self) -> Result<u32, SaveStateError> {
+        Ok(u32::from_le_bytes(self.take(4)?.try_into().unwrap()))
+    }
+
+    pub fn read_u64(&mut self) -> Result<u64, SaveStateError> {
+        Ok(u64::from_le_bytes(self.take(8)?.try_into().unwrap()))
+    }
+
+    pub fn read_bytes(&mut self, destination: &mut [u8]) -> Result<(), SaveStateError> {
+        destination.copy_from_slice(self.take(destination.len())?);
+        Ok(())
+    }
+
+    pub fn read_vec(&mut self) -> Result<Vec<u8>, SaveStateError> {
+        let length = self.read_u32()? as usize;
+        Ok(self.take(length)?.to_vec())
+    }
+
+    #[must_use]
+    pub fn is_empty(&self) -> bool {
+        self.data.is_empty()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn header_bytes(magic: &[u8; 8], version: u32) -> Vec<u8> {
+        let mut writer = StateWriter::default();
+        writer.write_bytes(magic);
+        writer.write_u32(version);
+        writer.write_vec(b"game 1234");
+        writer.into_inner()
+    }
+
+    #[test]
+    fn test_header_round_trip() {
+        for &kind in &[FileKind::SaveState, FileKind::Movie] {
+            let header = FileHeader::new(kind, "game 1234".to_string());
+            let mut writer = StateWriter::default();
+            header.write(&mut writer);
+            writer.write_u8(0x42);
+            let data = writer.into_inner();
+
+            let mut reader = StateReader::new(&data);
+            assert_eq!(FileHeader::read(kind, &mut reader).unwrap(), header);
+            assert_eq!(reader.version(), kind.current_version());
+            assert_eq!(reader.read_u8().unwrap(), 0x42);
+            assert!(reader.is_empty());
+        }
+    }
+
+    #[test]
+    fn test_header_versions() {
+        // Older versions are read as themselves
+        let data = header_bytes(SAVESTATE_MAGIC, OLDEST_SAVESTATE_VERSION);
+        let mut reader = StateReader::new(&data);
+        let header = FileHeader::read(FileKind::SaveState, &mut reader).unwrap();
+        assert_eq!(header.version, OLDEST_SAVESTATE_VERSION);
+        assert_eq!(reader.version(), OLDEST_SAVESTATE_VERSION);
+
+        for &version in &[OLDEST_SAVESTATE_VERSION - 1, SAVESTATE_VERSION + 1] {
+            let data = header_bytes(SAVESTATE_MAGIC, version);
+            let result = FileHeader::read(FileKind::SaveState, &mut StateReader::new(&data));
+            assert!(matches!(
+                result,
+                Err(SaveStateError::UnsupportedVersion {
+                    kind: FileKind::SaveState,
+                    version: v,
+                }) if v == version
+            ));
+        }
+
+        // A movie isn't a savestate
+        let data = header_bytes(MOVIE_MAGIC, MOVIE_VERSION);
+        let result = FileHeader::read(FileKind::SaveState, &mut StateReader::new(&data));
+        assert!(matches!(
+            result,
+            Err(SaveStateError::BadMagic(FileKind::SaveState))
+        ));
+    }
+
+    #[test]
+    fn test_truncated_header() {
+        let data = header_bytes(SAVESTATE_MAGIC, SAVESTATE_VERSION);
+        let mut reader = StateReader::new(&data[..data.len() - 1]);
+        assert!(matches!(
+            FileHeader::read(FileKind::SaveState, &mut reader),
+            Err(SaveStateError::Truncated)
+        ));
+    }
+}
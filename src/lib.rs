@@ -1,2 +1,3 @@
 pub mod gbc;
-pub mod debugger;
\ No newline at end of file
+pub mod debugger;
+pub mod frontend;
\ No newline at end of file
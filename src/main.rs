@@ -1,130 +1,592 @@
 use clap::{App, Arg};
+use sdl2::pixels::{Color, PixelFormatEnum};
+use sdl2::rect::Rect;
 use sdl2::render::Canvas;
 use sdl2::video::Window;
-use sdl2::{event::Event, keyboard::Keycode};
+use sdl2::{
+    controller::{self, Axis, GameController},
+    event::{Event, EventSender, WindowEvent},
+    keyboard::{Keycode, Scancode},
+    GameControllerSubsystem,
+};
+use std::collections::HashMap;
+use std::io::ErrorKind;
+use std::path::{Path, PathBuf};
 use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::mpsc::{self, Receiver, RecvTimeoutError, SyncSender};
 use std::sync::{Arc, Mutex};
 use std::thread;
 use std::time::{Duration, Instant};
 
 use rust_gbc_emu::{
     debugger::Debugger,
-    gbc::{mmio::lcd, Gbc, InputState},
+    frontend::{
+        game_config::GameConfig,
+        keymap::{Button, Keymap},
+        latency::{FrameProducedProbe, LatencyStats},
+        overlay, tile_viewer,
+        turbo::Turbo,
+    },
+    gbc::{
+        events::Event as GbcEvent,
+        link::LinkPort,
+        memory_snapshot::MemorySnapshot,
+        mmio::serial::SerialDeviceKind,
+        movie::Movie,
+        ppu::{FinishedFrame, Renderer, RgbaFrame, RgbaLayout, RGBA_FRAME_SIZE},
+        Gbc, InputState, SaveRamFlush, SoftResetCombo, WarpTarget,
+    },
 };
 
-fn run_debugger(gbc: Gbc) {
+fn run_debugger(gbc: Gbc, script: Option<&Path>) {
     let dbg = Debugger::new(gbc);
-    dbg.run();
+    dbg.run(script);
+}
+
+/// Tells the frontend loop to exit from another thread
+fn push_quit_event(event_sender: &EventSender, start: Instant) {
+    #[allow(clippy::cast_possible_truncation)]
+    event_sender
+        .push_event(Event::Quit {
+            timestamp: (Instant::now() - start).as_millis() as u32,
+        })
+        .unwrap();
+}
+
+// Frames a core can get ahead of the frontend before it starts skipping them
+const FRAME_QUEUE_LENGTH: usize = 2;
+
+/// One core's output, drawn into a window next to any other screens
+/// sharing it, and the input state fed by the keys pressed while it has
+/// focus
+struct Screen {
+    frame_sender: SyncSender<FinishedFrame>,
+    frames: Receiver<FinishedFrame>,
+    input_state: Arc<Mutex<InputState>>,
+    // Index of the canvas it is drawn to
+    canvas: usize,
+    // Position within that canvas, in Game Boy pixels
+    x: i32,
+}
+
+impl Screen {
+    fn new(canvas: usize, x: i32) -> Self {
+        let (frame_sender, frames) = mpsc::sync_channel(FRAME_QUEUE_LENGTH);
+        Self {
+            frame_sender,
+            frames,
+            input_state: Arc::new(Mutex::new(InputState::default())),
+            canvas,
+            x,
+        }
+    }
+
+    fn rect(&self) -> Rect {
+        Rect::new(self.x, 0, 160, 144)
+    }
+
+    /// Waits up to `timeout` for the core to finish a frame, skipping to
+    /// the newest one if several are waiting
+    fn next_frame(&self, timeout: Duration) -> Option<FinishedFrame> {
+        let mut frame = match self.frames.recv_timeout(timeout) {
+            Ok(frame) => frame,
+            Err(RecvTimeoutError::Timeout) => return None,
+            Err(RecvTimeoutError::Disconnected) => {
+                // The core is gone, don't spin
+                thread::sleep(timeout);
+                return None;
+            }
+        };
+        while let Ok(newer) = self.frames.try_recv() {
+            frame = newer;
+        }
+        Some(frame)
+    }
+}
+
+/// The buttons each key presses
+type KeyBindings = HashMap<Scancode, Vec<Button>>;
+
+fn key_bindings(keymap: &Keymap) -> KeyBindings {
+    let mut bindings = KeyBindings::new();
+    for (key, button) in keymap.iter() {
+        match Scancode::from_name(key) {
+            Some(scancode) => bindings.entry(scancode).or_default().push(button),
+            None => eprintln!("Warning: unknown key {} bound to {}", key, button),
+        }
+    }
+    bindings
+}
+
+fn set_key(
+    screen: &Screen,
+    turbo: &mut Turbo,
+    bindings: &KeyBindings,
+    key: Scancode,
+    pressed: bool,
+) {
+    if let Some(buttons) = bindings.get(&key) {
+        let mut input_state = screen.input_state.lock().unwrap();
+        for &button in buttons {
+            if button.is_turbo() {
+                turbo.hold(button, pressed, &mut input_state);
+            } else {
+                button.set(&mut input_state, pressed);
+            }
+        }
+    }
+}
+
+// How far the left stick has to be pushed before it works the d-pad
+const STICK_DEADZONE: i16 = 8000;
+
+/// The button a controller button presses. The face buttons go by where
+/// they are rather than their names, so the right one is A like on a
+/// Game Boy.
+fn controller_button(button: controller::Button) -> Option<Button> {
+    match button {
+        controller::Button::B => Some(Button::A),
+        controller::Button::A => Some(Button::B),
+        controller::Button::Back => Some(Button::Select),
+        controller::Button::Start => Some(Button::Start),
+        controller::Button::DPadRight => Some(Button::Right),
+        controller::Button::DPadLeft => Some(Button::Left),
+        controller::Button::DPadUp => Some(Button::Up),
+        controller::Button::DPadDown => Some(Button::Down),
+        _ => None,
+    }
+}
+
+/// The way the left stick is pushed along `axis`, if it's past the deadzone
+fn stick_direction(axis: Axis, value: i16) -> Option<Button> {
+    match axis {
+        Axis::LeftX if value < -STICK_DEADZONE => Some(Button::Left),
+        Axis::LeftX if value > STICK_DEADZONE => Some(Button::Right),
+        Axis::LeftY if value < -STICK_DEADZONE => Some(Button::Up),
+        Axis::LeftY if value > STICK_DEADZONE => Some(Button::Down),
+        _ => None,
+    }
+}
+
+/// A game controller that's plugged in, and the directions its left stick
+/// is holding down on each axis
+struct OpenController {
+    controller: GameController,
+    stick: [Option<Button>; 2],
+}
+
+/// Game controllers, opened as they're plugged in and dropped when they're
+/// pulled out. SDL reports the ones already there as being plugged in at
+/// startup. Every controller plays the focused game in the first window.
+struct Controllers {
+    subsystem: Option<GameControllerSubsystem>,
+    // Keyed by joystick instance id
+    open: HashMap<u32, OpenController>,
+}
+
+impl Controllers {
+    fn new(subsystem: Result<GameControllerSubsystem, String>) -> Self {
+        let subsystem = match subsystem {
+            Ok(subsystem) => Some(subsystem),
+            Err(e) => {
+                eprintln!("Warning: game controllers are unavailable: {}", e);
+                None
+            }
+        };
+        Self {
+            subsystem,
+            open: HashMap::new(),
+        }
+    }
+
+    fn add(&mut self, joystick_index: u32) {
+        let subsystem = match &self.subsystem {
+            Some(subsystem) => subsystem,
+            None => return,
+        };
+        match subsystem.open(joystick_index) {
+            Ok(controller) => {
+                println!("Controller connected: {}", controller.name());
+                let open = OpenController {
+                    controller,
+                    stick: [None; 2],
+                };
+                self.open.insert(open.controller.instance_id(), open);
+            }
+            Err(e) => eprintln!("Could not open controller {}: {}", joystick_index, e),
+        }
+    }
+
+    fn remove(&mut self, id: u32, input_state: &Mutex<InputState>) {
+        if let Some(open) = self.open.remove(&id) {
+            println!("Controller disconnected: {}", open.controller.name());
+            // Don't leave whatever it was holding held down
+            *input_state.lock().unwrap() = InputState::default();
+        }
+    }
+
+    fn move_stick(&mut self, id: u32, axis: Axis, value: i16, input_state: &Mutex<InputState>) {
+        let held = match (self.open.get_mut(&id), axis) {
+            (Some(open), Axis::LeftX) => &mut open.stick[0],
+            (Some(open), Axis::LeftY) => &mut open.stick[1],
+            _ => return,
+        };
+        let direction = stick_direction(axis, value);
+        if direction == *held {
+            return;
+        }
+        let mut input_state = input_state.lock().unwrap();
+        if let Some(button) = held.take() {
+            button.set(&mut input_state, false);
+        }
+        if let Some(button) = direction {
+            button.set(&mut input_state, true);
+        }
+        *held = direction;
+    }
+}
+
+/// Copies a frame into a streaming texture, a row at a time since the
+/// texture's rows can be padded
+fn upload_frame(data: &mut [u8], pitch: usize, frame: &RgbaFrame) {
+    for (row, pixels) in data.chunks_mut(pitch).zip(frame.chunks(160 * 4)) {
+        row[..160 * 4].copy_from_slice(pixels);
+    }
+}
+
+/// Where each channel goes in memory for the 32 bit texture formats
+fn texture_rgba_layout(format: PixelFormatEnum) -> Result<RgbaLayout, String> {
+    // Positions of red, green, blue and alpha (or padding) within the
+    // packed pixel, counting from the most significant byte
+    let (red, green, blue, alpha) = match format {
+        PixelFormatEnum::ARGB8888 | PixelFormatEnum::RGB888 => (1, 2, 3, 0),
+        PixelFormatEnum::ABGR8888 | PixelFormatEnum::BGR888 => (3, 2, 1, 0),
+        PixelFormatEnum::RGBA8888 | PixelFormatEnum::RGBX8888 => (0, 1, 2, 3),
+        PixelFormatEnum::BGRA8888 | PixelFormatEnum::BGRX8888 => (2, 1, 0, 3),
+        _ => return Err(format!("Unsupported texture format {:?}", format)),
+    };
+    let byte = |position: usize| {
+        if cfg!(target_endian = "little") {
+            3 - position
+        } else {
+            position
+        }
+    };
+    Ok(RgbaLayout {
+        red: byte(red),
+        green: byte(green),
+        blue: byte(blue),
+        alpha: byte(alpha),
+    })
+}
+
+/// The format to make streaming textures for `canvas` in, and where the
+/// channels go in it. The canvas's own format is used when frames can be
+/// drawn straight into it, otherwise ARGB8888, which SDL converts from.
+fn texture_format(canvas: &Canvas<Window>) -> (PixelFormatEnum, RgbaLayout) {
+    let format = canvas.default_pixel_format();
+    match texture_rgba_layout(format) {
+        Ok(layout) => (format, layout),
+        Err(_) => {
+            let fallback = PixelFormatEnum::ARGB8888;
+            (fallback, texture_rgba_layout(fallback).unwrap())
+        }
+    }
+}
+
+/// A second window showing what is in VRAM and OAM, redrawn from the first
+/// core's memory snapshot whenever it finishes a frame
+struct TileViewer {
+    canvas: Canvas<Window>,
+    snapshot: Arc<Mutex<MemorySnapshot>>,
+    shown: bool,
+}
+
+impl TileViewer {
+    fn toggle(&mut self) {
+        self.shown = !self.shown;
+        if self.shown {
+            self.canvas.window_mut().show();
+        } else {
+            self.canvas.window_mut().hide();
+        }
+    }
+}
+
+/// Outlines the screen that keys go to when a window shows several games
+fn draw_focus_indicator(canvas: &mut Canvas<Window>, screen: &Screen) {
+    canvas.set_draw_color(Color::RGB(0xff, 0x40, 0x40));
+    canvas.draw_rect(screen.rect()).unwrap();
+    canvas.set_draw_color(Color::RGB(0, 0, 0));
+}
+
+/// How the frontend loop behaves, from the command line and config
+struct FrontendOptions<'a> {
+    // Set while the debugger's prompt is up on the terminal
+    debugger_running: &'a AtomicBool,
+    latency_probe: Option<&'a FrameProducedProbe>,
+    show_timer: bool,
+    key_bindings: KeyBindings,
+    // Presses a second for the turbo buttons
+    autofire_rate: u32,
 }
 
 fn run(
-    mut canvas: Canvas<Window>,
+    mut canvases: Vec<Canvas<Window>>,
+    screens: &[Screen],
     mut event_pump: sdl2::EventPump,
-    debugger_running: bool,
-    framebuffer: &Arc<Mutex<[[lcd::Color; 160]; 144]>>,
     gbc_running: &Arc<AtomicBool>,
-    input_state: &Arc<Mutex<InputState>>
+    mut tile_viewer: TileViewer,
+    mut controllers: Controllers,
+    options: &FrontendOptions<'_>,
 ) {
-    canvas.set_logical_size(160, 144).unwrap();
-    canvas.clear();
-    canvas.present();
-    let texture_creator = canvas.texture_creator();
-    let mut texture = texture_creator
-        .create_texture_streaming(canvas.default_pixel_format(), 160, 144)
-        .unwrap();
-    let format = texture.query().format;
+    let FrontendOptions {
+        debugger_running,
+        latency_probe,
+        show_timer,
+        ref key_bindings,
+        autofire_rate,
+    } = *options;
+    let screens_on_canvas = |canvas: usize| -> Vec<usize> {
+        (0..screens.len())
+            .filter(|&i| screens[i].canvas == canvas)
+            .collect()
+    };
+    // Which screen gets the keys pressed in each window, Tab moves it along
+    // when a window is split
+    let mut focused: Vec<usize> = (0..canvases.len())
+        .map(|canvas| screens_on_canvas(canvas)[0])
+        .collect();
+    // Autofire for each screen, kept in step with the frames it sends
+    let mut turbos: Vec<Turbo> = screens.iter().map(|_| Turbo::new(autofire_rate)).collect();
+    for (i, canvas) in canvases.iter_mut().enumerate() {
+        #[allow(clippy::cast_possible_truncation)]
+        let width = 160 * screens_on_canvas(i).len() as u32;
+        canvas.set_logical_size(width, 144).unwrap();
+        canvas.clear();
+        canvas.present();
+    }
+    let texture_creators: Vec<_> = canvases
+        .iter()
+        .map(|canvas| canvas.texture_creator())
+        .collect();
+    // Each screen's texture follows the canvas it is on, and frames that
+    // come without RGBA are converted into the same layout
+    let layouts: Vec<RgbaLayout> = screens
+        .iter()
+        .map(|screen| texture_format(&canvases[screen.canvas]).1)
+        .collect();
+    let mut textures: Vec<_> = screens
+        .iter()
+        .map(|screen| {
+            texture_creators[screen.canvas]
+                .create_texture_streaming(texture_format(&canvases[screen.canvas]).0, 160, 144)
+                .unwrap()
+        })
+        .collect();
+    let format = textures[0].query().format;
     println!("Texture format: {:?}", format);
-    let frame_duration = Duration::from_nanos(1_000_000_000_u64 / 60);
+    tile_viewer
+        .canvas
+        .set_logical_size(tile_viewer::WIDTH, tile_viewer::HEIGHT)
+        .unwrap();
+    let tile_viewer_texture_creator = tile_viewer.canvas.texture_creator();
+    let (tile_viewer_format, tile_viewer_layout) = texture_format(&tile_viewer.canvas);
+    let mut tile_viewer_texture = tile_viewer_texture_creator
+        .create_texture_streaming(tile_viewer_format, tile_viewer::WIDTH, tile_viewer::HEIGHT)
+        .unwrap();
+    let tile_viewer_id = tile_viewer.canvas.window().id();
+    // Host input is pumped far more often than frames are presented, so a
+    // button press reaches InputState well before the core latches it
+    let input_poll_interval = Duration::from_millis(1);
+    let mut frames: Vec<Arc<RgbaFrame>> = screens
+        .iter()
+        .map(|_| Arc::new([0xff; RGBA_FRAME_SIZE]))
+        .collect();
+    let mut latency_stats = LatencyStats::default();
+    let timer_start = Instant::now();
     'running: loop {
+        // Set when a window needs drawing even without a new frame
+        let mut redraw = false;
         for event in event_pump.poll_iter() {
             match event {
+                // Closing the tile viewer only hides it
+                Event::Window {
+                    window_id,
+                    win_event: WindowEvent::Close,
+                    ..
+                } if window_id == tile_viewer_id => tile_viewer.toggle(),
+                Event::KeyDown {
+                    keycode: Some(Keycode::F1),
+                    repeat: false,
+                    ..
+                } => tile_viewer.toggle(),
+                // Breaks into the debugger with the game paused where it
+                // is, `run` carries on from there
+                Event::KeyDown {
+                    keycode: Some(Keycode::Pause),
+                    repeat: false,
+                    ..
+                } if debugger_running.load(Ordering::Relaxed) => {
+                    gbc_running.store(false, Ordering::Relaxed);
+                }
                 Event::Quit { .. }
+                | Event::Window {
+                    win_event: WindowEvent::Close,
+                    ..
+                }
                 | Event::KeyDown {
                     keycode: Some(Keycode::Escape),
                     ..
                 } => {
-                    if debugger_running {
+                    // Ctrl-C in the terminal arrives as a quit too. With the
+                    // debugger up the window stays open until it exits, the
+                    // game is only stopped so the prompt comes back.
+                    if debugger_running.load(Ordering::Relaxed) {
                         if gbc_running.load(Ordering::Relaxed) {
                             gbc_running.store(false, Ordering::Relaxed);
                         } else {
-                            break 'running;
+                            println!("Quit the debugger to close the window");
                         }
                     } else {
                         gbc_running.store(false, Ordering::Relaxed);
                         break 'running;
                     }
                 }
-                Event::KeyDown { keycode: Some(key), .. } => {
-                    match key {
-                        Keycode::Up => input_state.lock().unwrap().up_pressed = true,
-                        Keycode::Down => input_state.lock().unwrap().down_pressed = true,
-                        Keycode::Left => input_state.lock().unwrap().left_pressed = true,
-                        Keycode::Right => input_state.lock().unwrap().right_pressed = true,
-                        Keycode::Z => input_state.lock().unwrap().a_pressed = true,
-                        Keycode::X => input_state.lock().unwrap().b_pressed = true,
-                        Keycode::A => input_state.lock().unwrap().select_pressed = true,
-                        Keycode::S => input_state.lock().unwrap().start_pressed = true,
-                        _ => (),
+                Event::KeyDown {
+                    window_id,
+                    keycode,
+                    scancode,
+                    ..
+                }
+                | Event::KeyUp {
+                    window_id,
+                    keycode,
+                    scancode,
+                    ..
+                } => {
+                    // Keys go to the focused game in whichever window has
+                    // focus
+                    let canvas = match canvases
+                        .iter()
+                        .position(|canvas| canvas.window().id() == window_id)
+                    {
+                        Some(canvas) => canvas,
+                        None => continue,
+                    };
+                    let pressed = matches!(event, Event::KeyDown { .. });
+                    if keycode == Some(Keycode::Tab) && pressed {
+                        let on_canvas = screens_on_canvas(canvas);
+                        let position = on_canvas
+                            .iter()
+                            .position(|&i| i == focused[canvas])
+                            .unwrap();
+                        // Release everything held for the game losing focus
+                        *screens[focused[canvas]].input_state.lock().unwrap() =
+                            InputState::default();
+                        turbos[focused[canvas]].release_all();
+                        focused[canvas] = on_canvas[(position + 1) % on_canvas.len()];
+                    } else if let Some(scancode) = scancode {
+                        let screen = focused[canvas];
+                        let turbo = &mut turbos[screen];
+                        set_key(&screens[screen], turbo, key_bindings, scancode, pressed);
                     }
                 }
-                Event::KeyUp { keycode: Some(key), .. } => {
-                    match key {
-                        Keycode::Up => input_state.lock().unwrap().up_pressed = false,
-                        Keycode::Down => input_state.lock().unwrap().down_pressed = false,
-                        Keycode::Left => input_state.lock().unwrap().left_pressed = false,
-                        Keycode::Right => input_state.lock().unwrap().right_pressed = false,
-                        Keycode::Z => input_state.lock().unwrap().a_pressed = false,
-                        Keycode::X => input_state.lock().unwrap().b_pressed = false,
-                        Keycode::A => input_state.lock().unwrap().select_pressed = false,
-                        Keycode::S => input_state.lock().unwrap().start_pressed = false,
-                        _ => (),
+                Event::ControllerDeviceAdded { which, .. } => controllers.add(which),
+                Event::ControllerDeviceRemoved { which, .. } => {
+                    controllers.remove(which, &screens[focused[0]].input_state);
+                    turbos[focused[0]].release_all();
+                }
+                Event::ControllerButtonDown { button, .. }
+                | Event::ControllerButtonUp { button, .. } => {
+                    if let Some(button) = controller_button(button) {
+                        let pressed = matches!(event, Event::ControllerButtonDown { .. });
+                        let mut input_state = screens[focused[0]].input_state.lock().unwrap();
+                        button.set(&mut input_state, pressed);
                     }
                 }
+                Event::ControllerAxisMotion {
+                    which, axis, value, ..
+                } => {
+                    let input_state = &screens[focused[0]].input_state;
+                    controllers.move_stick(which, axis, value, input_state);
+                }
+                Event::Window {
+                    win_event: WindowEvent::Exposed,
+                    ..
+                } => redraw = true,
                 _ => {}
             }
         }
 
-        let framebuffer = {
-            let lock = framebuffer.lock().unwrap();
-            *lock
-        };
+        // Present as soon as the first core finishes a frame, only waiting
+        // for it as long as input can go without being pumped
+        let mut new_frame = false;
+        for (i, screen) in screens.iter().enumerate() {
+            let timeout = if i == 0 {
+                input_poll_interval
+            } else {
+                Duration::ZERO
+            };
+            // Every core is set up to draw RGBA frames for these textures,
+            // anything else is converted here
+            if let Some(FinishedFrame { colors, rgba }) = screen.next_frame(timeout) {
+                frames[i] = rgba.unwrap_or_else(|| Arc::new(layouts[i].convert_frame(&colors)));
+                new_frame = true;
+                turbos[i].next_frame(&mut screen.input_state.lock().unwrap());
+            }
+        }
+        if !new_frame && !redraw {
+            continue;
+        }
 
-        // TODO other formats
-        texture
-            .with_lock(None, |data, pitch| {
-                let mut row_i = 0;
-                for row in framebuffer {
-                    let mut i = row_i;
-                    for pixel in row {
-                        let bytes = match pixel {
-                            lcd::Color::White => [0xff, 0xff, 0xff],
-                            lcd::Color::LightGray => [0xaa, 0xaa, 0xaa],
-                            lcd::Color::DarkGray => [0x77, 0x77, 0x77],
-                            lcd::Color::Black => [0x00, 0x00, 0x00],
-                        };
-                        data[i] = bytes[0];
-                        data[i + 1] = bytes[1];
-                        data[i + 2] = bytes[2];
-                        i += 4;
+        // The latency probe only watches the first core
+        let frame_produced = latency_probe.and_then(|probe| probe.lock().unwrap().take());
+        for ((screen, texture), frame) in screens.iter().zip(&mut textures).zip(&frames) {
+            texture
+                .with_lock(None, |data, pitch| {
+                    upload_frame(data, pitch, frame);
+                    if show_timer {
+                        overlay::draw_timer(data, pitch, timer_start.elapsed());
                     }
-                    row_i += pitch;
-                }
-            })
-            .unwrap();
-
-        // for row in 0..144u32 {
-        //     for col in 0..160u32 {
-        //         let color_gbc = framebuffer[row as usize][col as usize];
-        //         // let bytes = color_u32.to_be_bytes();
-        //         // let color = Color::RGBA(bytes[0], bytes[1], bytes[2], bytes[3]);
-        //         let color = gbc_color_to_sdl_color(color_gbc);
-        //         canvas.pixel(col as i16, row as i16, color).unwrap();
-        //     }
-        // }
-
-        canvas.copy(&texture, None, None).unwrap();
-        canvas.present();
-        ::std::thread::sleep(frame_duration);
+                })
+                .unwrap();
+
+            canvases[screen.canvas]
+                .copy(texture, None, screen.rect())
+                .unwrap();
+        }
+        for (i, canvas) in canvases.iter_mut().enumerate() {
+            if screens_on_canvas(i).len() > 1 {
+                draw_focus_indicator(canvas, &screens[focused[i]]);
+            }
+        }
+        let frame_uploaded = Instant::now();
+        for canvas in &mut canvases {
+            canvas.present();
+        }
+        if latency_probe.is_some() {
+            latency_stats.record(frame_produced, frame_uploaded, Instant::now());
+        }
+
+        if tile_viewer.shown {
+            // Copy it out so the core isn't kept waiting while this draws
+            let snapshot = tile_viewer.snapshot.lock().unwrap().clone();
+            tile_viewer_texture
+                .with_lock(None, |data, pitch| {
+                    tile_viewer::draw(data, pitch, tile_viewer_layout, &snapshot);
+                })
+                .unwrap();
+            tile_viewer
+                .canvas
+                .copy(&tile_viewer_texture, None, None)
+                .unwrap();
+            tile_viewer.canvas.present();
+        }
+    }
+
+    if latency_probe.is_some() {
+        println!("{}", latency_stats);
     }
 }
 
@@ -145,12 +607,194 @@ fn main() {
                 .long("instructions")
                 .help("Shows each instruction as it's executed"),
         )
+        .arg(
+            Arg::with_name("trace")
+                .long("trace")
+                .value_name("FILE")
+                .help(
+                    "Logs each instruction's registers to a file in gameboy-doctor's format, \
+                     from when the boot ROM is done",
+                ),
+        )
+        .arg(
+            Arg::with_name("compare-trace")
+                .long("compare-trace")
+                .value_name("FILE")
+                .help("Stops where execution differs from a gameboy-doctor format trace"),
+        )
         .arg(
             Arg::with_name("turbo")
                 .short("t")
                 .long("turbo")
                 .help("Removes limits on run speed"),
         )
+        .arg(
+            Arg::with_name("latency")
+                .short("l")
+                .long("measure-latency")
+                .help("Reports frame latency statistics on exit"),
+        )
+        .arg(
+            Arg::with_name("trace-mbc")
+                .long("trace-mbc")
+                .help("Logs every write to the cartridge's bank controller"),
+        )
+        .arg(
+            Arg::with_name("oam-corruption")
+                .long("oam-corruption")
+                .help("Emulates the DMG bug where 16 bit INC/DEC can corrupt OAM"),
+        )
+        .arg(
+            Arg::with_name("renderer")
+                .long("renderer")
+                .value_name("RENDERER")
+                .possible_values(&["direct", "fifo"])
+                .help("Pixel FIFO rendering is slower, but gets mode 3's length right"),
+        )
+        .arg(
+            Arg::with_name("serial")
+                .long("serial")
+                .value_name("DEVICE")
+                .possible_values(&["disconnected", "loopback", "stdout"])
+                .help("What is plugged into the serial port, stdout prints what the game sends"),
+        )
+        .arg(
+            Arg::with_name("serial-console")
+                .long("serial-console")
+                .help("Prints what test ROMs send over serial, whatever is plugged in"),
+        )
+        .arg(
+            Arg::with_name("soft-reset-combo")
+                .long("soft-reset-combo")
+                .value_name("ACTION")
+                .possible_values(&["pass", "reset"])
+                .help("What A+B+Start+Select does, overrides the game's .cfg file"),
+        )
+        .arg(
+            Arg::with_name("save-interval")
+                .long("save-interval")
+                .value_name("WHEN")
+                .validator(|v| v.parse::<SaveRamFlush>().map(|_| ()))
+                .help(
+                    "When battery backed RAM is written to disk: change, exit or a number of \
+                     seconds, overrides the game's .cfg file",
+                ),
+        )
+        .arg(
+            Arg::with_name("warp-to")
+                .long("warp-to")
+                .value_name("FRAMES|SECONDS")
+                .validator(|v| v.parse::<WarpTarget>().map(|_| ()))
+                .help(
+                    "Fast forwards without showing anything until the given frame or emulated \
+                     second (e.g. 600 or 10s), then runs at normal speed",
+                ),
+        )
+        .arg(
+            Arg::with_name("load-state")
+                .long("load-state")
+                .value_name("FILE")
+                .help("Starts from the given savestate instead of power on"),
+        )
+        .arg(
+            Arg::with_name("record-movie")
+                .long("record-movie")
+                .value_name("FILE")
+                .help("Records the buttons pressed each frame, written out on exit"),
+        )
+        .arg(
+            Arg::with_name("play-movie")
+                .long("play-movie")
+                .value_name("FILE")
+                .conflicts_with_all(&["load-state", "record-movie"])
+                .help("Replays a recorded movie, then hands the controls back"),
+        )
+        .arg(
+            Arg::with_name("timer")
+                .long("timer")
+                .help("Shows an elapsed time overlay"),
+        )
+        .arg(
+            Arg::with_name("race")
+                .long("race")
+                .conflicts_with_all(&[
+                    "debug",
+                    "instructions",
+                    "trace",
+                    "compare-trace",
+                    "turbo",
+                    "warp-to",
+                ])
+                .help(
+                    "Locks the emulator down for races and run verification, \
+                     implies --timer, --no-patches and --no-rng-watches",
+                ),
+        )
+        .arg(
+            Arg::with_name("no-patch")
+                .long("no-patch")
+                .value_name("NAME")
+                .multiple(true)
+                .number_of_values(1)
+                .help("Turns off a ROM patch from the game config, can be given more than once"),
+        )
+        .arg(
+            Arg::with_name("no-patches")
+                .long("no-patches")
+                .help("Turns off every ROM patch from the game config"),
+        )
+        .arg(
+            Arg::with_name("no-rng-watches")
+                .long("no-rng-watches")
+                .help("Ignores the RNG addresses from the game config"),
+        )
+        .arg(
+            Arg::with_name("link")
+                .long("link")
+                .value_name("ROM")
+                .conflicts_with_all(&["debug", "race", "serial"])
+                .help("Runs a second game in its own window, connected by a link cable"),
+        )
+        .arg(
+            Arg::with_name("split-screen")
+                .long("split-screen")
+                .requires("link")
+                .help("Shows both linked games side by side in one window, Tab switches input"),
+        )
+        .arg(
+            Arg::with_name("tile-viewer")
+                .long("tile-viewer")
+                .help("Opens a window showing VRAM and OAM as they change, F1 toggles it"),
+        )
+        .arg(
+            Arg::with_name("autofire-rate")
+                .long("autofire-rate")
+                .value_name("HZ")
+                .validator(|v| match v.parse::<u32>() {
+                    Ok(rate) if (1..=30).contains(&rate) => Ok(()),
+                    _ => Err("expected a number of presses a second from 1 to 30".to_string()),
+                })
+                .help("How fast the turbo_a and turbo_b keys press their buttons, 15 by default"),
+        )
+        .arg(
+            Arg::with_name("keymap")
+                .long("keymap")
+                .value_name("FILE")
+                .help("Key bindings to use over the defaults and the game's config"),
+        )
+        .arg(
+            Arg::with_name("symbols")
+                .long("symbols")
+                .value_name("FILE")
+                .help("Symbol file for the debugger, the ROM's .sym file by default"),
+        )
+        .arg(
+            Arg::with_name("debugger-script")
+                .long("debugger-script")
+                .value_name("FILE")
+                .requires("debug")
+                .help("Debugger commands to run when it starts, e.g. a project's .gbcdbg"),
+        )
         .arg(Arg::with_name("ROM").required(true).index(1))
         .get_matches();
 
@@ -158,51 +802,250 @@ fn main() {
     let show_instructions = matches.is_present("instructions");
     let debug = matches.is_present("debug");
     let turbo = matches.is_present("turbo");
+    let measure_latency = matches.is_present("latency");
+    let trace_mbc = matches.is_present("trace-mbc");
+    let oam_corruption = matches.is_present("oam-corruption");
+    let renderer = matches
+        .value_of("renderer")
+        .map_or(Renderer::Direct, |r| r.parse().unwrap());
+    let serial_console = matches.is_present("serial-console");
+    let serial_device = matches
+        .value_of("serial")
+        .map_or(SerialDeviceKind::Disconnected, |d| d.parse().unwrap());
+    let game_config = GameConfig::load_for_rom(&rom);
+    let soft_reset_combo = matches
+        .value_of("soft-reset-combo")
+        .map(|action| action.parse().unwrap())
+        .or(game_config.soft_reset_combo)
+        .unwrap_or(SoftResetCombo::PassToGame);
+    let save_ram_flush = matches
+        .value_of("save-interval")
+        .map(|when| when.parse().unwrap())
+        .or(game_config.save_ram_flush)
+        .unwrap_or(SaveRamFlush::EverySeconds(1));
+    let race = matches.is_present("race");
+    let rng_addresses = if race || matches.is_present("no-rng-watches") {
+        Vec::new()
+    } else {
+        game_config.rng_addresses
+    };
+    let savepoints = game_config.savepoints;
+    let mut patches = game_config.patches;
+    // Turned off patches are still added so the debugger can turn them back on
+    let no_patches = race || matches.is_present("no-patches");
+    for name in matches.values_of("no-patch").into_iter().flatten() {
+        match patches.iter_mut().find(|patch| patch.name == name) {
+            Some(patch) => patch.enabled = false,
+            None => eprintln!("Warning: no patch named {} in the game config", name),
+        }
+    }
+    if no_patches {
+        for patch in &mut patches {
+            patch.enabled = false;
+        }
+    }
+    let mut keymap = Keymap::default();
+    for (button, keys) in game_config.keymap {
+        keymap.bind(button, keys);
+    }
+    if let Some(path) = matches.value_of("keymap") {
+        if let Err(e) = keymap.load(path) {
+            eprintln!("Error reading {}: {}", path, e);
+        }
+    }
+    let autofire_rate = matches
+        .value_of("autofire-rate")
+        .map_or(15, |rate| rate.parse().unwrap());
+    // Only a missing symbol file that was asked for is an error
+    let (symbol_file, symbol_file_given) = match matches.value_of("symbols") {
+        Some(path) => (PathBuf::from(path), true),
+        None => (Path::new(&rom).with_extension("sym"), false),
+    };
+    let debugger_script = matches.value_of("debugger-script").map(PathBuf::from);
+    let trace_file = matches.value_of("trace").map(str::to_string);
+    let compare_trace_file = matches.value_of("compare-trace").map(str::to_string);
+    let state_file = matches.value_of("load-state").map(str::to_string);
+    let record_movie = matches.value_of("record-movie").map(str::to_string);
+    let play_movie = matches.value_of("play-movie").map(str::to_string);
+    let warp_target: Option<WarpTarget> = matches
+        .value_of("warp-to")
+        .map(|target| target.parse().unwrap());
+    let show_timer = race || matches.is_present("timer");
+    let link_rom = matches.value_of("link").map(str::to_string);
+    let (link_port, link_partner_port) = match link_rom {
+        Some(_) => {
+            let (port, partner_port) = LinkPort::pair();
+            (Some(port), Some(partner_port))
+        }
+        None => (None, None),
+    };
 
     let gbc_running = Arc::new(AtomicBool::new(false));
-    let framebuffer = Arc::new(Mutex::new([[lcd::Color::White; 160]; 144]));
-    let input_state = Arc::new(Mutex::new(InputState::default()));
+    let debugger_running = Arc::new(AtomicBool::new(false));
+    let split_screen = matches.is_present("split-screen");
+    let show_tile_viewer = matches.is_present("tile-viewer");
+    let latency_probe: Option<FrameProducedProbe> = if measure_latency {
+        Some(Arc::new(Mutex::new(None)))
+    } else {
+        None
+    };
 
     let sdl_context = sdl2::init().unwrap();
     let video_subsystem = sdl_context.video().unwrap();
 
+    let (width, height) = if split_screen { (1280, 576) } else { (800, 600) };
     let window = video_subsystem
-        .window("Rust GBC Emu", 800, 600)
+        .window("Rust GBC Emu", width, height)
         .position_centered()
         .build()
         .unwrap();
 
-    let canvas = window.into_canvas().build().unwrap();
+    let mut canvases = vec![window.into_canvas().build().unwrap()];
+    let (_, rgba_layout) = texture_format(&canvases[0]);
+    let mut screens = vec![Screen::new(0, 0)];
+
+    let mut tile_viewer_builder = video_subsystem.window(
+        "Rust GBC Emu (tiles)",
+        2 * tile_viewer::WIDTH,
+        2 * tile_viewer::HEIGHT,
+    );
+    if !show_tile_viewer {
+        tile_viewer_builder.hidden();
+    }
+    let tile_viewer_window = tile_viewer_builder.build().unwrap();
+    let tile_viewer = TileViewer {
+        canvas: tile_viewer_window.into_canvas().build().unwrap(),
+        snapshot: Arc::new(Mutex::new(MemorySnapshot::default())),
+        shown: show_tile_viewer,
+    };
 
     let event_sender = sdl_context.event().unwrap().event_sender();
     let start = Instant::now();
 
     let gbc_running_gbc = gbc_running.clone();
-    let framebuffer_gbc = framebuffer.clone();
-    let input_state_gbc = input_state.clone();
+    let debugger_running_gbc = debugger_running.clone();
+    let frame_sender_gbc = screens[0].frame_sender.clone();
+    let input_state_gbc = screens[0].input_state.clone();
+    let latency_probe_gbc = latency_probe.clone();
+    let snapshot_gbc = tile_viewer.snapshot.clone();
     let t = thread::spawn(move || {
-        let mut gbc = Gbc::new(
-            rom,
-            framebuffer_gbc,
+        let mut gbc = match Gbc::new(
+            &rom,
+            frame_sender_gbc,
             gbc_running_gbc,
             turbo,
             show_instructions,
-            input_state_gbc
-        )
-        .expect("Error Loading rom!");
+            input_state_gbc,
+        ) {
+            Ok(gbc) => gbc,
+            Err(e) => {
+                eprintln!("Error loading {}: {}", rom, e);
+                push_quit_event(&event_sender, start);
+                return;
+            }
+        };
+        for warning in &gbc.get_cartridge().warnings {
+            eprintln!("Warning: {}", warning);
+        }
+        gbc.set_trace_mbc_writes(trace_mbc);
+        if let Some(path) = &trace_file {
+            if let Err(e) = gbc.start_trace(path) {
+                eprintln!("Could not create trace {}: {}", path, e);
+            }
+        }
+        if let Some(path) = &compare_trace_file {
+            if let Err(e) = gbc.start_trace_comparison(path) {
+                eprintln!("Could not open reference trace {}: {}", path, e);
+                push_quit_event(&event_sender, start);
+                return;
+            }
+        }
+        gbc.set_emulate_oam_corruption(oam_corruption);
+        gbc.set_renderer(renderer);
+        gbc.set_serial_device(serial_device.create());
+        if serial_console {
+            gbc.capture_serial_output(true);
+        }
+        gbc.set_rgba_layout(rgba_layout);
+        gbc.set_memory_snapshot(snapshot_gbc);
+        gbc.set_soft_reset_combo(soft_reset_combo);
+        gbc.set_save_ram_flush(save_ram_flush);
+        match gbc.load_symbols(&symbol_file) {
+            Ok(_) => {}
+            Err(e) if e.kind() == ErrorKind::NotFound && !symbol_file_given => {}
+            Err(e) => eprintln!("Error reading {}: {}", symbol_file.display(), e),
+        }
+        if let Some(port) = link_port {
+            gbc.set_serial_device(Box::new(port));
+        }
+        for &address in &rng_addresses {
+            gbc.add_rng_watch(address);
+        }
+        for (address, label) in savepoints {
+            gbc.add_savepoint(address, label);
+        }
+        let patch_names: Vec<String> = patches.iter().map(|patch| patch.name.clone()).collect();
+        for patch in patches {
+            if let Err(e) = gbc.add_patch(patch) {
+                eprintln!("Warning: {}", e);
+            }
+        }
+        if let Some(state_file) = &state_file {
+            if let Err(e) = gbc.load_state(state_file) {
+                eprintln!("Could not load state {}: {}", state_file, e);
+                push_quit_event(&event_sender, start);
+                return;
+            }
+        }
+        if let Some(path) = &play_movie {
+            if let Err(e) = Movie::load(path).and_then(|movie| gbc.play_movie(movie)) {
+                eprintln!("Could not play movie {}: {}", path, e);
+                push_quit_event(&event_sender, start);
+                return;
+            }
+            gbc.subscribe(Box::new(|event| {
+                if let GbcEvent::MovieFinished { frames } = event {
+                    println!("Movie finished after {} frames", frames);
+                }
+            }));
+        }
+        if let Some(path) = &record_movie {
+            gbc.record_movie(path);
+        }
+        if race {
+            let cartridge = gbc.get_cartridge();
+            println!("Race mode");
+            println!("\tROM: {} ({:04x})", cartridge.title, cartridge.global_checksum);
+            println!(
+                "\tStart: {}",
+                state_file.as_deref().unwrap_or("power on")
+            );
+            println!("\tTurbo, debugger and instruction tracing are disabled");
+            if patch_names.is_empty() {
+                println!("\tPatches: none configured");
+            } else {
+                println!("\tPatches turned off: {}", patch_names.join(", "));
+            }
+        }
+        if let Some(target) = warp_target {
+            gbc.warp_to(target);
+        }
+        if let Some(probe) = latency_probe_gbc {
+            gbc.set_frame_produced_probe(probe);
+        }
         if debug {
-            run_debugger(gbc);
-            #[allow(clippy::cast_possible_truncation)]
-            event_sender
-                .push_event(Event::Quit {
-                    timestamp: (Instant::now() - start).as_millis() as u32,
-                })
-                .unwrap();
+            debugger_running_gbc.store(true, Ordering::Relaxed);
+            run_debugger(gbc, debugger_script.as_deref());
+            debugger_running_gbc.store(false, Ordering::Relaxed);
+            push_quit_event(&event_sender, start);
         } else {
             let start = Instant::now();
-            let (cycles, encountered_problem) = gbc.run();
-            if encountered_problem {
-                println!("Encountered an unknown instruction, halting!");
+            let (cycles, error) = gbc.run();
+            if let Some(e) = error {
+                println!("Error: {}, halting!", e);
+            }
+            if let Some(stop) = gbc.take_trace_comparison_stop() {
+                println!("{}", stop);
             }
             let runtime = Instant::now() - start;
             let cpu_speed = gbc.get_clock_speed();
@@ -220,8 +1063,88 @@ fn main() {
         }
     });
 
+    let mut link_thread = None;
+    if let (Some(link_rom), Some(port)) = (link_rom, link_partner_port) {
+        let screen = if split_screen {
+            Screen::new(0, 160)
+        } else {
+            let window = video_subsystem
+                .window("Rust GBC Emu (player 2)", 800, 600)
+                .position_centered()
+                .build()
+                .unwrap();
+            canvases.push(window.into_canvas().build().unwrap());
+            Screen::new(1, 0)
+        };
+        let (_, rgba_layout) = texture_format(&canvases[screen.canvas]);
+
+        let gbc_running_gbc = gbc_running.clone();
+        let frame_sender_gbc = screen.frame_sender.clone();
+        let input_state_gbc = screen.input_state.clone();
+        let event_sender = sdl_context.event().unwrap().event_sender();
+        link_thread = Some(thread::spawn(move || {
+            let mut gbc = match Gbc::new(
+                &link_rom,
+                frame_sender_gbc,
+                gbc_running_gbc,
+                turbo,
+                false,
+                input_state_gbc,
+            ) {
+                Ok(gbc) => gbc,
+                Err(e) => {
+                    eprintln!("Error loading {}: {}", link_rom, e);
+                    push_quit_event(&event_sender, start);
+                    return;
+                }
+            };
+            for warning in &gbc.get_cartridge().warnings {
+                eprintln!("Warning: {}", warning);
+            }
+            // The second game only gets the settings from its own .cfg file
+            let game_config = GameConfig::load_for_rom(&link_rom);
+            gbc.set_soft_reset_combo(
+                game_config
+                    .soft_reset_combo
+                    .unwrap_or(SoftResetCombo::PassToGame),
+            );
+            gbc.set_save_ram_flush(
+                game_config
+                    .save_ram_flush
+                    .unwrap_or(SaveRamFlush::EverySeconds(1)),
+            );
+            gbc.set_rgba_layout(rgba_layout);
+            gbc.set_serial_device(Box::new(port));
+            let (_, error) = gbc.run();
+            if let Some(e) = error {
+                println!("Player 2 error: {}, halting!", e);
+            }
+            push_quit_event(&event_sender, start);
+        }));
+        screens.push(screen);
+    }
+
+    let controllers = Controllers::new(sdl_context.game_controller());
     let event_pump = sdl_context.event_pump().unwrap();
-    run(canvas, event_pump, debug, &framebuffer, &gbc_running, &input_state);
+    let options = FrontendOptions {
+        debugger_running: &debugger_running,
+        latency_probe: latency_probe.as_ref(),
+        show_timer,
+        key_bindings: key_bindings(&keymap),
+        autofire_rate,
+    };
+    run(
+        canvases,
+        &screens,
+        event_pump,
+        &gbc_running,
+        tile_viewer,
+        controllers,
+        &options,
+    );
 
     t.join().expect("Error joining");
+    if let Some(link_thread) = link_thread {
+        link_thread.join().expect("Error joining");
+    }
 }